@@ -9,6 +9,7 @@ in vec3 v_normal;
 in vec3 v_view_dir;
 
 uniform vec3 light_direction; // Uniform to control the light direction
+uniform bool highlight; // Set for bodies that need the user's attention
 out vec4 fragColor;
 // Function to create a pseudo-random value based on a 3D vector (v_normal)
 float random(vec3 value) {
@@ -50,5 +51,8 @@ void main() {
     // vec3 randomColor = vec3(r, g, b);
 
     // fragColor = vec4(randomColor, 1.0);
+    if (highlight) {
+        final_color = mix(final_color, vec3(1.0, 0.1, 0.1), 0.6);
+    }
     fragColor = vec4(final_color, 1.0);
 }
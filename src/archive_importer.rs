@@ -0,0 +1,374 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Importers for archived and compressed meshes.
+//!
+//! Every entry of a `.zip` archive with a mesh extension is read with the importer for its
+//! name, which may be an archive again. The UI lists the entries with [`list_meshes`] so
+//! the user can pick some. A gzip file holds a single mesh named like the file without
+//! `.gz`. Entries are inflated up to the size [`MAX_TRIANGLES`] triangles could take and
+//! archives are followed [`MAX_ARCHIVE_DEPTH`] levels deep, so a zip bomb is refused
+//! instead of exhausting memory.
+
+use crate::mesh_importer::{
+    self, ImportError, ImportProgress, ImportedMesh, MeshImporter, MAX_ARCHIVE_DEPTH, MAX_TRIANGLES,
+};
+use flate2::read::MultiGzDecoder;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek};
+use std::path::Path;
+use zip::ZipArchive;
+
+/// Most bytes a mesh format spends on a triangle, an ASCII STL facet with long numbers
+const MAX_TRIANGLE_SIZE: u64 = 256;
+/// Largest entry inflated, bigger ones are refused as too large
+pub const MAX_ENTRY_SIZE: u64 = MAX_TRIANGLES as u64 * MAX_TRIANGLE_SIZE;
+
+pub struct ZipImporter;
+
+impl MeshImporter for ZipImporter {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["zip"]
+    }
+
+    fn sniff(&self, header: &[u8], _file_size: u64) -> bool {
+        header.starts_with(b"PK\x03\x04")
+    }
+
+    fn read_bytes(&self, data: &[u8]) -> io::Result<Vec<ImportedMesh>> {
+        self.read_nested(data, 0)
+    }
+
+    fn read_nested(&self, data: &[u8], depth: usize) -> io::Result<Vec<ImportedMesh>> {
+        check_depth(depth)?;
+        read_all(&mut ZipArchive::new(Cursor::new(data))?, depth)
+    }
+
+    fn import(&self, path: &Path, progress: &ImportProgress) -> io::Result<Vec<ImportedMesh>> {
+        read_all(&mut ZipArchive::new(progress.open(path)?)?, 0)
+    }
+}
+
+pub struct GzipImporter;
+
+impl MeshImporter for GzipImporter {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["gz"]
+    }
+
+    fn sniff(&self, header: &[u8], _file_size: u64) -> bool {
+        header.starts_with(&[0x1f, 0x8b])
+    }
+
+    fn read_bytes(&self, data: &[u8]) -> io::Result<Vec<ImportedMesh>> {
+        self.read_nested(data, 0)
+    }
+
+    fn read_nested(&self, data: &[u8], depth: usize) -> io::Result<Vec<ImportedMesh>> {
+        check_depth(depth)?;
+        let (stored_name, data) = decompress(data, MAX_ENTRY_SIZE)?;
+        let name = stored_name.unwrap_or_else(|| "compressed mesh".to_string());
+        mesh_importer::read_named(&name, &data, depth + 1)
+    }
+
+    fn import(&self, path: &Path, progress: &ImportProgress) -> io::Result<Vec<ImportedMesh>> {
+        let (_, data) = decompress(progress.open(path)?, MAX_ENTRY_SIZE)?;
+        // part.stl.gz holds part.stl
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        mesh_importer::read_named(&name, &data, 1)
+    }
+}
+
+/// Whether the file is an archive the user picks meshes from
+pub fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+/// Names of the entries of an archive holding meshes, in the order they are stored
+pub fn list_meshes(path: &Path) -> io::Result<Vec<String>> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(path)?))?;
+    Ok(mesh_entries(&mut archive))
+}
+
+/// Reads the named entries of an archive, every entry gets its own result so one broken
+/// mesh doesn't stop the others
+pub fn import_entries(
+    path: &Path,
+    names: &[String],
+    progress: &ImportProgress,
+) -> io::Result<Vec<(String, io::Result<Vec<ImportedMesh>>)>> {
+    let mut archive = ZipArchive::new(progress.open(path)?)?;
+    Ok(names
+        .iter()
+        .map(|name| (name.clone(), read_entry(&mut archive, name, 0)))
+        .collect())
+}
+
+fn mesh_entries<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Vec<String> {
+    let mut names = Vec::new();
+    for i in 0..archive.len() {
+        let Ok(entry) = archive.by_index_raw(i) else {
+            continue;
+        };
+        let name = Path::new(entry.name());
+        // Resource forks added by macOS look like meshes but aren't
+        let hidden = name.starts_with("__MACOSX")
+            || name
+                .file_name()
+                .is_some_and(|file_name| file_name.to_string_lossy().starts_with("._"));
+        if entry.is_file() && !hidden && mesh_importer::is_mesh_file(name) {
+            names.push(entry.name().to_string());
+        }
+    }
+    names
+}
+
+/// Reads an entry of an archive found `depth` archives deep
+fn read_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+    depth: usize,
+) -> io::Result<Vec<ImportedMesh>> {
+    let data = read_limited(archive.by_name(name)?, MAX_ENTRY_SIZE)?;
+    mesh_importer::read_named(name, &data, depth + 1)
+}
+
+/// Reads every mesh of an archive found `depth` archives deep, named after their entries
+fn read_all<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    depth: usize,
+) -> io::Result<Vec<ImportedMesh>> {
+    let names = mesh_entries(archive);
+    if names.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The archive holds no meshes",
+        ));
+    }
+    let mut meshes = Vec::new();
+    for name in names {
+        let entry_meshes = read_entry(archive, &name, depth).map_err(|e| {
+            // Keep errors the importer reports as they are, e.g. TooLarge
+            if e.get_ref().is_some_and(|inner| inner.is::<ImportError>()) {
+                e
+            } else {
+                io::Error::new(e.kind(), format!("{}: {}", name, e))
+            }
+        })?;
+        meshes.extend(entry_meshes.into_iter().map(|mesh| ImportedMesh {
+            name: Some(match mesh.name {
+                Some(object) => format!("{} - {}", name, object),
+                None => name.clone(),
+            }),
+            geometry: mesh.geometry,
+        }));
+    }
+    Ok(meshes)
+}
+
+/// Refuses archives nested deeper than [`MAX_ARCHIVE_DEPTH`]
+fn check_depth(depth: usize) -> io::Result<()> {
+    if depth > MAX_ARCHIVE_DEPTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Archives are nested too deep",
+        ));
+    }
+    Ok(())
+}
+
+/// Reads up to `limit` bytes, failing with [`ImportError::TooLarge`] when there are more
+pub fn read_limited<R: Read>(reader: R, limit: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > limit {
+        return Err(io::Error::other(ImportError::TooLarge { triangles: None }));
+    }
+    Ok(data)
+}
+
+/// Decompresses up to `limit` bytes of gzip data, returning the file name stored in it,
+/// if any
+fn decompress<R: Read>(data: R, limit: u64) -> io::Result<(Option<String>, Vec<u8>)> {
+    let mut decoder = MultiGzDecoder::new(data);
+    let decompressed = read_limited(&mut decoder, limit)?;
+    let name = decoder
+        .header()
+        .and_then(|header| header.filename())
+        .map(|name| String::from_utf8_lossy(name).into_owned());
+    Ok((name, decompressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::{Compression, GzBuilder};
+    use std::fs;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    const TRIANGLE_STL: &str = "solid t\nfacet normal 0 0 1\nouter loop\n\
+        vertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\n\
+        endloop\nendfacet\nendsolid t\n";
+    const TWO_OBJECTS_OBJ: &str = "v 0 0 0\nv 1 0 0\nv 0 1 0\no Base\nf 1 2 3\no Lid\nf 1 3 2\n";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in entries {
+            if name.ends_with('/') {
+                zip.add_directory(*name, SimpleFileOptions::default())
+                    .unwrap();
+            } else {
+                zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+                zip.write_all(content).unwrap();
+            }
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_gzip() {
+        let progress = ImportProgress::default();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("part.stl.gz");
+        fs::write(&path, gzip(TRIANGLE_STL.as_bytes())).unwrap();
+
+        let importer = mesh_importer::importer_for(&path).unwrap();
+        let meshes = importer.import(&path, &progress).unwrap();
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].name, None);
+        assert_eq!(meshes[0].triangle_count(), 1);
+
+        // Without a file name the name stored in the gzip header picks the importer
+        let mut encoder = GzBuilder::new()
+            .filename("model.obj")
+            .write(Vec::new(), Compression::default());
+        encoder.write_all(TWO_OBJECTS_OBJ.as_bytes()).unwrap();
+        let meshes = GzipImporter.read_bytes(&encoder.finish().unwrap()).unwrap();
+        assert_eq!(meshes.len(), 2);
+    }
+
+    #[test]
+    fn test_zip() {
+        let progress = ImportProgress::default();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("pack.zip");
+        let nested = zip(&[("inner.stl", TRIANGLE_STL.as_bytes())]);
+        let compressed = gzip(TRIANGLE_STL.as_bytes());
+        fs::write(
+            &path,
+            zip(&[
+                ("readme.txt", b"Print at 0.05 mm"),
+                ("parts/", b""),
+                ("parts/a.stl", TRIANGLE_STL.as_bytes()),
+                ("__MACOSX/parts/._a.stl", b"\0\x05\x16\x07"),
+                ("parts/b.obj", TWO_OBJECTS_OBJ.as_bytes()),
+                ("c.stl.gz", &compressed),
+                ("nested.zip", &nested),
+            ]),
+        )
+        .unwrap();
+
+        assert!(is_archive(&path));
+        let names = list_meshes(&path).unwrap();
+        assert_eq!(
+            names,
+            ["parts/a.stl", "parts/b.obj", "c.stl.gz", "nested.zip"]
+        );
+
+        let picked = import_entries(&path, &names[1..3], &progress).unwrap();
+        assert_eq!(picked.len(), 2);
+        assert_eq!(picked[0].0, "parts/b.obj");
+        assert_eq!(picked[0].1.as_ref().unwrap().len(), 2);
+        assert_eq!(picked[1].1.as_ref().unwrap().len(), 1);
+
+        // Importing the whole archive names the meshes after their entries
+        let meshes = ZipImporter.import(&path, &progress).unwrap();
+        let mesh_names: Vec<_> = meshes
+            .iter()
+            .map(|mesh| mesh.name.as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            mesh_names,
+            [
+                "parts/a.stl",
+                "parts/b.obj - Base",
+                "parts/b.obj - Lid",
+                "c.stl.gz",
+                "nested.zip - inner.stl",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_broken_entry() {
+        let progress = ImportProgress::default();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("pack.zip");
+        fs::write(
+            &path,
+            zip(&[
+                ("good.stl", TRIANGLE_STL.as_bytes()),
+                ("bad.obj", b"v 0 0\n"),
+            ]),
+        )
+        .unwrap();
+
+        let picked = import_entries(&path, &list_meshes(&path).unwrap(), &progress).unwrap();
+        assert!(picked[0].1.is_ok());
+        assert_eq!(
+            picked[1].1.as_ref().unwrap_err().to_string(),
+            "Invalid vertex on line 1"
+        );
+
+        let error = ZipImporter.import(&path, &progress).unwrap_err();
+        assert_eq!(error.to_string(), "bad.obj: Invalid vertex on line 1");
+
+        let empty = zip(&[("readme.txt", b"")]);
+        let error = ZipImporter.read_bytes(&empty).unwrap_err();
+        assert_eq!(error.to_string(), "The archive holds no meshes");
+    }
+
+    #[test]
+    fn test_nesting_depth() {
+        let inner = zip(&[("part.stl", TRIANGLE_STL.as_bytes())]);
+        let nested = zip(&[("inner.zip", &inner)]);
+        assert_eq!(ZipImporter.read_bytes(&nested).unwrap().len(), 1);
+
+        let too_deep = zip(&[("nested.zip", &nested)]);
+        let error = ZipImporter.read_bytes(&too_deep).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "nested.zip: inner.zip: Archives are nested too deep"
+        );
+        // A zip compressed again counts as an archive level of its own
+        let compressed = zip(&[("inner.zip.gz", &gzip(&inner))]);
+        let error = ZipImporter.read_bytes(&compressed).unwrap_err();
+        assert!(error.to_string().ends_with("Archives are nested too deep"));
+    }
+
+    #[test]
+    fn test_inflating_past_the_limit() {
+        let zeros = gzip(&[0; 4096]);
+        assert_eq!(decompress(&zeros[..], 4096).unwrap().1.len(), 4096);
+
+        let error = ImportError::from(decompress(&zeros[..], 4095).unwrap_err());
+        assert!(matches!(error, ImportError::TooLarge { triangles: None }));
+
+        let mut archive = ZipArchive::new(Cursor::new(zip(&[("zeros.stl", &[0; 4096])]))).unwrap();
+        let error = read_limited(archive.by_name("zeros.stl").unwrap(), 1000).unwrap_err();
+        assert!(matches!(
+            ImportError::from(error),
+            ImportError::TooLarge { triangles: None }
+        ));
+    }
+}
@@ -0,0 +1,271 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Imports running on worker threads so large files don't block the UI.
+//!
+//! Every file, or set of entries picked from an archive, is imported by an [`ImportTask`]
+//! on a thread of its own, so several files load in parallel. The meshes are repaired there
+//! too when asked to. The UI polls the tasks for their progress and turns the bodies of
+//! finished ones into scene objects.
+
+use crate::archive_importer;
+use crate::body::Body;
+use crate::mesh::{WeldStats, DEFAULT_WELD_TOLERANCE};
+use crate::mesh_importer::{self, ImportError, ImportProgress};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// How the meshes of a task are imported
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImportOptions {
+    /// Repair the meshes once they are imported
+    pub repair: bool,
+    /// Distance below which corners of triangles become one vertex
+    pub weld_tolerance: f32,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            repair: false,
+            weld_tolerance: DEFAULT_WELD_TOLERANCE,
+        }
+    }
+}
+
+/// What became of a file or archive entry
+pub struct ImportResult {
+    pub source: String,
+    pub bodies: Result<Vec<Body>, ImportError>,
+    /// What the repair changed, a line for every body it changed
+    pub repairs: Vec<String>,
+}
+
+impl ImportResult {
+    fn new(source: String, bodies: Result<Vec<Body>, ImportError>, repair: bool) -> Self {
+        let mut result = Self {
+            source,
+            bodies,
+            repairs: Vec::new(),
+        };
+        if let (true, Ok(bodies)) = (repair, &mut result.bodies) {
+            for body in bodies {
+                let report = body.repair();
+                if !report.is_empty() {
+                    result.repairs.push(format!("{}: {}", body.name, report));
+                }
+            }
+        }
+        result
+    }
+}
+
+pub type ImportResults = Vec<ImportResult>;
+
+pub struct ImportTask {
+    /// Name of the file shown while the task runs
+    pub name: String,
+    progress: Arc<ImportProgress>,
+    handle: JoinHandle<ImportResults>,
+}
+
+impl ImportTask {
+    /// Imports a mesh file
+    pub fn file(path: PathBuf, options: ImportOptions) -> Self {
+        let name = file_name(&path);
+        let source = name.clone();
+        Self::spawn(name, move |progress| {
+            let bodies = mesh_importer::importer_for(&path)
+                .map_err(ImportError::from)
+                .and_then(|importer| {
+                    Body::import(&path, importer, options.weld_tolerance, progress)
+                });
+            vec![ImportResult::new(source, bodies, options.repair)]
+        })
+    }
+
+    /// Imports the named entries of an archive, each gets a result of its own
+    pub fn archive_entries(path: PathBuf, entries: Vec<String>, options: ImportOptions) -> Self {
+        Self::spawn(file_name(&path), move |progress| {
+            import_archive_entries(&path, &entries, options, progress)
+        })
+    }
+
+    fn spawn(
+        name: String,
+        import: impl FnOnce(&ImportProgress) -> ImportResults + Send + 'static,
+    ) -> Self {
+        let progress = Arc::new(ImportProgress::default());
+        let worker_progress = Arc::clone(&progress);
+        let handle = thread::spawn(move || import(&worker_progress));
+        Self {
+            name,
+            progress,
+            handle,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Asks the worker to stop, it finishes with [`ImportError::Cancelled`]
+    pub fn cancel(&self) {
+        self.progress.cancel();
+    }
+
+    /// What welding did to the meshes imported so far
+    pub fn weld_stats(&self) -> WeldStats {
+        self.progress.weld_stats()
+    }
+
+    /// Describes how far the import got, for the status line
+    pub fn status(&self) -> String {
+        let welded = self.progress.vertices_welded();
+        if welded > 0 {
+            format!("{}: {} vertices welded", self.name, welded)
+        } else {
+            format!(
+                "{}: {:.0}% read",
+                self.name,
+                self.progress.fraction_read() * 100.0
+            )
+        }
+    }
+
+    /// Waits for the worker and takes its results. A panicking importer is reported as
+    /// an error of the task instead of taking the UI down with it.
+    pub fn join(self) -> ImportResults {
+        let name = self.name;
+        self.handle.join().unwrap_or_else(|_| {
+            vec![ImportResult::new(
+                name,
+                Err(ImportError::Parse("The importer crashed".to_string())),
+                false,
+            )]
+        })
+    }
+}
+
+fn import_archive_entries(
+    path: &Path,
+    entries: &[String],
+    options: ImportOptions,
+    progress: &ImportProgress,
+) -> ImportResults {
+    match archive_importer::import_entries(path, entries, progress) {
+        Ok(imported) => imported
+            .into_iter()
+            .map(|(entry, meshes)| {
+                let bodies = meshes
+                    .map_err(|e| progress.read_error(e))
+                    .and_then(|meshes| {
+                        Body::from_imported(&entry, meshes, options.weld_tolerance, progress)
+                    });
+                ImportResult::new(entry, bodies, options.repair)
+            })
+            .collect(),
+        Err(e) => vec![ImportResult::new(
+            file_name(path),
+            Err(progress.read_error(e)),
+            false,
+        )],
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stl_processor::StlProcessor;
+    use std::fs;
+    use std::io::{Cursor, Write};
+    use std::time::Duration;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    const TRIANGLE_STL: &str = "solid t\nfacet normal 0 0 1\nouter loop\n\
+        vertex 0 0 0\nvertex 1 0 0\nvertex 0 1 0\n\
+        endloop\nendfacet\nendsolid t\n";
+
+    #[test]
+    fn test_files_import_in_parallel() {
+        let directory = tempfile::tempdir().unwrap();
+        let tasks: Vec<ImportTask> = ["a.stl", "b.stl", "broken.obj"]
+            .iter()
+            .map(|name| {
+                let path = directory.path().join(name);
+                let content = if name.ends_with(".stl") {
+                    TRIANGLE_STL
+                } else {
+                    "f 1 2 3\n"
+                };
+                fs::write(&path, content).unwrap();
+                let options = ImportOptions {
+                    repair: *name == "b.stl",
+                    ..ImportOptions::default()
+                };
+                ImportTask::file(path, options)
+            })
+            .collect();
+
+        let results: Vec<_> = tasks.into_iter().flat_map(ImportTask::join).collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].source, "a.stl");
+        assert_eq!(results[0].bodies.as_ref().unwrap()[0].name, "a.stl");
+        assert!(results[0].repairs.is_empty());
+        // The lone triangle of b.stl is closed by a second one facing the other way
+        assert_eq!(results[1].bodies.as_ref().unwrap()[0].mesh.indices.len(), 6);
+        assert_eq!(results[1].repairs, ["b.stl: 1 holes filled"]);
+        assert!(matches!(results[2].bodies, Err(ImportError::Parse(_))));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("part.stl");
+        fs::write(&path, TRIANGLE_STL).unwrap();
+
+        // Cancelled before the worker reads anything
+        let task = ImportTask::spawn("part.stl".to_string(), move |progress| {
+            progress.cancel();
+            vec![ImportResult::new(
+                "part.stl".to_string(),
+                Body::import(&path, &StlProcessor, DEFAULT_WELD_TOLERANCE, progress),
+                false,
+            )]
+        });
+        while !task.is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let results = task.join();
+        assert!(matches!(results[0].bodies, Err(ImportError::Cancelled)));
+    }
+
+    #[test]
+    fn test_cancel_archive() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("pack.zip");
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for name in ["a.stl", "b.stl"] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(TRIANGLE_STL.as_bytes()).unwrap();
+        }
+        fs::write(&path, zip.finish().unwrap().into_inner()).unwrap();
+        let entries = vec!["a.stl".to_string(), "b.stl".to_string()];
+
+        let progress = ImportProgress::default();
+        progress.cancel();
+        let results = import_archive_entries(&path, &entries, ImportOptions::default(), &progress);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].source, "pack.zip");
+        assert!(matches!(results[0].bodies, Err(ImportError::Cancelled)));
+    }
+}
@@ -2,19 +2,21 @@
 // Distributed under the GNU Affero General Public License v3.0 or later.
 // See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
 
-use std::ffi::OsStr;
 use std::path::Path;
 
 use crate::mesh::Mesh;
-use crate::stl_processor::StlProcessorTrait;
-use nalgebra::{Matrix4, Quaternion, UnitQuaternion, Vector3};
+use crate::mesh_analysis::{MeshAnalysis, Topology};
+use crate::mesh_importer::{
+    Geometry, ImportError, ImportProgress, ImportedMesh, MeshImporter, MAX_TRIANGLES,
+};
+use crate::mesh_repair::{self, RepairReport};
+use nalgebra::{Matrix4, Point3, Quaternion, UnitQuaternion, Vector3};
 use slint::SharedString;
 use uuid::Uuid;
-#[allow(dead_code)]
-#[derive(Default)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
 pub struct AABB {
-    min: Vector3<f32>,
-    max: Vector3<f32>,
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
 }
 
 impl AABB {
@@ -69,6 +71,11 @@ pub struct Body {
     pub visible: bool,
     pub uuid: Uuid,
     pub aabb: AABB,
+    pub has_placement_issue: bool,
+    /// Topology of the mesh, worked out whenever the mesh is made or repaired as it takes
+    /// long on large meshes, and its analysis for the model matrix it was last made for
+    topology: Topology,
+    analysis: Option<(Matrix4<f32>, MeshAnalysis)>,
 }
 
 impl Default for Body {
@@ -84,6 +91,9 @@ impl Default for Body {
             visible: true,
             uuid: Uuid::new_v4(),
             aabb: AABB::default(),
+            has_placement_issue: false,
+            topology: Topology::default(),
+            analysis: None,
         }
     }
 }
@@ -96,8 +106,11 @@ impl PartialEq for Body {
 }
 
 impl Body {
+    /// Body of the mesh, working out its topology. That is slow for large meshes, imports
+    /// make their bodies on a worker thread for it.
     pub fn new(mesh: Mesh) -> Self {
         let mut b = Body::default();
+        b.topology = Topology::of(&mesh);
         b.mesh = mesh;
         b
     }
@@ -111,20 +124,135 @@ impl Body {
         self.uuid.to_string() == *other.to_string()
     }
 
-    pub fn new_from_stl<P: AsRef<OsStr>, Processor: StlProcessorTrait>(
-        filename: P,
-        processor: &Processor,
-    ) -> Self {
-        let mut body = Body::default();
-        let path = Path::new(filename.as_ref());
-        body.name = path
+    /// Imports every mesh in the file as a body of its own, named after the file and
+    /// the object it came from
+    pub fn import<Importer: MeshImporter + ?Sized>(
+        path: &Path,
+        importer: &Importer,
+        weld_tolerance: f32,
+        progress: &ImportProgress,
+    ) -> Result<Vec<Self>, ImportError> {
+        let file_name = path
             .file_name()
             .unwrap_or_default()
             .to_string_lossy()
             .into_owned();
-        body.mesh.import_stl(filename, processor);
-        body.aabb = AABB::from_vertices(&body.mesh.vertices);
-        body
+        let meshes = importer
+            .import(path, progress)
+            .map_err(|e| progress.read_error(e))?;
+        Body::from_imported(&file_name, meshes, weld_tolerance, progress)
+    }
+
+    /// Builds a body from every mesh of the file or archive entry named `source`, welding
+    /// corners of triangles closer than `weld_tolerance`. Meshes without triangles are left
+    /// out, it is an error when none remain.
+    pub fn from_imported(
+        source: &str,
+        meshes: Vec<ImportedMesh>,
+        weld_tolerance: f32,
+        progress: &ImportProgress,
+    ) -> Result<Vec<Self>, ImportError> {
+        let triangles = meshes.iter().map(ImportedMesh::triangle_count).sum();
+        if triangles > MAX_TRIANGLES {
+            return Err(ImportError::TooLarge {
+                triangles: Some(triangles),
+            });
+        }
+        let bodies: Vec<Self> = meshes
+            .into_iter()
+            .filter(|imported| imported.triangle_count() > 0)
+            .map(|imported| {
+                let mesh = match imported.geometry {
+                    Geometry::Triangles(triangles) => {
+                        Mesh::from_triangles(triangles, weld_tolerance, progress)
+                    }
+                    Geometry::Indexed {
+                        positions,
+                        normals,
+                        indices,
+                    } => Mesh::from_indexed(positions, normals, indices, progress),
+                };
+                let mut body = Body::new(mesh);
+                body.name = match imported.name {
+                    Some(name) => format!("{} - {}", source, name),
+                    None => source.to_string(),
+                };
+                body
+            })
+            // Only degenerate triangles leave nothing to print
+            .filter(|body| !body.mesh.indices.is_empty())
+            .map(|mut body| {
+                body.aabb = AABB::from_vertices(&body.mesh.vertices);
+                body
+            })
+            .collect();
+        if progress.is_cancelled() {
+            return Err(ImportError::Cancelled);
+        }
+        if bodies.is_empty() {
+            return Err(ImportError::EmptyMesh);
+        }
+        Ok(bodies)
+    }
+
+    /// Repairs the mesh, see [`mesh_repair::repair`]
+    pub fn repair(&mut self) -> RepairReport {
+        let report = mesh_repair::repair(&mut self.mesh);
+        self.aabb = AABB::from_vertices(&self.mesh.vertices);
+        self.topology = Topology::of(&self.mesh);
+        self.analysis = None;
+        report
+    }
+
+    /// Analysis of the mesh as placed, kept until the mesh or its placement changes
+    pub fn analysis(&mut self) -> &MeshAnalysis {
+        let model_matrix = self.get_model_matrix();
+        if !matches!(&self.analysis, Some((matrix, _)) if *matrix == model_matrix) {
+            let analysis = MeshAnalysis::new(&self.mesh, &model_matrix, self.topology);
+            self.analysis = Some((model_matrix, analysis));
+        }
+        &self.analysis.as_ref().unwrap().1
+    }
+
+    /// Model info shown for the body, as of the last [`Body::analysis`]
+    pub fn model_info(&self) -> String {
+        self.analysis
+            .as_ref()
+            .map(|(_, analysis)| analysis.to_string())
+            .unwrap_or_default()
+    }
+
+    /// Splits the body into a body per part of its mesh, numbered after it. Every part is
+    /// moved to be centred on its own origin, keeping its place in the scene. Returns no
+    /// bodies when the mesh is in one piece.
+    pub fn split_into_parts(&self) -> Vec<Body> {
+        let parts = self.mesh.split_parts();
+        if parts.len() < 2 {
+            return Vec::new();
+        }
+        let model_matrix = self.get_model_matrix();
+        parts
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut mesh)| {
+                let bounds = AABB::from_vertices(&mesh.vertices);
+                let centre = (bounds.min + bounds.max) / 2.0;
+                for vertex in &mut mesh.vertices {
+                    vertex.position = (Vector3::from(vertex.position) - centre).into();
+                }
+                mesh.ready_for_slicing();
+                let mut body = Body::new(mesh);
+                body.name = format!("{} - part {}", self.name, i + 1);
+                body.position = model_matrix.transform_point(&Point3::from(centre)).coords;
+                body.rotation = self.rotation;
+                body.scale = self.scale;
+                body.enabled = self.enabled;
+                body.visible = self.visible;
+                body.selected = self.selected;
+                body.aabb = AABB::from_vertices(&body.mesh.vertices);
+                body
+            })
+            .collect()
     }
 
     pub fn get_model_matrix(&self) -> Matrix4<f32> {
@@ -140,6 +268,21 @@ impl Body {
         model *= Matrix4::new_nonuniform_scaling(&self.scale);
         model
     }
+
+    /// Axis aligned bounding box of the mesh after applying the model matrix.
+    /// Returns None if the body has no vertices.
+    pub fn world_aabb(&self) -> Option<AABB> {
+        let model_matrix = self.get_model_matrix();
+        let mut vertices = self.mesh.vertices.iter().map(|vertex| {
+            model_matrix
+                .transform_point(&Point3::from(vertex.position))
+                .coords
+        });
+        let first = vertices.next()?;
+        let (min, max) = vertices.fold((first, first), |(min, max), v| (min.inf(&v), max.sup(&v)));
+        Some(AABB { min, max })
+    }
+
     #[allow(dead_code)]
     pub fn translate(&mut self, val: Vector3<f32>) {
         self.position += val;
@@ -218,30 +361,71 @@ impl Body {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::mesh::Vertex;
-    use crate::stl_processor::StlProcessorTrait;
+    use crate::mesh::{Vertex, DEFAULT_WELD_TOLERANCE};
     use approx::relative_eq;
     use nalgebra::{Matrix4, UnitQuaternion, Vector3};
     use stl_io::Triangle;
 
     const EPSILON: f32 = 1e-4;
 
-    // Mock implementation of StlProcessorTrait without cloning
+    // Mock importer returning a unit square without touching the file system
     struct MockStlProcessor;
 
-    impl StlProcessorTrait for MockStlProcessor {
-        fn read_stl(&self, _filename: &OsStr) -> Result<Vec<Triangle>, std::io::Error> {
-            Ok(vec![
-                create_triangle([0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
-                create_triangle([1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]),
-            ])
+    impl MeshImporter for MockStlProcessor {
+        fn extensions(&self) -> &'static [&'static str] {
+            &["stl"]
+        }
+
+        fn read_bytes(&self, _data: &[u8]) -> Result<Vec<ImportedMesh>, std::io::Error> {
+            unreachable!("Bodies are imported from paths")
+        }
+
+        fn import(
+            &self,
+            _path: &Path,
+            _progress: &ImportProgress,
+        ) -> Result<Vec<ImportedMesh>, std::io::Error> {
+            Ok(vec![ImportedMesh::from_triangles(
+                None,
+                vec![
+                    create_triangle([0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+                    create_triangle([1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]),
+                ],
+            )])
+        }
+    }
+
+    // Mock importer returning two named objects
+    struct MockObjImporter;
+
+    impl MeshImporter for MockObjImporter {
+        fn extensions(&self) -> &'static [&'static str] {
+            &["obj"]
+        }
+
+        fn read_bytes(&self, _data: &[u8]) -> Result<Vec<ImportedMesh>, std::io::Error> {
+            unreachable!("Bodies are imported from paths")
+        }
+
+        fn import(
+            &self,
+            _path: &Path,
+            _progress: &ImportProgress,
+        ) -> Result<Vec<ImportedMesh>, std::io::Error> {
+            let mesh = |name: &str, z: f32| {
+                ImportedMesh::from_triangles(
+                    Some(name.to_string()),
+                    vec![create_triangle([0.0, 0.0, z], [1.0, 0.0, z], [0.0, 1.0, z])],
+                )
+            };
+            Ok(vec![mesh("Base", 0.0), mesh("Lid", 2.0)])
         }
     }
 
     // Helper function to create a triangle
     fn create_triangle(v0: [f32; 3], v1: [f32; 3], v2: [f32; 3]) -> Triangle {
         Triangle {
-            normal: [0.0, 0.0, 1.0], // Placeholder; Mesh::import_stl will recalculate normals
+            normal: [0.0, 0.0, 1.0], // Placeholder; Mesh::from_triangles will recalculate normals
             vertices: [v0, v1, v2],
         }
     }
@@ -280,12 +464,21 @@ mod tests {
     }
 
     #[test]
-    fn test_new_from_stl() {
+    fn test_import() {
         // Arrange: Create a mock processor
         let mock_processor = MockStlProcessor;
 
         // Act: Create Body from STL using mock processor
-        let body = Body::new_from_stl("dummy_filename.stl", &mock_processor);
+        let mut bodies = Body::import(
+            Path::new("dummy_filename.stl"),
+            &mock_processor,
+            DEFAULT_WELD_TOLERANCE,
+            &ImportProgress::default(),
+        )
+        .expect("Mock import should succeed");
+        assert_eq!(bodies.len(), 1);
+        let body = bodies.remove(0);
+        assert_eq!(body.name, "dummy_filename.stl");
 
         // Additionally, check that vertices and indices are generated correctly
         let expected_vertices = vec![
@@ -341,6 +534,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_import_objects_as_bodies() {
+        let progress = ImportProgress::default();
+        let bodies = Body::import(
+            Path::new("models/box.obj"),
+            &MockObjImporter,
+            DEFAULT_WELD_TOLERANCE,
+            &progress,
+        )
+        .expect("Mock import should succeed");
+
+        let names: Vec<&str> = bodies.iter().map(|body| body.name.as_str()).collect();
+        assert_eq!(names, ["box.obj - Base", "box.obj - Lid"]);
+        assert_eq!(bodies[1].aabb.min, Vector3::new(0.0, 0.0, 2.0));
+        assert_ne!(bodies[0].uuid, bodies[1].uuid);
+    }
+
+    #[test]
+    fn test_import_without_triangles() {
+        // An empty mesh is left out, a file with only empty or flat meshes is an error
+        let flat = create_triangle([0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [2.0, 0.0, 0.0]);
+        let progress = ImportProgress::default();
+        let square = MockStlProcessor
+            .import(Path::new("square.stl"), &progress)
+            .unwrap();
+        let mut meshes = vec![ImportedMesh::from_triangles(None, Vec::new())];
+        meshes.extend(square);
+        let bodies =
+            Body::from_imported("parts.obj", meshes, DEFAULT_WELD_TOLERANCE, &progress).unwrap();
+        assert_eq!(bodies.len(), 1);
+
+        for meshes in [
+            Vec::new(),
+            vec![ImportedMesh::from_triangles(None, vec![flat])],
+        ] {
+            let error = Body::from_imported("flat.stl", meshes, DEFAULT_WELD_TOLERANCE, &progress)
+                .err()
+                .unwrap();
+            assert!(matches!(error, ImportError::EmptyMesh));
+        }
+    }
+
     #[test]
     fn test_get_model_matrix() {
         // Arrange: Create a Body with known position, rotation, and scale
@@ -365,6 +600,9 @@ mod tests {
             visible: true,
             uuid: Uuid::new_v4(),
             aabb: AABB::default(),
+            has_placement_issue: false,
+            topology: Topology::default(),
+            analysis: None,
         };
 
         // Act: Compute the model matrix
@@ -396,6 +634,88 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_world_aabb() {
+        let mock_processor = MockStlProcessor;
+        let mut body = Body::import(
+            Path::new("dummy_filename.stl"),
+            &mock_processor,
+            DEFAULT_WELD_TOLERANCE,
+            &ImportProgress::default(),
+        )
+        .unwrap()
+        .remove(0);
+        body.set_position(Vector3::new(10.0, 0.0, 5.0));
+        body.set_scale(Vector3::new(2.0, 3.0, 1.0));
+        body.set_rotation(Vector3::new(0.0, 0.0, 90.0));
+
+        let aabb = body
+            .world_aabb()
+            .expect("Body with vertices should have bounds");
+
+        // The unit square is scaled to 2x3 and then rotated a quarter turn about Z
+        assert!((aabb.min - Vector3::new(7.0, 0.0, 5.0)).norm() < EPSILON);
+        assert!((aabb.max - Vector3::new(10.0, 2.0, 5.0)).norm() < EPSILON);
+    }
+
+    #[test]
+    fn test_world_aabb_empty_mesh() {
+        assert!(Body::default().world_aabb().is_none());
+    }
+
+    #[test]
+    fn test_split_into_parts() {
+        let corners = [
+            [0.0, 0.0, 0.0],
+            [2.0, 0.0, 0.0],
+            [0.0, 2.0, 0.0],
+            [10.0, 0.0, 4.0],
+            [12.0, 0.0, 4.0],
+            [10.0, 2.0, 4.0],
+        ];
+        let mut body = Body::new(Mesh {
+            vertices: corners
+                .iter()
+                .map(|&position| Vertex::new(position, [0.0, 0.0, 1.0]))
+                .collect(),
+            indices: vec![0, 1, 2, 3, 4, 5],
+            ..Mesh::default()
+        });
+        body.name = "parts.stl".to_string();
+        body.set_position(Vector3::new(10.0, 0.0, 5.0));
+        body.set_scale(Vector3::new(2.0, 3.0, 1.0));
+        body.set_rotation(Vector3::new(0.0, 0.0, 90.0));
+        let world = |body: &Body| -> Vec<Vector3<f32>> {
+            let model_matrix = body.get_model_matrix();
+            body.mesh
+                .indices
+                .iter()
+                .map(|&i| {
+                    let position = body.mesh.vertices[i as usize].position;
+                    model_matrix.transform_point(&Point3::from(position)).coords
+                })
+                .collect()
+        };
+
+        assert_eq!(body.topology.shells, 2);
+        let parts = body.split_into_parts();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].topology.shells, 1);
+        assert_eq!(parts[1].name, "parts.stl - part 2");
+        let placed: Vec<Vector3<f32>> = parts.iter().flat_map(world).collect();
+        for (part, expected) in placed.iter().zip(world(&body)) {
+            assert!((part - expected).norm() < EPSILON);
+        }
+        // Each part is centred on its own origin
+        for part in &parts {
+            assert!((part.aabb.min + part.aabb.max).norm() < EPSILON);
+            assert_eq!(part.rotation, body.rotation);
+            assert_eq!(part.scale, body.scale);
+        }
+
+        assert!(parts[0].split_into_parts().is_empty());
+    }
+
     #[test]
     fn test_ray_intersects_aabb() {
         let aabb = AABB {
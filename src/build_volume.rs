@@ -0,0 +1,237 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+use crate::body::{Body, AABB};
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use uuid::Uuid;
+
+/// How far in millimeters a body may sit above or sink below the plate and still count as touching it
+const PLATE_TOLERANCE: f32 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlacementIssue {
+    OutsideXY,
+    AboveMaxZ,
+    BelowPlate,
+    Floating,
+}
+
+impl PlacementIssue {
+    /// Blocking issues make the print impossible, the rest are only warnings
+    pub fn is_blocking(&self) -> bool {
+        !matches!(self, PlacementIssue::Floating)
+    }
+}
+
+impl fmt::Display for PlacementIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            PlacementIssue::OutsideXY => "extends outside the printable area",
+            PlacementIssue::AboveMaxZ => "is taller than the maximum build height",
+            PlacementIssue::BelowPlate => "extends below the build plate",
+            PlacementIssue::Floating => "is not touching the build plate",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlacementReport {
+    pub uuid: Uuid,
+    pub name: String,
+    pub issues: Vec<PlacementIssue>,
+}
+
+impl PlacementReport {
+    pub fn is_blocking(&self) -> bool {
+        self.issues.iter().any(PlacementIssue::is_blocking)
+    }
+}
+
+impl fmt::Display for PlacementReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let issues: Vec<String> = self.issues.iter().map(|i| i.to_string()).collect();
+        write!(f, "{} {}", self.name, issues.join(", "))
+    }
+}
+
+/// The printable space of a printer. The plate is centered on the origin in XY
+/// and its surface is at Z = 0. Sizes are in millimeters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BuildVolume {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl BuildVolume {
+    pub fn new(x: f32, y: f32, z: f32) -> Self {
+        Self { x, y, z }
+    }
+
+    /// Checks a world space bounding box against the volume
+    pub fn check_bounds(&self, aabb: &AABB) -> Vec<PlacementIssue> {
+        let mut issues = Vec::new();
+        let half_x = self.x / 2.0;
+        let half_y = self.y / 2.0;
+
+        if aabb.min.x < -half_x
+            || aabb.max.x > half_x
+            || aabb.min.y < -half_y
+            || aabb.max.y > half_y
+        {
+            issues.push(PlacementIssue::OutsideXY);
+        }
+        if aabb.max.z > self.z {
+            issues.push(PlacementIssue::AboveMaxZ);
+        }
+        if aabb.min.z < -PLATE_TOLERANCE {
+            issues.push(PlacementIssue::BelowPlate);
+        } else if aabb.min.z > PLATE_TOLERANCE {
+            issues.push(PlacementIssue::Floating);
+        }
+        issues
+    }
+
+    /// Validates every body and flags the ones with problems so the renderer can highlight them.
+    /// Only bodies with at least one issue are reported.
+    pub fn validate_bodies(&self, bodies: &[Rc<RefCell<Body>>]) -> Vec<PlacementReport> {
+        let mut reports = Vec::new();
+        for body_rc in bodies {
+            let mut body = body_rc.borrow_mut();
+            let issues = match body.world_aabb() {
+                Some(aabb) => self.check_bounds(&aabb),
+                None => Vec::new(),
+            };
+            body.has_placement_issue = !issues.is_empty();
+            if !issues.is_empty() {
+                reports.push(PlacementReport {
+                    uuid: body.uuid,
+                    name: body.name.clone(),
+                    issues,
+                });
+            }
+        }
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::{Mesh, Vertex};
+    use nalgebra::Vector3;
+
+    fn volume() -> BuildVolume {
+        BuildVolume::new(100.0, 50.0, 80.0)
+    }
+
+    fn aabb(min: [f32; 3], max: [f32; 3]) -> AABB {
+        AABB {
+            min: Vector3::from(min),
+            max: Vector3::from(max),
+        }
+    }
+
+    // A 10mm cube sitting on the plate, centered on the origin
+    fn cube_body() -> Rc<RefCell<Body>> {
+        let mut vertices = Vec::new();
+        for x in [-5.0, 5.0] {
+            for y in [-5.0, 5.0] {
+                for z in [0.0, 10.0] {
+                    vertices.push(Vertex::new([x, y, z], [0.0, 0.0, 1.0]));
+                }
+            }
+        }
+        let mesh = Mesh {
+            vertices,
+            indices: Vec::new(),
+            triangles_for_slicing: Vec::new(),
+        };
+        let mut body = Body::new(mesh);
+        body.name = "cube".to_string();
+        Rc::new(RefCell::new(body))
+    }
+
+    #[test]
+    fn test_body_inside_volume() {
+        let issues = volume().check_bounds(&aabb([-10.0, -10.0, 0.0], [10.0, 10.0, 20.0]));
+        assert!(issues.is_empty(), "Unexpected issues: {:?}", issues);
+    }
+
+    #[test]
+    fn test_body_outside_xy() {
+        let issues = volume().check_bounds(&aabb([40.0, 0.0, 0.0], [60.0, 10.0, 10.0]));
+        assert_eq!(issues, vec![PlacementIssue::OutsideXY]);
+
+        let issues = volume().check_bounds(&aabb([0.0, -30.0, 0.0], [10.0, 0.0, 10.0]));
+        assert_eq!(issues, vec![PlacementIssue::OutsideXY]);
+    }
+
+    #[test]
+    fn test_body_too_tall() {
+        let issues = volume().check_bounds(&aabb([0.0, 0.0, 0.0], [10.0, 10.0, 81.0]));
+        assert_eq!(issues, vec![PlacementIssue::AboveMaxZ]);
+    }
+
+    #[test]
+    fn test_body_below_plate() {
+        let issues = volume().check_bounds(&aabb([0.0, 0.0, -1.0], [10.0, 10.0, 10.0]));
+        assert_eq!(issues, vec![PlacementIssue::BelowPlate]);
+        assert!(issues[0].is_blocking());
+    }
+
+    #[test]
+    fn test_body_floating() {
+        let issues = volume().check_bounds(&aabb([0.0, 0.0, 2.0], [10.0, 10.0, 12.0]));
+        assert_eq!(issues, vec![PlacementIssue::Floating]);
+        assert!(!issues[0].is_blocking());
+    }
+
+    #[test]
+    fn test_validate_bodies_uses_world_transform() {
+        let on_plate = cube_body();
+        let moved_off_plate = cube_body();
+        moved_off_plate
+            .borrow_mut()
+            .set_position(Vector3::new(48.0, 0.0, 0.0));
+        let scaled_too_tall = cube_body();
+        scaled_too_tall
+            .borrow_mut()
+            .set_scale(Vector3::new(1.0, 1.0, 10.0));
+
+        let bodies = vec![
+            Rc::clone(&on_plate),
+            Rc::clone(&moved_off_plate),
+            Rc::clone(&scaled_too_tall),
+        ];
+        let reports = volume().validate_bodies(&bodies);
+
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].uuid, moved_off_plate.borrow().uuid);
+        assert_eq!(reports[0].issues, vec![PlacementIssue::OutsideXY]);
+        assert_eq!(reports[1].uuid, scaled_too_tall.borrow().uuid);
+        assert_eq!(reports[1].issues, vec![PlacementIssue::AboveMaxZ]);
+        assert!(reports.iter().all(PlacementReport::is_blocking));
+
+        assert!(!on_plate.borrow().has_placement_issue);
+        assert!(moved_off_plate.borrow().has_placement_issue);
+        assert!(scaled_too_tall.borrow().has_placement_issue);
+    }
+
+    #[test]
+    fn test_validate_bodies_clears_fixed_issues() {
+        let body = cube_body();
+        body.borrow_mut().set_position(Vector3::new(0.0, 0.0, 5.0));
+        let bodies = vec![Rc::clone(&body)];
+
+        assert_eq!(volume().validate_bodies(&bodies).len(), 1);
+        assert!(body.borrow().has_placement_issue);
+
+        body.borrow_mut().set_position(Vector3::zeros());
+        assert!(volume().validate_bodies(&bodies).is_empty());
+        assert!(!body.borrow().has_placement_issue);
+    }
+}
@@ -0,0 +1,62 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Helpers shared by the readers of little endian printer files.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Reads `size` bytes at `offset`. The bytes are read before allocating for all of them,
+/// so a corrupt size fails with an error instead of a huge allocation.
+pub fn read_at<R: Read + Seek>(input: &mut R, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    input.seek(SeekFrom::Start(offset))?;
+    let mut bytes = Vec::new();
+    input.take(size as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Section extends past the end of the file",
+        ));
+    }
+    Ok(bytes)
+}
+
+pub fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn f32_at(bytes: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Widens a stored value to the shortest decimal that reads back as the same `f32`,
+/// so a layer height of 0.05 stays 0.05 instead of 0.05000000074505806.
+pub fn to_f64(value: f32) -> f64 {
+    value.to_string().parse().unwrap_or(value as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_to_f64_keeps_the_decimal() {
+        assert_eq!(to_f64(0.05), 0.05);
+        assert_eq!(to_f64(218.88), 218.88);
+    }
+
+    #[test]
+    fn test_read_at() {
+        let mut input = Cursor::new((0..16).collect::<Vec<u8>>());
+        let bytes = read_at(&mut input, 4, 8).unwrap();
+        assert_eq!(u32_at(&bytes, 0), 0x0706_0504);
+        assert_eq!(u16_at(&bytes, 6), 0x0B0A);
+
+        let error = read_at(&mut input, 12, usize::MAX).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
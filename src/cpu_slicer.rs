@@ -41,6 +41,10 @@ impl CPUSlicer {
         }
     }
 
+    pub fn slice_thickness(&self) -> f64 {
+        self.slice_thickness
+    }
+
     pub fn slice_bodies(
         &self,
         bodies: Vec<Rc<RefCell<Body>>>,
@@ -0,0 +1,404 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Reader for ChiTuBox `.ctb` printer files.
+//!
+//! Reads the layout written by [`CtbWriter`](crate::ctb_writer::CtbWriter) back into a
+//! [`SliceJob`]. Every section is found through the offsets in the header, so files from
+//! other slicers using the same container read as well, encrypted or not. The exposure and
+//! motion settings come from the header and print parameters, the per layer values are not
+//! kept.
+
+use crate::byte_reader::{f32_at, read_at, to_f64, u16_at, u32_at};
+use crate::ctb_writer::{
+    crypt_layer, crypt_settings, decode_preview, ENCRYPTED_HEADER_SIZE, ENCRYPTED_LAYER_DEF_SIZE,
+    ENCRYPTED_SETTINGS_SIZE, HEADER_SIZE, LAYER_DEF_SIZE, LAYER_POINTER_SIZE, MAGIC,
+    MAGIC_ENCRYPTED, PREVIEW_HEADER_SIZE, PRINT_PARAMETERS_SIZE, PRINT_PARAMETERS_V4_SIZE,
+    SLICER_INFO_SIZE,
+};
+use crate::layer_codec::decode_rle7;
+use crate::printer_profile::PrinterProfile;
+use crate::resin_profile::{ExposureSettings, MotionSettings, ResinProfile};
+use crate::slice_job::SliceJob;
+use image::{ImageBuffer, Luma, RgbImage};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek};
+use std::path::Path;
+
+pub fn read_from_file<P: AsRef<Path>>(path: P) -> io::Result<SliceJob> {
+    read(&mut BufReader::new(File::open(path)?))
+}
+
+pub fn read<R: Read + Seek>(input: &mut R) -> io::Result<SliceJob> {
+    let magic = read_at(input, 0, 4)?;
+    match u32_at(&magic, 0) {
+        MAGIC => read_unencrypted(input),
+        MAGIC_ENCRYPTED => read_encrypted(input),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Not a CTB file")),
+    }
+}
+
+fn read_unencrypted<R: Read + Seek>(input: &mut R) -> io::Result<SliceJob> {
+    let header = read_at(input, 0, HEADER_SIZE as usize)?;
+    let version = u32_at(&header, 4);
+    let pixel_x = u32_at(&header, 52);
+    let pixel_y = u32_at(&header, 56);
+    let layer_count = u32_at(&header, 68) as usize;
+    let encryption_seed = u32_at(&header, 100);
+
+    let print_parameters = read_at(
+        input,
+        u32_at(&header, 84) as u64,
+        PRINT_PARAMETERS_SIZE as usize,
+    )?;
+
+    let mut machine_name = String::new();
+    let mut transition_layer_count = 0;
+    let mut print_parameters_v4_offset = 0;
+    let slicer_info_offset = u32_at(&header, 104) as u64;
+    if slicer_info_offset != 0 {
+        let slicer_info = read_at(input, slicer_info_offset, SLICER_INFO_SIZE as usize)?;
+        let name = read_at(
+            input,
+            u32_at(&slicer_info, 28) as u64,
+            u32_at(&slicer_info, 32) as usize,
+        )?;
+        machine_name = String::from_utf8_lossy(&name).into_owned();
+        transition_layer_count = u32_at(&slicer_info, 60);
+        print_parameters_v4_offset = u32_at(&slicer_info, 64) as u64;
+    }
+
+    let normal_motion = MotionSettings {
+        lift_distance: f32_at(&print_parameters, 8),
+        lift_speed: f32_at(&print_parameters, 12),
+        retract_distance: f32_at(&print_parameters, 8),
+        retract_speed: f32_at(&print_parameters, 16),
+    };
+    let mut bottom_motion = MotionSettings {
+        lift_distance: f32_at(&print_parameters, 0),
+        lift_speed: f32_at(&print_parameters, 4),
+        retract_distance: f32_at(&print_parameters, 0),
+        retract_speed: normal_motion.retract_speed,
+    };
+    let mut resin = ResinProfile::default();
+    if version >= 4 && print_parameters_v4_offset != 0 {
+        let v4 = read_at(
+            input,
+            print_parameters_v4_offset,
+            PRINT_PARAMETERS_V4_SIZE as usize,
+        )?;
+        bottom_motion.retract_speed = f32_at(&v4, 0);
+        let resin_name_size = u32_at(&v4, 84) as usize;
+        if resin_name_size > 0 {
+            let name = read_at(input, u32_at(&v4, 80) as u64, resin_name_size)?;
+            resin.name = String::from_utf8_lossy(&name).into_owned();
+        }
+    }
+    resin.exposure = ExposureSettings {
+        normal_exposure_time: f32_at(&header, 36),
+        bottom_exposure_time: f32_at(&header, 40),
+        bottom_layer_count: u32_at(&header, 48),
+        transition_layer_count,
+        light_off_delay: f32_at(&header, 44),
+        bottom_motion,
+        normal_motion,
+        light_pwm: u16_at(&header, 96).min(255) as u8,
+    };
+
+    let printer = PrinterProfile {
+        name: machine_name,
+        pixel_x,
+        pixel_y,
+        physical_x: to_f64(f32_at(&header, 8)),
+        physical_y: to_f64(f32_at(&header, 12)),
+        physical_z: to_f64(f32_at(&header, 16)),
+        output_format: "ctb".to_string(),
+        file_version: version,
+        anti_alias_level: u32_at(&header, 92).max(1),
+        ..PrinterProfile::default()
+    };
+
+    let thumbnail = read_preview(input, u32_at(&header, 60) as u64)?;
+
+    let layer_table = read_at(
+        input,
+        u32_at(&header, 64) as u64,
+        LAYER_DEF_SIZE as usize * layer_count,
+    )?;
+    let mut layer_data = Vec::with_capacity(layer_count);
+    for definition in layer_table.chunks_exact(LAYER_DEF_SIZE as usize) {
+        // The page number holds the high bits of offsets past 4GB
+        let offset = u32_at(definition, 12) as u64 | (u32_at(definition, 20) as u64) << 32;
+        layer_data.push(read_at(input, offset, u32_at(definition, 16) as usize)?);
+    }
+    let layers = decode_layers(layer_data, encryption_seed, pixel_x, pixel_y)?;
+
+    let mut job = SliceJob::new(layers, to_f64(f32_at(&header, 32)), printer, resin);
+    job.thumbnail = thumbnail;
+    Ok(job)
+}
+
+fn read_encrypted<R: Read + Seek>(input: &mut R) -> io::Result<SliceJob> {
+    let header = read_at(input, 0, ENCRYPTED_HEADER_SIZE as usize)?;
+    if u32_at(&header, 4) != ENCRYPTED_SETTINGS_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unexpected size of the encrypted CTB settings",
+        ));
+    }
+    let mut settings = read_at(
+        input,
+        u32_at(&header, 8) as u64,
+        ENCRYPTED_SETTINGS_SIZE as usize,
+    )?;
+    crypt_settings(&mut settings, false);
+    let pixel_x = u32_at(&settings, 56);
+    let pixel_y = u32_at(&settings, 60);
+    let layer_count = u32_at(&settings, 64) as usize;
+
+    let name = read_at(
+        input,
+        u32_at(&settings, 160) as u64,
+        u32_at(&settings, 164) as usize,
+    )?;
+    let printer = PrinterProfile {
+        name: String::from_utf8_lossy(&name).into_owned(),
+        pixel_x,
+        pixel_y,
+        physical_x: to_f64(f32_at(&settings, 12)),
+        physical_y: to_f64(f32_at(&settings, 16)),
+        physical_z: to_f64(f32_at(&settings, 20)),
+        output_format: "ctb".to_string(),
+        file_version: u32_at(&header, 16),
+        anti_alias_level: u32_at(&settings, 176).max(1),
+        ctb_encrypted: true,
+        ..PrinterProfile::default()
+    };
+    let normal_motion = MotionSettings {
+        lift_distance: f32_at(&settings, 92),
+        lift_speed: f32_at(&settings, 96),
+        retract_distance: f32_at(&settings, 92),
+        retract_speed: f32_at(&settings, 100),
+    };
+    let resin = ResinProfile {
+        exposure: ExposureSettings {
+            normal_exposure_time: f32_at(&settings, 40),
+            bottom_exposure_time: f32_at(&settings, 44),
+            bottom_layer_count: u32_at(&settings, 52),
+            transition_layer_count: u32_at(&settings, 192),
+            light_off_delay: f32_at(&settings, 48),
+            bottom_motion: MotionSettings {
+                lift_distance: f32_at(&settings, 84),
+                lift_speed: f32_at(&settings, 88),
+                retract_distance: f32_at(&settings, 84),
+                retract_speed: f32_at(&settings, 196),
+            },
+            normal_motion,
+            light_pwm: u16_at(&settings, 124).min(255) as u8,
+        },
+        ..ResinProfile::default()
+    };
+
+    let thumbnail = read_preview(input, u32_at(&settings, 68) as u64)?;
+
+    let pointers = read_at(
+        input,
+        u32_at(&settings, 8) as u64,
+        LAYER_POINTER_SIZE as usize * layer_count,
+    )?;
+    let mut layer_data = Vec::with_capacity(layer_count);
+    for pointer in pointers.chunks_exact(LAYER_POINTER_SIZE as usize) {
+        let offset = u32_at(pointer, 0) as u64 | (u32_at(pointer, 4) as u64) << 32;
+        let definition = read_at(input, offset, ENCRYPTED_LAYER_DEF_SIZE as usize)?;
+        let data_offset = u32_at(&definition, 16) as u64 | (u32_at(&definition, 20) as u64) << 32;
+        layer_data.push(read_at(
+            input,
+            data_offset,
+            u32_at(&definition, 24) as usize,
+        )?);
+    }
+    let layers = decode_layers(layer_data, u32_at(&settings, 128), pixel_x, pixel_y)?;
+
+    let mut job = SliceJob::new(layers, to_f64(f32_at(&settings, 36)), printer, resin);
+    job.thumbnail = thumbnail;
+    Ok(job)
+}
+
+fn decode_layers(
+    layer_data: Vec<Vec<u8>>,
+    encryption_seed: u32,
+    pixel_x: u32,
+    pixel_y: u32,
+) -> io::Result<Vec<ImageBuffer<Luma<u8>, Vec<u8>>>> {
+    layer_data
+        .into_par_iter()
+        .enumerate()
+        .map(|(i, mut data)| {
+            crypt_layer(encryption_seed, i as u32, &mut data);
+            decode_rle7(&data, pixel_x, pixel_y)
+        })
+        .collect()
+}
+
+fn read_preview<R: Read + Seek>(input: &mut R, offset: u64) -> io::Result<Option<RgbImage>> {
+    if offset == 0 {
+        return Ok(None);
+    }
+    let header = read_at(input, offset, PREVIEW_HEADER_SIZE as usize)?;
+    let (width, height) = (u32_at(&header, 0), u32_at(&header, 4));
+    let data = read_at(
+        input,
+        u32_at(&header, 8) as u64,
+        u32_at(&header, 12) as usize,
+    )?;
+    decode_preview(&data, width, height).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ctb_writer::{CtbVersion, CtbWriter, LARGE_PREVIEW_SIZE};
+    use crate::layer_codec::quantize_grey;
+    use image::{ImageBuffer, Luma};
+    use std::io::Cursor;
+
+    fn test_job() -> SliceJob {
+        let layers = (0..4)
+            .map(|i| {
+                ImageBuffer::from_fn(24, 16, |x, y| Luma([((x * 11 + y * 5) * (i + 1)) as u8]))
+            })
+            .collect();
+        let printer = PrinterProfile {
+            name: "Test Printer".to_string(),
+            pixel_x: 24,
+            pixel_y: 16,
+            anti_alias_level: 4,
+            ..PrinterProfile::default()
+        };
+        let resin = ResinProfile {
+            name: "Test Resin".to_string(),
+            exposure: ExposureSettings {
+                normal_exposure_time: 2.5,
+                bottom_exposure_time: 25.0,
+                bottom_layer_count: 2,
+                transition_layer_count: 1,
+                light_off_delay: 0.5,
+                bottom_motion: MotionSettings {
+                    lift_distance: 7.0,
+                    lift_speed: 40.0,
+                    retract_distance: 7.0,
+                    retract_speed: 120.0,
+                },
+                normal_motion: MotionSettings {
+                    lift_distance: 5.0,
+                    lift_speed: 70.0,
+                    retract_distance: 5.0,
+                    retract_speed: 160.0,
+                },
+                light_pwm: 200,
+            },
+        };
+        SliceJob::new(layers, 0.05, printer, resin)
+    }
+
+    fn round_trip(job: &SliceJob, writer: &CtbWriter) -> SliceJob {
+        let mut bytes = Vec::new();
+        writer.write(job, &mut bytes).unwrap();
+        read(&mut Cursor::new(bytes)).expect("Written file should read back")
+    }
+
+    #[test]
+    fn test_round_trip_every_version() {
+        let job = test_job();
+        for version in [CtbVersion::V3, CtbVersion::V4, CtbVersion::V5] {
+            let writer = CtbWriter::new(version, 4, 0x1234_5678);
+            let read_job = round_trip(&job, &writer);
+
+            assert_eq!(read_job.layer_height, 0.05);
+            assert_eq!(read_job.printer.name, "Test Printer");
+            assert_eq!(read_job.printer.file_version, version as u32);
+            assert_eq!(read_job.printer.anti_alias_level, 4);
+            assert_eq!(read_job.printer.physical_x, 218.88);
+            assert_eq!(read_job.layer_count(), job.layer_count());
+            for (read_layer, layer) in read_job.layers.iter().zip(&job.layers) {
+                let expected: Vec<u8> = layer
+                    .as_raw()
+                    .iter()
+                    // RLE-7 drops the low bit
+                    .map(|&v| {
+                        let code = quantize_grey(v, 4) >> 1;
+                        (code << 1) | (code & 1)
+                    })
+                    .collect();
+                assert_eq!(
+                    read_layer.as_raw(),
+                    &expected,
+                    "{:?} layers differ",
+                    version
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip_encrypted() {
+        let job = test_job();
+        for version in [CtbVersion::V4, CtbVersion::V5] {
+            let mut writer = CtbWriter::new(version, 4, 0x1234_5678);
+            let plain = round_trip(&job, &writer);
+            writer.encrypted = true;
+            let read_job = round_trip(&job, &writer);
+
+            assert!(read_job.printer.ctb_encrypted);
+            assert_eq!(read_job.printer.file_version, version as u32);
+            assert_eq!(read_job.printer.name, "Test Printer");
+            assert_eq!(read_job.layer_height, 0.05);
+            assert_eq!(read_job.resin.exposure, job.resin.exposure);
+            assert_eq!(read_job.layers, plain.layers);
+            assert_eq!(
+                read_job
+                    .thumbnail
+                    .as_ref()
+                    .map(|thumbnail| thumbnail.dimensions()),
+                Some(LARGE_PREVIEW_SIZE)
+            );
+        }
+    }
+
+    #[test]
+    fn test_round_trip_settings() {
+        let job = test_job();
+        let read_job = round_trip(&job, &CtbWriter::new(CtbVersion::V5, 4, 0));
+
+        assert_eq!(read_job.resin, job.resin);
+        // The large preview becomes the thumbnail
+        assert_eq!(
+            read_job
+                .thumbnail
+                .as_ref()
+                .map(|thumbnail| thumbnail.dimensions()),
+            Some(LARGE_PREVIEW_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_resin_name_needs_v5() {
+        let read_job = round_trip(&test_job(), &CtbWriter::new(CtbVersion::V4, 4, 0));
+        assert_eq!(read_job.resin.name, ResinProfile::default().name);
+        assert_eq!(read_job.resin.exposure.bottom_motion.retract_speed, 120.0);
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        let Err(error) = read(&mut Cursor::new(vec![0u8; HEADER_SIZE as usize])) else {
+            panic!("Reading should fail");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let Err(error) = read(&mut Cursor::new(MAGIC.to_le_bytes().to_vec())) else {
+            panic!("Reading should fail");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,1079 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Writer for ChiTuBox `.ctb` printer files.
+//!
+//! A file is a fixed header followed by sections found through absolute offsets:
+//! two RGB15 preview images, the print parameters, the slicer info with the machine name,
+//! the v4 parameters (v4 and v5 only), the layer table and finally the layer images.
+//! Every layer image is preceded by an extended layer definition holding its motion settings.
+//! All values are little endian.
+//!
+//! Newer firmwares take the encrypted container instead: a small header, the print settings
+//! encrypted with AES-256-CBC under a key shared by every file, the previews, a table of
+//! pointers to the layer definitions, the layers and a signature ending the file.
+
+use crate::ctb_reader;
+use crate::layer_codec::encode_rle7;
+use crate::output_format::{Capabilities, OutputFormat};
+use crate::printer_profile::PrinterProfile;
+use crate::slice_job::SliceJob;
+use aes::cipher::block_padding::NoPadding;
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use aes::Aes256;
+use image::{Rgb, RgbImage};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MAGIC: u32 = 0x12FD_0086;
+pub const HEADER_SIZE: u64 = 112;
+pub const PREVIEW_HEADER_SIZE: u64 = 32;
+pub const PRINT_PARAMETERS_SIZE: u32 = 60;
+pub const SLICER_INFO_SIZE: u32 = 76;
+pub const PRINT_PARAMETERS_V4_SIZE: u64 = 472;
+pub const LAYER_DEF_SIZE: u64 = 36;
+const LAYER_DEF_EX_SIZE: u32 = 84;
+const SOFTWARE_VERSION: u32 = 0x0109_0000;
+/// Projector type telling the firmware the image is shown on a mirrored LCD
+const PROJECTOR_LCD_X_MIRROR: u32 = 1;
+/// Flag marking that the extended layer definitions carry per layer settings
+const PER_LAYER_SETTINGS: u8 = 0x40;
+/// Longest run a single RGB15 preview entry can hold
+const MAX_PREVIEW_RUN: u32 = 0x0FFF;
+/// Set in an RGB15 color when a run length follows it
+const PREVIEW_RUN_FLAG: u16 = 0x20;
+
+pub const MAGIC_ENCRYPTED: u32 = 0x12FD_0107;
+pub const ENCRYPTED_HEADER_SIZE: u64 = 48;
+pub const ENCRYPTED_SETTINGS_SIZE: u32 = 288;
+pub const LAYER_POINTER_SIZE: u64 = 16;
+pub const ENCRYPTED_LAYER_DEF_SIZE: u32 = 88;
+const SIGNATURE_SIZE: u32 = 32;
+/// Checksum the signature of an encrypted file is made from
+const CHECKSUM: u64 = 0xCAFE_BABE;
+/// Flag in the encrypted settings allowing per layer settings
+const ENCRYPTED_PER_LAYER_SETTINGS: u8 = 0x50;
+/// AES key and IV of the encrypted settings, the same in every file
+const SETTINGS_KEY: [u8; 32] = [
+    0xD0, 0x5B, 0x8E, 0x33, 0x71, 0xDE, 0x3D, 0x1A, 0xE5, 0x4F, 0x22, 0xDD, 0xDF, 0x5B, 0xFD, 0x94,
+    0xAB, 0x5D, 0x64, 0x3A, 0x9D, 0x7E, 0xBF, 0xAF, 0x42, 0x03, 0xF3, 0x10, 0xD8, 0x52, 0x2A, 0xEA,
+];
+const SETTINGS_IV: [u8; 16] = [
+    0x0F, 0x01, 0x0A, 0x05, 0x05, 0x0B, 0x06, 0x07, 0x08, 0x06, 0x0A, 0x0C, 0x0C, 0x0D, 0x09, 0x0F,
+];
+
+pub const LARGE_PREVIEW_SIZE: (u32, u32) = (400, 300);
+pub const SMALL_PREVIEW_SIZE: (u32, u32) = (200, 125);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CtbVersion {
+    V3 = 3,
+    V4 = 4,
+    V5 = 5,
+}
+
+pub struct CtbFormat;
+
+impl OutputFormat for CtbFormat {
+    fn name(&self) -> &'static str {
+        "Chitubox"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["ctb"]
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            grayscale: true,
+            per_layer_settings: true,
+            thumbnail_sizes: &[LARGE_PREVIEW_SIZE, SMALL_PREVIEW_SIZE],
+        }
+    }
+
+    fn write_to_file(&self, job: &SliceJob, path: &Path) -> io::Result<()> {
+        CtbWriter::for_printer(&job.printer).write_to_file(job, path)
+    }
+
+    fn reader(&self) -> Option<fn(&Path) -> io::Result<SliceJob>> {
+        Some(|path| ctb_reader::read_from_file(path))
+    }
+}
+
+pub struct CtbWriter {
+    pub version: CtbVersion,
+    /// Number of grey levels kept in the layer images, 1 writes pure black and white
+    pub anti_alias_level: u32,
+    /// Seed for the layer encryption, 0 leaves the layers unencrypted
+    pub encryption_seed: u32,
+    /// Writes the encrypted container, v4 and v5 only
+    pub encrypted: bool,
+}
+
+impl Default for CtbWriter {
+    fn default() -> Self {
+        Self {
+            version: CtbVersion::V4,
+            anti_alias_level: 8,
+            encryption_seed: 0x5EA1_5EA1,
+            encrypted: false,
+        }
+    }
+}
+
+impl CtbWriter {
+    pub fn new(version: CtbVersion, anti_alias_level: u32, encryption_seed: u32) -> Self {
+        Self {
+            version,
+            anti_alias_level: anti_alias_level.max(1),
+            encryption_seed,
+            encrypted: false,
+        }
+    }
+
+    /// Writer for the file version and anti-aliasing the printer asks for
+    pub fn for_printer(printer: &PrinterProfile) -> Self {
+        let version = match printer.file_version {
+            3 => CtbVersion::V3,
+            5 => CtbVersion::V5,
+            _ => CtbVersion::V4,
+        };
+        let encryption_seed = if version == CtbVersion::V3 {
+            0
+        } else {
+            Self::default().encryption_seed
+        };
+        let mut writer = Self::new(version, printer.anti_alias_level, encryption_seed);
+        writer.encrypted = printer.ctb_encrypted && version >= CtbVersion::V4;
+        writer
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, job: &SliceJob, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(job, &mut writer)?;
+        writer.flush()
+    }
+
+    pub fn write<W: Write>(&self, job: &SliceJob, out: &mut W) -> io::Result<()> {
+        if self.encrypted {
+            return self.write_encrypted(job, out);
+        }
+        let exposure = &job.resin.exposure;
+        let layer_count = job.layer_count() as u32;
+        let large_preview = job.preview(LARGE_PREVIEW_SIZE.0, LARGE_PREVIEW_SIZE.1);
+        let small_preview = job.preview(SMALL_PREVIEW_SIZE.0, SMALL_PREVIEW_SIZE.1);
+        let large_preview_data = encode_preview(&large_preview);
+        let small_preview_data = encode_preview(&small_preview);
+        let machine_name = job.printer.name.as_bytes();
+        let resin_name = job.resin.name.as_bytes();
+        let layer_data = self.encode_layers(job);
+
+        // Lay out every section before writing so the offsets can go in the header
+        let large_preview_offset = HEADER_SIZE;
+        let small_preview_offset =
+            large_preview_offset + PREVIEW_HEADER_SIZE + large_preview_data.len() as u64;
+        let print_parameters_offset =
+            small_preview_offset + PREVIEW_HEADER_SIZE + small_preview_data.len() as u64;
+        let slicer_info_offset = print_parameters_offset + PRINT_PARAMETERS_SIZE as u64;
+        let machine_name_offset = slicer_info_offset + SLICER_INFO_SIZE as u64;
+        let mut next_offset = machine_name_offset + machine_name.len() as u64;
+        let mut print_parameters_v4_offset = 0;
+        let mut resin_name_offset = 0;
+        let mut resin_name_size = 0;
+        if self.version >= CtbVersion::V4 {
+            print_parameters_v4_offset = next_offset;
+            next_offset += PRINT_PARAMETERS_V4_SIZE;
+        }
+        if self.version >= CtbVersion::V5 {
+            resin_name_offset = next_offset;
+            resin_name_size = resin_name.len() as u32;
+            next_offset += resin_name.len() as u64;
+        }
+        let layer_table_offset = next_offset;
+        next_offset += LAYER_DEF_SIZE * layer_count as u64;
+        let mut layer_data_offsets = Vec::with_capacity(layer_data.len());
+        for data in &layer_data {
+            next_offset += LAYER_DEF_EX_SIZE as u64;
+            layer_data_offsets.push(next_offset);
+            next_offset += data.len() as u64;
+        }
+
+        let total_height = job.layer_height * layer_count as f64;
+
+        // Header
+        put_u32(out, MAGIC)?;
+        put_u32(out, self.version as u32)?;
+        put_f32(out, job.printer.physical_x as f32)?;
+        put_f32(out, job.printer.physical_y as f32)?;
+        put_f32(out, job.printer.physical_z as f32)?;
+        put_u32(out, 0)?;
+        put_u32(out, 0)?;
+        put_f32(out, total_height as f32)?;
+        put_f32(out, job.layer_height as f32)?;
+        put_f32(out, exposure.normal_exposure_time)?;
+        put_f32(out, exposure.bottom_exposure_time)?;
+        put_f32(out, exposure.light_off_delay)?;
+        put_u32(out, exposure.bottom_layer_count)?;
+        put_u32(out, job.printer.pixel_x)?;
+        put_u32(out, job.printer.pixel_y)?;
+        put_u32(out, large_preview_offset as u32)?;
+        put_u32(out, layer_table_offset as u32)?;
+        put_u32(out, layer_count)?;
+        put_u32(out, small_preview_offset as u32)?;
+        put_u32(out, job.estimated_print_time().as_secs() as u32)?;
+        put_u32(out, PROJECTOR_LCD_X_MIRROR)?;
+        put_u32(out, print_parameters_offset as u32)?;
+        put_u32(out, PRINT_PARAMETERS_SIZE)?;
+        put_u32(out, self.anti_alias_level)?;
+        put_u16(out, exposure.light_pwm as u16)?;
+        put_u16(out, exposure.light_pwm as u16)?;
+        put_u32(out, self.encryption_seed)?;
+        put_u32(out, slicer_info_offset as u32)?;
+        put_u32(out, SLICER_INFO_SIZE)?;
+
+        // Previews
+        write_preview(
+            out,
+            &large_preview,
+            large_preview_offset + PREVIEW_HEADER_SIZE,
+            &large_preview_data,
+        )?;
+        write_preview(
+            out,
+            &small_preview,
+            small_preview_offset + PREVIEW_HEADER_SIZE,
+            &small_preview_data,
+        )?;
+
+        // Print parameters
+        put_f32(out, exposure.bottom_motion.lift_distance)?;
+        put_f32(out, exposure.bottom_motion.lift_speed)?;
+        put_f32(out, exposure.normal_motion.lift_distance)?;
+        put_f32(out, exposure.normal_motion.lift_speed)?;
+        put_f32(out, exposure.normal_motion.retract_speed)?;
+        put_f32(out, job.estimated_volume_ml() as f32)?;
+        put_f32(out, 0.0)?; // Weight in grams, unknown without the resin density
+        put_f32(out, 0.0)?; // Cost
+        put_f32(out, exposure.light_off_delay)?; // Bottom light off delay
+        put_f32(out, exposure.light_off_delay)?;
+        put_u32(out, exposure.bottom_layer_count)?;
+        put_padding(out, 4)?;
+
+        // Slicer info, the second lift stage is not used
+        put_padding(out, 6)?;
+        put_f32(out, 0.0)?; // Rest time after lift
+        put_u32(out, machine_name_offset as u32)?;
+        put_u32(out, machine_name.len() as u32)?;
+        let anti_alias_flag = if self.version >= CtbVersion::V4 {
+            0xF
+        } else {
+            0x7
+        };
+        out.write_all(&[anti_alias_flag, 0, 0, PER_LAYER_SETTINGS])?;
+        put_u32(out, minutes_since_epoch())?;
+        put_u32(out, self.anti_alias_level)?;
+        put_u32(out, SOFTWARE_VERSION)?;
+        put_f32(out, 0.0)?; // Rest time after retract
+        put_f32(out, 0.0)?; // Rest time after the second lift stage
+        put_u32(out, exposure.transition_layer_count)?;
+        put_u32(out, print_parameters_v4_offset as u32)?;
+        put_padding(out, 2)?;
+        out.write_all(machine_name)?;
+
+        if self.version >= CtbVersion::V4 {
+            put_f32(out, exposure.bottom_motion.retract_speed)?;
+            put_f32(out, 0.0)?; // Bottom retract speed of the second stage
+            put_padding(out, 1)?;
+            put_f32(out, 4.0)?;
+            put_padding(out, 1)?;
+            put_f32(out, 4.0)?;
+            put_f32(out, 0.0)?; // Rest time after retract
+            put_f32(out, 0.0)?; // Rest time after lift
+            put_f32(out, 0.0)?; // Rest time before lift
+            put_f32(out, 0.0)?; // Bottom retract height of the second stage
+
+            // Constants every ChiTuBox v4 file carries
+            put_f32(out, 2955.996)?;
+            put_u32(out, 73470)?;
+            put_u32(out, 5)?;
+            put_u32(out, layer_count.saturating_sub(1))?;
+            put_padding(out, 4)?;
+            put_u32(out, 0)?; // Disclaimer offset
+            put_u32(out, 0)?; // Disclaimer size
+            put_u32(out, resin_name_offset as u32)?;
+            put_u32(out, resin_name_size)?;
+            put_padding(out, 96)?;
+        }
+        if self.version >= CtbVersion::V5 {
+            out.write_all(resin_name)?;
+        }
+
+        // Layer table
+        for (i, data) in layer_data.iter().enumerate() {
+            write_layer_def(out, job, i, layer_data_offsets[i], data.len() as u32)?;
+        }
+
+        // Layer data, each preceded by its extended definition
+        for (i, data) in layer_data.iter().enumerate() {
+            let settings = job.layer_settings(i);
+            write_layer_def(out, job, i, layer_data_offsets[i], data.len() as u32)?;
+            put_u32(out, data.len() as u32 + LAYER_DEF_EX_SIZE)?;
+            put_f32(out, settings.motion.lift_distance)?;
+            put_f32(out, settings.motion.lift_speed)?;
+            put_f32(out, 0.0)?; // Lift height of the second stage
+            put_f32(out, 0.0)?; // Lift speed of the second stage
+            put_f32(out, settings.motion.retract_speed)?;
+            put_f32(out, 0.0)?; // Retract height of the second stage
+            put_f32(out, 0.0)?; // Retract speed of the second stage
+            put_f32(out, 0.0)?; // Rest time before lift
+            put_f32(out, 0.0)?; // Rest time after lift
+            put_f32(out, 0.0)?; // Rest time after retract
+            put_f32(out, settings.light_pwm as f32)?;
+            out.write_all(data)?;
+        }
+        Ok(())
+    }
+}
+
+impl CtbWriter {
+    fn encode_layers(&self, job: &SliceJob) -> Vec<Vec<u8>> {
+        job.layers
+            .par_iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                let mut data = encode_rle7(layer, self.anti_alias_level);
+                crypt_layer(self.encryption_seed, i as u32, &mut data);
+                data
+            })
+            .collect()
+    }
+
+    /// Writes the encrypted container. The resin name has no place in it.
+    fn write_encrypted<W: Write>(&self, job: &SliceJob, out: &mut W) -> io::Result<()> {
+        let exposure = &job.resin.exposure;
+        let layer_count = job.layer_count() as u32;
+        let large_preview = job.preview(LARGE_PREVIEW_SIZE.0, LARGE_PREVIEW_SIZE.1);
+        let small_preview = job.preview(SMALL_PREVIEW_SIZE.0, SMALL_PREVIEW_SIZE.1);
+        let large_preview_data = encode_preview(&large_preview);
+        let small_preview_data = encode_preview(&small_preview);
+        let machine_name = job.printer.name.as_bytes();
+        let layer_data = self.encode_layers(job);
+
+        let large_preview_offset = ENCRYPTED_HEADER_SIZE + ENCRYPTED_SETTINGS_SIZE as u64;
+        let small_preview_offset =
+            large_preview_offset + PREVIEW_HEADER_SIZE + large_preview_data.len() as u64;
+        let machine_name_offset =
+            small_preview_offset + PREVIEW_HEADER_SIZE + small_preview_data.len() as u64;
+        let layer_pointers_offset = machine_name_offset + machine_name.len() as u64;
+        let mut next_offset = layer_pointers_offset + LAYER_POINTER_SIZE * layer_count as u64;
+        let mut layer_def_offsets = Vec::with_capacity(layer_data.len());
+        for data in &layer_data {
+            layer_def_offsets.push(next_offset);
+            next_offset += ENCRYPTED_LAYER_DEF_SIZE as u64 + data.len() as u64;
+        }
+        let signature_offset = next_offset;
+
+        // Header
+        put_u32(out, MAGIC_ENCRYPTED)?;
+        put_u32(out, ENCRYPTED_SETTINGS_SIZE)?;
+        put_u32(out, ENCRYPTED_HEADER_SIZE as u32)?;
+        put_u32(out, 0)?;
+        put_u32(out, self.version as u32)?;
+        put_u32(out, SIGNATURE_SIZE)?;
+        put_u32(out, signature_offset as u32)?;
+        put_u32(out, 0)?;
+        put_u16(out, 1)?;
+        put_u16(out, 1)?;
+        put_u32(out, 0)?;
+        put_u32(out, 42)?;
+        put_padding(out, 1)?;
+
+        // Settings, encrypted as a whole
+        let total_height = job.layer_height * layer_count as f64;
+        let mut settings = Vec::with_capacity(ENCRYPTED_SETTINGS_SIZE as usize);
+        settings.extend_from_slice(&CHECKSUM.to_le_bytes());
+        put_u32(&mut settings, layer_pointers_offset as u32)?;
+        put_f32(&mut settings, job.printer.physical_x as f32)?;
+        put_f32(&mut settings, job.printer.physical_y as f32)?;
+        put_f32(&mut settings, job.printer.physical_z as f32)?;
+        put_padding(&mut settings, 2)?;
+        put_f32(&mut settings, total_height as f32)?;
+        put_f32(&mut settings, job.layer_height as f32)?;
+        put_f32(&mut settings, exposure.normal_exposure_time)?;
+        put_f32(&mut settings, exposure.bottom_exposure_time)?;
+        put_f32(&mut settings, exposure.light_off_delay)?;
+        put_u32(&mut settings, exposure.bottom_layer_count)?;
+        put_u32(&mut settings, job.printer.pixel_x)?;
+        put_u32(&mut settings, job.printer.pixel_y)?;
+        put_u32(&mut settings, layer_count)?;
+        put_u32(&mut settings, large_preview_offset as u32)?;
+        put_u32(&mut settings, small_preview_offset as u32)?;
+        put_u32(&mut settings, job.estimated_print_time().as_secs() as u32)?;
+        put_u32(&mut settings, PROJECTOR_LCD_X_MIRROR)?;
+        put_f32(&mut settings, exposure.bottom_motion.lift_distance)?;
+        put_f32(&mut settings, exposure.bottom_motion.lift_speed)?;
+        put_f32(&mut settings, exposure.normal_motion.lift_distance)?;
+        put_f32(&mut settings, exposure.normal_motion.lift_speed)?;
+        put_f32(&mut settings, exposure.normal_motion.retract_speed)?;
+        put_f32(&mut settings, job.estimated_volume_ml() as f32)?;
+        put_f32(&mut settings, 0.0)?; // Weight in grams, unknown without the resin density
+        put_f32(&mut settings, 0.0)?; // Cost
+        put_f32(&mut settings, exposure.light_off_delay)?; // Bottom light off delay
+        put_u32(&mut settings, 1)?;
+        put_u16(&mut settings, exposure.light_pwm as u16)?;
+        put_u16(&mut settings, exposure.light_pwm as u16)?;
+        put_u32(&mut settings, self.encryption_seed)?;
+        // The second lift stage is not used
+        put_padding(&mut settings, 6)?;
+        put_f32(&mut settings, 0.0)?; // Rest time after lift
+        put_u32(&mut settings, machine_name_offset as u32)?;
+        put_u32(&mut settings, machine_name.len() as u32)?;
+        settings.push(0xF); // Anti-aliasing flag
+        settings.extend_from_slice(&[0, 0]);
+        settings.push(ENCRYPTED_PER_LAYER_SETTINGS);
+        put_u32(&mut settings, minutes_since_epoch())?;
+        put_u32(&mut settings, self.anti_alias_level)?;
+        put_u32(&mut settings, SOFTWARE_VERSION)?;
+        put_f32(&mut settings, 0.0)?; // Rest time after retract
+        put_f32(&mut settings, 0.0)?; // Rest time after the second lift stage
+        put_u32(&mut settings, exposure.transition_layer_count)?;
+        put_f32(&mut settings, exposure.bottom_motion.retract_speed)?;
+        put_f32(&mut settings, 0.0)?; // Bottom retract speed of the second stage
+        put_padding(&mut settings, 1)?;
+        put_f32(&mut settings, 4.0)?;
+        put_padding(&mut settings, 1)?;
+        put_f32(&mut settings, 4.0)?;
+        put_f32(&mut settings, 0.0)?; // Rest time after retract
+        put_f32(&mut settings, 0.0)?; // Rest time after lift
+        put_f32(&mut settings, 0.0)?; // Rest time before lift
+        put_f32(&mut settings, 0.0)?; // Bottom retract height of the second stage
+        put_padding(&mut settings, 2)?;
+        put_u32(&mut settings, 4)?;
+        put_u32(&mut settings, layer_count.saturating_sub(1))?;
+        put_padding(&mut settings, 4)?;
+        put_u32(&mut settings, 0)?; // Disclaimer offset
+        put_u32(&mut settings, 0)?; // Disclaimer size
+        put_u32(&mut settings, 0)?; // Resin parameters offset
+        put_padding(&mut settings, 2)?;
+        debug_assert_eq!(settings.len(), ENCRYPTED_SETTINGS_SIZE as usize);
+        crypt_settings(&mut settings, true);
+        out.write_all(&settings)?;
+
+        // Previews and machine name
+        write_preview(
+            out,
+            &large_preview,
+            large_preview_offset + PREVIEW_HEADER_SIZE,
+            &large_preview_data,
+        )?;
+        write_preview(
+            out,
+            &small_preview,
+            small_preview_offset + PREVIEW_HEADER_SIZE,
+            &small_preview_data,
+        )?;
+        out.write_all(machine_name)?;
+
+        // Layer pointers
+        for &offset in &layer_def_offsets {
+            put_u32(out, offset as u32)?;
+            put_u32(out, (offset >> 32) as u32)?; // Page number for files over 4GB
+            put_u32(out, ENCRYPTED_LAYER_DEF_SIZE)?;
+            put_padding(out, 1)?;
+        }
+
+        // Layers, each preceded by its definition
+        for (i, data) in layer_data.iter().enumerate() {
+            let settings = job.layer_settings(i);
+            let data_offset = layer_def_offsets[i] + ENCRYPTED_LAYER_DEF_SIZE as u64;
+            put_u32(out, ENCRYPTED_LAYER_DEF_SIZE)?;
+            put_f32(out, job.layer_z(i) as f32)?;
+            put_f32(out, settings.exposure_time)?;
+            put_f32(out, settings.light_off_delay)?;
+            put_u32(out, data_offset as u32)?;
+            put_u32(out, (data_offset >> 32) as u32)?;
+            put_u32(out, data.len() as u32)?;
+            put_u32(out, 0)?;
+            put_u32(out, 0)?; // Offset of an AES encrypted part of the data, none
+            put_u32(out, 0)?; // Length of that part
+            put_f32(out, settings.motion.lift_distance)?;
+            put_f32(out, settings.motion.lift_speed)?;
+            put_f32(out, 0.0)?; // Lift height of the second stage
+            put_f32(out, 0.0)?; // Lift speed of the second stage
+            put_f32(out, settings.motion.retract_speed)?;
+            put_f32(out, 0.0)?; // Retract height of the second stage
+            put_f32(out, 0.0)?; // Retract speed of the second stage
+            put_f32(out, 0.0)?; // Rest time before lift
+            put_f32(out, 0.0)?; // Rest time after lift
+            put_f32(out, 0.0)?; // Rest time after retract
+            put_f32(out, settings.light_pwm as f32)?;
+            put_u32(out, 0)?;
+            out.write_all(data)?;
+        }
+
+        out.write_all(&signature())
+    }
+}
+
+/// Encrypts or decrypts the settings of an encrypted file in place. Their size is a
+/// multiple of the AES block size, so no padding is added.
+pub fn crypt_settings(data: &mut [u8], encrypt: bool) {
+    let length = data.len();
+    let key = SETTINGS_KEY.into();
+    let iv = SETTINGS_IV.into();
+    if encrypt {
+        cbc::Encryptor::<Aes256>::new(&key, &iv)
+            .encrypt_padded_mut::<NoPadding>(data, length)
+            .expect("Settings are whole AES blocks");
+    } else {
+        cbc::Decryptor::<Aes256>::new(&key, &iv)
+            .decrypt_padded_mut::<NoPadding>(data)
+            .expect("Settings are whole AES blocks");
+    }
+}
+
+/// Signature ending an encrypted file, the encrypted hash of its checksum
+pub fn signature() -> Vec<u8> {
+    let mut hash = Sha256::digest(CHECKSUM.to_le_bytes()).to_vec();
+    crypt_settings(&mut hash, true);
+    hash
+}
+
+fn write_preview<W: Write>(
+    out: &mut W,
+    preview: &RgbImage,
+    data_offset: u64,
+    data: &[u8],
+) -> io::Result<()> {
+    put_u32(out, preview.width())?;
+    put_u32(out, preview.height())?;
+    put_u32(out, data_offset as u32)?;
+    put_u32(out, data.len() as u32)?;
+    put_padding(out, 4)?;
+    out.write_all(data)
+}
+
+fn write_layer_def<W: Write>(
+    out: &mut W,
+    job: &SliceJob,
+    layer_index: usize,
+    data_offset: u64,
+    data_size: u32,
+) -> io::Result<()> {
+    let settings = job.layer_settings(layer_index);
+    put_f32(out, job.layer_z(layer_index) as f32)?;
+    put_f32(out, settings.exposure_time)?;
+    put_f32(out, settings.light_off_delay)?;
+    put_u32(out, data_offset as u32)?;
+    put_u32(out, data_size)?;
+    put_u32(out, (data_offset >> 32) as u32)?; // Page number for files over 4GB
+    put_u32(out, LAYER_DEF_EX_SIZE)?;
+    put_padding(out, 2)
+}
+
+fn put_u16<W: Write>(out: &mut W, value: u16) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn put_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn put_f32<W: Write>(out: &mut W, value: f32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+/// Writes `count` zeroed 32 bit words
+fn put_padding<W: Write>(out: &mut W, count: usize) -> io::Result<()> {
+    out.write_all(&vec![0u8; count * 4])
+}
+
+fn minutes_since_epoch() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| (d.as_secs() / 60) as u32)
+        .unwrap_or(0)
+}
+
+/// Encrypts or decrypts layer data in place. The cipher is a XOR with a key stream
+/// derived from the file seed and the layer index, so applying it twice is a no-op.
+pub fn crypt_layer(seed: u32, layer_index: u32, data: &mut [u8]) {
+    if seed == 0 {
+        return;
+    }
+    let init = seed.wrapping_mul(0x2D83_CDAC).wrapping_add(0xD8A8_3423);
+    let mut key = layer_index
+        .wrapping_mul(0x1E15_30CD)
+        .wrapping_add(0xEC3D_47CD)
+        .wrapping_mul(init);
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= (key >> (8 * (i % 4))) as u8;
+        if i % 4 == 3 {
+            key = key.wrapping_add(init);
+        }
+    }
+}
+
+fn rgb15(pixel: &Rgb<u8>) -> u16 {
+    ((pixel[0] as u16 >> 3) << 11) | ((pixel[1] as u16 >> 3) << 6) | (pixel[2] as u16 >> 3)
+}
+
+/// Encodes a preview as run length encoded RGB15. Each entry is a 16 bit color; when
+/// its run flag is set the next 16 bits hold the run length minus one.
+pub fn encode_preview(image: &RgbImage) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut current = None;
+    let mut run: u32 = 0;
+    for pixel in image.pixels() {
+        let color = rgb15(pixel);
+        if current == Some(color) && run < MAX_PREVIEW_RUN {
+            run += 1;
+            continue;
+        }
+        if let Some(previous) = current {
+            push_preview_run(&mut data, previous, run);
+        }
+        current = Some(color);
+        run = 1;
+    }
+    if let Some(previous) = current {
+        push_preview_run(&mut data, previous, run);
+    }
+    data
+}
+
+fn push_preview_run(data: &mut Vec<u8>, color: u16, run: u32) {
+    if run == 1 {
+        data.extend_from_slice(&color.to_le_bytes());
+    } else {
+        data.extend_from_slice(&(color | PREVIEW_RUN_FLAG).to_le_bytes());
+        data.extend_from_slice(&((run - 1) as u16 | 0x3000).to_le_bytes());
+    }
+}
+
+/// Decodes RGB15 preview data back into an image
+pub fn decode_preview(data: &[u8], width: u32, height: u32) -> io::Result<RgbImage> {
+    let pixel_count = (width * height) as usize;
+    let mut pixels: Vec<u8> = Vec::with_capacity(pixel_count * 3);
+    let mut words = data
+        .chunks_exact(2)
+        .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]));
+    while let Some(color) = words.next() {
+        let mut run = 1;
+        if color & PREVIEW_RUN_FLAG != 0 {
+            let length = words.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated preview data")
+            })?;
+            run = (length & 0x0FFF) as usize + 1;
+        }
+        let rgb = [
+            ((color >> 11) as u8) << 3,
+            (((color >> 6) & 0x1F) as u8) << 3,
+            ((color & 0x1F) as u8) << 3,
+        ];
+        for _ in 0..run {
+            pixels.extend_from_slice(&rgb);
+        }
+    }
+    pixels.resize(pixel_count * 3, 0);
+    Ok(RgbImage::from_raw(width, height, pixels).expect("Pixel count matches the size"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer_codec::decode_rle7;
+    use crate::resin_profile::{ExposureSettings, ResinProfile};
+    use image::{ImageBuffer, Luma};
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn f32_at(bytes: &[u8], offset: usize) -> f32 {
+        f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn test_job(layers: Vec<ImageBuffer<Luma<u8>, Vec<u8>>>) -> SliceJob {
+        let (width, height) = layers[0].dimensions();
+        let printer = PrinterProfile {
+            name: "Test Printer".to_string(),
+            pixel_x: width,
+            pixel_y: height,
+            ..PrinterProfile::default()
+        };
+        let resin = ResinProfile {
+            name: "Test Resin".to_string(),
+            exposure: ExposureSettings {
+                normal_exposure_time: 2.0,
+                bottom_exposure_time: 20.0,
+                bottom_layer_count: 1,
+                transition_layer_count: 1,
+                ..ExposureSettings::default()
+            },
+        };
+        SliceJob::new(layers, 0.05, printer, resin)
+    }
+
+    // A 32x8 layer with a horizontal grey gradient
+    fn gradient_layer() -> ImageBuffer<Luma<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(32, 8, |x, _| Luma([(x * 255 / 31) as u8]))
+    }
+
+    fn write_job(writer: &CtbWriter, job: &SliceJob) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        writer
+            .write(job, &mut bytes)
+            .expect("Writing to memory should succeed");
+        bytes
+    }
+
+    /// Reads the layer images back from a written file
+    fn read_layers(bytes: &[u8]) -> Vec<ImageBuffer<Luma<u8>, Vec<u8>>> {
+        let width = u32_at(bytes, 52);
+        let height = u32_at(bytes, 56);
+        let table = u32_at(bytes, 64) as usize;
+        let count = u32_at(bytes, 68) as usize;
+        let seed = u32_at(bytes, 100);
+        (0..count)
+            .map(|i| {
+                let def = table + i * LAYER_DEF_SIZE as usize;
+                let address = u32_at(bytes, def + 12) as usize;
+                let size = u32_at(bytes, def + 16) as usize;
+                let mut data = bytes[address..address + size].to_vec();
+                crypt_layer(seed, i as u32, &mut data);
+                decode_rle7(&data, width, height).expect("Layer should decode")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_header_and_parameters_round_trip() {
+        // Arrange
+        let job = test_job(vec![gradient_layer(); 3]);
+        let writer = CtbWriter::new(CtbVersion::V3, 1, 0);
+
+        // Act
+        let bytes = write_job(&writer, &job);
+
+        // Assert
+        assert_eq!(u32_at(&bytes, 0), MAGIC);
+        assert_eq!(u32_at(&bytes, 4), 3);
+        assert_eq!(f32_at(&bytes, 8), 218.88);
+        assert!((f32_at(&bytes, 28) - 0.15).abs() < 1e-6, "Total height");
+        assert_eq!(f32_at(&bytes, 32), 0.05);
+        assert_eq!(f32_at(&bytes, 36), 2.0);
+        assert_eq!(f32_at(&bytes, 40), 20.0);
+        assert_eq!(u32_at(&bytes, 48), 1);
+        assert_eq!((u32_at(&bytes, 52), u32_at(&bytes, 56)), (32, 8));
+        assert_eq!(u32_at(&bytes, 68), 3);
+        assert_eq!(
+            u32_at(&bytes, 76),
+            job.estimated_print_time().as_secs() as u32
+        );
+
+        let parameters = u32_at(&bytes, 84) as usize;
+        assert_eq!(u32_at(&bytes, 88), PRINT_PARAMETERS_SIZE);
+        assert_eq!(f32_at(&bytes, parameters + 4), 45.0, "Bottom lift speed");
+        assert_eq!(f32_at(&bytes, parameters + 12), 65.0, "Lift speed");
+        assert_eq!(u32_at(&bytes, parameters + 40), 1, "Bottom layer count");
+
+        let slicer_info = u32_at(&bytes, 104) as usize;
+        let name_offset = u32_at(&bytes, slicer_info + 28) as usize;
+        let name_size = u32_at(&bytes, slicer_info + 32) as usize;
+        assert_eq!(
+            &bytes[name_offset..name_offset + name_size],
+            b"Test Printer"
+        );
+        assert_eq!(
+            u32_at(&bytes, slicer_info + 60),
+            1,
+            "Transition layer count"
+        );
+        assert_eq!(
+            u32_at(&bytes, slicer_info + 64),
+            0,
+            "No v4 parameters in v3"
+        );
+
+        // Layer table holds z, exposure with the transition step, and the data size
+        let table = u32_at(&bytes, 64) as usize;
+        let expected_exposure = [20.0, 11.0, 2.0];
+        for (i, exposure) in expected_exposure.iter().enumerate() {
+            let def = table + i * LAYER_DEF_SIZE as usize;
+            assert!((f32_at(&bytes, def) - job.layer_z(i) as f32).abs() < 1e-6);
+            assert_eq!(
+                f32_at(&bytes, def + 4),
+                *exposure,
+                "Exposure of layer {}",
+                i
+            );
+            // The extended definition sits right before the data
+            let address = u32_at(&bytes, def + 12) as usize;
+            let ex = address - LAYER_DEF_EX_SIZE as usize;
+            assert_eq!(f32_at(&bytes, ex + 4), *exposure);
+            assert_eq!(
+                u32_at(&bytes, ex + 36),
+                u32_at(&bytes, def + 16) + LAYER_DEF_EX_SIZE
+            );
+        }
+        let last = table + 2 * LAYER_DEF_SIZE as usize;
+        assert_eq!(
+            u32_at(&bytes, last + 12) as usize + u32_at(&bytes, last + 16) as usize,
+            bytes.len(),
+            "Last layer should end the file"
+        );
+    }
+
+    #[test]
+    fn test_layers_round_trip_black_and_white() {
+        let mut layer = ImageBuffer::from_pixel(32, 8, Luma([0u8]));
+        for x in 4..20 {
+            layer.put_pixel(x, 3, Luma([255]));
+        }
+        let job = test_job(vec![
+            layer.clone(),
+            ImageBuffer::from_pixel(32, 8, Luma([255u8])),
+        ]);
+
+        let bytes = write_job(&CtbWriter::new(CtbVersion::V3, 1, 0), &job);
+        let layers = read_layers(&bytes);
+
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0], layer);
+        assert_eq!(layers[1], job.layers[1]);
+    }
+
+    #[test]
+    fn test_layers_round_trip_anti_aliased() {
+        let job = test_job(vec![gradient_layer()]);
+
+        for level in [1, 2, 4, 8, 16] {
+            let bytes = write_job(&CtbWriter::new(CtbVersion::V3, level, 0), &job);
+            let layer = &read_layers(&bytes)[0];
+            assert_eq!(u32_at(&bytes, 92), level, "Anti alias level in header");
+
+            let mut distinct: Vec<u8> = layer.as_raw().clone();
+            distinct.sort();
+            distinct.dedup();
+            assert!(
+                distinct.len() as u32 <= level.max(2),
+                "Level {} produced {} grey values",
+                level,
+                distinct.len()
+            );
+            let max_error = if level <= 2 {
+                128
+            } else {
+                255 / (2 * (level - 1)) + 2
+            };
+            for (original, decoded) in job.layers[0].as_raw().iter().zip(layer.as_raw()) {
+                assert!(
+                    (*original as i32 - *decoded as i32).unsigned_abs() <= max_error,
+                    "Level {}: {} decoded as {}",
+                    level,
+                    original,
+                    decoded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_v4_layers_are_encrypted() {
+        let job = test_job(vec![gradient_layer(); 2]);
+        let plain = write_job(&CtbWriter::new(CtbVersion::V4, 16, 0), &job);
+        let encrypted = write_job(&CtbWriter::new(CtbVersion::V4, 16, 0x1234_5678), &job);
+
+        assert_eq!(u32_at(&encrypted, 4), 4);
+        assert_eq!(u32_at(&encrypted, 100), 0x1234_5678);
+        assert_eq!(plain.len(), encrypted.len());
+        let table = u32_at(&encrypted, 64) as usize;
+        let address = u32_at(&encrypted, table + 12) as usize;
+        let size = u32_at(&encrypted, table + 16) as usize;
+        assert_ne!(
+            &plain[address..address + size],
+            &encrypted[address..address + size]
+        );
+        assert_eq!(read_layers(&plain), read_layers(&encrypted));
+
+        // The v4 parameters sit after the machine name and know the last layer
+        let slicer_info = u32_at(&encrypted, 104) as usize;
+        let v4 = u32_at(&encrypted, slicer_info + 64) as usize;
+        assert_eq!(f32_at(&encrypted, v4), 150.0, "Bottom retract speed");
+        assert_eq!(u32_at(&encrypted, v4 + 52), 1, "Last layer index");
+    }
+
+    #[test]
+    fn test_slicer_info_layout() {
+        // Field offsets of the SlicerInfo block as UVtools and ChiTuBox lay it out
+        let job = test_job(vec![gradient_layer()]);
+        let bytes = write_job(&CtbWriter::new(CtbVersion::V4, 4, 0), &job);
+
+        let slicer_info = u32_at(&bytes, 104) as usize;
+        assert_eq!(u32_at(&bytes, 108), 76, "SlicerInfo size");
+        assert_eq!(u32_at(&bytes, slicer_info + 28), slicer_info as u32 + 76);
+        assert_eq!(u32_at(&bytes, slicer_info + 32), 12, "Machine name size");
+        assert_eq!(
+            bytes[slicer_info + 36..slicer_info + 40],
+            [0xF, 0, 0, PER_LAYER_SETTINGS],
+            "Anti-alias flag, padding and per layer settings share a word"
+        );
+        assert_eq!(u32_at(&bytes, slicer_info + 44), 4, "Anti-alias level");
+        assert_eq!(u32_at(&bytes, slicer_info + 48), SOFTWARE_VERSION);
+        assert_eq!(
+            u32_at(&bytes, slicer_info + 60),
+            1,
+            "Transition layer count"
+        );
+        let v4 = u32_at(&bytes, slicer_info + 64) as usize;
+        assert_eq!(
+            v4,
+            slicer_info + 76 + 12,
+            "V4 parameters follow the machine name"
+        );
+        assert_eq!(f32_at(&bytes, v4), 150.0, "Bottom retract speed");
+    }
+
+    #[test]
+    fn test_v5_stores_resin_name() {
+        let job = test_job(vec![gradient_layer()]);
+        let bytes = write_job(&CtbWriter::new(CtbVersion::V5, 4, 0), &job);
+
+        let slicer_info = u32_at(&bytes, 104) as usize;
+        let v4 = u32_at(&bytes, slicer_info + 64) as usize;
+        let name_offset = u32_at(&bytes, v4 + 80) as usize;
+        let name_size = u32_at(&bytes, v4 + 84) as usize;
+        assert_eq!(&bytes[name_offset..name_offset + name_size], b"Test Resin");
+        assert_eq!(read_layers(&bytes)[0].dimensions(), (32, 8));
+    }
+
+    #[test]
+    fn test_encrypted_container() {
+        let job = test_job(vec![gradient_layer(); 2]);
+        let mut writer = CtbWriter::new(CtbVersion::V5, 4, 0x1234_5678);
+        writer.encrypted = true;
+        let bytes = write_job(&writer, &job);
+
+        assert_eq!(u32_at(&bytes, 0), MAGIC_ENCRYPTED);
+        assert_eq!(u32_at(&bytes, 4), ENCRYPTED_SETTINGS_SIZE);
+        assert_eq!(u32_at(&bytes, 16), 5);
+        let settings_offset = u32_at(&bytes, 8) as usize;
+        let mut settings =
+            bytes[settings_offset..settings_offset + ENCRYPTED_SETTINGS_SIZE as usize].to_vec();
+        assert_ne!(u32_at(&settings, 56), 32, "Settings should be encrypted");
+        crypt_settings(&mut settings, false);
+        assert_eq!(u32_at(&settings, 0), CHECKSUM as u32);
+        assert_eq!(f32_at(&settings, 36), 0.05);
+        assert_eq!((u32_at(&settings, 56), u32_at(&settings, 60)), (32, 8));
+        assert_eq!(u32_at(&settings, 64), 2);
+        assert_eq!(u32_at(&settings, 128), 0x1234_5678);
+        assert_eq!(u32_at(&settings, 248), 1, "Last layer index");
+
+        // The signature ends the file
+        let signature_offset = u32_at(&bytes, 24) as usize;
+        assert_eq!(u32_at(&bytes, 20), SIGNATURE_SIZE);
+        assert_eq!(signature_offset + SIGNATURE_SIZE as usize, bytes.len());
+        assert_eq!(&bytes[signature_offset..], &signature()[..]);
+
+        // Layer pointers lead to the definitions and through them to the data
+        let pointers = u32_at(&settings, 8) as usize;
+        for i in 0..2 {
+            let definition = u32_at(&bytes, pointers + i * LAYER_POINTER_SIZE as usize) as usize;
+            assert_eq!(u32_at(&bytes, definition), ENCRYPTED_LAYER_DEF_SIZE);
+            assert!((f32_at(&bytes, definition + 4) - job.layer_z(i) as f32).abs() < 1e-6);
+            let address = u32_at(&bytes, definition + 16) as usize;
+            let size = u32_at(&bytes, definition + 24) as usize;
+            assert_eq!(address, definition + ENCRYPTED_LAYER_DEF_SIZE as usize);
+            let mut data = bytes[address..address + size].to_vec();
+            crypt_layer(0x1234_5678, i as u32, &mut data);
+            assert_eq!(
+                decode_rle7(&data, 32, 8).unwrap(),
+                decode_rle7(&encode_rle7(&job.layers[i], 4), 32, 8).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_crypt_settings_is_reversible() {
+        let original: Vec<u8> = (0..=255).collect();
+        let mut data = original.clone();
+
+        crypt_settings(&mut data, true);
+        assert_ne!(data, original);
+        crypt_settings(&mut data, false);
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn test_crypt_layer_is_symmetric() {
+        let original: Vec<u8> = (0..=255).collect();
+        let mut data = original.clone();
+
+        crypt_layer(42, 7, &mut data);
+        assert_ne!(data, original);
+        crypt_layer(42, 7, &mut data);
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn test_previews_round_trip() {
+        let job = test_job(vec![gradient_layer()]);
+        let bytes = write_job(&CtbWriter::default(), &job);
+
+        for (header_offset, size) in [(60, LARGE_PREVIEW_SIZE), (72, SMALL_PREVIEW_SIZE)] {
+            let header = u32_at(&bytes, header_offset) as usize;
+            assert_eq!((u32_at(&bytes, header), u32_at(&bytes, header + 4)), size);
+            let address = u32_at(&bytes, header + 8) as usize;
+            let length = u32_at(&bytes, header + 12) as usize;
+
+            let decoded =
+                decode_preview(&bytes[address..address + length], size.0, size.1).unwrap();
+
+            let expected = job.preview(size.0, size.1);
+            for (a, b) in expected.pixels().zip(decoded.pixels()) {
+                for c in 0..3 {
+                    assert_eq!(a[c] & 0xF8, b[c], "RGB15 keeps the top five bits");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_for_printer() {
+        let printer = PrinterProfile {
+            file_version: 3,
+            anti_alias_level: 0,
+            ..PrinterProfile::default()
+        };
+        let writer = CtbWriter::for_printer(&printer);
+        assert_eq!(writer.version, CtbVersion::V3);
+        assert_eq!(writer.anti_alias_level, 1);
+        assert_eq!(writer.encryption_seed, 0, "v3 layers are not encrypted");
+        assert!(!writer.encrypted);
+
+        let writer = CtbWriter::for_printer(&PrinterProfile::default());
+        assert_eq!(writer.version, CtbVersion::V4);
+        assert_eq!(writer.anti_alias_level, 8);
+        assert_ne!(writer.encryption_seed, 0);
+        assert!(!writer.encrypted);
+
+        let printer = PrinterProfile {
+            ctb_encrypted: true,
+            ..PrinterProfile::default()
+        };
+        assert!(CtbWriter::for_printer(&printer).encrypted);
+        let printer = PrinterProfile {
+            file_version: 3,
+            ..printer
+        };
+        assert!(
+            !CtbWriter::for_printer(&printer).encrypted,
+            "v3 has no encrypted container"
+        );
+    }
+}
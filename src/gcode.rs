@@ -0,0 +1,492 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! G-code generation from user editable templates.
+//!
+//! A printer profile holds a start, a layer, an optional bottom layer and an end template.
+//! Placeholders such as `{z}` or `{exposure_ms}` are replaced with the values of the job,
+//! and for the layer templates with the settings of the layer being written. `{{` and `}}`
+//! write literal braces. Distances are in millimeters, speeds in millimeters per minute.
+//!
+//! Templates for common firmwares live in `profiles/printers`, their output is checked
+//! against `tests/golden`.
+
+use crate::slice_job::SliceJob;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::io;
+
+/// Templates of a printer's G-code, the defaults drive NanoDLP style firmwares
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GcodeTemplates {
+    /// Written once before the first layer
+    pub start: String,
+    /// Written for every layer
+    pub layer: String,
+    /// Written for the bottom layers instead of `layer` when set
+    pub bottom_layer: Option<String>,
+    /// Written once after the last layer
+    pub end: String,
+}
+
+impl Default for GcodeTemplates {
+    fn default() -> Self {
+        Self {
+            start: "\
+;printer:{printer_name}
+;resolutionX:{resolution_x}
+;resolutionY:{resolution_y}
+;layerHeight:{layer_height}
+;totalLayer:{layer_count}
+;bottomLayerCount:{bottom_layer_count}
+;bottomExposureTime:{bottom_exposure_time}
+;normalExposureTime:{normal_exposure_time}
+;estimatedPrintTime:{print_time}
+;volume:{volume}
+G21 ;Set units to millimeters
+G90 ;Use absolute positioning
+M17 ;Enable motors
+M106 S0 ;Light off
+G28 Z0 ;Home the build plate
+"
+            .to_string(),
+            layer: "\
+;LAYER_START:{layer_index}
+;currPos:{z}
+M6054 \"{image}\" ;Show layer image
+G0 Z{z} F{retract_speed} ;Move to layer
+G4 P{light_off_delay_ms} ;Wait for the resin to settle
+M106 S{light_pwm} ;Light on
+G4 P{exposure_ms} ;Expose
+M106 S0 ;Light off
+G0 Z{lift_z} F{lift_speed} ;Lift
+;LAYER_END
+"
+            .to_string(),
+            bottom_layer: None,
+            end: "\
+M106 S0 ;Light off
+G0 Z{max_z} F{normal_lift_speed} ;Raise the build plate
+M18 ;Disable motors
+"
+            .to_string(),
+        }
+    }
+}
+
+impl GcodeTemplates {
+    /// Checks that every template only uses placeholders available to it
+    pub fn validate(&self) -> io::Result<()> {
+        self.compile().map(|_| ())
+    }
+
+    /// Fills in the templates for every layer of the job. `image_name` gives the name
+    /// of a layer's image as the firmware will look it up.
+    pub fn generate<F>(&self, job: &SliceJob, image_name: F) -> io::Result<String>
+    where
+        F: Fn(usize) -> String,
+    {
+        let templates = self.compile()?;
+        let mut gcode = String::new();
+        templates.start.render(job, None, &mut gcode);
+        for layer_index in 0..job.layer_count() {
+            let template = match &templates.bottom_layer {
+                Some(bottom_layer) if job.resin.exposure.is_bottom_layer(layer_index) => {
+                    bottom_layer
+                }
+                _ => &templates.layer,
+            };
+            let image = image_name(layer_index);
+            template.render(job, Some((layer_index, &image)), &mut gcode);
+        }
+        templates.end.render(job, None, &mut gcode);
+        Ok(gcode)
+    }
+
+    fn compile(&self) -> io::Result<CompiledTemplates> {
+        let bottom_layer = match &self.bottom_layer {
+            Some(source) => Some(Template::parse(source, "bottom layer", true)?),
+            None => None,
+        };
+        Ok(CompiledTemplates {
+            start: Template::parse(&self.start, "start", false)?,
+            layer: Template::parse(&self.layer, "layer", true)?,
+            bottom_layer,
+            end: Template::parse(&self.end, "end", false)?,
+        })
+    }
+}
+
+struct CompiledTemplates {
+    start: Template,
+    layer: Template,
+    bottom_layer: Option<Template>,
+    end: Template,
+}
+
+/// Values that can be filled into a template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    PrinterName,
+    ResolutionX,
+    ResolutionY,
+    LayerHeight,
+    LayerCount,
+    BottomLayerCount,
+    BottomExposureTime,
+    NormalExposureTime,
+    NormalLiftSpeed,
+    PrintTime,
+    Volume,
+    MaxZ,
+    // Only available in the layer templates
+    LayerIndex,
+    LayerNumber,
+    Z,
+    LiftZ,
+    LiftHeight,
+    LiftSpeed,
+    RetractHeight,
+    RetractSpeed,
+    ExposureTime,
+    ExposureMs,
+    LightOffDelayMs,
+    LightPwm,
+    Image,
+}
+
+impl Placeholder {
+    const ALL: [(&'static str, Placeholder); 25] = [
+        ("printer_name", Placeholder::PrinterName),
+        ("resolution_x", Placeholder::ResolutionX),
+        ("resolution_y", Placeholder::ResolutionY),
+        ("layer_height", Placeholder::LayerHeight),
+        ("layer_count", Placeholder::LayerCount),
+        ("bottom_layer_count", Placeholder::BottomLayerCount),
+        ("bottom_exposure_time", Placeholder::BottomExposureTime),
+        ("normal_exposure_time", Placeholder::NormalExposureTime),
+        ("normal_lift_speed", Placeholder::NormalLiftSpeed),
+        ("print_time", Placeholder::PrintTime),
+        ("volume", Placeholder::Volume),
+        ("max_z", Placeholder::MaxZ),
+        ("layer_index", Placeholder::LayerIndex),
+        ("layer_number", Placeholder::LayerNumber),
+        ("z", Placeholder::Z),
+        ("lift_z", Placeholder::LiftZ),
+        ("lift_height", Placeholder::LiftHeight),
+        ("lift_speed", Placeholder::LiftSpeed),
+        ("retract_height", Placeholder::RetractHeight),
+        ("retract_speed", Placeholder::RetractSpeed),
+        ("exposure_time", Placeholder::ExposureTime),
+        ("exposure_ms", Placeholder::ExposureMs),
+        ("light_off_delay_ms", Placeholder::LightOffDelayMs),
+        ("light_pwm", Placeholder::LightPwm),
+        ("image", Placeholder::Image),
+    ];
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .find(|(placeholder_name, _)| *placeholder_name == name)
+            .map(|(_, placeholder)| *placeholder)
+    }
+
+    fn is_per_layer(self) -> bool {
+        self as usize >= Placeholder::LayerIndex as usize
+    }
+
+    /// Writes the value, `layer` is the index and image name of the current layer
+    fn write(self, job: &SliceJob, layer: Option<(usize, &str)>, out: &mut String) {
+        let exposure = &job.resin.exposure;
+        let printer = &job.printer;
+        // Writing to a String can't fail
+        let _ = match self {
+            Placeholder::PrinterName => write!(out, "{}", printer.name),
+            Placeholder::ResolutionX => write!(out, "{}", printer.pixel_x),
+            Placeholder::ResolutionY => write!(out, "{}", printer.pixel_y),
+            Placeholder::LayerHeight => write!(out, "{}", job.layer_height),
+            Placeholder::LayerCount => write!(out, "{}", job.layer_count()),
+            Placeholder::BottomLayerCount => write!(out, "{}", exposure.bottom_layer_count),
+            Placeholder::BottomExposureTime => write!(out, "{}", exposure.bottom_exposure_time),
+            Placeholder::NormalExposureTime => write!(out, "{}", exposure.normal_exposure_time),
+            Placeholder::NormalLiftSpeed => write!(out, "{}", exposure.normal_motion.lift_speed),
+            Placeholder::PrintTime => write!(out, "{}", job.estimated_print_time().as_secs()),
+            Placeholder::Volume => write!(out, "{:.3}", job.estimated_volume_ml()),
+            Placeholder::MaxZ => write!(out, "{:.3}", printer.physical_z),
+            _ => {
+                let (layer_index, image) =
+                    layer.expect("Layer placeholders are only parsed in layer templates");
+                let settings = job.layer_settings(layer_index);
+                let z = job.layer_z(layer_index);
+                match self {
+                    Placeholder::LayerIndex => write!(out, "{}", layer_index),
+                    Placeholder::LayerNumber => write!(out, "{}", layer_index + 1),
+                    Placeholder::Z => write!(out, "{:.3}", z),
+                    Placeholder::LiftZ => {
+                        write!(out, "{:.3}", z + settings.motion.lift_distance as f64)
+                    }
+                    Placeholder::LiftHeight => write!(out, "{}", settings.motion.lift_distance),
+                    Placeholder::LiftSpeed => write!(out, "{}", settings.motion.lift_speed),
+                    // Relative move from the lifted plate down to the next layer
+                    Placeholder::RetractHeight => write!(
+                        out,
+                        "{:.3}",
+                        settings.motion.lift_distance as f64 - job.layer_height
+                    ),
+                    Placeholder::RetractSpeed => write!(out, "{}", settings.motion.retract_speed),
+                    Placeholder::ExposureTime => write!(out, "{}", settings.exposure_time),
+                    Placeholder::ExposureMs => {
+                        write!(out, "{}", milliseconds(settings.exposure_time))
+                    }
+                    Placeholder::LightOffDelayMs => {
+                        write!(out, "{}", milliseconds(settings.light_off_delay))
+                    }
+                    Placeholder::LightPwm => write!(out, "{}", settings.light_pwm),
+                    Placeholder::Image => write!(out, "{}", image),
+                    _ => unreachable!("Job placeholders are handled above"),
+                }
+            }
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// A parsed template, ready to be filled in
+#[derive(Debug, Clone, PartialEq)]
+struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Parses `source`, `name` is used in errors and layer placeholders are only
+    /// accepted when `per_layer` is set
+    fn parse(source: &str, name: &str, per_layer: bool) -> io::Result<Self> {
+        let error = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} in the {} template", message, name),
+            )
+        };
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            match c {
+                '{' if chars.peek().map(|&(_, c)| c) == Some('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek().map(|&(_, c)| c) == Some('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = &source[start + 1..];
+                    let Some(length) = rest
+                        .find(['}', '{', '\n'])
+                        .filter(|&end| rest[end..].starts_with('}'))
+                    else {
+                        let line = line_of(source, start);
+                        return Err(error(format!("Unclosed {{ on line {}", line)));
+                    };
+                    let placeholder_name = &rest[..length];
+                    let placeholder =
+                        Placeholder::from_name(placeholder_name).ok_or_else(|| {
+                            error(format!("Unknown placeholder {{{}}}", placeholder_name))
+                        })?;
+                    if placeholder.is_per_layer() && !per_layer {
+                        return Err(error(format!(
+                            "{{{}}} is only available to layers",
+                            placeholder_name
+                        )));
+                    }
+                    for _ in 0..=length {
+                        chars.next();
+                    }
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Placeholder(placeholder));
+                }
+                '}' => {
+                    let line = line_of(source, start);
+                    return Err(error(format!("Unmatched }} on line {}", line)));
+                }
+                c => text.push(c),
+            }
+        }
+        // Keep whatever follows on a line of its own
+        if !source.is_empty() && !source.ends_with('\n') {
+            text.push('\n');
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        Ok(Self { segments })
+    }
+
+    fn render(&self, job: &SliceJob, layer: Option<(usize, &str)>, out: &mut String) {
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => out.push_str(text),
+                Segment::Placeholder(placeholder) => placeholder.write(job, layer, out),
+            }
+        }
+    }
+}
+
+fn line_of(source: &str, byte_index: usize) -> usize {
+    source[..byte_index].matches('\n').count() + 1
+}
+
+fn milliseconds(seconds: f32) -> u32 {
+    (seconds.max(0.0) * 1000.0).round() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printer_profile::PrinterProfile;
+    use crate::resin_profile::{ExposureSettings, ResinProfile};
+    use image::{ImageBuffer, Luma};
+    use std::fs;
+    use std::path::Path;
+
+    fn test_job(printer: PrinterProfile) -> SliceJob {
+        let layers = (0..3)
+            .map(|i| ImageBuffer::from_fn(16, 9, |x, y| Luma([((x + y) * 16 + i) as u8])))
+            .collect();
+        let resin = ResinProfile {
+            name: "Test Resin".to_string(),
+            exposure: ExposureSettings {
+                normal_exposure_time: 2.5,
+                bottom_exposure_time: 30.0,
+                bottom_layer_count: 1,
+                light_off_delay: 0.5,
+                ..ExposureSettings::default()
+            },
+        };
+        SliceJob::new(
+            layers,
+            0.05,
+            PrinterProfile {
+                pixel_x: 16,
+                pixel_y: 9,
+                ..printer
+            },
+            resin,
+        )
+    }
+
+    /// Compares with a file in `tests/golden`, run with `UPDATE_GOLDEN=1` to rewrite it
+    fn assert_golden(gcode: &str, file_name: &str) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(file_name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&path, gcode).unwrap();
+        }
+        let expected = fs::read_to_string(&path).expect("Golden file should exist");
+        assert_eq!(gcode, expected, "G-code differs from {}", path.display());
+    }
+
+    fn assert_profile_golden(profile_json: &str, file_name: &str) {
+        let printer = PrinterProfile::from_json(profile_json).expect("Profile should parse");
+        let settings = printer.zip_gcode.clone();
+        let job = test_job(printer);
+        let gcode = settings
+            .gcode
+            .generate(&job, |i| settings.layer_file_name(i))
+            .expect("Templates should be valid");
+        assert_golden(&gcode, file_name);
+    }
+
+    #[test]
+    fn test_nanodlp_golden() {
+        let settings = PrinterProfile::default().zip_gcode;
+        let job = test_job(PrinterProfile::default());
+        let gcode = settings
+            .gcode
+            .generate(&job, |i| settings.layer_file_name(i))
+            .unwrap();
+        assert_golden(&gcode, "nanodlp.gcode");
+    }
+
+    #[test]
+    fn test_chitubox_golden() {
+        assert_profile_golden(
+            include_str!("../profiles/printers/chitubox_zip.json"),
+            "chitubox_zip.gcode",
+        );
+    }
+
+    #[test]
+    fn test_creation_workshop_golden() {
+        assert_profile_golden(
+            include_str!("../profiles/printers/creation_workshop.json"),
+            "creation_workshop.gcode",
+        );
+    }
+
+    #[test]
+    fn test_bottom_layer_template() {
+        let templates = GcodeTemplates {
+            start: String::new(),
+            layer: "L{layer_number} {exposure_time}".to_string(),
+            bottom_layer: Some("B{layer_number} {exposure_time} {{{image}}}".to_string()),
+            end: "; {{end}}\n".to_string(),
+        };
+        let job = test_job(PrinterProfile::default());
+
+        let gcode = templates.generate(&job, |i| format!("{}.png", i)).unwrap();
+
+        assert_eq!(gcode, "B1 30 {0.png}\nL2 2.5\nL3 2.5\n; {end}\n");
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let cases = [
+            (
+                "start",
+                "G28 {zz}",
+                "Unknown placeholder {zz} in the start template",
+            ),
+            (
+                "start",
+                "G0 Z{z}",
+                "{z} is only available to layers in the start template",
+            ),
+            (
+                "layer",
+                "M106 S{light_pwm",
+                "Unclosed { on line 1 in the layer template",
+            ),
+            ("end", "M18\n}", "Unmatched } on line 2 in the end template"),
+            (
+                "bottom layer",
+                "{}",
+                "Unknown placeholder {} in the bottom layer template",
+            ),
+        ];
+        for (template, source, message) in cases {
+            let mut templates = GcodeTemplates::default();
+            match template {
+                "start" => templates.start = source.to_string(),
+                "layer" => templates.layer = source.to_string(),
+                "end" => templates.end = source.to_string(),
+                _ => templates.bottom_layer = Some(source.to_string()),
+            }
+
+            let error = templates.validate().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(error.to_string(), message);
+        }
+        assert!(GcodeTemplates::default().validate().is_ok());
+    }
+}
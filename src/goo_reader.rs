@@ -0,0 +1,296 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Reader for Elegoo `.goo` printer files.
+//!
+//! Reads the layout written by [`GooWriter`](crate::goo_writer::GooWriter) back into a
+//! [`SliceJob`]. The sections follow each other without offsets apart from the start of
+//! the layers, so the header is read field by field in order. Every layer's magic byte,
+//! checksum and delimiters are checked before its image is decoded.
+
+use crate::byte_reader::to_f64;
+use crate::goo_writer::{
+    checksum, decode_preview, BIG_PREVIEW_SIZE, DELIMITER, LAYER_DATA_MAGIC, MAGIC,
+    SMALL_PREVIEW_SIZE, VERSION,
+};
+use crate::layer_codec::decode_goo;
+use crate::printer_profile::PrinterProfile;
+use crate::resin_profile::{ExposureSettings, MotionSettings, ResinProfile};
+use crate::slice_job::SliceJob;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+pub fn read_from_file<P: AsRef<Path>>(path: P) -> io::Result<SliceJob> {
+    read(&mut BufReader::new(File::open(path)?))
+}
+
+pub fn read<R: Read + Seek>(input: &mut R) -> io::Result<SliceJob> {
+    if get_str(input, 4)? != VERSION || get_bytes(input, MAGIC.len())? != MAGIC {
+        return Err(invalid("Not a GOO file or an unsupported version"));
+    }
+    get_bytes(input, 32 + 24 + 24)?; // Software name, version and creation date
+    let machine_name = get_str(input, 32)?;
+    get_bytes(input, 32)?; // Machine type
+    let resin_name = get_str(input, 32)?;
+    let anti_alias_level = get_u16(input)? as u32;
+    get_bytes(input, 2 * 2)?; // Grey and blur level
+
+    let small_preview_size = SMALL_PREVIEW_SIZE * SMALL_PREVIEW_SIZE * 2;
+    get_bytes(input, small_preview_size as usize)?;
+    expect_delimiter(input)?;
+    let big_preview = get_bytes(input, (BIG_PREVIEW_SIZE * BIG_PREVIEW_SIZE * 2) as usize)?;
+    expect_delimiter(input)?;
+    let thumbnail = decode_preview(&big_preview, BIG_PREVIEW_SIZE, BIG_PREVIEW_SIZE)?;
+
+    let layer_count = get_u32(input)? as usize;
+    let pixel_x = get_u16(input)? as u32;
+    let pixel_y = get_u16(input)? as u32;
+    get_bytes(input, 2)?; // Mirroring
+    let physical_x = to_f64(get_f32(input)?);
+    let physical_y = to_f64(get_f32(input)?);
+    let physical_z = to_f64(get_f32(input)?);
+    let layer_height = to_f64(get_f32(input)?);
+    let normal_exposure_time = get_f32(input)?;
+    get_bytes(input, 1)?; // Delay mode
+    let light_off_delay = get_f32(input)?;
+    get_bytes(input, 4 * 6)?; // Rest times
+    let bottom_exposure_time = get_f32(input)?;
+    let bottom_layer_count = get_u32(input)?;
+    let bottom_lift_distance = get_f32(input)?;
+    let bottom_lift_speed = get_f32(input)?;
+    let lift_distance = get_f32(input)?;
+    let lift_speed = get_f32(input)?;
+    let bottom_motion = MotionSettings {
+        lift_distance: bottom_lift_distance,
+        lift_speed: bottom_lift_speed,
+        retract_distance: get_f32(input)?,
+        retract_speed: get_f32(input)?,
+    };
+    let normal_motion = MotionSettings {
+        lift_distance,
+        lift_speed,
+        retract_distance: get_f32(input)?,
+        retract_speed: get_f32(input)?,
+    };
+    get_bytes(input, 4 * 8)?; // Second lift and retract stages
+    get_u16(input)?; // Bottom light PWM
+    let light_pwm = get_u16(input)?.min(255) as u8;
+    // Per layer flag, print time, volume, weight, price and price unit
+    get_bytes(input, 1 + 4 * 4 + 8)?;
+    let layer_offset = get_u32(input)? as u64;
+    get_bytes(input, 1)?; // Grey scale range
+    let transition_layer_count = get_u16(input)? as u32;
+
+    input.seek(SeekFrom::Start(layer_offset))?;
+    let mut layer_data = Vec::new();
+    for i in 0..layer_count {
+        // Pause, position, exposure, motion and light PWM of the layer
+        get_bytes(input, 2 + 4 * 15 + 2)?;
+        expect_delimiter(input)?;
+        let size = get_u32(input)? as usize;
+        if size < 2 || get_u8(input)? != LAYER_DATA_MAGIC {
+            return Err(invalid(&format!("Layer {} has no image data", i)));
+        }
+        let data = get_bytes(input, size - 2)?;
+        if get_u8(input)? != checksum(&data) {
+            return Err(invalid(&format!("Layer {} fails its checksum", i)));
+        }
+        expect_delimiter(input)?;
+        layer_data.push(data);
+    }
+    let layers = layer_data
+        .par_iter()
+        .map(|data| decode_goo(data, pixel_x, pixel_y))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let printer = PrinterProfile {
+        name: machine_name,
+        pixel_x,
+        pixel_y,
+        physical_x,
+        physical_y,
+        physical_z,
+        output_format: "goo".to_string(),
+        anti_alias_level: anti_alias_level.max(1),
+        ..PrinterProfile::default()
+    };
+    let resin = ResinProfile {
+        name: resin_name,
+        exposure: ExposureSettings {
+            normal_exposure_time,
+            bottom_exposure_time,
+            bottom_layer_count,
+            transition_layer_count,
+            light_off_delay,
+            bottom_motion,
+            normal_motion,
+            light_pwm,
+        },
+    };
+    let mut job = SliceJob::new(layers, layer_height, printer, resin);
+    job.thumbnail = Some(thumbnail);
+    Ok(job)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn expect_delimiter<R: Read>(input: &mut R) -> io::Result<()> {
+    if get_bytes(input, DELIMITER.len())? != DELIMITER {
+        return Err(invalid("Missing section delimiter"));
+    }
+    Ok(())
+}
+
+/// Reads `count` bytes without allocating for all of them up front, so a corrupt
+/// size fails with an error instead of a huge allocation.
+fn get_bytes<R: Read>(input: &mut R, count: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    input.take(count as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < count {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Unexpected end of file",
+        ));
+    }
+    Ok(bytes)
+}
+
+fn get_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut bytes = [0u8; 1];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn get_u16<R: Read>(input: &mut R) -> io::Result<u16> {
+    let mut bytes = [0u8; 2];
+    input.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+fn get_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn get_f32<R: Read>(input: &mut R) -> io::Result<f32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(f32::from_be_bytes(bytes))
+}
+
+/// Reads a fixed size zero padded string field
+fn get_str<R: Read>(input: &mut R, size: usize) -> io::Result<String> {
+    let field = get_bytes(input, size)?;
+    let end = field.iter().position(|&b| b == 0).unwrap_or(size);
+    Ok(String::from_utf8_lossy(&field[..end]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::goo_writer::{GooWriter, FOOTER};
+    use crate::layer_codec::quantize_grey;
+    use image::{ImageBuffer, Luma};
+    use std::io::Cursor;
+
+    /// Footer, delimiter and checksum after the last run of the last layer
+    const FOOTER_AND_LAYER_END: usize = FOOTER.len() + DELIMITER.len() + 2;
+
+    fn test_job() -> SliceJob {
+        let layers = (0..3)
+            .map(|i| ImageBuffer::from_fn(30, 12, |x, y| Luma([((x * 9 + y) * (i + 1)) as u8])))
+            .collect();
+        let printer = PrinterProfile {
+            name: "Saturn 3 Ultra".to_string(),
+            pixel_x: 30,
+            pixel_y: 12,
+            output_format: "goo".to_string(),
+            anti_alias_level: 4,
+            ..PrinterProfile::default()
+        };
+        let resin = ResinProfile {
+            name: "ABS-Like".to_string(),
+            exposure: ExposureSettings {
+                normal_exposure_time: 2.0,
+                bottom_exposure_time: 25.0,
+                bottom_layer_count: 1,
+                transition_layer_count: 2,
+                light_pwm: 180,
+                ..ExposureSettings::default()
+            },
+        };
+        SliceJob::new(layers, 0.05, printer, resin)
+    }
+
+    fn write(job: &SliceJob) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        GooWriter::for_printer(&job.printer)
+            .write(job, &mut bytes)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let job = test_job();
+
+        let read_job = read(&mut Cursor::new(write(&job))).expect("Written file should read back");
+
+        assert_eq!(read_job.printer.name, "Saturn 3 Ultra");
+        assert_eq!(read_job.printer.anti_alias_level, 4);
+        assert_eq!(read_job.printer.physical_y, 122.88);
+        assert_eq!(read_job.layer_height, 0.05);
+        assert_eq!(read_job.resin, job.resin);
+        assert_eq!(
+            read_job
+                .thumbnail
+                .as_ref()
+                .map(|thumbnail| thumbnail.dimensions()),
+            Some((BIG_PREVIEW_SIZE, BIG_PREVIEW_SIZE))
+        );
+        assert_eq!(read_job.layer_count(), job.layer_count());
+        for (read_layer, layer) in read_job.layers.iter().zip(&job.layers) {
+            let expected: Vec<u8> = layer
+                .as_raw()
+                .iter()
+                .map(|&v| quantize_grey(v, 4))
+                .collect();
+            assert_eq!(read_layer.as_raw(), &expected);
+        }
+    }
+
+    #[test]
+    fn test_rejects_corrupt_layers() {
+        let job = test_job();
+        let mut bytes = write(&job);
+        // Flip a bit in the last run of the last layer, just before its checksum
+        let last_run = bytes.len() - FOOTER_AND_LAYER_END;
+        bytes[last_run] ^= 0x01;
+
+        let Err(error) = read(&mut Cursor::new(bytes)) else {
+            panic!("Reading should fail");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("checksum"));
+    }
+
+    #[test]
+    fn test_rejects_truncated_files() {
+        let bytes = write(&test_job());
+
+        let Err(error) = read(&mut Cursor::new(bytes[..bytes.len() / 2].to_vec())) else {
+            panic!("Reading should fail");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let Err(error) = read(&mut Cursor::new(b"V2.0".to_vec())) else {
+            panic!("Reading should fail");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
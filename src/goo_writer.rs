@@ -0,0 +1,465 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Writer for Elegoo `.goo` printer files.
+//!
+//! A file is a header with two RGB565 previews, followed by every layer as a parameter
+//! block and its run length encoded image, and a fixed footer. Unlike the other formats
+//! every value is big endian and the sections are laid out one after another without offsets.
+
+use crate::goo_reader;
+use crate::layer_codec::encode_goo;
+use crate::output_format::{Capabilities, OutputFormat};
+use crate::printer_profile::PrinterProfile;
+use crate::slice_job::SliceJob;
+use crate::time_util::{unix_now, utc_date_time};
+use image::RgbImage;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+pub const VERSION: &str = "V3.0";
+pub const MAGIC: [u8; 8] = [0x07, 0x00, 0x00, 0x00, 0x44, 0x4C, 0x50, 0x00];
+pub const FOOTER: [u8; 11] = [
+    0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x44, 0x4C, 0x50, 0x00,
+];
+/// Ends the previews and both parts of every layer
+pub const DELIMITER: [u8; 2] = [0x0D, 0x0A];
+/// First byte of every layer image
+pub const LAYER_DATA_MAGIC: u8 = 0x55;
+pub const SMALL_PREVIEW_SIZE: u32 = 116;
+pub const BIG_PREVIEW_SIZE: u32 = 290;
+const SOFTWARE_NAME: &str = "SealSlicer";
+const SOFTWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+const PRICE_UNIT: &str = "$";
+
+pub struct GooFormat;
+
+impl OutputFormat for GooFormat {
+    fn name(&self) -> &'static str {
+        "Elegoo GOO"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["goo"]
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            grayscale: true,
+            per_layer_settings: true,
+            thumbnail_sizes: &[
+                (SMALL_PREVIEW_SIZE, SMALL_PREVIEW_SIZE),
+                (BIG_PREVIEW_SIZE, BIG_PREVIEW_SIZE),
+            ],
+        }
+    }
+
+    fn write_to_file(&self, job: &SliceJob, path: &Path) -> io::Result<()> {
+        GooWriter::for_printer(&job.printer).write_to_file(job, path)
+    }
+
+    fn reader(&self) -> Option<fn(&Path) -> io::Result<SliceJob>> {
+        Some(|path| goo_reader::read_from_file(path))
+    }
+}
+
+pub struct GooWriter {
+    /// Number of grey levels kept in the layer images, 1 writes pure black and white
+    pub anti_alias_level: u32,
+}
+
+impl Default for GooWriter {
+    fn default() -> Self {
+        Self::new(8)
+    }
+}
+
+impl GooWriter {
+    pub fn new(anti_alias_level: u32) -> Self {
+        Self {
+            anti_alias_level: anti_alias_level.max(1),
+        }
+    }
+
+    pub fn for_printer(printer: &PrinterProfile) -> Self {
+        Self::new(printer.anti_alias_level)
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, job: &SliceJob, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(job, &mut writer)?;
+        writer.flush()
+    }
+
+    pub fn write<W: Write>(&self, job: &SliceJob, out: &mut W) -> io::Result<()> {
+        let exposure = &job.resin.exposure;
+        let (date, time) = utc_date_time(unix_now());
+        let layer_data: Vec<Vec<u8>> = job
+            .layers
+            .par_iter()
+            .map(|layer| encode_goo(layer, self.anti_alias_level))
+            .collect();
+
+        put_str(out, VERSION, 4)?;
+        out.write_all(&MAGIC)?;
+        put_str(out, SOFTWARE_NAME, 32)?;
+        put_str(out, SOFTWARE_VERSION, 24)?;
+        put_str(out, &format!("{} {}", date, time), 24)?;
+        put_str(out, &job.printer.name, 32)?;
+        put_str(out, "MSLA", 32)?;
+        put_str(out, &job.resin.name, 32)?;
+        put_u16(out, self.anti_alias_level as u16)?;
+        put_u16(out, 0)?; // Grey level
+        put_u16(out, 0)?; // Blur level
+
+        for size in [SMALL_PREVIEW_SIZE, BIG_PREVIEW_SIZE] {
+            out.write_all(&encode_preview(&job.preview(size, size)))?;
+            out.write_all(&DELIMITER)?;
+        }
+
+        put_u32(out, job.layer_count() as u32)?;
+        put_u16(out, job.printer.pixel_x as u16)?;
+        put_u16(out, job.printer.pixel_y as u16)?;
+        put_u8(out, 0)?; // Mirror X
+        put_u8(out, 0)?; // Mirror Y
+        put_f32(out, job.printer.physical_x as f32)?;
+        put_f32(out, job.printer.physical_y as f32)?;
+        put_f32(out, job.printer.physical_z as f32)?;
+        put_f32(out, job.layer_height as f32)?;
+        put_f32(out, exposure.normal_exposure_time)?;
+        put_u8(out, 0)?; // Delay mode, wait for the light off delay instead of fixed rests
+        put_f32(out, exposure.light_off_delay)?;
+        // Bottom and normal rest times before lift, after lift and after retract
+        for _ in 0..6 {
+            put_f32(out, 0.0)?;
+        }
+        put_f32(out, exposure.bottom_exposure_time)?;
+        put_u32(out, exposure.bottom_layer_count)?;
+        put_f32(out, exposure.bottom_motion.lift_distance)?;
+        put_f32(out, exposure.bottom_motion.lift_speed)?;
+        put_f32(out, exposure.normal_motion.lift_distance)?;
+        put_f32(out, exposure.normal_motion.lift_speed)?;
+        put_f32(out, exposure.bottom_motion.retract_distance)?;
+        put_f32(out, exposure.bottom_motion.retract_speed)?;
+        put_f32(out, exposure.normal_motion.retract_distance)?;
+        put_f32(out, exposure.normal_motion.retract_speed)?;
+        // Second lift and retract stages, not used
+        for _ in 0..8 {
+            put_f32(out, 0.0)?;
+        }
+        put_u16(out, exposure.light_pwm as u16)?; // Bottom light PWM
+        put_u16(out, exposure.light_pwm as u16)?;
+        put_u8(out, 1)?; // Use the per layer settings
+        put_u32(out, job.estimated_print_time().as_secs() as u32)?;
+        put_f32(out, job.estimated_volume_ml() as f32)?;
+        put_f32(out, 0.0)?; // Weight in grams
+        put_f32(out, 0.0)?; // Price
+        put_str(out, PRICE_UNIT, 8)?;
+        put_u32(out, header_size() as u32)?;
+        put_u8(out, 0)?; // Grey values span 0x00 to 0xFF
+        put_u16(out, exposure.transition_layer_count as u16)?;
+
+        for (i, data) in layer_data.iter().enumerate() {
+            let settings = job.layer_settings(i);
+            put_u16(out, 0)?; // Pause flag
+            put_f32(out, job.printer.physical_z as f32)?; // Where to move when paused
+            put_f32(out, job.layer_z(i) as f32)?;
+            put_f32(out, settings.exposure_time)?;
+            put_f32(out, settings.light_off_delay)?;
+            put_f32(out, 0.0)?; // Rest before lift
+            put_f32(out, 0.0)?; // Rest after lift
+            put_f32(out, 0.0)?; // Rest after retract
+            put_f32(out, settings.motion.lift_distance)?;
+            put_f32(out, settings.motion.lift_speed)?;
+            put_f32(out, 0.0)?; // Second lift distance
+            put_f32(out, 0.0)?; // Second lift speed
+            put_f32(out, settings.motion.retract_distance)?;
+            put_f32(out, settings.motion.retract_speed)?;
+            put_f32(out, 0.0)?; // Second retract distance
+            put_f32(out, 0.0)?; // Second retract speed
+            put_u16(out, settings.light_pwm as u16)?;
+            out.write_all(&DELIMITER)?;
+
+            // Magic byte, runs and checksum
+            put_u32(out, data.len() as u32 + 2)?;
+            put_u8(out, LAYER_DATA_MAGIC)?;
+            out.write_all(data)?;
+            put_u8(out, checksum(data))?;
+            out.write_all(&DELIMITER)?;
+        }
+
+        out.write_all(&FOOTER)
+    }
+}
+
+/// Size of everything before the first layer
+fn header_size() -> usize {
+    let strings = 4 + 8 + 32 + 24 + 24 + 32 * 3;
+    let image_settings = 2 * 3;
+    let previews = (SMALL_PREVIEW_SIZE.pow(2) + BIG_PREVIEW_SIZE.pow(2)) as usize * 2 + 2 * 2;
+    // Layer count, resolution, mirroring and platform size
+    let machine = 4 + 2 * 2 + 2 + 4 * 3;
+    // Layer height, exposure, delay mode, light off delay, rests, bottom exposure and layers
+    let exposure = 4 + 4 + 1 + 4 + 4 * 6 + 4 + 4;
+    // Lift and retract stages, light PWM and the per layer flag
+    let motion = 4 * 16 + 2 * 2 + 1;
+    // Print time, volume, weight, price, price unit, layer offset, grey scale and transition
+    let totals = 4 * 4 + 8 + 4 + 1 + 2;
+    strings + image_settings + previews + machine + exposure + motion + totals
+}
+
+fn put_u8<W: Write>(out: &mut W, value: u8) -> io::Result<()> {
+    out.write_all(&[value])
+}
+
+fn put_u16<W: Write>(out: &mut W, value: u16) -> io::Result<()> {
+    out.write_all(&value.to_be_bytes())
+}
+
+fn put_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_be_bytes())
+}
+
+fn put_f32<W: Write>(out: &mut W, value: f32) -> io::Result<()> {
+    out.write_all(&value.to_be_bytes())
+}
+
+/// Writes a string into a fixed size zero padded field, cutting it if it is too long
+fn put_str<W: Write>(out: &mut W, value: &str, size: usize) -> io::Result<()> {
+    let mut field = vec![0u8; size];
+    let bytes = value.as_bytes();
+    let length = bytes.len().min(size);
+    field[..length].copy_from_slice(&bytes[..length]);
+    out.write_all(&field)
+}
+
+/// Encodes a preview as uncompressed big endian RGB565
+pub fn encode_preview(image: &RgbImage) -> Vec<u8> {
+    let mut data = Vec::with_capacity((image.width() * image.height() * 2) as usize);
+    for pixel in image.pixels() {
+        let color =
+            ((pixel[0] as u16 >> 3) << 11) | ((pixel[1] as u16 >> 2) << 5) | (pixel[2] as u16 >> 3);
+        data.extend_from_slice(&color.to_be_bytes());
+    }
+    data
+}
+
+/// Decodes big endian RGB565 preview data back into an image
+pub fn decode_preview(data: &[u8], width: u32, height: u32) -> io::Result<RgbImage> {
+    let pixel_count = (width * height) as usize;
+    if data.len() < pixel_count * 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Truncated preview data",
+        ));
+    }
+    let pixels = data[..pixel_count * 2]
+        .chunks_exact(2)
+        .flat_map(|chunk| {
+            let color = u16::from_be_bytes([chunk[0], chunk[1]]);
+            [
+                ((color >> 11) as u8) << 3,
+                (((color >> 5) & 0x3F) as u8) << 2,
+                ((color & 0x1F) as u8) << 3,
+            ]
+        })
+        .collect();
+    Ok(RgbImage::from_raw(width, height, pixels).expect("Pixel count matches the size"))
+}
+
+/// Checksum stored after every layer image, the inverted low byte of the sum of its runs
+pub fn checksum(data: &[u8]) -> u8 {
+    !data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer_codec::{decode_goo, quantize_grey};
+    use crate::resin_profile::{ExposureSettings, ResinProfile};
+    use image::{ImageBuffer, Luma};
+
+    /// Reads big endian values from a written file in order
+    struct Reader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl<'a> Reader<'a> {
+        fn new(bytes: &'a [u8]) -> Self {
+            Self { bytes, position: 0 }
+        }
+
+        fn take(&mut self, count: usize) -> &'a [u8] {
+            let slice = &self.bytes[self.position..self.position + count];
+            self.position += count;
+            slice
+        }
+
+        fn u8(&mut self) -> u8 {
+            self.take(1)[0]
+        }
+
+        fn u16(&mut self) -> u16 {
+            u16::from_be_bytes(self.take(2).try_into().unwrap())
+        }
+
+        fn u32(&mut self) -> u32 {
+            u32::from_be_bytes(self.take(4).try_into().unwrap())
+        }
+
+        fn f32(&mut self) -> f32 {
+            f32::from_be_bytes(self.take(4).try_into().unwrap())
+        }
+
+        fn string(&mut self, size: usize) -> String {
+            let field = self.take(size);
+            let end = field.iter().position(|&b| b == 0).unwrap_or(size);
+            String::from_utf8(field[..end].to_vec()).unwrap()
+        }
+
+        fn skip(&mut self, count: usize) {
+            self.position += count;
+        }
+    }
+
+    struct ParsedLayer {
+        z: f32,
+        exposure_time: f32,
+        lift_speed: f32,
+        image: ImageBuffer<Luma<u8>, Vec<u8>>,
+    }
+
+    fn test_job() -> SliceJob {
+        let mut first = ImageBuffer::from_pixel(40, 20, Luma([0u8]));
+        for x in 5..30 {
+            first.put_pixel(x, 10, Luma([255]));
+        }
+        first.put_pixel(30, 10, Luma([128]));
+        let gradient = ImageBuffer::from_fn(40, 20, |x, _| Luma([(x * 6) as u8]));
+        let printer = PrinterProfile {
+            name: "Saturn 3 Ultra".to_string(),
+            pixel_x: 40,
+            pixel_y: 20,
+            output_format: "goo".to_string(),
+            ..PrinterProfile::default()
+        };
+        let resin = ResinProfile {
+            name: "ABS-Like".to_string(),
+            exposure: ExposureSettings {
+                normal_exposure_time: 2.0,
+                bottom_exposure_time: 25.0,
+                bottom_layer_count: 1,
+                ..ExposureSettings::default()
+            },
+        };
+        SliceJob::new(vec![first, gradient], 0.05, printer, resin)
+    }
+
+    fn parse(bytes: &[u8], layer_count: usize) -> (Reader<'_>, Vec<ParsedLayer>) {
+        let mut reader = Reader::new(bytes);
+        reader.skip(header_size());
+        let layers = (0..layer_count)
+            .map(|_| {
+                assert_eq!(reader.u16(), 0, "Pause flag");
+                reader.f32();
+                let z = reader.f32();
+                let exposure_time = reader.f32();
+                reader.skip(4 * 5);
+                let lift_speed = reader.f32();
+                reader.skip(4 * 6 + 2);
+                assert_eq!(reader.take(2), DELIMITER);
+                let size = reader.u32() as usize;
+                let content = reader.take(size);
+                assert_eq!(content[0], LAYER_DATA_MAGIC);
+                let data = &content[1..size - 1];
+                assert_eq!(content[size - 1], checksum(data), "Layer checksum");
+                assert_eq!(reader.take(2), DELIMITER);
+                ParsedLayer {
+                    z,
+                    exposure_time,
+                    lift_speed,
+                    image: decode_goo(data, 40, 20).expect("Layer should decode"),
+                }
+            })
+            .collect();
+        (reader, layers)
+    }
+
+    fn write_job(writer: &GooWriter, job: &SliceJob) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        writer.write(job, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let job = test_job();
+        let bytes = write_job(&GooWriter::new(4), &job);
+        let mut reader = Reader::new(&bytes);
+
+        assert_eq!(reader.string(4), "V3.0");
+        assert_eq!(reader.take(8), MAGIC);
+        assert_eq!(reader.string(32), "SealSlicer");
+        reader.skip(24 + 24);
+        assert_eq!(reader.string(32), "Saturn 3 Ultra");
+        reader.skip(32);
+        assert_eq!(reader.string(32), "ABS-Like");
+        assert_eq!(reader.u16(), 4, "Anti alias level");
+        reader.skip(4);
+        reader.skip(SMALL_PREVIEW_SIZE.pow(2) as usize * 2);
+        assert_eq!(reader.take(2), DELIMITER);
+        reader.skip(BIG_PREVIEW_SIZE.pow(2) as usize * 2);
+        assert_eq!(reader.take(2), DELIMITER);
+        assert_eq!(reader.u32(), 2, "Layer count");
+        assert_eq!((reader.u16(), reader.u16()), (40, 20));
+        reader.skip(2);
+        assert_eq!(reader.f32(), 218.88);
+        reader.skip(8);
+        assert_eq!(reader.f32(), 0.05, "Layer height");
+        assert_eq!(reader.f32(), 2.0, "Exposure");
+        reader.skip(1);
+        assert_eq!(reader.f32(), 1.0, "Light off delay");
+        reader.skip(4 * 6);
+        assert_eq!(reader.f32(), 25.0, "Bottom exposure");
+        assert_eq!(reader.u32(), 1, "Bottom layers");
+        reader.skip(4 * 16 + 2 * 2);
+        assert_eq!(reader.u8(), 1, "Per layer settings");
+        assert_eq!(reader.u32(), job.estimated_print_time().as_secs() as u32);
+        reader.skip(4 * 3 + 8);
+        assert_eq!(reader.u32() as usize, header_size(), "Layer content offset");
+        reader.skip(1 + 2);
+        assert_eq!(reader.position, header_size());
+    }
+
+    #[test]
+    fn test_layers_round_trip() {
+        let job = test_job();
+        let bytes = write_job(&GooWriter::new(16), &job);
+
+        let (mut reader, layers) = parse(&bytes, job.layer_count());
+
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].exposure_time, 25.0);
+        assert_eq!(layers[0].lift_speed, 45.0);
+        assert_eq!(layers[1].exposure_time, 2.0);
+        assert_eq!(layers[1].lift_speed, 65.0);
+        assert!((layers[1].z - 0.1).abs() < 1e-6);
+        for (original, parsed) in job.layers.iter().zip(&layers) {
+            for (value, decoded) in original.as_raw().iter().zip(parsed.image.as_raw()) {
+                assert_eq!(quantize_grey(*value, 16), *decoded);
+            }
+        }
+        assert_eq!(reader.take(FOOTER.len()), FOOTER);
+        assert_eq!(reader.position, bytes.len());
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum(&[]), 0xFF);
+        assert_eq!(checksum(&[0x01, 0x02]), 0xFC);
+        assert_eq!(checksum(&[0xFF, 0x02]), 0xFE);
+    }
+}
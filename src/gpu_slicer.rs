@@ -52,6 +52,10 @@ impl GPUSlicer {
         }
     }
 
+    pub fn slice_thickness(&self) -> f64 {
+        self.slice_thickness
+    }
+
     pub fn slice_bodies(&self, _bodies: Vec<Rc<RefCell<Body>>>) -> Result<Vec<ImageBuffer<Luma<u8>, Vec<u8>>>, Box<dyn std::error::Error>> {
         let triangles: Vec<Triangle> = Vec::new();    
         self.generate_slice_images(&triangles)
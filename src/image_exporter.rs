@@ -0,0 +1,300 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Exporter for the raw layer images of a slice job.
+//!
+//! Every layer is written as its own grayscale image into an output directory, named
+//! from a template with the layer number. The images are encoded in parallel straight
+//! from the layer data, without converting them to RGB first.
+
+use crate::layer_codec::pack_bits;
+use image::codecs::bmp::BmpEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{ExtendedColorType, GrayImage};
+use rayon::prelude::*;
+use std::fs;
+use std::io::{self, Seek, Write};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// File format of the exported layer images
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SliceImageFormat {
+    /// 8 bit grayscale PNG
+    Png,
+    /// 1 bit black and white PNG, grey pixels are thresholded
+    PngMono,
+    /// Lossless WebP
+    #[default]
+    Webp,
+    Bmp,
+}
+
+impl SliceImageFormat {
+    pub const ALL: [SliceImageFormat; 4] = [
+        SliceImageFormat::Png,
+        SliceImageFormat::PngMono,
+        SliceImageFormat::Webp,
+        SliceImageFormat::Bmp,
+    ];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            SliceImageFormat::Png | SliceImageFormat::PngMono => "png",
+            SliceImageFormat::Webp => "webp",
+            SliceImageFormat::Bmp => "bmp",
+        }
+    }
+
+    /// Name shown in the format picker
+    pub fn label(self) -> &'static str {
+        match self {
+            SliceImageFormat::Png => "PNG",
+            SliceImageFormat::PngMono => "PNG 1-bit",
+            SliceImageFormat::Webp => "WebP",
+            SliceImageFormat::Bmp => "BMP",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.label() == label)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageExportSettings {
+    pub format: SliceImageFormat,
+    /// Every slicing run is written to a directory named after its time stamp in here
+    pub directory: PathBuf,
+    /// Name of the layer images without the extension, `{n}` is replaced by the layer number
+    pub file_name: String,
+    /// Layer numbers are padded with zeros to at least this many digits
+    pub layer_number_digits: usize,
+}
+
+impl Default for ImageExportSettings {
+    fn default() -> Self {
+        Self {
+            format: SliceImageFormat::default(),
+            directory: PathBuf::from("slices"),
+            file_name: "slice_{n}".to_string(),
+            layer_number_digits: 4,
+        }
+    }
+}
+
+impl ImageExportSettings {
+    pub fn layer_file_name(&self, layer_index: usize) -> String {
+        let number = format!("{:0width$}", layer_index, width = self.layer_number_digits);
+        format!(
+            "{}.{}",
+            self.file_name.replace("{n}", &number),
+            self.format.extension()
+        )
+    }
+
+    /// Directory the run started at `timestamp` is written to
+    pub fn run_directory(&self, timestamp: u64) -> PathBuf {
+        self.directory.join(timestamp.to_string())
+    }
+}
+
+pub struct ImageExporter {
+    pub settings: ImageExportSettings,
+}
+
+impl ImageExporter {
+    pub fn new(settings: ImageExportSettings) -> Self {
+        Self { settings }
+    }
+
+    /// Writes every layer into `directory`, creating it if needed
+    pub fn export<P: AsRef<Path>>(&self, layers: &[GrayImage], directory: P) -> io::Result<()> {
+        if !self.settings.file_name.contains("{n}") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Slice file name \"{}\" needs {{n}} for the layer number",
+                    self.settings.file_name
+                ),
+            ));
+        }
+        let directory = directory.as_ref();
+        fs::create_dir_all(directory)?;
+        layers.par_iter().enumerate().try_for_each(|(i, layer)| {
+            let data = encode_layer(layer, self.settings.format)?;
+            fs::write(directory.join(self.settings.layer_file_name(i)), data)
+        })
+    }
+}
+
+/// Encodes a layer in the given format
+pub fn encode_layer(layer: &GrayImage, format: SliceImageFormat) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    match format {
+        SliceImageFormat::Png => {
+            return encode_layer_png(layer, png::BitDepth::Eight, png::Compression::Default)
+        }
+        SliceImageFormat::PngMono => {
+            return encode_layer_png(layer, png::BitDepth::One, png::Compression::Default)
+        }
+        SliceImageFormat::Webp => WebPEncoder::new_lossless(&mut data)
+            .encode(
+                layer.as_raw(),
+                layer.width(),
+                layer.height(),
+                ExtendedColorType::L8,
+            )
+            .map_err(io::Error::other)?,
+        SliceImageFormat::Bmp => BmpEncoder::new(&mut data)
+            .encode(
+                layer.as_raw(),
+                layer.width(),
+                layer.height(),
+                ExtendedColorType::L8,
+            )
+            .map_err(io::Error::other)?,
+    }
+    Ok(data)
+}
+
+/// Encodes a layer as a grayscale PNG keeping the most significant `bit_depth` bits
+/// Zip entry options for PNGs, stored as they are already compressed
+pub fn png_entry_options() -> SimpleFileOptions {
+    SimpleFileOptions::default().compression_method(CompressionMethod::Stored)
+}
+
+/// Writes every layer into the zip as a PNG named by `file_name`. Layers are encoded a
+/// batch at a time and written straight into the zip, so only one batch of PNGs is held
+/// in memory.
+pub fn write_png_layers<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    layers: &[GrayImage],
+    encode: impl Fn(&GrayImage) -> io::Result<Vec<u8>> + Sync,
+    file_name: impl Fn(usize) -> String,
+) -> io::Result<()> {
+    let batch_size = rayon::current_num_threads().max(1);
+    for (batch_index, batch) in layers.chunks(batch_size).enumerate() {
+        let encoded: Vec<Vec<u8>> = batch.par_iter().map(&encode).collect::<io::Result<_>>()?;
+        for (i, png) in encoded.iter().enumerate() {
+            zip.start_file(file_name(batch_index * batch_size + i), png_entry_options())
+                .map_err(io::Error::from)?;
+            zip.write_all(png)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn encode_layer_png(
+    layer: &GrayImage,
+    bit_depth: png::BitDepth,
+    compression: png::Compression,
+) -> io::Result<Vec<u8>> {
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, layer.width(), layer.height());
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(bit_depth);
+    encoder.set_compression(compression);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer
+        .write_image_data(&pack_bits(layer, bit_depth as u8))
+        .map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    fn layers() -> Vec<GrayImage> {
+        (0..3)
+            .map(|i| GrayImage::from_fn(13, 7, |x, y| Luma([((x * 19 + y * 7) * (i + 1)) as u8])))
+            .collect()
+    }
+
+    #[test]
+    fn test_layer_file_name() {
+        let settings = ImageExportSettings::default();
+        assert_eq!(settings.layer_file_name(7), "slice_0007.webp");
+
+        let settings = ImageExportSettings {
+            format: SliceImageFormat::PngMono,
+            file_name: "{n}_layer".to_string(),
+            layer_number_digits: 0,
+            ..ImageExportSettings::default()
+        };
+        assert_eq!(settings.layer_file_name(12), "12_layer.png");
+    }
+
+    #[test]
+    fn test_formats_round_trip() {
+        let directory = tempfile::tempdir().unwrap();
+        let layers = layers();
+        for format in SliceImageFormat::ALL {
+            let settings = ImageExportSettings {
+                format,
+                ..ImageExportSettings::default()
+            };
+            let run_directory = directory.path().join(format.label());
+            ImageExporter::new(settings.clone())
+                .export(&layers, &run_directory)
+                .unwrap();
+
+            for (i, layer) in layers.iter().enumerate() {
+                let path = run_directory.join(settings.layer_file_name(i));
+                let image = image::open(&path).unwrap();
+                // The WebP and paletted BMP decoders hand back RGB, whatever was encoded
+                if format.extension() == "png" {
+                    assert!(
+                        matches!(image, image::DynamicImage::ImageLuma8(_)),
+                        "{} should stay grayscale",
+                        path.display()
+                    );
+                }
+                let expected = match format {
+                    SliceImageFormat::PngMono => GrayImage::from_fn(13, 7, |x, y| {
+                        Luma([if layer.get_pixel(x, y)[0] >= 128 {
+                            255
+                        } else {
+                            0
+                        }])
+                    }),
+                    _ => layer.clone(),
+                };
+                assert_eq!(image.into_luma8(), expected, "{}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn test_format_labels() {
+        for format in SliceImageFormat::ALL {
+            assert_eq!(SliceImageFormat::from_label(format.label()), Some(format));
+        }
+        assert_eq!(SliceImageFormat::from_label("TIFF"), None);
+    }
+
+    #[test]
+    fn test_errors_are_returned() {
+        let directory = tempfile::tempdir().unwrap();
+        let blocker = directory.path().join("file");
+        fs::write(&blocker, b"not a directory").unwrap();
+
+        let error = ImageExporter::new(ImageExportSettings::default())
+            .export(&layers(), blocker.join("run"))
+            .unwrap_err();
+        assert_ne!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let settings = ImageExportSettings {
+            file_name: "slice".to_string(),
+            ..ImageExportSettings::default()
+        };
+        let error = ImageExporter::new(settings)
+            .export(&layers(), directory.path())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,586 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Run length and bit packing codecs for layer images.
+//!
+//! Every printer format stores the same grayscale layer with its own encoding. Encoders
+//! first reduce the image to the grey levels the printer shows with [`quantize_grey`], so
+//! decoding an encoded layer gives back the quantized layer, less any bits the encoding
+//! drops. Encoders and decoders only read their input, so layers can be handed to them
+//! from a parallel iterator.
+
+use image::{ImageBuffer, Luma};
+use std::io;
+
+type Layer = ImageBuffer<Luma<u8>, Vec<u8>>;
+
+/// Longest run a single RLE-7, RLE-8 or GOO entry can hold
+const MAX_LONG_RUN: u32 = 0x0FFF_FFFF;
+/// Longest black or white run a single PW0 entry can hold
+const MAX_PW0_EXTREME_RUN: u32 = 0x0FFF;
+/// Longest grey run a single PW0 entry can hold
+const MAX_PW0_GREY_RUN: u32 = 0x0F;
+/// Largest layer a decoder accepts, well above the 16K screens of current printers
+const MAX_LAYER_PIXELS: usize = 1 << 28;
+
+/// Run types stored in the top two bits of a GOO chunk. The remaining type, 0b10,
+/// stores the difference to the previous grey value.
+const GOO_BLACK: u8 = 0b00 << 6;
+const GOO_GREY: u8 = 0b01 << 6;
+const GOO_WHITE: u8 = 0b11 << 6;
+
+/// Reduces a grey value to one of `levels` evenly spaced levels. With one or two
+/// levels the image is thresholded to black and white.
+pub fn quantize_grey(value: u8, levels: u32) -> u8 {
+    if levels <= 2 {
+        return if value >= 128 { 255 } else { 0 };
+    }
+    let steps = (levels - 1) as f32;
+    let level = (value as f32 * steps / 255.0).round();
+    (level * 255.0 / steps).round() as u8
+}
+
+/// Encodes a layer with the ChiTu RLE-7 scheme. Each entry is a byte with the 7 most
+/// significant bits of the grey value; when its top bit is set a run length of one to
+/// four bytes follows, the number of leading ones in its first byte giving the extra bytes.
+pub fn encode_rle7(layer: &Layer, anti_alias_level: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    let codes = layer
+        .as_raw()
+        .iter()
+        .map(|&value| quantize_grey(value, anti_alias_level) >> 1);
+    for_each_run(
+        codes,
+        |_| MAX_LONG_RUN,
+        |code, run| {
+            if run == 1 {
+                data.push(code);
+            } else {
+                data.push(code | 0x80);
+                push_chitu_run_length(&mut data, run);
+            }
+        },
+    );
+    data
+}
+
+/// Decodes RLE-7 layer data back into an image. The dropped low bit is restored from
+/// the bit above it so full white stays 255.
+pub fn decode_rle7(data: &[u8], width: u32, height: u32) -> io::Result<Layer> {
+    let mut layer = LayerBuilder::new(width, height)?;
+    let mut bytes = data.iter().copied();
+    while let Some(entry) = bytes.next() {
+        let code = entry & 0x7F;
+        let run = if entry & 0x80 != 0 {
+            read_chitu_run_length(&mut bytes)?
+        } else {
+            1
+        };
+        layer.push_run((code << 1) | (code & 1), run)?;
+    }
+    layer.finish()
+}
+
+/// Encodes a layer with the ChiTu RLE-8 scheme, which keeps the full grey value. Every
+/// entry is the grey value followed by its run length, stored as in RLE-7.
+#[cfg(test)]
+pub fn encode_rle8(layer: &Layer, anti_alias_level: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    let values = layer
+        .as_raw()
+        .iter()
+        .map(|&value| quantize_grey(value, anti_alias_level));
+    for_each_run(
+        values,
+        |_| MAX_LONG_RUN,
+        |value, run| {
+            data.push(value);
+            push_chitu_run_length(&mut data, run);
+        },
+    );
+    data
+}
+
+/// Decodes RLE-8 layer data back into an image
+#[cfg(test)]
+pub fn decode_rle8(data: &[u8], width: u32, height: u32) -> io::Result<Layer> {
+    let mut layer = LayerBuilder::new(width, height)?;
+    let mut bytes = data.iter().copied();
+    while let Some(value) = bytes.next() {
+        let run = read_chitu_run_length(&mut bytes)?;
+        layer.push_run(value, run)?;
+    }
+    layer.finish()
+}
+
+fn push_chitu_run_length(data: &mut Vec<u8>, run: u32) {
+    if run <= 0x7F {
+        data.push(run as u8);
+    } else if run <= 0x3FFF {
+        data.push((run >> 8) as u8 | 0x80);
+        data.push(run as u8);
+    } else if run <= 0x1F_FFFF {
+        data.push((run >> 16) as u8 | 0xC0);
+        data.push((run >> 8) as u8);
+        data.push(run as u8);
+    } else {
+        data.push((run >> 24) as u8 | 0xE0);
+        data.push((run >> 16) as u8);
+        data.push((run >> 8) as u8);
+        data.push(run as u8);
+    }
+}
+
+fn read_chitu_run_length(bytes: &mut impl Iterator<Item = u8>) -> io::Result<usize> {
+    let first = bytes.next().ok_or_else(truncated)? as usize;
+    let (mut run, extra) = if first & 0x80 == 0 {
+        (first, 0)
+    } else if first & 0xC0 == 0x80 {
+        (first & 0x3F, 1)
+    } else if first & 0xE0 == 0xC0 {
+        (first & 0x1F, 2)
+    } else {
+        (first & 0x0F, 3)
+    };
+    for _ in 0..extra {
+        run = (run << 8) | bytes.next().ok_or_else(truncated)? as usize;
+    }
+    Ok(run)
+}
+
+/// Encodes a layer with the Anycubic PW0 scheme. The top four bits of every entry hold
+/// the grey level. Black and white runs take two bytes with a 12 bit length, grey runs
+/// take a single byte with a 4 bit length.
+pub fn encode_pw0(layer: &Layer, anti_alias_level: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    let colors = layer
+        .as_raw()
+        .iter()
+        .map(|&value| quantize_grey(value, anti_alias_level) >> 4);
+    let max_run = |color| {
+        if is_pw0_extreme(color) {
+            MAX_PW0_EXTREME_RUN
+        } else {
+            MAX_PW0_GREY_RUN
+        }
+    };
+    for_each_run(colors, max_run, |color, run| {
+        if is_pw0_extreme(color) {
+            data.push((color << 4) | (run >> 8) as u8);
+            data.push(run as u8);
+        } else {
+            data.push((color << 4) | run as u8);
+        }
+    });
+    data
+}
+
+/// Decodes PW0 layer data back into an image
+pub fn decode_pw0(data: &[u8], width: u32, height: u32) -> io::Result<Layer> {
+    let mut layer = LayerBuilder::new(width, height)?;
+    let mut bytes = data.iter().copied();
+    while let Some(entry) = bytes.next() {
+        let color = entry >> 4;
+        let mut run = (entry & 0x0F) as usize;
+        if is_pw0_extreme(color) {
+            run = (run << 8) | bytes.next().ok_or_else(truncated)? as usize;
+        }
+        layer.push_run(color * 0x11, run)?;
+    }
+    layer.finish()
+}
+
+fn is_pw0_extreme(color: u8) -> bool {
+    color == 0 || color == 0x0F
+}
+
+/// Encodes a layer with the Elegoo GOO scheme. The top two bits of a chunk give its
+/// type: black, white or a grey value stored in the next byte. The next two bits give how
+/// many more bytes the run length takes, and the low four bits are its most significant bits.
+pub fn encode_goo(layer: &Layer, anti_alias_level: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    let values = layer
+        .as_raw()
+        .iter()
+        .map(|&value| quantize_grey(value, anti_alias_level));
+    for_each_run(
+        values,
+        |_| MAX_LONG_RUN,
+        |value, run| {
+            let chunk_type = match value {
+                0x00 => GOO_BLACK,
+                0xFF => GOO_WHITE,
+                _ => GOO_GREY,
+            };
+            let (size_bits, extra_bytes) = match run {
+                0..=0xF => (0b00, 0),
+                0x10..=0xFFF => (0b01, 1),
+                0x1000..=0xF_FFFF => (0b10, 2),
+                _ => (0b11, 3),
+            };
+            data.push(chunk_type | (size_bits << 4) | ((run >> (8 * extra_bytes)) as u8 & 0x0F));
+            if chunk_type == GOO_GREY {
+                data.push(value);
+            }
+            for i in (0..extra_bytes).rev() {
+                data.push((run >> (8 * i)) as u8);
+            }
+        },
+    );
+    data
+}
+
+/// Decodes GOO layer runs back into an image. Difference chunks are never written by
+/// [`encode_goo`] and are rejected.
+pub fn decode_goo(data: &[u8], width: u32, height: u32) -> io::Result<Layer> {
+    let mut layer = LayerBuilder::new(width, height)?;
+    let mut bytes = data.iter().copied();
+    while let Some(chunk) = bytes.next() {
+        let value = match chunk & 0xC0 {
+            GOO_BLACK => 0x00,
+            GOO_WHITE => 0xFF,
+            GOO_GREY => bytes.next().ok_or_else(truncated)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Difference chunks are not supported",
+                ))
+            }
+        };
+        let mut run = (chunk & 0x0F) as usize;
+        for _ in 0..(chunk >> 4) & 0b11 {
+            run = (run << 8) | bytes.next().ok_or_else(truncated)? as usize;
+        }
+        layer.push_run(value, run)?;
+    }
+    layer.finish()
+}
+
+/// Packs every row into `bits` wide samples holding the most significant bits of each
+/// pixel, first pixel in the most significant bits of a byte. Rows start on a byte
+/// boundary as PNG requires. `bits` is 1, 2, 4 or 8.
+pub fn pack_bits(layer: &Layer, bits: u8) -> Vec<u8> {
+    debug_assert!(matches!(bits, 1 | 2 | 4 | 8));
+    if bits == 8 {
+        return layer.as_raw().clone();
+    }
+    let samples_per_byte = (8 / bits) as usize;
+    let row_length = layer.width() as usize;
+    let mut packed =
+        Vec::with_capacity(packed_row_size(layer.width(), bits) * layer.height() as usize);
+    for row in layer.as_raw().chunks(row_length.max(1)) {
+        for samples in row.chunks(samples_per_byte) {
+            let byte = samples
+                .iter()
+                .fold(0u8, |byte, &value| (byte << bits) | (value >> (8 - bits)));
+            packed.push(byte << (bits as usize * (samples_per_byte - samples.len())));
+        }
+    }
+    packed
+}
+
+/// Unpacks rows written by [`pack_bits`], scaling every sample back to the full grey range
+#[cfg(test)]
+pub fn unpack_bits(data: &[u8], width: u32, height: u32, bits: u8) -> io::Result<Layer> {
+    if !matches!(bits, 1 | 2 | 4 | 8) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported bit depth: {}", bits),
+        ));
+    }
+    let pixel_count = pixel_count(width, height)?;
+    let row_size = packed_row_size(width, bits);
+    if data.len() < row_size * height as usize {
+        return Err(truncated());
+    }
+    let scale = 255 / ((1u16 << bits) - 1) as u8;
+    let mask = ((1u16 << bits) - 1) as u8;
+    let mut pixels = Vec::with_capacity(pixel_count);
+    for row in data.chunks(row_size.max(1)).take(height as usize) {
+        for x in 0..width as usize {
+            let bit_offset = x * bits as usize;
+            let shift = 8 - bits as usize - bit_offset % 8;
+            let sample = (row[bit_offset / 8] >> shift) & mask;
+            pixels.push(sample * scale);
+        }
+    }
+    Ok(ImageBuffer::from_raw(width, height, pixels).expect("Pixel count matches the size"))
+}
+
+fn packed_row_size(width: u32, bits: u8) -> usize {
+    (width as usize * bits as usize).div_ceil(8)
+}
+
+/// Splits values into runs of equal values, none longer than `max_run` allows for its value
+fn for_each_run(
+    values: impl Iterator<Item = u8>,
+    max_run: impl Fn(u8) -> u32,
+    mut push: impl FnMut(u8, u32),
+) {
+    let mut current = None;
+    let mut run: u32 = 0;
+    for value in values {
+        if current == Some(value) && run < max_run(value) {
+            run += 1;
+            continue;
+        }
+        if let Some(previous) = current {
+            push(previous, run);
+        }
+        current = Some(value);
+        run = 1;
+    }
+    if let Some(previous) = current {
+        push(previous, run);
+    }
+}
+
+/// Number of pixels in a layer of the given size, refusing sizes no printer has so a
+/// corrupt header fails instead of allocating gigabytes
+fn pixel_count(width: u32, height: u32) -> io::Result<usize> {
+    (width as usize)
+        .checked_mul(height as usize)
+        .filter(|&count| count <= MAX_LAYER_PIXELS)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Layer size {}x{} is too large", width, height),
+            )
+        })
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated layer data")
+}
+
+/// Collects decoded runs, refusing any that would overflow the image or leave part of it
+/// uncovered. Memory grows with the decoded runs rather than being reserved for the size
+/// in the file header.
+struct LayerBuilder {
+    width: u32,
+    height: u32,
+    pixel_count: usize,
+    pixels: Vec<u8>,
+}
+
+impl LayerBuilder {
+    fn new(width: u32, height: u32) -> io::Result<Self> {
+        Ok(Self {
+            width,
+            height,
+            pixel_count: pixel_count(width, height)?,
+            pixels: Vec::new(),
+        })
+    }
+
+    fn push_run(&mut self, value: u8, run: usize) -> io::Result<()> {
+        if run > self.pixel_count - self.pixels.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Layer data is larger than the image",
+            ));
+        }
+        self.pixels.extend(std::iter::repeat_n(value, run));
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<Layer> {
+        if self.pixels.len() < self.pixel_count {
+            return Err(truncated());
+        }
+        Ok(ImageBuffer::from_raw(self.width, self.height, self.pixels)
+            .expect("Pixel count matches the size"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Layers built from runs of random length, so long runs show up as often as noise
+    fn layers() -> impl Strategy<Value = Layer> {
+        (
+            1u32..300,
+            1u32..40,
+            prop::collection::vec((any::<u8>(), 1usize..5000), 1..40),
+        )
+            .prop_map(|(width, height, runs)| {
+                let pixel_count = (width * height) as usize;
+                let mut pixels: Vec<u8> = runs
+                    .into_iter()
+                    .flat_map(|(value, run)| std::iter::repeat_n(value, run))
+                    .take(pixel_count)
+                    .collect();
+                pixels.resize(pixel_count, 0);
+                ImageBuffer::from_raw(width, height, pixels).unwrap()
+            })
+    }
+
+    fn anti_alias_levels() -> impl Strategy<Value = u32> {
+        prop_oneof![Just(1u32), Just(2), Just(4), Just(8), Just(16), Just(256)]
+    }
+
+    fn map_pixels(layer: &Layer, f: impl Fn(u8) -> u8) -> Layer {
+        ImageBuffer::from_fn(layer.width(), layer.height(), |x, y| {
+            Luma([f(layer.get_pixel(x, y)[0])])
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_rle7_round_trip(layer in layers(), levels in anti_alias_levels()) {
+            let data = encode_rle7(&layer, levels);
+            let decoded = decode_rle7(&data, layer.width(), layer.height()).unwrap();
+            let expected = map_pixels(&layer, |v| {
+                let code = quantize_grey(v, levels) >> 1;
+                (code << 1) | (code & 1)
+            });
+            prop_assert_eq!(decoded, expected);
+        }
+
+        #[test]
+        fn prop_rle8_round_trip(layer in layers(), levels in anti_alias_levels()) {
+            let data = encode_rle8(&layer, levels);
+            let decoded = decode_rle8(&data, layer.width(), layer.height()).unwrap();
+            prop_assert_eq!(decoded, map_pixels(&layer, |v| quantize_grey(v, levels)));
+        }
+
+        #[test]
+        fn prop_pw0_round_trip(layer in layers(), levels in anti_alias_levels()) {
+            let data = encode_pw0(&layer, levels);
+            let decoded = decode_pw0(&data, layer.width(), layer.height()).unwrap();
+            let expected = map_pixels(&layer, |v| (quantize_grey(v, levels) >> 4) * 0x11);
+            prop_assert_eq!(decoded, expected);
+        }
+
+        #[test]
+        fn prop_goo_round_trip(layer in layers(), levels in anti_alias_levels()) {
+            let data = encode_goo(&layer, levels);
+            let decoded = decode_goo(&data, layer.width(), layer.height()).unwrap();
+            prop_assert_eq!(decoded, map_pixels(&layer, |v| quantize_grey(v, levels)));
+        }
+
+        #[test]
+        fn prop_packed_round_trip(layer in layers(), bits in prop_oneof![Just(1u8), Just(2), Just(4), Just(8)]) {
+            let data = pack_bits(&layer, bits);
+            prop_assert_eq!(data.len(), packed_row_size(layer.width(), bits) * layer.height() as usize);
+            let decoded = unpack_bits(&data, layer.width(), layer.height(), bits).unwrap();
+            let scale = 255 / ((1u16 << bits) - 1) as u8;
+            prop_assert_eq!(decoded, map_pixels(&layer, |v| (v >> (8 - bits)) * scale));
+        }
+
+        #[test]
+        fn prop_decoders_reject_garbage_without_panicking(data in prop::collection::vec(any::<u8>(), 0..200)) {
+            // Any outcome is fine as long as nothing panics or overflows the image
+            for decode in [decode_rle7, decode_rle8, decode_pw0, decode_goo] {
+                if let Ok(layer) = decode(&data, 17, 5) {
+                    prop_assert_eq!(layer.dimensions(), (17, 5));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantize_grey() {
+        assert_eq!(quantize_grey(127, 1), 0);
+        assert_eq!(quantize_grey(128, 2), 255);
+        assert_eq!(quantize_grey(128, 16), 136);
+        assert_eq!(quantize_grey(100, 256), 100);
+    }
+
+    #[test]
+    fn test_rle7_long_runs_round_trip() {
+        // Runs needing two, three and four length bytes
+        for (width, height) in [(100, 100), (1000, 1000), (3000, 1000)] {
+            let layer = ImageBuffer::from_pixel(width, height, Luma([255u8]));
+            let data = encode_rle7(&layer, 1);
+            assert!(data.len() <= 5, "A uniform layer should be a single run");
+            assert_eq!(decode_rle7(&data, width, height).unwrap(), layer);
+        }
+    }
+
+    #[test]
+    fn test_pw0_bytes() {
+        // Two black pixels, five white pixels and a single grey pixel of level 8
+        let layer = ImageBuffer::from_raw(4, 2, vec![0, 0, 255, 255, 255, 255, 255, 128]).unwrap();
+        assert_eq!(encode_pw0(&layer, 16), vec![0x00, 0x02, 0xF0, 0x05, 0x81]);
+
+        // Without anti-aliasing the grey pixel is thresholded to white and joins the white run
+        assert_eq!(encode_pw0(&layer, 1), vec![0x00, 0x02, 0xF0, 0x06]);
+    }
+
+    #[test]
+    fn test_pw0_splits_long_runs() {
+        let black = ImageBuffer::from_pixel(5000, 1, Luma([0u8]));
+        assert_eq!(encode_pw0(&black, 16), vec![0x0F, 0xFF, 0x03, 0x89]);
+
+        let grey = ImageBuffer::from_pixel(20, 1, Luma([136u8]));
+        assert_eq!(encode_pw0(&grey, 16), vec![0x8F, 0x85]);
+    }
+
+    #[test]
+    fn test_pw0_round_trip() {
+        let layer = ImageBuffer::from_fn(64, 16, |x, y| Luma([((x * 4 + y) % 256) as u8]));
+        let data = encode_pw0(&layer, 16);
+        let decoded = decode_pw0(&data, 64, 16).unwrap();
+        for (original, decoded) in layer.as_raw().iter().zip(decoded.as_raw()) {
+            assert_eq!(quantize_grey(*original, 16) >> 4, decoded >> 4);
+        }
+    }
+
+    #[test]
+    fn test_goo_bytes() {
+        let layer = ImageBuffer::from_raw(6, 1, vec![0, 0, 0, 255, 128, 128]).unwrap();
+
+        let data = encode_goo(&layer, 256);
+
+        assert_eq!(data, vec![0x03, 0xC1, 0x42, 0x80]);
+    }
+
+    #[test]
+    fn test_goo_long_runs_round_trip() {
+        for (width, height, expected_size) in [(20, 1, 2), (100, 100, 3), (2000, 1000, 4)] {
+            let layer = ImageBuffer::from_pixel(width, height, Luma([255u8]));
+            let data = encode_goo(&layer, 1);
+            assert_eq!(data.len(), expected_size, "{}x{}", width, height);
+            assert_eq!(decode_goo(&data, width, height).unwrap(), layer);
+        }
+    }
+
+    #[test]
+    fn test_rle8_bytes() {
+        let layer = ImageBuffer::from_raw(5, 1, vec![7, 7, 7, 200, 0]).unwrap();
+        assert_eq!(encode_rle8(&layer, 256), vec![7, 3, 200, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_pack_bits_pads_rows() {
+        let layer = ImageBuffer::from_raw(3, 2, vec![255, 0, 255, 0, 255, 0]).unwrap();
+        assert_eq!(pack_bits(&layer, 1), vec![0b1010_0000, 0b0100_0000]);
+        assert_eq!(pack_bits(&layer, 4), vec![0xF0, 0xF0, 0x0F, 0x00]);
+    }
+
+    #[test]
+    fn test_decoders_refuse_overflowing_runs() {
+        let error = decode_goo(&[0xC0 | 0x10, 0xFF], 4, 4).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = decode_rle7(&[0x81], 4, 4).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_decoders_refuse_oversized_layers() {
+        // A single black run claiming a layer far larger than any printer screen
+        let error = decode_pw0(&[0x0F, 0xFF], u32::MAX, u32::MAX).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = decode_rle7(&[0x00], 100_000, 100_000).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = unpack_bits(&[0xFF], u32::MAX, 1, 1).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Data covering only part of the layer fails instead of being padded
+        let error = decode_goo(&[0xC1], 4, 4).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
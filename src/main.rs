@@ -2,39 +2,80 @@
 // Distributed under the GNU Affero General Public License v3.0 or later.
 // See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
 
+mod archive_importer;
+mod background_import;
 mod body;
+mod build_volume;
+mod byte_reader;
 mod camera;
 mod cpu_slicer;
+mod ctb_reader;
+mod ctb_writer;
+mod gcode;
+mod goo_reader;
+mod goo_writer;
 mod gpu_slicer;
+mod image_exporter;
+mod layer_codec;
 mod mesh;
+mod mesh_analysis;
+mod mesh_exporter;
+mod mesh_importer;
 mod mesh_renderer;
+mod mesh_repair;
+mod obj_importer;
+mod output_format;
+mod photon_workshop_reader;
+mod photon_workshop_writer;
+mod ply_importer;
+mod preview_renderer;
+mod printer_profile;
+mod resin_profile;
+mod sl1_reader;
+mod sl1_writer;
+mod slice_job;
 mod stl_processor;
 mod texture;
+mod threemf_importer;
+mod time_util;
+mod zip_gcode_writer;
+use background_import::{ImportOptions, ImportResult, ImportTask};
 use body::Body;
+use build_volume::{BuildVolume, PlacementReport};
 use cpu_slicer::CPUSlicer;
+use futures::channel::oneshot;
 use glow::Context as GlowContext;
 use glow::HasContext;
 use gpu_slicer::GPUSlicer;
-use image::EncodableLayout;
-use image::Rgb;
+use i_slint_backend_winit::winit::event::WindowEvent;
+use i_slint_backend_winit::{WinitWindowAccessor, WinitWindowEventResult};
 use image::{ImageBuffer, Luma};
+use image::{Rgb, RgbImage};
+use image_exporter::{ImageExportSettings, ImageExporter, SliceImageFormat};
 use log::debug;
+use mesh::DEFAULT_WELD_TOLERANCE;
+use mesh_exporter::ExportFormat;
+use mesh_importer::ImportError;
 use mesh_renderer::MeshRenderer;
 use nalgebra::Vector3;
-use rayon::iter::IntoParallelRefIterator;
-use rayon::prelude::*;
-use rfd::AsyncFileDialog;
+use output_format::OutputFormat;
+use preview_renderer::{render_preview, PreviewRequest, THUMBNAIL_SIZE};
+use printer_profile::PrinterProfile;
+use resin_profile::ResinProfile;
+use rfd::{AsyncFileDialog, AsyncMessageDialog, MessageButtons, MessageLevel};
+use slice_job::SliceJob;
 use slint::platform::PointerEventButton;
-use slint::SharedString;
+use slint::{Model, SharedString};
 use std::cell::RefCell;
-use std::fs;
+use std::collections::VecDeque;
+use std::io;
 use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::Duration;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 use stl_io::Triangle;
-use stl_processor::StlProcessor;
-use webp::Encoder as WebpEncoder;
 slint::include_modules!();
 macro_rules! define_scoped_binding {
     (struct $binding_ty_name:ident => $obj_name:path, $param_name:path, $binding_fn:ident, $target_name:path) => {
@@ -116,6 +157,19 @@ type SharedMeshRenderer = Rc<RefCell<Option<MeshRenderer>>>;
 type SharedMouseState = Rc<RefCell<MouseState>>;
 type SharedCPUSlicer = Rc<RefCell<CPUSlicer>>;
 type SharedGPUSlicer = Rc<RefCell<Option<GPUSlicer>>>;
+type SharedResinProfile = Rc<RefCell<ResinProfile>>;
+type SharedPrinterProfile = Rc<RefCell<PrinterProfile>>;
+type SharedSliceJob = Rc<RefCell<Option<SliceJob>>>;
+type SharedImageExportSettings = Rc<RefCell<ImageExportSettings>>;
+type SharedPendingArchives = Rc<RefCell<VecDeque<PendingArchive>>>;
+type SharedImportTasks = Rc<RefCell<Vec<ImportTask>>>;
+type SharedPreviewRequests = Rc<RefCell<Vec<PreviewRequest>>>;
+
+/// Archive waiting for the user to pick the meshes to import from it
+struct PendingArchive {
+    path: PathBuf,
+    entries: Vec<String>,
+}
 // type SharedGlContext = Rc<RefCell<Option<GlowContext>>>;
 
 struct AppState {
@@ -124,6 +178,13 @@ struct AppState {
     shared_bodies: SharedBodies,
     shared_cpu_slicer: SharedCPUSlicer,
     shared_gpu_slicer: SharedGPUSlicer,
+    shared_resin_profile: SharedResinProfile,
+    shared_printer_profile: SharedPrinterProfile,
+    shared_slice_job: SharedSliceJob,
+    shared_image_export_settings: SharedImageExportSettings,
+    shared_pending_archives: SharedPendingArchives,
+    shared_import_tasks: SharedImportTasks,
+    shared_preview_requests: SharedPreviewRequests,
     // let_shared_gl_context: SharedGlContext
 }
 
@@ -131,11 +192,6 @@ fn main() {
     // Initialize the Slint application
     let app = App::new().unwrap();
     let app_weak = app.as_weak();
-    let printer_pixel_x = 1920; // 11520
-    let printer_pixel_y = 1080; // 5120
-    let printer_physical_x = 218.880; // mm
-    let printer_physical_y = 122.880; // mm
-    let printer_physical_z = 220.000; // mm
 
     let state = AppState {
         mouse_state: Rc::new(RefCell::new(MouseState::default())),
@@ -143,7 +199,23 @@ fn main() {
         shared_bodies: Rc::new(RefCell::new(Vec::<Rc<RefCell<Body>>>::new())), // Initialized as empty Vec
         shared_cpu_slicer: Rc::new(RefCell::new(CPUSlicer::default())),
         shared_gpu_slicer: Rc::new(RefCell::new(None)),
+        shared_resin_profile: Rc::new(RefCell::new(ResinProfile::default())),
+        shared_printer_profile: Rc::new(RefCell::new(PrinterProfile::default())),
+        shared_slice_job: Rc::new(RefCell::new(None)),
+        shared_image_export_settings: Rc::new(RefCell::new(ImageExportSettings::default())),
+        shared_pending_archives: Rc::new(RefCell::new(VecDeque::new())),
+        shared_import_tasks: Rc::new(RefCell::new(Vec::new())),
+        shared_preview_requests: Rc::new(RefCell::new(Vec::new())),
     };
+    app.set_resin_profile_name(state.shared_resin_profile.borrow().name.clone().into());
+    app.set_printer_profile_name(state.shared_printer_profile.borrow().name.clone().into());
+    app.set_weld_tolerance(DEFAULT_WELD_TOLERANCE.to_string().into());
+    {
+        let settings = state.shared_image_export_settings.borrow();
+        app.set_slice_image_format(settings.format.label().into());
+        app.set_slice_file_name(settings.file_name.clone().into());
+        app.set_slice_output_directory(settings.directory.display().to_string().into());
+    }
 
     // let size = app.window().size();
     let internal_render_width = 1920;
@@ -156,6 +228,8 @@ fn main() {
         let bodies_clone = Rc::clone(&state.shared_bodies);
         let gpu_slicer_clone = Rc::clone(&state.shared_gpu_slicer);
         let cpu_slicer_clone = Rc::clone(&state.shared_cpu_slicer);
+        let printer_profile_clone = Rc::clone(&state.shared_printer_profile);
+        let preview_requests_clone = Rc::clone(&state.shared_preview_requests);
         if let Err(error) = app.window().set_rendering_notifier({
             // Move clones into the closure
 
@@ -199,7 +273,14 @@ fn main() {
                         // *gpu_slicer_clone.borrow_mut() = Some(gpu_slicer);
                         *gpu_slicer_clone.borrow_mut() = None; // Disabling the gpu slicer for now
 
-                        let cpu_slicer = CPUSlicer::new(printer_pixel_x, printer_pixel_y, slice_thickness, printer_physical_x, printer_physical_y);
+                        let printer = printer_profile_clone.borrow();
+                        let cpu_slicer = CPUSlicer::new(
+                            printer.pixel_x,
+                            printer.pixel_y,
+                            slice_thickness,
+                            printer.physical_x,
+                            printer.physical_y,
+                        );
                         *cpu_slicer_clone.borrow_mut() = cpu_slicer;
                     }
                     slint::RenderingState::BeforeRendering => {
@@ -207,6 +288,16 @@ fn main() {
                         if let Some(renderer) = mesh_renderer_clone.borrow_mut().as_mut() {
                             // Get actual window size
                             if let Some(app) = app_weak_clone.upgrade() {
+                                // Thumbnails waiting for the OpenGL context
+                                for request in preview_requests_clone.borrow_mut().drain(..) {
+                                    let thumbnail = renderer.render_thumbnail(
+                                        &request.bodies,
+                                        request.width,
+                                        request.height,
+                                    );
+                                    // Nobody waits for it once the slice is abandoned
+                                    let _ = request.reply.send(thumbnail);
+                                }
                                 // Render and get the texture
                                 let texture = renderer.render(
                                     internal_render_width as u32,
@@ -217,7 +308,7 @@ fn main() {
                                 let mut num_bodies = 0;
                                 for body in bodies_clone.borrow_mut().iter() {
                                     num_bodies += 1;
-                                    let b = body.borrow_mut();
+                                    let b = body.borrow();
                                     // println!("{:.3},{:.3},{:.3}",b.position.x,b.position.y,b.position.z);
                                     // println!("{:.3},{:.3},{:.3}, {:.3}",b.rotation.i, b.rotation.j, b.rotation.k, b.rotation.w);
                                     // println!("{:.3},{:.3},{:.3}",b.scale.x,b.scale.y,b.scale.z);
@@ -236,6 +327,7 @@ fn main() {
                                         s_x: b.scale.x.to_string().clone().into(),
                                         s_y: b.scale.y.to_string().clone().into(),
                                         s_z: b.scale.z.to_string().clone().into(),
+                                        model_info: b.model_info().into(),
                                     })
                                 }
 
@@ -254,8 +346,9 @@ fn main() {
                         // Optional: Perform any post-rendering tasks
                     }
                     slint::RenderingState::RenderingTeardown => {
-                        // Clean up the renderer
+                        // Clean up the renderer, queued thumbnails are rendered in software
                         *mesh_renderer_clone.borrow_mut() = None;
+                        preview_requests_clone.borrow_mut().clear();
                     }
                     _ => {}
                 }
@@ -368,45 +461,597 @@ fn main() {
         });
     }
 
+    /// Shows placement problems in the status area, or clears it if there are none
+    fn show_placement_reports(app_weak: &slint::Weak<App>, reports: &[PlacementReport]) {
+        if let Some(app) = app_weak.upgrade() {
+            let lines: Vec<String> = reports.iter().map(|r| r.to_string()).collect();
+            app.set_status_text(lines.join("\n").into());
+        }
+    }
+
+    /// Checks where every body is and brings their model info up to date
+    fn validate_placement(
+        app_weak: &slint::Weak<App>,
+        build_volume: &BuildVolume,
+        bodies_clone: &SharedBodies,
+    ) {
+        let reports = build_volume.validate_bodies(&bodies_clone.borrow());
+        show_placement_reports(app_weak, &reports);
+        // Bodies were added or moved, the model info follows
+        for body in bodies_clone.borrow().iter() {
+            body.borrow_mut().analysis();
+        }
+    }
+
+    /// Validates the bodies about to be sliced. Returns false if any of them cannot be printed.
+    fn check_placement_before_slicing(
+        app_weak: &slint::Weak<App>,
+        build_volume: &BuildVolume,
+        bodies: &[Rc<RefCell<Body>>],
+    ) -> bool {
+        let reports = build_volume.validate_bodies(bodies);
+        show_placement_reports(app_weak, &reports);
+        if reports.iter().any(PlacementReport::is_blocking) {
+            eprintln!("Slicing blocked, some bodies do not fit in the build volume");
+            return false;
+        }
+        for report in &reports {
+            println!("Warning: {}", report);
+        }
+        true
+    }
+
     async fn open_files_from_dialog(
-        mesh_renderer_clone: &Rc<RefCell<Option<MeshRenderer>>>,
-        bodies_clone: &Rc<RefCell<Vec<Rc<RefCell<Body>>>>>,
+        app_weak: &slint::Weak<App>,
+        pending_archives: &SharedPendingArchives,
+        import_tasks: &SharedImportTasks,
     ) {
-        let paths = AsyncFileDialog::new()
-            .add_filter("stl", &["stl", "STL"])
+        let extensions: Vec<String> = mesh_importer::extensions()
+            .into_iter()
+            .flat_map(|extension| [extension.to_string(), extension.to_uppercase()])
+            .collect();
+        let Some(paths) = AsyncFileDialog::new()
+            .add_filter("Meshes", &extensions)
             .set_directory("~")
             .pick_files()
             .await
-            .unwrap();
+        else {
+            return;
+        };
 
-        let stl_processor = StlProcessor::new();
-        let mut bodies_vec: Vec<Rc<RefCell<Body>>> = Vec::new();
+        let paths = paths.iter().map(|path| path.path().to_path_buf()).collect();
+        import_files(app_weak, pending_archives, import_tasks, paths);
+    }
+
+    /// Options for new imports from the settings in the UI
+    fn import_options(app_weak: &slint::Weak<App>) -> Option<ImportOptions> {
+        let app = app_weak.upgrade()?;
+        let text = app.get_weld_tolerance();
+        match text.trim().parse::<f32>() {
+            Ok(weld_tolerance) if weld_tolerance.is_finite() && weld_tolerance >= 0.0 => {
+                Some(ImportOptions {
+                    repair: app.get_repair_on_import(),
+                    weld_tolerance,
+                })
+            }
+            _ => {
+                show_error(app_weak, format!("Invalid weld tolerance: {}", text));
+                None
+            }
+        }
+    }
 
+    /// Starts importing files picked in the dialog, given on the command line or dropped
+    /// onto the window
+    fn import_files(
+        app_weak: &slint::Weak<App>,
+        pending_archives: &SharedPendingArchives,
+        import_tasks: &SharedImportTasks,
+        paths: Vec<PathBuf>,
+    ) {
+        let Some(options) = import_options(app_weak) else {
+            return;
+        };
+        let mut errors = Vec::new();
         for path in paths {
-            let body = Rc::new(RefCell::new(Body::new_from_stl(
-                path.path().as_os_str(),
-                &stl_processor,
-            )));
-            bodies_vec.push(Rc::clone(&body));
-            println!("Loaded body: {}", path.file_name());
+            // Archives with several meshes wait for the user to pick some
+            if archive_importer::is_archive(&path) {
+                match archive_importer::list_meshes(&path) {
+                    Ok(entries) if entries.len() > 1 => {
+                        pending_archives
+                            .borrow_mut()
+                            .push_back(PendingArchive { path, entries });
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        errors.push(format!("Failed to open {}: {}", path.display(), e));
+                        continue;
+                    }
+                }
+            }
+            import_tasks
+                .borrow_mut()
+                .push(ImportTask::file(path, options));
         }
+        show_next_archive(app_weak, pending_archives);
+        if !errors.is_empty() {
+            slint::spawn_local(async_compat::Compat::new(show_import_errors(errors))).unwrap();
+        }
+    }
+
+    /// Reports the files that failed to import in one dialog, the others are loaded already
+    async fn show_import_errors(errors: Vec<String>) {
+        if errors.is_empty() {
+            return;
+        }
+        for error in &errors {
+            eprintln!("{}", error);
+        }
+        AsyncMessageDialog::new()
+            .set_level(MessageLevel::Error)
+            .set_title("Import failed")
+            .set_description(errors.join("\n"))
+            .set_buttons(MessageButtons::Ok)
+            .show()
+            .await;
+    }
+
+    fn add_bodies(
+        app_weak: &slint::Weak<App>,
+        build_volume: &BuildVolume,
+        mesh_renderer_clone: &Rc<RefCell<Option<MeshRenderer>>>,
+        bodies_clone: &Rc<RefCell<Vec<Rc<RefCell<Body>>>>>,
+        mut bodies_vec: Vec<Rc<RefCell<Body>>>,
+    ) {
         bodies_vec.iter_mut().for_each(|body| {
             if let Some(renderer) = mesh_renderer_clone.borrow_mut().as_mut() {
                 renderer.add_body(Rc::clone(&body));
             }
         });
         bodies_clone.borrow_mut().append(&mut bodies_vec);
+        validate_placement(app_weak, build_volume, bodies_clone);
+    }
+
+    /// Hands the bodies of finished imports to the scene and shows how far the others got
+    fn poll_imports(
+        app_weak: &slint::Weak<App>,
+        build_volume: &BuildVolume,
+        mesh_renderer_clone: &Rc<RefCell<Option<MeshRenderer>>>,
+        bodies_clone: &Rc<RefCell<Vec<Rc<RefCell<Body>>>>>,
+        import_tasks: &SharedImportTasks,
+    ) {
+        let finished: Vec<ImportTask> = {
+            let mut tasks = import_tasks.borrow_mut();
+            if tasks.is_empty() {
+                return;
+            }
+            let (finished, running) = tasks.drain(..).partition(ImportTask::is_finished);
+            *tasks = running;
+            finished
+        };
+
+        let mut bodies_vec: Vec<Rc<RefCell<Body>>> = Vec::new();
+        let mut errors = Vec::new();
+        let mut repairs = Vec::new();
+        for task in finished {
+            let task_name = task.name.clone();
+            let weld_stats = task.weld_stats();
+            if weld_stats.corners > 0 {
+                println!("{}: {}", task_name, weld_stats);
+            }
+            for ImportResult {
+                source,
+                bodies,
+                repairs: body_repairs,
+            } in task.join()
+            {
+                repairs.extend(body_repairs);
+                match bodies {
+                    Ok(bodies) => {
+                        println!("Loaded {} bodies from {}", bodies.len(), source);
+                        bodies_vec
+                            .extend(bodies.into_iter().map(|body| Rc::new(RefCell::new(body))));
+                    }
+                    Err(ImportError::Cancelled) => println!("Import of {} cancelled", source),
+                    Err(e) if source == task_name => {
+                        errors.push(format!("Failed to import {}: {}", source, e))
+                    }
+                    Err(e) => errors.push(format!(
+                        "Failed to import {} from {}: {}",
+                        source, task_name, e
+                    )),
+                }
+            }
+        }
+        if !bodies_vec.is_empty() {
+            add_bodies(
+                app_weak,
+                build_volume,
+                mesh_renderer_clone,
+                bodies_clone,
+                bodies_vec,
+            );
+        }
+        if let Some(app) = app_weak.upgrade() {
+            let status: Vec<String> = import_tasks
+                .borrow()
+                .iter()
+                .map(ImportTask::status)
+                .collect();
+            app.set_import_status(status.join("\n").into());
+        }
+        if !errors.is_empty() {
+            slint::spawn_local(async_compat::Compat::new(show_import_errors(errors))).unwrap();
+        }
+        if !repairs.is_empty() {
+            slint::spawn_local(async_compat::Compat::new(show_repairs(repairs))).unwrap();
+        }
+    }
+
+    /// Tells what repairing the meshes changed, a line for every body
+    async fn show_repairs(repairs: Vec<String>) {
+        for repair in &repairs {
+            println!("{}", repair);
+        }
+        AsyncMessageDialog::new()
+            .set_level(MessageLevel::Info)
+            .set_title("Mesh repair")
+            .set_description(repairs.join("\n"))
+            .set_buttons(MessageButtons::Ok)
+            .show()
+            .await;
     }
 
-    // Handler for opening STL importer file picker
+    /// Lists the meshes of the first archive waiting for a pick, or hides the list
+    fn show_next_archive(app_weak: &slint::Weak<App>, pending_archives: &SharedPendingArchives) {
+        let Some(app) = app_weak.upgrade() else {
+            return;
+        };
+        let pending_archives = pending_archives.borrow();
+        let (name, entries) = match pending_archives.front() {
+            Some(archive) => (
+                archive
+                    .path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned(),
+                archive
+                    .entries
+                    .iter()
+                    .map(|entry| ArchiveEntryUI {
+                        name: entry.into(),
+                        selected: true,
+                    })
+                    .collect(),
+            ),
+            None => (String::new(), Vec::new()),
+        };
+        app.set_archive_name(name.into());
+        app.set_archive_entries(Rc::new(slint::VecModel::from(entries)).into());
+    }
+
+    // Handlers for the list of meshes in an opened archive
+    {
+        let app_weak_clone = app_weak.clone();
+        let pending_archives_clone = Rc::clone(&state.shared_pending_archives);
+        let import_tasks_clone = Rc::clone(&state.shared_import_tasks);
+        app.on_import_archive_entries(move || {
+            let Some(app) = app_weak_clone.upgrade() else {
+                return;
+            };
+            let Some(options) = import_options(&app_weak_clone) else {
+                return;
+            };
+            let Some(archive) = pending_archives_clone.borrow_mut().pop_front() else {
+                return;
+            };
+            let picked: Vec<String> = app
+                .get_archive_entries()
+                .iter()
+                .filter(|entry| entry.selected)
+                .map(|entry| entry.name.to_string())
+                .collect();
+            if !picked.is_empty() {
+                import_tasks_clone
+                    .borrow_mut()
+                    .push(ImportTask::archive_entries(archive.path, picked, options));
+            }
+            show_next_archive(&app_weak_clone, &pending_archives_clone);
+        });
+    }
+    {
+        let app_weak_clone = app_weak.clone();
+        let pending_archives_clone = Rc::clone(&state.shared_pending_archives);
+        app.on_cancel_archive_import(move || {
+            pending_archives_clone.borrow_mut().pop_front();
+            show_next_archive(&app_weak_clone, &pending_archives_clone);
+        });
+    }
+
+    // Imports run on worker threads, their results are picked up on the UI thread
+    let import_timer = slint::Timer::default();
     {
+        let app_weak_clone = app_weak.clone();
         let mesh_renderer_clone = Rc::clone(&state.shared_mesh_renderer);
         let bodies_clone = Rc::clone(&state.shared_bodies);
-        app.on_click_import_stl(move || {
-            let mrc_clone = Rc::clone(&mesh_renderer_clone);
-            let bc_clone = Rc::clone(&bodies_clone);
+        let printer_profile_clone = Rc::clone(&state.shared_printer_profile);
+        let import_tasks_clone = Rc::clone(&state.shared_import_tasks);
+        import_timer.start(
+            slint::TimerMode::Repeated,
+            Duration::from_millis(100),
+            move || {
+                let build_volume = printer_profile_clone.borrow().build_volume();
+                poll_imports(
+                    &app_weak_clone,
+                    &build_volume,
+                    &mesh_renderer_clone,
+                    &bodies_clone,
+                    &import_tasks_clone,
+                );
+            },
+        );
+    }
+    {
+        let import_tasks_clone = Rc::clone(&state.shared_import_tasks);
+        app.on_cancel_imports(move || {
+            import_tasks_clone
+                .borrow()
+                .iter()
+                .for_each(ImportTask::cancel);
+        });
+    }
+
+    // Files given on the command line, e.g. by "Open with" of a file manager
+    import_files(
+        &app_weak,
+        &state.shared_pending_archives,
+        &state.shared_import_tasks,
+        std::env::args_os().skip(1).map(PathBuf::from).collect(),
+    );
+
+    // Files dropped onto the window
+    {
+        let app_weak_clone = app_weak.clone();
+        let pending_archives_clone = Rc::clone(&state.shared_pending_archives);
+        let import_tasks_clone = Rc::clone(&state.shared_import_tasks);
+        app.window().on_winit_window_event(move |_, event| {
+            if let WindowEvent::DroppedFile(path) = event {
+                import_files(
+                    &app_weak_clone,
+                    &pending_archives_clone,
+                    &import_tasks_clone,
+                    vec![path.clone()],
+                );
+            }
+            WinitWindowEventResult::Propagate
+        });
+    }
+
+    // Repairs the selected bodies on demand
+    {
+        let app_weak_clone = app_weak.clone();
+        let bodies_clone = Rc::clone(&state.shared_bodies);
+        let printer_profile_clone = Rc::clone(&state.shared_printer_profile);
+        app.on_repair_selected(move || {
+            let mut selected = 0;
+            let mut repairs = Vec::new();
+            for body in bodies_clone.borrow().iter() {
+                let mut body = body.borrow_mut();
+                if !body.selected {
+                    continue;
+                }
+                selected += 1;
+                let report = body.repair();
+                if !report.is_empty() {
+                    repairs.push(format!("{}: {}", body.name, report));
+                }
+            }
+            if selected == 0 {
+                show_error(&app_weak_clone, "Select the bodies to repair".to_string());
+                return;
+            }
+            let build_volume = printer_profile_clone.borrow().build_volume();
+            validate_placement(&app_weak_clone, &build_volume, &bodies_clone);
+            if repairs.is_empty() {
+                repairs.push("Nothing to repair in the selected bodies".to_string());
+            }
+            slint::spawn_local(async_compat::Compat::new(show_repairs(repairs))).unwrap();
+        });
+    }
+
+    // Splits the selected bodies into a body per part
+    {
+        let app_weak_clone = app_weak.clone();
+        let bodies_clone = Rc::clone(&state.shared_bodies);
+        let mesh_renderer_clone = Rc::clone(&state.shared_mesh_renderer);
+        let printer_profile_clone = Rc::clone(&state.shared_printer_profile);
+        app.on_split_selected(move || {
+            let selected: Vec<Rc<RefCell<Body>>> = bodies_clone
+                .borrow()
+                .iter()
+                .filter(|body| body.borrow().selected)
+                .cloned()
+                .collect();
+            if selected.is_empty() {
+                show_error(&app_weak_clone, "Select the bodies to split".to_string());
+                return;
+            }
+            let mut parts = Vec::new();
+            for body in selected {
+                let body_parts = body.borrow().split_into_parts();
+                if body_parts.is_empty() {
+                    continue;
+                }
+                let uuid = body.borrow().uuid.to_string().into();
+                delete_body_by_uuid(&mesh_renderer_clone, &bodies_clone, uuid);
+                parts.extend(
+                    body_parts
+                        .into_iter()
+                        .map(|part| Rc::new(RefCell::new(part))),
+                );
+            }
+            if parts.is_empty() {
+                show_error(
+                    &app_weak_clone,
+                    "The selected bodies are in one piece".to_string(),
+                );
+                return;
+            }
+            let build_volume = printer_profile_clone.borrow().build_volume();
+            add_bodies(
+                &app_weak_clone,
+                &build_volume,
+                &mesh_renderer_clone,
+                &bodies_clone,
+                parts,
+            );
+        });
+    }
+
+    // Handler for opening the mesh importer file picker
+    {
+        let app_weak_clone = app_weak.clone();
+        let pending_archives_clone = Rc::clone(&state.shared_pending_archives);
+        let import_tasks_clone = Rc::clone(&state.shared_import_tasks);
+        app.on_click_import_mesh(move || {
+            let app_weak_clone = app_weak_clone.clone();
+            let pa_clone = Rc::clone(&pending_archives_clone);
+            let it_clone = Rc::clone(&import_tasks_clone);
             let slint_future = async move {
-                open_files_from_dialog(&mrc_clone, &bc_clone).await;
+                open_files_from_dialog(&app_weak_clone, &pa_clone, &it_clone).await;
+            };
+            slint::spawn_local(async_compat::Compat::new(slint_future)).unwrap();
+        });
+    }
+
+    async fn open_resin_profile_from_dialog(
+        app_weak: slint::Weak<App>,
+        resin_profile_clone: SharedResinProfile,
+    ) {
+        let Some(file) = AsyncFileDialog::new()
+            .add_filter("json", &["json", "JSON"])
+            .set_directory("~")
+            .pick_file()
+            .await
+        else {
+            return;
+        };
+
+        match ResinProfile::load_from_file(file.path()) {
+            Ok(profile) => {
+                println!("Loaded resin profile: {}", profile.name);
+                if let Some(app) = app_weak.upgrade() {
+                    app.set_resin_profile_name(profile.name.clone().into());
+                }
+                *resin_profile_clone.borrow_mut() = profile;
+            }
+            Err(e) => eprintln!("Failed to load resin profile {}: {}", file.file_name(), e),
+        }
+    }
+
+    // Handler for loading a resin profile
+    {
+        let app_weak_clone = app_weak.clone();
+        let resin_profile_clone = Rc::clone(&state.shared_resin_profile);
+        app.on_click_load_resin_profile(move || {
+            let slint_future = open_resin_profile_from_dialog(
+                app_weak_clone.clone(),
+                Rc::clone(&resin_profile_clone),
+            );
+            slint::spawn_local(async_compat::Compat::new(slint_future)).unwrap();
+        });
+    }
+
+    async fn open_printer_profile_from_dialog(
+        app_weak: slint::Weak<App>,
+        printer_profile_clone: SharedPrinterProfile,
+        cpu_slicer_clone: SharedCPUSlicer,
+        bodies_clone: SharedBodies,
+    ) {
+        let Some(file) = AsyncFileDialog::new()
+            .add_filter("json", &["json", "JSON"])
+            .set_directory("~")
+            .pick_file()
+            .await
+        else {
+            return;
+        };
+
+        match PrinterProfile::load_from_file(file.path()) {
+            Ok(profile) => {
+                println!("Loaded printer profile: {}", profile.name);
+                if let Some(app) = app_weak.upgrade() {
+                    app.set_printer_profile_name(profile.name.clone().into());
+                }
+                // Keep the layer height, only the plate changed
+                let slice_thickness = cpu_slicer_clone.borrow().slice_thickness();
+                *cpu_slicer_clone.borrow_mut() = CPUSlicer::new(
+                    profile.pixel_x,
+                    profile.pixel_y,
+                    slice_thickness,
+                    profile.physical_x,
+                    profile.physical_y,
+                );
+                validate_placement(&app_weak, &profile.build_volume(), &bodies_clone);
+                *printer_profile_clone.borrow_mut() = profile;
+            }
+            Err(e) => eprintln!("Failed to load printer profile {}: {}", file.file_name(), e),
+        }
+    }
+
+    // Handler for loading a printer profile
+    {
+        let app_weak_clone = app_weak.clone();
+        let printer_profile_clone = Rc::clone(&state.shared_printer_profile);
+        let cpu_slicer_clone = Rc::clone(&state.shared_cpu_slicer);
+        let bodies_clone = Rc::clone(&state.shared_bodies);
+        app.on_click_load_printer_profile(move || {
+            let slint_future = open_printer_profile_from_dialog(
+                app_weak_clone.clone(),
+                Rc::clone(&printer_profile_clone),
+                Rc::clone(&cpu_slicer_clone),
+                Rc::clone(&bodies_clone),
+            );
+            slint::spawn_local(async_compat::Compat::new(slint_future)).unwrap();
+        });
+    }
+
+    // Handlers for the slice image export settings
+    {
+        let settings_clone = Rc::clone(&state.shared_image_export_settings);
+        app.on_slice_image_format_changed(move |label: slint::SharedString| {
+            if let Some(format) = SliceImageFormat::from_label(&label) {
+                settings_clone.borrow_mut().format = format;
+            }
+        });
+    }
+    {
+        let settings_clone = Rc::clone(&state.shared_image_export_settings);
+        app.on_slice_file_name_edited(move |file_name: slint::SharedString| {
+            settings_clone.borrow_mut().file_name = file_name.to_string();
+        });
+    }
+    {
+        let app_weak_clone = app_weak.clone();
+        let settings_clone = Rc::clone(&state.shared_image_export_settings);
+        app.on_click_choose_slice_directory(move || {
+            let app_weak = app_weak_clone.clone();
+            let settings_clone = Rc::clone(&settings_clone);
+            let slint_future = async move {
+                let Some(folder) = AsyncFileDialog::new()
+                    .set_directory("~")
+                    .pick_folder()
+                    .await
+                else {
+                    return;
+                };
+                let directory = folder.path().to_path_buf();
+                if let Some(app) = app_weak.upgrade() {
+                    app.set_slice_output_directory(directory.display().to_string().into());
+                }
+                settings_clone.borrow_mut().directory = directory;
             };
             slint::spawn_local(async_compat::Compat::new(slint_future)).unwrap();
         });
@@ -414,7 +1059,9 @@ fn main() {
 
     // Handlers for objectlistitem editing
     {
+        let app_weak_clone = app_weak.clone();
         let bodies_clone = Rc::clone(&state.shared_bodies);
+        let printer_profile_clone = Rc::clone(&state.shared_printer_profile);
         app.on_body_position_edited_single_axis(
             move |uuid: slint::SharedString, amt: f32, axis: i32| {
                 let bodies = bodies_clone.borrow();
@@ -430,10 +1077,14 @@ fn main() {
                         body.set_position(v);
                     }
                 }
+                let build_volume = printer_profile_clone.borrow().build_volume();
+                validate_placement(&app_weak_clone, &build_volume, &bodies_clone);
             },
         );
 
+        let app_weak_clone = app_weak.clone();
         let bodies_clone = Rc::clone(&state.shared_bodies);
+        let printer_profile_clone = Rc::clone(&state.shared_printer_profile);
         app.on_body_rotation_edited_single_axis(
             move |uuid: slint::SharedString, amt: f32, axis: i32| {
                 let bodies = bodies_clone.borrow();
@@ -450,10 +1101,14 @@ fn main() {
                         body.set_rotation(v);
                     }
                 }
+                let build_volume = printer_profile_clone.borrow().build_volume();
+                validate_placement(&app_weak_clone, &build_volume, &bodies_clone);
             },
         );
 
+        let app_weak_clone = app_weak.clone();
         let bodies_clone = Rc::clone(&state.shared_bodies);
+        let printer_profile_clone = Rc::clone(&state.shared_printer_profile);
         app.on_body_scale_edited_single_axis(
             move |uuid: slint::SharedString, amt: f32, axis: i32| {
                 let bodies = bodies_clone.borrow();
@@ -469,6 +1124,8 @@ fn main() {
                         body.set_scale(v);
                     }
                 }
+                let build_volume = printer_profile_clone.borrow().build_volume();
+                validate_placement(&app_weak_clone, &build_volume, &bodies_clone);
             },
         );
 
@@ -484,127 +1141,434 @@ fn main() {
         });
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn slice_all_bodies(
+        app_weak: slint::Weak<App>,
+        printer_profile_clone: SharedPrinterProfile,
         bodies_clone: Rc<RefCell<Vec<Rc<RefCell<Body>>>>>,
         gpu_slicer_clone: Rc<RefCell<Option<GPUSlicer>>>,
         cpu_slicer_clone: Rc<RefCell<CPUSlicer>>,
-    ) -> Vec<ImageBuffer<Luma<u8>, Vec<u8>>> {
-        // Clone the Rc<RefCell<Body>>s into a new vector to avoid borrowing issues
-        let bodies_vec = {
-            let bodies_ref = bodies_clone.borrow();
-            bodies_ref.as_slice().to_vec()
-        };
+        resin_profile_clone: SharedResinProfile,
+        mesh_renderer_clone: SharedMeshRenderer,
+        preview_requests_clone: SharedPreviewRequests,
+        image_export_settings_clone: SharedImageExportSettings,
+    ) -> Result<Option<SliceJob>, Box<dyn std::error::Error>> {
+        let bodies = bodies_clone.borrow().to_vec();
+        slice_bodies(
+            app_weak,
+            printer_profile_clone,
+            bodies,
+            gpu_slicer_clone,
+            cpu_slicer_clone,
+            resin_profile_clone,
+            mesh_renderer_clone,
+            preview_requests_clone,
+            image_export_settings_clone,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn slice_selected_bodies(
+        app_weak: slint::Weak<App>,
+        printer_profile_clone: SharedPrinterProfile,
+        bodies_clone: Rc<RefCell<Vec<Rc<RefCell<Body>>>>>,
+        gpu_slicer_clone: Rc<RefCell<Option<GPUSlicer>>>,
+        cpu_slicer_clone: Rc<RefCell<CPUSlicer>>,
+        resin_profile_clone: SharedResinProfile,
+        mesh_renderer_clone: SharedMeshRenderer,
+        preview_requests_clone: SharedPreviewRequests,
+        image_export_settings_clone: SharedImageExportSettings,
+    ) -> Result<Option<SliceJob>, Box<dyn std::error::Error>> {
+        let bodies = bodies_clone
+            .borrow()
+            .iter()
+            .filter(|body| body.borrow().selected)
+            .cloned()
+            .collect();
+        slice_bodies(
+            app_weak,
+            printer_profile_clone,
+            bodies,
+            gpu_slicer_clone,
+            cpu_slicer_clone,
+            resin_profile_clone,
+            mesh_renderer_clone,
+            preview_requests_clone,
+            image_export_settings_clone,
+        )
+        .await
+    }
+
+    /// Slices the bodies into a job and saves it. Returns no job when the bodies don't fit
+    /// in the build volume.
+    #[allow(clippy::too_many_arguments)]
+    async fn slice_bodies(
+        app_weak: slint::Weak<App>,
+        printer_profile_clone: SharedPrinterProfile,
+        bodies: Vec<Rc<RefCell<Body>>>,
+        gpu_slicer_clone: Rc<RefCell<Option<GPUSlicer>>>,
+        cpu_slicer_clone: Rc<RefCell<CPUSlicer>>,
+        resin_profile_clone: SharedResinProfile,
+        mesh_renderer_clone: SharedMeshRenderer,
+        preview_requests_clone: SharedPreviewRequests,
+        image_export_settings_clone: SharedImageExportSettings,
+    ) -> Result<Option<SliceJob>, Box<dyn std::error::Error>> {
+        let build_volume = printer_profile_clone.borrow().build_volume();
+        if !check_placement_before_slicing(&app_weak, &build_volume, &bodies) {
+            return Ok(None);
+        }
+        // Render the thumbnail first, slicing takes the bodies
+        let thumbnail = render_thumbnail(
+            &app_weak,
+            &mesh_renderer_clone,
+            &preview_requests_clone,
+            &bodies,
+        )
+        .await;
         let output: Vec<ImageBuffer<Luma<u8>, Vec<u8>>>;
+        let layer_height: f64;
         if let Some(gpu_slicer) = gpu_slicer_clone.borrow_mut().as_mut() {
-            output = gpu_slicer.slice_bodies(bodies_vec).unwrap()
+            output = gpu_slicer.slice_bodies(bodies)?;
+            layer_height = gpu_slicer.slice_thickness();
         } else {
-            output = cpu_slicer_clone
-                .borrow_mut()
-                .slice_bodies(bodies_vec)
-                .unwrap()
+            let cpu_slicer = cpu_slicer_clone.borrow();
+            output = cpu_slicer.slice_bodies(bodies)?;
+            layer_height = cpu_slicer.slice_thickness();
         }
-        // For now let's try just writing the data to a series of images in the test slices dir inside of a new dir with a current unix timestamp as the name
-        // insert folder and file writing code here.
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
-        let timestamp = since_the_epoch.as_secs();
-
-        // Create a new directory inside "test slices" with the timestamp as its name
-        let dir_path = format!("slices/{}", timestamp);
-        fs::create_dir_all(&dir_path).expect("Failed to create directory");
-
-        // Iterate over the output images and save each one to a file in lossless WebP format
-        output.par_iter().enumerate().for_each(|(i, image)| {
-            let file_path = format!("{}/slice_{:04}.webp", dir_path, i);
+        let mut job = SliceJob::new(
+            output,
+            layer_height,
+            printer_profile_clone.borrow().clone(),
+            resin_profile_clone.borrow().clone(),
+        );
+        job.thumbnail = Some(thumbnail);
+        println!(
+            "Sliced {} layers of {}mm with resin profile \"{}\", estimated print time: {}s",
+            job.layer_count(),
+            job.layer_height,
+            job.resin.name,
+            job.estimated_print_time().as_secs()
+        );
+        save_job(&app_weak, &image_export_settings_clone.borrow(), &job);
+        Ok(Some(job))
+    }
 
-            // Convert ImageBuffer<Luma<u8>, Vec<u8>> to ImageBuffer<Rgb<u8>, Vec<u8>>
-            let rgb_image: ImageBuffer<Rgb<u8>, Vec<u8>> = convert_luma_to_rgb(image);
+    /// Renders the thumbnail of a slice job on the GPU in the next frame, or in software
+    /// when there is no OpenGL context
+    async fn render_thumbnail(
+        app_weak: &slint::Weak<App>,
+        mesh_renderer: &SharedMeshRenderer,
+        preview_requests: &SharedPreviewRequests,
+        bodies: &[Rc<RefCell<Body>>],
+    ) -> RgbImage {
+        let (width, height) = THUMBNAIL_SIZE;
+        let gl_available = mesh_renderer.borrow().is_some();
+        if let Some(app) = app_weak.upgrade().filter(|_| gl_available) {
+            let (reply, thumbnail) = oneshot::channel();
+            preview_requests.borrow_mut().push(PreviewRequest {
+                bodies: bodies.to_vec(),
+                width,
+                height,
+                reply,
+            });
+            app.window().request_redraw();
+            if let Ok(thumbnail) = thumbnail.await {
+                return thumbnail;
+            }
+        }
+        render_preview(bodies, width, height)
+    }
 
-            // Retrieve width and height before moving rgb_image
-            let width = rgb_image.width();
-            let height = rgb_image.height();
+    /// Writes the slice images and the printer file of a new job, showing any failure
+    /// in the status area
+    fn save_job(
+        app_weak: &slint::Weak<App>,
+        image_export_settings: &ImageExportSettings,
+        job: &SliceJob,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let dir_path = image_export_settings.run_directory(timestamp);
+        let exporter = ImageExporter::new(image_export_settings.clone());
+        if let Err(e) = exporter.export(&job.layers, &dir_path) {
+            show_error(
+                app_weak,
+                format!("Failed to export slices to {}: {}", dir_path.display(), e),
+            );
+            return;
+        }
+        println!(
+            "Wrote {} slices to {}",
+            job.layer_count(),
+            dir_path.display()
+        );
+        save_printer_file(app_weak, job, &dir_path, timestamp);
+    }
 
-            // Flatten the RGB image into a Vec<u8>
-            let rgb_data = rgb_image.into_raw();
+    fn save_printer_file(
+        app_weak: &slint::Weak<App>,
+        job: &SliceJob,
+        dir_path: &Path,
+        timestamp: u64,
+    ) {
+        match write_printer_file(job, dir_path, timestamp) {
+            Ok((file_path, format)) => {
+                println!("Wrote {}", file_path.display());
+                show_limitations(app_weak, format, job);
+            }
+            Err(e) => show_error(app_weak, format!("Failed to write printer file: {}", e)),
+        }
+    }
 
-            // Create a WebP encoder with lossless encoding
-            let encoder = WebpEncoder::from_rgb(&rgb_data, width, height);
+    /// Notes in the status area what the format couldn't store, the file is still written
+    fn show_limitations(app_weak: &slint::Weak<App>, format: &dyn OutputFormat, job: &SliceJob) {
+        let limitations = format.limitations(job);
+        if !limitations.is_empty() {
+            show_info(app_weak, limitations.join("\n"));
+        }
+    }
 
-            // Encode the image in lossless mode
-            let webp_data = encoder.encode_lossless();
+    /// Writes the job in the format the printer profile asks for into `dir_path`
+    fn write_printer_file(
+        job: &SliceJob,
+        dir_path: &Path,
+        timestamp: u64,
+    ) -> io::Result<(PathBuf, &'static dyn OutputFormat)> {
+        let format = job.printer.printer_file_format()?;
+        let extension = job.printer.output_format.to_lowercase();
+        let file_path = dir_path.join(format!("{}.{}", timestamp, extension));
+        format.write_to_file(job, &file_path)?;
+        Ok((file_path, format))
+    }
 
-            // Convert WebPMemory to Vec<u8> using `as_bytes()`
-            let webp_bytes = webp_data.as_bytes();
+    /// Asks where to write the job, offering every output format with the printer's first.
+    /// The job is written with the loaded printer and resin profiles, the job itself keeps
+    /// its own so cancelling changes nothing.
+    async fn save_printer_file_from_dialog(
+        app_weak: slint::Weak<App>,
+        slice_job_clone: SharedSliceJob,
+        printer_profile_clone: SharedPrinterProfile,
+        resin_profile_clone: SharedResinProfile,
+        directory: PathBuf,
+    ) {
+        let printer_extension = printer_profile_clone.borrow().output_format.to_lowercase();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let mut formats = output_format::FORMATS.to_vec();
+        formats.sort_by_key(|format| !format.extensions().contains(&printer_extension.as_str()));
+        let mut dialog = AsyncFileDialog::new()
+            .set_directory(&directory)
+            .set_file_name(format!("{}.{}", timestamp, printer_extension));
+        for format in formats {
+            dialog = dialog.add_filter(format.name(), format.extensions());
+        }
+        let Some(file) = dialog.save_file().await else {
+            return;
+        };
 
-            // Save the encoded WebP data to a file
-            fs::write(&file_path, webp_bytes).expect("Failed to save WebP image");
-        });
-        output
+        let Some(mut job) = slice_job_clone.borrow().clone() else {
+            return;
+        };
+        let printer = printer_profile_clone.borrow();
+        if (job.printer.pixel_x, job.printer.pixel_y) != (printer.pixel_x, printer.pixel_y) {
+            println!(
+                "Layers are {}x{} but printer profile \"{}\" is {}x{}, keeping their resolution",
+                job.printer.pixel_x,
+                job.printer.pixel_y,
+                printer.name,
+                printer.pixel_x,
+                printer.pixel_y
+            );
+        }
+        job.retarget(&printer, &resin_profile_clone.borrow());
+        let result = output_format::for_path(file.path())
+            .and_then(|format| format.write_to_file(&job, file.path()).map(|_| format));
+        match result {
+            Ok(format) => {
+                println!("Wrote {}", file.path().display());
+                show_limitations(&app_weak, format, &job);
+            }
+            Err(e) => show_error(
+                &app_weak,
+                format!("Failed to write {}: {}", file.file_name(), e),
+            ),
+        }
     }
 
-    async fn slice_selected_bodies(
-        bodies_clone: Rc<RefCell<Vec<Rc<RefCell<Body>>>>>,
-        gpu_slicer_clone: Rc<RefCell<Option<GPUSlicer>>>,
-        cpu_slicer_clone: Rc<RefCell<CPUSlicer>>,
-    ) -> Vec<ImageBuffer<Luma<u8>, Vec<u8>>> {
-        // Clone the Rc<RefCell<Body>>s into a new vector to avoid borrowing issues
-        let bodies_vec = {
-            let bodies_ref = bodies_clone.borrow();
-            bodies_ref.as_slice().to_vec()
-        };
+    /// Shows a note in the status area
+    fn show_info(app_weak: &slint::Weak<App>, message: String) {
+        println!("{}", message);
+        if let Some(app) = app_weak.upgrade() {
+            app.set_status_text(message.into());
+        }
+    }
 
-        let mut bodies_vec_filtered = Vec::new();
-        for b in bodies_vec {
-            if b.borrow().selected {bodies_vec_filtered.push(b)};
+    /// Shows an error in the status area
+    fn show_error(app_weak: &slint::Weak<App>, message: String) {
+        eprintln!("{}", message);
+        if let Some(app) = app_weak.upgrade() {
+            app.set_status_text(message.into());
         }
-        let output: Vec<ImageBuffer<Luma<u8>, Vec<u8>>>;
-        if let Some(gpu_slicer) = gpu_slicer_clone.borrow_mut().as_mut() {
-            output = gpu_slicer.slice_bodies(bodies_vec_filtered).unwrap()
-        } else {
-            output = cpu_slicer_clone
-                .borrow_mut()
-                .slice_bodies(bodies_vec_filtered)
-                .unwrap()
+    }
+
+    /// Shows a job in the layer viewer and keeps it for exporting
+    fn view_job(app_weak: &slint::Weak<App>, slice_job_clone: &SharedSliceJob, job: SliceJob) {
+        if let Some(app) = app_weak.upgrade() {
+            app.set_layer_count(job.layer_count() as i32);
+            app.set_layer_position(0.0);
+            show_layer(&app, &job, 0);
         }
-        // For now let's try just writing the data to a series of images in the test slices dir inside of a new dir with a current unix timestamp as the name
-        let start = SystemTime::now();
-        let since_the_epoch = start
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
-        let timestamp = since_the_epoch.as_secs();
+        *slice_job_clone.borrow_mut() = Some(job);
+    }
 
-        let dir_path = format!("slices/{}", timestamp);
-        fs::create_dir_all(&dir_path).expect("Failed to create directory");
+    fn show_layer(app: &App, job: &SliceJob, layer_index: usize) {
+        let Some(layer) = job.layers.get(layer_index) else {
+            app.set_layer_image(slint::Image::default());
+            return;
+        };
+        let rgb_image = convert_luma_to_rgb(layer);
+        let buffer = slint::SharedPixelBuffer::<slint::Rgb8Pixel>::clone_from_slice(
+            rgb_image.as_raw(),
+            rgb_image.width(),
+            rgb_image.height(),
+        );
+        app.set_layer_image(slint::Image::from_rgb8(buffer));
+    }
 
-        // Iterate over the output images and save each one to a file in lossless WebP format
-        output.par_iter().enumerate().for_each(|(i, image)| {
-            let file_path = format!("{}/slice_{:04}.webp", dir_path, i);
+    async fn open_printer_file_from_dialog(
+        app_weak: slint::Weak<App>,
+        slice_job_clone: SharedSliceJob,
+    ) {
+        let Some(file) = AsyncFileDialog::new()
+            .add_filter("Printer files", &output_format::readable_extensions())
+            .set_directory("~")
+            .pick_file()
+            .await
+        else {
+            return;
+        };
 
-            // Convert ImageBuffer<Luma<u8>, Vec<u8>> to ImageBuffer<Rgb<u8>, Vec<u8>>
-            let rgb_image: ImageBuffer<Rgb<u8>, Vec<u8>> = convert_luma_to_rgb(image);
+        match output_format::read_from_file(file.path()) {
+            Ok(job) => {
+                println!(
+                    "Opened {}: {} layers of {}mm for \"{}\" with resin profile \"{}\"",
+                    file.file_name(),
+                    job.layer_count(),
+                    job.layer_height,
+                    job.printer.name,
+                    job.resin.name
+                );
+                view_job(&app_weak, &slice_job_clone, job);
+            }
+            Err(e) => show_error(
+                &app_weak,
+                format!("Failed to open printer file {}: {}", file.file_name(), e),
+            ),
+        }
+    }
 
-            // Retrieve width and height before moving rgb_image
-            let width = rgb_image.width();
-            let height = rgb_image.height();
+    /// Writes the selected or all bodies as a mesh with their transforms baked in
+    async fn export_bodies_from_dialog(
+        app_weak: slint::Weak<App>,
+        bodies_clone: Rc<RefCell<Vec<Rc<RefCell<Body>>>>>,
+        selected_only: bool,
+    ) {
+        let bodies_vec: Vec<Rc<RefCell<Body>>> = bodies_clone
+            .borrow()
+            .iter()
+            .filter(|body| !selected_only || body.borrow().selected)
+            .cloned()
+            .collect();
+        if bodies_vec.is_empty() {
+            show_error(&app_weak, "There are no bodies to export".to_string());
+            return;
+        }
+        let mut dialog = AsyncFileDialog::new()
+            .set_directory("~")
+            .set_file_name(format!("plate.{}", ExportFormat::Stl.extension()));
+        for format in ExportFormat::ALL {
+            dialog = dialog.add_filter(format.name(), &[format.extension()]);
+        }
+        let Some(file) = dialog.save_file().await else {
+            return;
+        };
 
-            // Flatten the RGB image into a Vec<u8>
-            let rgb_data = rgb_image.into_raw();
+        let borrowed: Vec<_> = bodies_vec.iter().map(|body| body.borrow()).collect();
+        let bodies: Vec<&Body> = borrowed.iter().map(|body| &**body).collect();
+        let result = ExportFormat::for_path(file.path())
+            .and_then(|format| format.write_to_file(&bodies, file.path()));
+        match result {
+            Ok(()) => println!(
+                "Exported {} bodies to {}",
+                bodies.len(),
+                file.path().display()
+            ),
+            Err(e) => show_error(
+                &app_weak,
+                format!("Failed to export {}: {}", file.file_name(), e),
+            ),
+        }
+    }
 
-            // Create a WebP encoder with lossless encoding
-            let encoder = WebpEncoder::from_rgb(&rgb_data, width, height);
+    {
+        let app_weak_clone = app_weak.clone();
+        let bodies_clone = Rc::clone(&state.shared_bodies);
+        app.on_click_export_bodies(move |selected_only| {
+            let slint_future = export_bodies_from_dialog(
+                app_weak_clone.clone(),
+                Rc::clone(&bodies_clone),
+                selected_only,
+            );
+            slint::spawn_local(async_compat::Compat::new(slint_future)).unwrap();
+        });
+    }
 
-            // Encode the image in lossless mode
-            let webp_data = encoder.encode_lossless();
+    // Layer viewer and printer file handlers
+    {
+        let app_weak_clone = app_weak.clone();
+        let slice_job_clone = Rc::clone(&state.shared_slice_job);
+        app.on_layer_changed(move |layer_index| {
+            if let (Some(app), Some(job)) =
+                (app_weak_clone.upgrade(), slice_job_clone.borrow().as_ref())
+            {
+                show_layer(&app, job, layer_index.max(0) as usize);
+            }
+        });
 
-            // Convert WebPMemory to Vec<u8> using `as_bytes()`
-            let webp_bytes = webp_data.as_bytes();
+        let app_weak_clone = app_weak.clone();
+        let slice_job_clone = Rc::clone(&state.shared_slice_job);
+        app.on_click_open_printer_file(move || {
+            let slint_future =
+                open_printer_file_from_dialog(app_weak_clone.clone(), Rc::clone(&slice_job_clone));
+            slint::spawn_local(async_compat::Compat::new(slint_future)).unwrap();
+        });
 
-            // Save the encoded WebP data to a file
-            fs::write(&file_path, webp_bytes).expect("Failed to save WebP image");
+        // Writes the job again with the loaded printer and resin profiles, e.g. an .sl1
+        // converted to .ctb with a different exposure
+        let app_weak_clone = app_weak.clone();
+        let slice_job_clone = Rc::clone(&state.shared_slice_job);
+        let printer_profile_clone = Rc::clone(&state.shared_printer_profile);
+        let resin_profile_clone = Rc::clone(&state.shared_resin_profile);
+        let image_export_settings_clone = Rc::clone(&state.shared_image_export_settings);
+        app.on_click_export_printer_file(move || {
+            if slice_job_clone.borrow().is_none() {
+                return;
+            }
+            let directory = image_export_settings_clone.borrow().directory.clone();
+            let slint_future = save_printer_file_from_dialog(
+                app_weak_clone.clone(),
+                Rc::clone(&slice_job_clone),
+                Rc::clone(&printer_profile_clone),
+                Rc::clone(&resin_profile_clone),
+                directory,
+            );
+            slint::spawn_local(async_compat::Compat::new(slint_future)).unwrap();
         });
-        output
     }
 
     /// Converts an ImageBuffer with Luma<u8> pixels to an ImageBuffer with Rgb<u8> pixels
@@ -625,13 +1589,42 @@ fn main() {
     let bodies_clone = Rc::clone(&state.shared_bodies);
     let gpu_slicer_clone = Rc::clone(&state.shared_gpu_slicer);
     let cpu_slicer_clone = Rc::clone(&state.shared_cpu_slicer);
+    let resin_profile_clone = Rc::clone(&state.shared_resin_profile);
+    let printer_profile_clone = Rc::clone(&state.shared_printer_profile);
+    let slice_job_clone = Rc::clone(&state.shared_slice_job);
+    let mesh_renderer_clone = Rc::clone(&state.shared_mesh_renderer);
+    let preview_requests_clone = Rc::clone(&state.shared_preview_requests);
+    let image_export_settings_clone = Rc::clone(&state.shared_image_export_settings);
+    let app_weak_clone = app_weak.clone();
     app.on_slice_selected(move || {
+        let app_weak_clone = app_weak_clone.clone();
         let bodies_clone = Rc::clone(&bodies_clone);
         let gpu_slicer_clone = Rc::clone(&gpu_slicer_clone);
         let cpu_slicer_clone = Rc::clone(&cpu_slicer_clone);
+        let resin_profile_clone = Rc::clone(&resin_profile_clone);
+        let printer_profile_clone = Rc::clone(&printer_profile_clone);
+        let slice_job_clone = Rc::clone(&slice_job_clone);
+        let mesh_renderer_clone = Rc::clone(&mesh_renderer_clone);
+        let preview_requests_clone = Rc::clone(&preview_requests_clone);
+        let image_export_settings_clone = Rc::clone(&image_export_settings_clone);
         let slint_future = async move {
-            slice_selected_bodies(bodies_clone, gpu_slicer_clone, cpu_slicer_clone).await;
-            // replace with slice selected bodies
+            let job = slice_selected_bodies(
+                app_weak_clone.clone(),
+                printer_profile_clone,
+                bodies_clone,
+                gpu_slicer_clone,
+                cpu_slicer_clone,
+                resin_profile_clone,
+                mesh_renderer_clone,
+                preview_requests_clone,
+                image_export_settings_clone,
+            )
+            .await;
+            match job {
+                Ok(Some(job)) => view_job(&app_weak_clone, &slice_job_clone, job),
+                Ok(None) => {}
+                Err(e) => show_error(&app_weak_clone, format!("Slicing failed: {}", e)),
+            }
         };
         slint::spawn_local(async_compat::Compat::new(slint_future)).unwrap();
     });
@@ -641,13 +1634,43 @@ fn main() {
         let bodies_clone = Rc::clone(&state.shared_bodies);
         let gpu_slicer_clone = Rc::clone(&state.shared_gpu_slicer);
         let cpu_slicer_clone = Rc::clone(&state.shared_cpu_slicer);
+        let resin_profile_clone = Rc::clone(&state.shared_resin_profile);
+        let printer_profile_clone = Rc::clone(&state.shared_printer_profile);
+        let slice_job_clone = Rc::clone(&state.shared_slice_job);
+        let mesh_renderer_clone = Rc::clone(&state.shared_mesh_renderer);
+        let preview_requests_clone = Rc::clone(&state.shared_preview_requests);
+        let image_export_settings_clone = Rc::clone(&state.shared_image_export_settings);
+        let app_weak_clone = app_weak.clone();
         app.on_slice_all(move || {
             // Clone the Rc pointers inside the closure
+            let app_weak_clone = app_weak_clone.clone();
             let bodies_clone = Rc::clone(&bodies_clone);
             let gpu_slicer_clone = Rc::clone(&gpu_slicer_clone);
             let cpu_slicer_clone = Rc::clone(&cpu_slicer_clone);
+            let resin_profile_clone = Rc::clone(&resin_profile_clone);
+            let printer_profile_clone = Rc::clone(&printer_profile_clone);
+            let slice_job_clone = Rc::clone(&slice_job_clone);
+            let mesh_renderer_clone = Rc::clone(&mesh_renderer_clone);
+            let preview_requests_clone = Rc::clone(&preview_requests_clone);
+            let image_export_settings_clone = Rc::clone(&image_export_settings_clone);
             let slint_future = async move {
-                slice_all_bodies(bodies_clone, gpu_slicer_clone, cpu_slicer_clone).await
+                let job = slice_all_bodies(
+                    app_weak_clone.clone(),
+                    printer_profile_clone,
+                    bodies_clone,
+                    gpu_slicer_clone,
+                    cpu_slicer_clone,
+                    resin_profile_clone,
+                    mesh_renderer_clone,
+                    preview_requests_clone,
+                    image_export_settings_clone,
+                )
+                .await;
+                match job {
+                    Ok(Some(job)) => view_job(&app_weak_clone, &slice_job_clone, job),
+                    Ok(None) => {}
+                    Err(e) => show_error(&app_weak_clone, format!("Slicing failed: {}", e)),
+                }
             };
             slint::spawn_local(async_compat::Compat::new(slint_future)).unwrap();
         });
@@ -659,6 +1682,8 @@ fn main() {
             let mesh_renderer_clone:SharedMeshRenderer = Rc::clone(&state.shared_mesh_renderer);
             let bodies_clone: SharedBodies = Rc::clone(&state.shared_bodies);
             delete_body_by_uuid(&mesh_renderer_clone, &bodies_clone, uuid);
+            let build_volume = state.shared_printer_profile.borrow().build_volume();
+            validate_placement(&app_weak, &build_volume, &bodies_clone);
         });
     }
     fn delete_body_by_uuid(
@@ -1,14 +1,25 @@
 // Distributed under the GNU Affero General Public License v3.0 or later.
 // See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
-use crate::stl_processor::StlProcessorTrait;
 use bytemuck::{Pod, Zeroable};
 use nalgebra::Vector3;
 use std::{
     collections::{HashMap, HashSet},
-    ffi::OsStr, hash::Hasher, hash::Hash,
+    fmt,
+    hash::Hash,
+    hash::Hasher,
 };
 use stl_io::Triangle;
 
+use crate::mesh_analysis::{shells, weld_faces};
+use crate::mesh_importer::ImportProgress;
+
+/// Triangles welded between two progress reports
+const PROGRESS_INTERVAL: usize = 65536;
+
+/// Distance in millimeters below which corners of triangles become one vertex. Exported
+/// STLs often place the same corner a few float steps apart in neighbouring triangles.
+pub const DEFAULT_WELD_TOLERANCE: f32 = 1e-5;
+
 #[repr(C)]
 #[derive(Default, Clone, Pod, Copy, PartialEq, Debug)]
 pub struct Vertex {
@@ -35,7 +46,6 @@ impl Hash for Vertex {
 }
 
 impl Vertex {
-    #[allow(dead_code)]
     pub fn new(position: [f32; 3], normal: [f32; 3]) -> Self {
         Self { position, normal }
     }
@@ -50,6 +60,110 @@ impl Vertex {
     }
 }
 
+/// What welding the corners of imported triangles did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WeldStats {
+    /// Corners of the triangles, three per triangle
+    pub corners: usize,
+    /// Vertices left after welding
+    pub vertices: usize,
+    /// Corners merged into a vertex at a slightly different position
+    pub near_merges: usize,
+}
+
+impl WeldStats {
+    pub fn add(&mut self, other: &WeldStats) {
+        self.corners += other.corners;
+        self.vertices += other.vertices;
+        self.near_merges += other.near_merges;
+    }
+}
+
+impl fmt::Display for WeldStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} corners welded into {} vertices, {} of them within the tolerance",
+            self.corners, self.vertices, self.near_merges
+        )
+    }
+}
+
+/// Offsets of a cell of the welding grid and the 26 cells around it
+const NEIGHBOUR_CELLS: [[i64; 3]; 27] = {
+    let mut cells = [[0; 3]; 27];
+    let mut i = 0;
+    while i < 27 {
+        cells[i] = [i as i64 / 9 - 1, i as i64 / 3 % 3 - 1, i as i64 % 3 - 1];
+        i += 1;
+    }
+    cells
+};
+
+/// Finds the vertex a corner welds to. Vertices are hashed by the cell of a grid as wide as
+/// the tolerance, so only the cell of a corner and its neighbours need to be searched.
+struct VertexWelder {
+    tolerance: f32,
+    exact: HashMap<Vertex, u32>,
+    cells: HashMap<[i64; 3], Vec<u32>>,
+}
+
+impl VertexWelder {
+    fn new(tolerance: f32) -> Self {
+        Self {
+            tolerance,
+            exact: HashMap::new(),
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, position: [f32; 3]) -> [i64; 3] {
+        position.map(|c| (c / self.tolerance).floor() as i64)
+    }
+
+    /// Index of the vertex at the position, a new one if none is within the tolerance
+    fn weld(
+        &mut self,
+        vertices: &mut Vec<Vertex>,
+        position: [f32; 3],
+        stats: &mut WeldStats,
+    ) -> u32 {
+        let vertex = Vertex::new(position, [0.0, 0.0, 0.0]);
+        if let Some(&index) = self.exact.get(&vertex) {
+            return index;
+        }
+        if self.tolerance <= 0.0 {
+            let index = vertices.len() as u32;
+            vertices.push(vertex);
+            self.exact.insert(vertex, index);
+            return index;
+        }
+
+        let [x, y, z] = self.cell(position);
+        let mut nearest: Option<(f32, u32)> = None;
+        for [dx, dy, dz] in NEIGHBOUR_CELLS {
+            let cell = [x + dx, y + dy, z + dz];
+            for &index in self.cells.get(&cell).into_iter().flatten() {
+                let welded = Vector3::from(vertices[index as usize].position);
+                let distance = (welded - Vector3::from(position)).norm();
+                if distance <= self.tolerance && nearest.is_none_or(|(best, _)| distance < best) {
+                    nearest = Some((distance, index));
+                }
+            }
+        }
+        if let Some((_, index)) = nearest {
+            stats.near_merges += 1;
+            return index;
+        }
+
+        let index = vertices.len() as u32;
+        vertices.push(vertex);
+        self.exact.insert(vertex, index);
+        self.cells.entry([x, y, z]).or_default().push(index);
+        index
+    }
+}
+
 #[derive(Default)]
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
@@ -62,33 +176,38 @@ impl Mesh {
         // This is hacky i don't like it i will fix it later
         self.triangles_for_slicing = self.into_triangle_vec();
     }
-    fn generate_vertices_and_indices(&mut self, original_triangles: &Vec<Triangle> ) {
+    /// Welds the corners of the triangles closer than `tolerance` into shared vertices
+    fn generate_vertices_and_indices(
+        &mut self,
+        original_triangles: &[Triangle],
+        tolerance: f32,
+        progress: &ImportProgress,
+    ) -> WeldStats {
         let mut unique_vertices = Vec::new();
         let mut indices = Vec::new();
-        let mut vertex_map: HashMap<Vertex, u32> = HashMap::new();
-
-        for triangle in original_triangles {
+        let mut welder = VertexWelder::new(tolerance);
+        let mut stats = WeldStats::default();
+
+        for (i, triangle) in original_triangles.iter().enumerate() {
+            // Report now and then, a cancelled import leaves the mesh unfinished
+            if i.is_multiple_of(PROGRESS_INTERVAL) {
+                progress.set_vertices_welded(unique_vertices.len());
+                if progress.is_cancelled() {
+                    break;
+                }
+            }
             for &vertex_pos in &triangle.vertices {
-                let vertex = Vertex {
-                    position: vertex_pos,
-                    normal: [0.0, 0.0, 0.0], // Initialize normals; will compute later
-                };
-
-                // Insert the vertex into the map if it's not already present
-                let index = if let Some(&existing_index) = vertex_map.get(&vertex) {
-                    existing_index
-                } else {
-                    let new_index = unique_vertices.len() as u32;
-                    unique_vertices.push(vertex.clone());
-                    vertex_map.insert(vertex, new_index);
-                    new_index
-                };
-                indices.push(index);
+                // Normals are computed once the vertices are shared
+                indices.push(welder.weld(&mut unique_vertices, vertex_pos, &mut stats));
             }
         }
 
+        progress.set_vertices_welded(unique_vertices.len());
+        stats.corners = indices.len();
+        stats.vertices = unique_vertices.len();
         self.vertices = unique_vertices;
         self.indices = indices;
+        stats
     }
 
     fn into_triangle_vec(&self) -> Vec<Triangle> {
@@ -156,19 +275,50 @@ impl Mesh {
         [v1[0] - v2[0], v1[1] - v2[1], v1[2] - v2[2]]
     }
 
-    //TODO: Make this asynchonous or use it asynchonously
-    pub fn import_stl<P: AsRef<OsStr>, Processor: StlProcessorTrait>(
-        &mut self,
-        filename: P,
-        processor: &Processor,
-    ) {
-        let imported_triangles: Vec<Triangle> = processor
-            .read_stl(filename.as_ref())
-            .expect("Error processing STL file");
-        self.generate_vertices_and_indices(&imported_triangles);
-        self.compute_vertex_normals();
-        self.remove_degenerate_triangles();
-        self.ready_for_slicing();
+    /// Builds a mesh ready for rendering and slicing from imported triangles, welding
+    /// corners closer than `weld_tolerance`. What the welding did is added to the progress.
+    pub fn from_triangles(
+        triangles: Vec<Triangle>,
+        weld_tolerance: f32,
+        progress: &ImportProgress,
+    ) -> Self {
+        let mut mesh = Mesh::default();
+        let stats = mesh.generate_vertices_and_indices(&triangles, weld_tolerance, progress);
+        progress.add_weld_stats(&stats);
+        mesh.compute_vertex_normals();
+        mesh.remove_degenerate_triangles();
+        mesh.ready_for_slicing();
+        mesh
+    }
+
+    /// Builds a mesh from vertices already shared between triangles, skipping the merging
+    /// of equal corners. Normals are computed from the faces when the file has none.
+    pub fn from_indexed(
+        positions: Vec<[f32; 3]>,
+        normals: Option<Vec<[f32; 3]>>,
+        indices: Vec<u32>,
+        progress: &ImportProgress,
+    ) -> Self {
+        progress.set_vertices_welded(positions.len());
+        let mut mesh = Mesh {
+            vertices: positions
+                .into_iter()
+                .map(|position| Vertex::new(position, [0.0, 0.0, 0.0]))
+                .collect(),
+            indices,
+            ..Mesh::default()
+        };
+        match normals {
+            Some(normals) => {
+                for (vertex, normal) in mesh.vertices.iter_mut().zip(normals) {
+                    vertex.normal = Mesh::normalize(normal);
+                }
+            }
+            None => mesh.compute_vertex_normals(),
+        }
+        mesh.remove_degenerate_triangles();
+        mesh.ready_for_slicing();
+        mesh
     }
 
     // Compute vertex normals from STL faces
@@ -213,6 +363,36 @@ impl Mesh {
         }
     }
 
+    /// Splits the mesh into its parts, the shells of triangles joined through edges with
+    /// corners at the same position, in the order their first triangles appear. Triangles
+    /// collapsed to an edge or point are left out, as they are from the model info shells.
+    pub fn split_parts(&self) -> Vec<Mesh> {
+        let (positions, welded) = weld_faces(self);
+        let faces: Vec<[u32; 3]> = welded.iter().map(|&(_, face)| face).collect();
+        let mut shells = shells(&positions, &faces);
+        // Faces of a shell are in order
+        shells.sort_unstable_by_key(|shell| shell.faces[0]);
+        shells
+            .into_iter()
+            .map(|shell| {
+                let mut part = Mesh::default();
+                // Where every vertex went in the part
+                let mut moved_to: HashMap<u32, u32> = HashMap::new();
+                for face in shell.faces {
+                    let triangle = welded[face].0;
+                    for &index in &self.indices[3 * triangle..3 * triangle + 3] {
+                        let moved = *moved_to.entry(index).or_insert_with(|| {
+                            part.vertices.push(self.vertices[index as usize]);
+                            part.vertices.len() as u32 - 1
+                        });
+                        part.indices.push(moved);
+                    }
+                }
+                part
+            })
+            .collect()
+    }
+
     /// Removes degenerate triangles (triangles with zero area).
     pub fn remove_degenerate_triangles(&mut self) {
         let mut valid_indices = Vec::new();
@@ -240,6 +420,7 @@ impl Mesh {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mesh_analysis::Topology;
     use stl_io::Triangle;
 
     const EPSILON: f32 = 1e-4;
@@ -640,4 +821,135 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_from_indexed() {
+        // A unit square sharing its diagonal, plus a degenerate triangle
+        let positions = vec![
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let indices = vec![0, 1, 2, 0, 2, 3, 0, 0, 1];
+
+        let mesh = Mesh::from_indexed(
+            positions.clone(),
+            None,
+            indices.clone(),
+            &ImportProgress::default(),
+        );
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.triangles_for_slicing.len(), 2);
+        for vertex in &mesh.vertices {
+            assert_eq!(vertex.normal, [0.0, 0.0, 1.0]);
+        }
+
+        // Normals from the file are kept, only normalized
+        let normals = vec![[0.0, 0.0, 2.0]; 4];
+        let progress = ImportProgress::default();
+        let mesh = Mesh::from_indexed(positions, Some(normals), indices, &progress);
+        assert_eq!(mesh.vertices[3].normal, [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_weld_near_coincident_vertices() {
+        // The second triangle of a square has its shared corners a float step or so away,
+        // the first one in the neighbouring cell of the welding grid
+        let triangles = || {
+            vec![
+                create_triangle([0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]),
+                create_triangle([1e-7, -1e-7, 0.0], [1.0, 1.0000001, 0.0], [0.0, 1.0, 0.0]),
+            ]
+        };
+
+        let progress = ImportProgress::default();
+        let mesh = Mesh::from_triangles(triangles(), DEFAULT_WELD_TOLERANCE, &progress);
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3]);
+        // Welded vertices stay where they were first seen
+        assert_eq!(mesh.vertices[0].position, [0.0, 0.0, 0.0]);
+        assert_eq!(
+            progress.weld_stats(),
+            WeldStats {
+                corners: 6,
+                vertices: 4,
+                near_merges: 2,
+            }
+        );
+
+        // Without a tolerance only equal corners are welded
+        let progress = ImportProgress::default();
+        let mesh = Mesh::from_triangles(triangles(), 0.0, &progress);
+        assert_eq!(mesh.vertices.len(), 6);
+        assert_eq!(progress.weld_stats().near_merges, 0);
+
+        // Corners further apart than the tolerance stay apart, statistics add up
+        let apart = vec![
+            create_triangle([0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]),
+            create_triangle([1e-3, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]),
+        ];
+        let mesh = Mesh::from_triangles(apart, DEFAULT_WELD_TOLERANCE, &progress);
+        assert_eq!(mesh.vertices.len(), 5);
+        assert_eq!(
+            progress.weld_stats(),
+            WeldStats {
+                corners: 12,
+                vertices: 11,
+                near_merges: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_split_parts() {
+        let progress = ImportProgress::default();
+        let triangles = vec![
+            create_triangle([0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]),
+            create_triangle([5.0, 0.0, 0.0], [6.0, 0.0, 0.0], [6.0, 1.0, 0.0]),
+            create_triangle([0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]),
+        ];
+        let mesh = Mesh::from_triangles(triangles, 0.0, &progress);
+        let parts = mesh.split_parts();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].vertices.len(), 4);
+        assert_eq!(parts[0].indices.len(), 6);
+        assert_eq!(parts[1].vertices.len(), 3);
+        assert_eq!(parts[1].vertices[0].position, [5.0, 0.0, 0.0]);
+        assert_eq!(parts[1].indices, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_split_parts_with_duplicated_corners() {
+        // Every triangle has corners of its own, the square's halves join through an edge and
+        // the last triangle only touches the square at a corner
+        let corners = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [2.0, 1.0, 0.0],
+            [2.0, 2.0, 0.0],
+        ];
+        let mesh = Mesh {
+            vertices: corners
+                .iter()
+                .map(|&position| Vertex::new(position, [0.0, 0.0, 1.0]))
+                .collect(),
+            indices: (0..9).collect(),
+            ..Mesh::default()
+        };
+
+        let parts = mesh.split_parts();
+        assert_eq!(parts.len(), Topology::of(&mesh).shells);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].vertices.len(), 6);
+        assert_eq!(parts[0].indices, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(parts[1].vertices[0].position, [1.0, 1.0, 0.0]);
+        assert_eq!(parts[1].vertices[0].normal, [0.0, 0.0, 1.0]);
+    }
 }
@@ -0,0 +1,391 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Measures meshes: the resin a body takes, its area and size, and whether its surface is
+//! closed and facing outwards so it slices as intended.
+//!
+//! [`Topology`] only depends on the mesh, [`MeshAnalysis`] adds what changes with the model
+//! matrix. Corners at the same position count as one, as they do in [`crate::mesh_repair`].
+
+use crate::body::AABB;
+use crate::mesh::Mesh;
+use nalgebra::{Matrix4, Point3, Vector3};
+use std::collections::HashMap;
+use std::fmt;
+
+/// How the faces of a mesh connect
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Topology {
+    /// Groups of faces connected through their edges
+    pub shells: usize,
+    /// Edges of a single face, the rims of holes
+    pub boundary_edges: usize,
+    /// Edges of more than two faces
+    pub non_manifold_edges: usize,
+    /// Shells facing inwards while not inside another shell, or the other way around
+    pub inverted_shells: usize,
+}
+
+impl Topology {
+    pub fn of(mesh: &Mesh) -> Self {
+        let (positions, faces) = weld(mesh);
+        let edges = edge_faces(&faces);
+        let shells = shells(&positions, &faces);
+        Self {
+            shells: shells.len(),
+            boundary_edges: edges.values().filter(|at_edge| at_edge.len() == 1).count(),
+            non_manifold_edges: edges.values().filter(|at_edge| at_edge.len() > 2).count(),
+            inverted_shells: shells
+                .iter()
+                .filter(|shell| shell.is_inverted(&shells))
+                .count(),
+        }
+    }
+
+    /// Whether every edge joins exactly two faces, so the mesh encloses a volume
+    pub fn is_watertight(&self) -> bool {
+        self.boundary_edges == 0 && self.non_manifold_edges == 0
+    }
+}
+
+/// A mesh measured in world space, lengths in mm
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeshAnalysis {
+    /// Enclosed volume in mm³, negative when the mesh faces inwards
+    pub volume: f32,
+    /// Surface area in mm²
+    pub area: f32,
+    /// None if the mesh has no vertices
+    pub bounds: Option<AABB>,
+    pub topology: Topology,
+}
+
+impl MeshAnalysis {
+    pub fn new(mesh: &Mesh, model_matrix: &Matrix4<f32>, topology: Topology) -> Self {
+        let world: Vec<Vector3<f32>> = mesh
+            .vertices
+            .iter()
+            .map(|vertex| {
+                model_matrix
+                    .transform_point(&Point3::from(vertex.position))
+                    .coords
+            })
+            .collect();
+        let mut volume = 0.0;
+        let mut area = 0.0;
+        for face in mesh.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| world[face[i] as usize]);
+            volume += a.dot(&b.cross(&c)) / 6.0;
+            area += (b - a).cross(&(c - a)).norm() / 2.0;
+        }
+        if is_mirroring(model_matrix) {
+            volume = -volume;
+        }
+        let bounds = world.first().map(|&first| {
+            let (min, max) = world
+                .iter()
+                .fold((first, first), |(min, max), v| (min.inf(v), max.sup(v)));
+            AABB { min, max }
+        });
+        Self {
+            volume,
+            area,
+            bounds,
+            topology,
+        }
+    }
+}
+
+impl fmt::Display for MeshAnalysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Volume: {:.2} ml", self.volume / 1000.0)?;
+        writeln!(f, "Area: {:.1} mm²", self.area)?;
+        if let Some(bounds) = &self.bounds {
+            let size = bounds.max - bounds.min;
+            writeln!(f, "Size: {:.2} x {:.2} x {:.2} mm", size.x, size.y, size.z)?;
+        }
+        let topology = &self.topology;
+        write!(f, "Shells: {}", topology.shells)?;
+        if topology.inverted_shells > 0 {
+            write!(f, " ({} inverted)", topology.inverted_shells)?;
+        }
+        if topology.is_watertight() {
+            write!(f, "\nWatertight")
+        } else {
+            write!(
+                f,
+                "\nNot watertight: {} boundary edges, {} non-manifold edges",
+                topology.boundary_edges, topology.non_manifold_edges
+            )
+        }
+    }
+}
+
+/// Whether the transform mirrors. Mirroring turns triangles inside out, their corners go
+/// round the other way, so swapping two corners keeps them facing out.
+pub fn is_mirroring(transform: &Matrix4<f32>) -> bool {
+    transform.fixed_view::<3, 3>(0, 0).determinant() < 0.0
+}
+
+/// Merges vertices at the same position, dropping faces that collapse to an edge or point
+pub fn weld(mesh: &Mesh) -> (Vec<[f32; 3]>, Vec<[u32; 3]>) {
+    let (positions, faces) = weld_faces(mesh);
+    (positions, faces.into_iter().map(|(_, face)| face).collect())
+}
+
+/// Triangle of a mesh and its corners once welded
+pub type WeldedFace = (usize, [u32; 3]);
+
+/// Like [`weld`], along with the triangle of the mesh every face comes from
+pub fn weld_faces(mesh: &Mesh) -> (Vec<[f32; 3]>, Vec<WeldedFace>) {
+    let mut positions = Vec::new();
+    let mut welded: HashMap<[u32; 3], u32> = HashMap::new();
+    let indices: Vec<u32> = mesh
+        .vertices
+        .iter()
+        .map(|vertex| {
+            // -0.0 and 0.0 are the same place
+            let key = vertex.position.map(|c| (c + 0.0).to_bits());
+            *welded.entry(key).or_insert_with(|| {
+                positions.push(vertex.position);
+                positions.len() as u32 - 1
+            })
+        })
+        .collect();
+    let faces = mesh
+        .indices
+        .chunks_exact(3)
+        .map(|face| [0, 1, 2].map(|i| indices[face[i] as usize]))
+        .enumerate()
+        .filter(|(_, [a, b, c])| a != b && b != c && c != a)
+        .collect();
+    (positions, faces)
+}
+
+pub fn edge_key(a: u32, b: u32) -> (u32, u32) {
+    (a.min(b), a.max(b))
+}
+
+/// Faces at every edge, whatever their direction
+pub fn edge_faces(faces: &[[u32; 3]]) -> HashMap<(u32, u32), Vec<usize>> {
+    let mut edges: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+    for (i, face) in faces.iter().enumerate() {
+        for j in 0..3 {
+            edges
+                .entry(edge_key(face[j], face[(j + 1) % 3]))
+                .or_default()
+                .push(i);
+        }
+    }
+    edges
+}
+
+/// Faces connected through their edges, with the volume they enclose and their bounds
+pub struct Shell {
+    pub faces: Vec<usize>,
+    pub volume: f32,
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Shell {
+    /// Whether the other shell lies within the bounding box of this one
+    pub fn encloses(&self, other: &Shell) -> bool {
+        (self.min, self.max) != (other.min, other.max)
+            && self.min <= other.min
+            && self.max >= other.max
+    }
+
+    /// Whether the shell encloses a negative volume outside of other shells, or a positive
+    /// one inside an odd number of them like the inner wall of a hollowed part. Nesting is
+    /// judged by bounding boxes.
+    pub fn is_inverted(&self, shells: &[Shell]) -> bool {
+        let depth = shells.iter().filter(|other| other.encloses(self)).count();
+        self.volume != 0.0 && (self.volume > 0.0) != (depth % 2 == 0)
+    }
+}
+
+/// Splits the faces into shells
+pub fn shells(positions: &[[f32; 3]], faces: &[[u32; 3]]) -> Vec<Shell> {
+    let mut shells = UnionFind::new(faces.len());
+    for at_edge in edge_faces(faces).values() {
+        for pair in at_edge.windows(2) {
+            shells.union(pair[0], pair[1]);
+        }
+    }
+    let mut shell_faces: HashMap<usize, Vec<usize>> = HashMap::new();
+    for face in 0..faces.len() {
+        shell_faces.entry(shells.find(face)).or_default().push(face);
+    }
+
+    let point = |vertex: u32| Vector3::from(positions[vertex as usize]);
+    shell_faces
+        .into_values()
+        .map(|members| {
+            let mut volume = 0.0;
+            let mut min = Vector3::repeat(f32::MAX);
+            let mut max = Vector3::repeat(f32::MIN);
+            for &face in &members {
+                let [a, b, c] = faces[face].map(point);
+                volume += a.dot(&b.cross(&c)) / 6.0;
+                for corner in [a, b, c] {
+                    min = min.inf(&corner);
+                    max = max.sup(&corner);
+                }
+            }
+            Shell {
+                faces: members,
+                volume,
+                min,
+                max,
+            }
+        })
+        .collect()
+}
+
+pub struct UnionFind {
+    parents: Vec<usize>,
+}
+
+impl UnionFind {
+    pub fn new(size: usize) -> Self {
+        Self {
+            parents: (0..size).collect(),
+        }
+    }
+
+    pub fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    pub fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents[a] = b;
+    }
+}
+
+/// Corners of the unit cube, [`CUBE_FACES`] splits each side into two triangles facing out
+#[cfg(test)]
+pub const CUBE_CORNERS: [[f32; 3]; 8] = [
+    [0.0, 0.0, 0.0],
+    [1.0, 0.0, 0.0],
+    [1.0, 1.0, 0.0],
+    [0.0, 1.0, 0.0],
+    [0.0, 0.0, 1.0],
+    [1.0, 0.0, 1.0],
+    [1.0, 1.0, 1.0],
+    [0.0, 1.0, 1.0],
+];
+#[cfg(test)]
+pub const CUBE_FACES: [[u32; 3]; 12] = [
+    [0, 2, 1],
+    [0, 3, 2],
+    [4, 5, 6],
+    [4, 6, 7],
+    [0, 1, 5],
+    [0, 5, 4],
+    [1, 2, 6],
+    [1, 6, 5],
+    [2, 3, 7],
+    [2, 7, 6],
+    [3, 0, 4],
+    [3, 4, 7],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::Vertex;
+
+    /// Cubes scaled about the origin, each with its own corners
+    fn cubes(scales: &[f32], flipped: bool) -> Mesh {
+        let mut mesh = Mesh::default();
+        for &scale in scales {
+            let offset = mesh.vertices.len() as u32;
+            mesh.vertices.extend(
+                CUBE_CORNERS
+                    .iter()
+                    .map(|corner| Vertex::new(corner.map(|c| (c - 0.5) * scale), [0.0, 0.0, 0.0])),
+            );
+            for face in CUBE_FACES {
+                let face = if flipped {
+                    [face[0], face[2], face[1]]
+                } else {
+                    face
+                };
+                mesh.indices.extend(face.map(|i| i + offset));
+            }
+        }
+        mesh
+    }
+
+    #[test]
+    fn test_unit_cube() {
+        let mesh = cubes(&[1.0], false);
+        let analysis = MeshAnalysis::new(&mesh, &Matrix4::identity(), Topology::of(&mesh));
+        assert!((analysis.volume - 1.0).abs() < 1e-6);
+        assert!((analysis.area - 6.0).abs() < 1e-6);
+        assert_eq!(
+            analysis.topology,
+            Topology {
+                shells: 1,
+                ..Topology::default()
+            }
+        );
+        assert!(analysis.topology.is_watertight());
+    }
+
+    #[test]
+    fn test_world_space_measures() {
+        let mesh = cubes(&[1.0], false);
+        let model_matrix = Matrix4::new_translation(&Vector3::new(10.0, 0.0, 0.0))
+            * Matrix4::new_nonuniform_scaling(&Vector3::new(2.0, 3.0, -4.0));
+        let analysis = MeshAnalysis::new(&mesh, &model_matrix, Topology::of(&mesh));
+        // Mirrored by the negative scale, still counted as facing outwards
+        assert!((analysis.volume - 24.0).abs() < 1e-4);
+        assert!((analysis.area - 52.0).abs() < 1e-4);
+        let bounds = analysis.bounds.unwrap();
+        assert_eq!(bounds.min, Vector3::new(9.0, -1.5, -2.0));
+        assert_eq!(bounds.max, Vector3::new(11.0, 1.5, 2.0));
+    }
+
+    #[test]
+    fn test_open_mesh() {
+        let mut mesh = cubes(&[1.0], false);
+        mesh.indices.truncate(mesh.indices.len() - 3);
+        let topology = Topology::of(&mesh);
+        assert_eq!(topology.boundary_edges, 3);
+        assert!(!topology.is_watertight());
+
+        // A third face on an edge of the cube
+        let mut mesh = cubes(&[1.0], false);
+        mesh.vertices
+            .push(Vertex::new([0.5, -1.0, -1.0], [0.0, 0.0, 0.0]));
+        mesh.indices.extend([0, 1, 8]);
+        let topology = Topology::of(&mesh);
+        assert_eq!(topology.non_manifold_edges, 1);
+        assert_eq!(topology.boundary_edges, 2);
+    }
+
+    #[test]
+    fn test_inverted_shells() {
+        // A hollowed cube, its inner wall facing inwards
+        let mut mesh = cubes(&[2.0], false);
+        let inner = cubes(&[1.0], true);
+        mesh.indices.extend(inner.indices.iter().map(|i| i + 8));
+        mesh.vertices.extend(inner.vertices);
+        let analysis = MeshAnalysis::new(&mesh, &Matrix4::identity(), Topology::of(&mesh));
+        assert!((analysis.volume - 7.0).abs() < 1e-5);
+        assert_eq!(analysis.topology.shells, 2);
+        assert_eq!(analysis.topology.inverted_shells, 0);
+
+        let mesh = cubes(&[2.0, 1.0], false);
+        assert_eq!(Topology::of(&mesh).inverted_shells, 1);
+        let mesh = cubes(&[1.0], true);
+        assert_eq!(Topology::of(&mesh).inverted_shells, 1);
+    }
+}
@@ -0,0 +1,333 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Writers handing the bodies of the scene to other tools.
+//!
+//! The model matrix of every body is baked into its vertices, so the meshes keep the place,
+//! rotation and scale they have on the plate. A binary STL merges all bodies into one mesh
+//! with facet normals computed from the transformed triangles, a 3MF package keeps one
+//! object per body named after it.
+
+use crate::body::Body;
+use crate::mesh_analysis::is_mirroring;
+use crate::threemf_importer::{DEFAULT_MODEL_PATH, MODEL_RELATIONSHIP, RELATIONSHIPS_PATH};
+use nalgebra::{Point3, Vector3};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::Path;
+use stl_io::Triangle;
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+const CORE_NAMESPACE: &str = "http://schemas.microsoft.com/3dmanufacturing/core/2015/02";
+const CONTENT_TYPES: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Types xmlns="http://schemas.openxmlformats.org/package/2006/content-types">
+  <Default Extension="rels" ContentType="application/vnd.openxmlformats-package.relationships+xml"/>
+  <Default Extension="model" ContentType="application/vnd.ms-package.3dmanufacturing-3dmodel+xml"/>
+</Types>"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Stl,
+    ThreeMf,
+}
+
+impl ExportFormat {
+    pub const ALL: [Self; 2] = [Self::Stl, Self::ThreeMf];
+
+    /// Name shown to the user
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Stl => "Binary STL",
+            Self::ThreeMf => "3MF",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Stl => "stl",
+            Self::ThreeMf => "3mf",
+        }
+    }
+
+    /// Picks the format from the file extension
+    pub fn for_path(path: &Path) -> io::Result<Self> {
+        let extension = path
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|format| format.extension() == extension)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Unsupported mesh file: {}", path.display()),
+                )
+            })
+    }
+
+    pub fn write_to_file(self, bodies: &[&Body], path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        match self {
+            Self::Stl => write_stl(&mut writer, bodies)?,
+            Self::ThreeMf => write_3mf(&mut writer, bodies)?,
+        }
+        writer.flush()
+    }
+}
+
+/// Vertices and triangles of a body in plate coordinates
+struct WorldMesh {
+    positions: Vec<Point3<f32>>,
+    triangles: Vec<[u32; 3]>,
+}
+
+fn world_mesh(body: &Body) -> WorldMesh {
+    let model_matrix = body.get_model_matrix();
+    let positions = body
+        .mesh
+        .vertices
+        .iter()
+        .map(|vertex| model_matrix.transform_point(&Point3::from(vertex.position)))
+        .collect();
+    let mirrored = is_mirroring(&model_matrix);
+    let triangles = body
+        .mesh
+        .indices
+        .chunks_exact(3)
+        .map(|triangle| {
+            if mirrored {
+                [triangle[0], triangle[2], triangle[1]]
+            } else {
+                [triangle[0], triangle[1], triangle[2]]
+            }
+        })
+        .collect();
+    WorldMesh {
+        positions,
+        triangles,
+    }
+}
+
+fn nothing_to_export() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "There are no bodies to export")
+}
+
+/// Writes all bodies merged into one binary STL
+pub fn write_stl<W: Write>(writer: &mut W, bodies: &[&Body]) -> io::Result<()> {
+    if bodies.is_empty() {
+        return Err(nothing_to_export());
+    }
+    let mut triangles = Vec::new();
+    for body in bodies {
+        let mesh = world_mesh(body);
+        triangles.extend(mesh.triangles.iter().map(|indices| {
+            let [a, b, c] = indices.map(|index| mesh.positions[index as usize]);
+            let normal = (b - a)
+                .cross(&(c - a))
+                .try_normalize(f32::EPSILON)
+                .unwrap_or_else(Vector3::zeros);
+            Triangle {
+                normal: normal.into(),
+                vertices: [a, b, c].map(|point| point.coords.into()),
+            }
+        }));
+    }
+    stl_io::write_stl(writer, triangles.iter())
+}
+
+/// Writes a 3MF package with an object and a build item for every body
+pub fn write_3mf<W: Write + Seek>(writer: W, bodies: &[&Body]) -> io::Result<()> {
+    if bodies.is_empty() {
+        return Err(nothing_to_export());
+    }
+    let mut zip = ZipWriter::new(writer);
+    let options = SimpleFileOptions::default();
+    zip.start_file("[Content_Types].xml", options)?;
+    zip.write_all(CONTENT_TYPES.as_bytes())?;
+    zip.start_file(RELATIONSHIPS_PATH, options)?;
+    write!(
+        zip,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+  <Relationship Target="/{}" Id="rel0" Type="{}"/>
+</Relationships>"#,
+        DEFAULT_MODEL_PATH, MODEL_RELATIONSHIP
+    )?;
+    zip.start_file(DEFAULT_MODEL_PATH, options)?;
+    zip.write_all(model_xml(bodies).as_bytes())?;
+    zip.finish()?;
+    Ok(())
+}
+
+fn model_xml(bodies: &[&Body]) -> String {
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <model unit=\"millimeter\" xml:lang=\"en-US\" xmlns=\"{}\">\n<resources>\n",
+        CORE_NAMESPACE
+    );
+    // Writing into a String can't fail
+    for (i, body) in bodies.iter().enumerate() {
+        let mesh = world_mesh(body);
+        let _ = writeln!(
+            xml,
+            r#"<object id="{}" name="{}" type="model"><mesh><vertices>"#,
+            i + 1,
+            escape(&body.name)
+        );
+        for position in &mesh.positions {
+            let _ = writeln!(
+                xml,
+                r#"<vertex x="{}" y="{}" z="{}"/>"#,
+                position.x, position.y, position.z
+            );
+        }
+        xml.push_str("</vertices><triangles>\n");
+        for [v1, v2, v3] in &mesh.triangles {
+            let _ = writeln!(xml, r#"<triangle v1="{}" v2="{}" v3="{}"/>"#, v1, v2, v3);
+        }
+        xml.push_str("</triangles></mesh></object>\n");
+    }
+    xml.push_str("</resources>\n<build>\n");
+    for i in 0..bodies.len() {
+        let _ = writeln!(xml, r#"<item objectid="{}"/>"#, i + 1);
+    }
+    xml.push_str("</build>\n</model>\n");
+    xml
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::{Mesh, DEFAULT_WELD_TOLERANCE};
+    use crate::mesh_importer::{ImportProgress, MeshImporter};
+    use crate::stl_processor::StlProcessor;
+    use crate::threemf_importer;
+    use std::io::Cursor;
+
+    /// A tetrahedron with its right angle corner at the origin, facing out
+    fn tetrahedron(name: &str) -> Body {
+        let corners = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, 0.0, 1.0],
+        ];
+        let faces = [[0, 2, 1], [0, 1, 3], [0, 3, 2], [1, 2, 3]];
+        let triangles = faces
+            .iter()
+            .map(|face| Triangle {
+                normal: [0.0, 0.0, 0.0],
+                vertices: face.map(|corner| corners[corner]),
+            })
+            .collect();
+        let progress = ImportProgress::default();
+        let mesh = Mesh::from_triangles(triangles, DEFAULT_WELD_TOLERANCE, &progress);
+        let mut body = Body::new(mesh);
+        body.name = name.to_string();
+        body
+    }
+
+    /// Volume enclosed by the triangles, negative when they face inwards
+    fn signed_volume(triangles: &[Triangle]) -> f32 {
+        triangles
+            .iter()
+            .map(|triangle| {
+                let [a, b, c] = triangle
+                    .vertices
+                    .map(|vertex| Vector3::new(vertex[0], vertex[1], vertex[2]));
+                a.dot(&b.cross(&c)) / 6.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_stl_bakes_transforms() {
+        let first = tetrahedron("first");
+        let mut second = tetrahedron("second");
+        second.set_position(Vector3::new(10.0, 0.0, 0.0));
+        second.set_scale(Vector3::new(2.0, 2.0, 2.0));
+
+        let mut data = Vec::new();
+        write_stl(&mut data, &[&first, &second]).unwrap();
+        let meshes = StlProcessor.read_bytes(&data).unwrap();
+        let triangles = meshes[0].triangles();
+        assert_eq!(triangles.len(), 8);
+        assert!((signed_volume(&triangles[..4]) - 1.0 / 6.0).abs() < 1e-5);
+        assert!((signed_volume(&triangles[4..]) - 8.0 / 6.0).abs() < 1e-4);
+        let max_x = triangles
+            .iter()
+            .flat_map(|triangle| triangle.vertices)
+            .map(|vertex| vertex[0])
+            .fold(f32::MIN, f32::max);
+        assert_eq!(max_x, 12.0);
+
+        // Normals point away from the tetrahedron
+        for triangle in &triangles[..4] {
+            let [a, b, c] = triangle
+                .vertices
+                .map(|vertex| Vector3::new(vertex[0], vertex[1], vertex[2]));
+            let normal = Vector3::new(triangle.normal[0], triangle.normal[1], triangle.normal[2]);
+            assert!((normal.norm() - 1.0).abs() < 1e-5);
+            assert!(normal.dot(&((a + b + c) / 3.0 - Vector3::repeat(0.25))) > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_mirrored_body_stays_outward() {
+        let mut body = tetrahedron("mirrored");
+        body.set_scale(Vector3::new(-1.0, 1.0, 1.0));
+
+        let mut data = Vec::new();
+        write_stl(&mut data, &[&body]).unwrap();
+        let meshes = StlProcessor.read_bytes(&data).unwrap();
+        assert!((signed_volume(meshes[0].triangles()) - 1.0 / 6.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_3mf_has_an_object_per_body() {
+        let first = tetrahedron("first & best");
+        let mut second = tetrahedron("second");
+        second.set_position(Vector3::new(0.0, 0.0, 5.0));
+
+        let mut data = Cursor::new(Vec::new());
+        write_3mf(&mut data, &[&first, &second]).unwrap();
+        data.set_position(0);
+        let meshes = threemf_importer::read(data).unwrap();
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[0].name.as_deref(), Some("first & best"));
+        assert_eq!(meshes[1].name.as_deref(), Some("second"));
+        let second_triangles = meshes[1].triangles();
+        assert!((signed_volume(second_triangles) - 1.0 / 6.0).abs() < 1e-5);
+        let min_z = second_triangles
+            .iter()
+            .flat_map(|triangle| triangle.vertices)
+            .map(|vertex| vertex[2])
+            .fold(f32::MAX, f32::min);
+        assert_eq!(min_z, 5.0);
+    }
+
+    #[test]
+    fn test_format_for_path() {
+        assert_eq!(
+            ExportFormat::for_path(Path::new("plate.STL")).unwrap(),
+            ExportFormat::Stl
+        );
+        assert_eq!(
+            ExportFormat::for_path(Path::new("plate.3mf")).unwrap(),
+            ExportFormat::ThreeMf
+        );
+        assert!(ExportFormat::for_path(Path::new("plate.obj")).is_err());
+        assert!(write_stl(&mut Vec::new(), &[]).is_err());
+    }
+}
@@ -0,0 +1,513 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Importers turning mesh files into triangles.
+//!
+//! Every importer implements [`MeshImporter`] and is listed in [`IMPORTERS`]. The importer
+//! for a file is picked by its extension, files with an unknown extension are matched by
+//! their first bytes. Archives hand their entries back to [`read_named`].
+
+use crate::archive_importer::{GzipImporter, ZipImporter};
+use crate::mesh::WeldStats;
+use crate::obj_importer::ObjImporter;
+use crate::ply_importer::PlyImporter;
+use crate::stl_processor::StlProcessor;
+use crate::threemf_importer::ThreeMfImporter;
+use nalgebra::Vector3;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use stl_io::Triangle;
+
+/// Number of bytes at the start of a file handed to [`MeshImporter::sniff`]
+pub const SNIFF_SIZE: usize = 512;
+/// Most triangles imported from one file, bigger meshes would exhaust memory when sliced
+pub const MAX_TRIANGLES: usize = 20_000_000;
+/// Archives inside an archive are read this many levels deep, e.g. a zip in a zip
+pub const MAX_ARCHIVE_DEPTH: usize = 1;
+
+/// Why a file couldn't be imported
+#[derive(Debug)]
+pub enum ImportError {
+    /// The file couldn't be read
+    Io(io::Error),
+    /// The content is malformed, the message says where
+    Parse(String),
+    /// The file holds no triangles with an area
+    EmptyMesh,
+    /// No importer reads the file
+    UnsupportedFormat,
+    /// The file has more than [`MAX_TRIANGLES`] triangles, None when it was refused by
+    /// its size before they were counted
+    TooLarge { triangles: Option<usize> },
+    /// The user cancelled the import
+    Cancelled,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Io(e) => write!(f, "Could not read the file: {}", e),
+            ImportError::Parse(message) => write!(f, "{}", message),
+            ImportError::EmptyMesh => write!(f, "The file holds no triangles"),
+            ImportError::UnsupportedFormat => write!(f, "The file format is not supported"),
+            ImportError::TooLarge {
+                triangles: Some(triangles),
+            } => write!(
+                f,
+                "The file has {} triangles, at most {} can be imported",
+                triangles, MAX_TRIANGLES
+            ),
+            ImportError::TooLarge { triangles: None } => write!(
+                f,
+                "The file is too large, at most {} triangles can be imported",
+                MAX_TRIANGLES
+            ),
+            ImportError::Cancelled => write!(f, "The import was cancelled"),
+        }
+    }
+}
+
+impl Error for ImportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ImportError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Importers report malformed content as `InvalidData` or `UnexpectedEof` and unknown
+/// formats as `Unsupported`, everything else is a failure to read. An [`ImportError`]
+/// wrapped in the error, see [`check_triangle_count`], is passed on as it is.
+impl From<io::Error> for ImportError {
+    fn from(error: io::Error) -> Self {
+        if error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<ImportError>())
+        {
+            let inner = error.into_inner().expect("The error wraps an ImportError");
+            return *inner
+                .downcast::<ImportError>()
+                .expect("The error wraps an ImportError");
+        }
+        match error.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+                ImportError::Parse(error.to_string())
+            }
+            io::ErrorKind::Unsupported => ImportError::UnsupportedFormat,
+            _ => ImportError::Io(error),
+        }
+    }
+}
+
+/// One object of an imported file, it becomes a body of its own
+#[derive(Debug, PartialEq)]
+pub struct ImportedMesh {
+    /// Name of the object or group, None when the file holds a single unnamed mesh
+    pub name: Option<String>,
+    pub geometry: Geometry,
+}
+
+/// Triangles as read from a file, either separate or sharing their vertices
+#[derive(Debug, PartialEq)]
+pub enum Geometry {
+    /// Triangles with their own corners, equal corners are merged when the mesh is built
+    Triangles(Vec<Triangle>),
+    /// Vertices shared between triangles, every three indices make a triangle
+    Indexed {
+        positions: Vec<[f32; 3]>,
+        /// Normals per vertex from the file, they are computed from the faces when None
+        normals: Option<Vec<[f32; 3]>>,
+        indices: Vec<u32>,
+    },
+}
+
+impl ImportedMesh {
+    pub fn from_triangles(name: Option<String>, triangles: Vec<Triangle>) -> Self {
+        Self {
+            name,
+            geometry: Geometry::Triangles(triangles),
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        match &self.geometry {
+            Geometry::Triangles(triangles) => triangles.len(),
+            Geometry::Indexed { indices, .. } => indices.len() / 3,
+        }
+    }
+
+    /// The triangles of a mesh imported as separate triangles
+    #[cfg(test)]
+    pub fn triangles(&self) -> &[Triangle] {
+        match &self.geometry {
+            Geometry::Triangles(triangles) => triangles,
+            Geometry::Indexed { .. } => panic!("The mesh was imported with indexed vertices"),
+        }
+    }
+}
+
+pub trait MeshImporter: Sync {
+    /// File extensions in lowercase
+    fn extensions(&self) -> &'static [&'static str];
+
+    /// Whether a file starting with `header` and `file_size` bytes long looks like one
+    /// this importer reads
+    fn sniff(&self, _header: &[u8], _file_size: u64) -> bool {
+        false
+    }
+
+    /// Reads the meshes of a file held in memory, e.g. an entry of an archive
+    fn read_bytes(&self, data: &[u8]) -> io::Result<Vec<ImportedMesh>>;
+
+    /// Reads a file found `depth` archives deep. Archives override it to refuse archives
+    /// nested deeper than [`MAX_ARCHIVE_DEPTH`].
+    fn read_nested(&self, data: &[u8], _depth: usize) -> io::Result<Vec<ImportedMesh>> {
+        self.read_bytes(data)
+    }
+
+    /// Reads the meshes of a file, importers override it to stream large files. The file
+    /// is opened with [`ImportProgress::open`] so the bytes read are counted.
+    fn import(&self, path: &Path, progress: &ImportProgress) -> io::Result<Vec<ImportedMesh>> {
+        let mut data = Vec::new();
+        progress.open(path)?.read_to_end(&mut data)?;
+        self.read_bytes(&data)
+    }
+}
+
+/// Progress of an import, shared between the thread importing and the one showing it
+#[derive(Debug, Default)]
+pub struct ImportProgress {
+    bytes_read: AtomicU64,
+    total_bytes: AtomicU64,
+    vertices_welded: AtomicUsize,
+    weld_stats: Mutex<WeldStats>,
+    cancelled: AtomicBool,
+}
+
+impl ImportProgress {
+    /// Opens a file counting the bytes read from it, reads fail once the import is cancelled
+    pub fn open(&self, path: &Path) -> io::Result<BufReader<ProgressReader<'_, File>>> {
+        let file = File::open(path)?;
+        self.total_bytes
+            .store(file.metadata()?.len(), Ordering::Relaxed);
+        Ok(BufReader::new(ProgressReader {
+            inner: file,
+            progress: self,
+        }))
+    }
+
+    /// Share of the file read so far, from 0 to 1
+    pub fn fraction_read(&self) -> f32 {
+        let total = self.total_bytes.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0.0;
+        }
+        (self.bytes_read.load(Ordering::Relaxed) as f32 / total as f32).min(1.0)
+    }
+
+    pub fn vertices_welded(&self) -> usize {
+        self.vertices_welded.load(Ordering::Relaxed)
+    }
+
+    pub fn set_vertices_welded(&self, count: usize) {
+        self.vertices_welded.store(count, Ordering::Relaxed);
+    }
+
+    /// What welding did to all meshes of the import so far
+    pub fn weld_stats(&self) -> WeldStats {
+        *self.weld_stats.lock().unwrap()
+    }
+
+    pub fn add_weld_stats(&self, stats: &WeldStats) {
+        self.weld_stats.lock().unwrap().add(stats);
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// The error of an import whose reading failed with `error`, which is how reads fail
+    /// once the import is cancelled
+    pub fn read_error(&self, error: io::Error) -> ImportError {
+        if self.is_cancelled() {
+            ImportError::Cancelled
+        } else {
+            ImportError::from(error)
+        }
+    }
+}
+
+/// Reader adding the bytes it reads to an [`ImportProgress`]
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a ImportProgress,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.progress.is_cancelled() {
+            return Err(io::Error::other("The import was cancelled"));
+        }
+        let count = self.inner.read(buf)?;
+        self.progress
+            .bytes_read
+            .fetch_add(count as u64, Ordering::Relaxed);
+        Ok(count)
+    }
+}
+
+impl<R: Seek> Seek for ProgressReader<'_, R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(position)
+    }
+}
+
+/// Every importer, in the order files of unknown type are tried
+pub static IMPORTERS: [&dyn MeshImporter; 6] = [
+    &StlProcessor,
+    &ObjImporter,
+    &ThreeMfImporter,
+    &PlyImporter,
+    &ZipImporter,
+    &GzipImporter,
+];
+
+/// Extensions of every importer, for file dialogs
+pub fn extensions() -> Vec<&'static str> {
+    IMPORTERS
+        .iter()
+        .flat_map(|importer| importer.extensions().iter().copied())
+        .collect()
+}
+
+/// Picks the importer for a file by its extension, or by its content when the
+/// extension is unknown
+pub fn importer_for(path: &Path) -> io::Result<&'static dyn MeshImporter> {
+    if let Some(importer) = importer_by_extension(path) {
+        return Ok(importer);
+    }
+
+    let file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let mut header = Vec::with_capacity(SNIFF_SIZE);
+    file.take(SNIFF_SIZE as u64).read_to_end(&mut header)?;
+    importer_by_content(&header, file_size).ok_or_else(|| unsupported(&path.display()))
+}
+
+/// Reads the meshes of a file held in memory, such as an archive entry named `name`
+/// found `depth` archives deep. The importer is picked like for [`importer_for`].
+pub fn read_named(name: &str, data: &[u8], depth: usize) -> io::Result<Vec<ImportedMesh>> {
+    let header = &data[..data.len().min(SNIFF_SIZE)];
+    importer_by_extension(Path::new(name))
+        .or_else(|| importer_by_content(header, data.len() as u64))
+        .ok_or_else(|| unsupported(&name))?
+        .read_nested(data, depth)
+}
+
+/// Whether the extension of `path` belongs to an importer
+pub fn is_mesh_file(path: &Path) -> bool {
+    importer_by_extension(path).is_some()
+}
+
+fn importer_by_extension(path: &Path) -> Option<&'static dyn MeshImporter> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    IMPORTERS
+        .iter()
+        .find(|importer| importer.extensions().contains(&extension.as_str()))
+        .copied()
+}
+
+fn importer_by_content(header: &[u8], file_size: u64) -> Option<&'static dyn MeshImporter> {
+    IMPORTERS
+        .iter()
+        .find(|importer| importer.sniff(header, file_size))
+        .copied()
+}
+
+fn unsupported(name: &dyn std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Unsupported mesh file: {}", name),
+    )
+}
+
+/// Fails with [`ImportError::TooLarge`] when a file declares more than [`MAX_TRIANGLES`],
+/// so importers refuse it before reading the triangles into memory
+pub fn check_triangle_count(triangles: usize) -> io::Result<()> {
+    if triangles > MAX_TRIANGLES {
+        return Err(io::Error::other(ImportError::TooLarge {
+            triangles: Some(triangles),
+        }));
+    }
+    Ok(())
+}
+
+/// Builds a triangle with the normal following from its winding
+pub fn triangle(a: [f32; 3], b: [f32; 3], c: [f32; 3]) -> Triangle {
+    let (a, b, c) = (Vector3::from(a), Vector3::from(b), Vector3::from(c));
+    let normal = (b - a)
+        .cross(&(c - a))
+        .try_normalize(0.0)
+        .unwrap_or_default();
+    Triangle {
+        normal: normal.into(),
+        vertices: [a.into(), b.into(), c.into()],
+    }
+}
+
+/// Splits a polygon into triangles by ear clipping, keeping the winding of the polygon.
+/// The polygon is projected onto the plane it mostly lies in. Falls back to a fan when
+/// no ear is found, e.g. for self intersecting polygons.
+pub fn triangulate(polygon: &[[f32; 3]]) -> Vec<[usize; 3]> {
+    if polygon.len() == 3 {
+        return vec![[0, 1, 2]];
+    }
+    let points: Vec<Vector3<f32>> = polygon.iter().map(|&p| Vector3::from(p)).collect();
+    // Newell's method gives the normal of non planar polygons too
+    let mut normal = Vector3::zeros();
+    for (i, p) in points.iter().enumerate() {
+        let q = points[(i + 1) % points.len()];
+        normal += Vector3::new(
+            (p.y - q.y) * (p.z + q.z),
+            (p.z - q.z) * (p.x + q.x),
+            (p.x - q.x) * (p.y + q.y),
+        );
+    }
+    let axis = normal.iamax();
+    let (u, v) = match axis {
+        0 => (1, 2),
+        1 => (2, 0),
+        _ => (0, 1),
+    };
+    let flat: Vec<[f32; 2]> = points.iter().map(|p| [p[u], p[v]]).collect();
+    let orientation = normal[axis].signum();
+    let cross = |a: [f32; 2], b: [f32; 2], c: [f32; 2]| {
+        ((b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])) * orientation
+    };
+
+    let mut remaining: Vec<usize> = (0..polygon.len()).collect();
+    let mut triangles = Vec::with_capacity(polygon.len() - 2);
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let ear = (0..n).find(|&i| {
+            let (a, b, c) = (
+                remaining[(i + n - 1) % n],
+                remaining[i],
+                remaining[(i + 1) % n],
+            );
+            if cross(flat[a], flat[b], flat[c]) <= 0.0 {
+                return false;
+            }
+            remaining.iter().all(|&p| {
+                p == a
+                    || p == b
+                    || p == c
+                    || cross(flat[a], flat[b], flat[p]) < 0.0
+                    || cross(flat[b], flat[c], flat[p]) < 0.0
+                    || cross(flat[c], flat[a], flat[p]) < 0.0
+            })
+        });
+        let Some(i) = ear else {
+            break;
+        };
+        triangles.push([
+            remaining[(i + n - 1) % n],
+            remaining[i],
+            remaining[(i + 1) % n],
+        ]);
+        remaining.remove(i);
+    }
+    for i in 1..remaining.len() - 1 {
+        triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+    }
+    triangles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn file_with(suffix: &str, content: &[u8]) -> NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+        file.write_all(content).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_importer_by_extension() {
+        for (suffix, expected) in [
+            (".STL", "stl"),
+            (".obj", "obj"),
+            (".3MF", "3mf"),
+            (".ply", "ply"),
+        ] {
+            let file = file_with(suffix, b"");
+            let importer = importer_for(file.path()).unwrap();
+            assert!(importer.extensions().contains(&expected), "{}", suffix);
+        }
+    }
+
+    #[test]
+    fn test_importer_by_content() {
+        let mut binary_stl = vec![0; 80];
+        binary_stl.extend_from_slice(&1u32.to_le_bytes());
+        binary_stl.extend_from_slice(&[0; 50]);
+
+        for (content, expected) in [
+            (b"solid part\nendsolid part\n".to_vec(), "stl"),
+            (binary_stl, "stl"),
+            (
+                b"# Exported\n\nmtllib a.mtl\no Cube\nv 0 0 0\n".to_vec(),
+                "obj",
+            ),
+            (b"ply\r\nformat ascii 1.0\r\n".to_vec(), "ply"),
+            (
+                b"PK\x03\x04\x14\0\0\0\0\0\0\0\0\0[Content_Types].xml".to_vec(),
+                "3mf",
+            ),
+        ] {
+            let file = file_with(".bin", &content);
+            let importer = importer_for(file.path()).unwrap();
+            assert!(importer.extensions().contains(&expected));
+        }
+
+        let file = file_with(".bin", b"\x89PNG\r\n\x1a\n not a mesh");
+        let error = importer_for(file.path()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_import_error_from_io() {
+        let parse = io::Error::new(io::ErrorKind::InvalidData, "Invalid vertex on line 3");
+        let error = ImportError::from(parse);
+        assert!(matches!(error, ImportError::Parse(_)));
+        assert_eq!(error.to_string(), "Invalid vertex on line 3");
+
+        let missing = importer_for(Path::new("/nonexistent/model")).err().unwrap();
+        let error = ImportError::from(missing);
+        assert!(matches!(error, ImportError::Io(_)));
+        assert!(error.source().is_some());
+
+        let unsupported = io::Error::new(io::ErrorKind::Unsupported, "Unsupported mesh file");
+        assert!(matches!(
+            ImportError::from(unsupported),
+            ImportError::UnsupportedFormat
+        ));
+    }
+}
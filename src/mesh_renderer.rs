@@ -8,12 +8,15 @@ use crate::body::Body;
 use crate::camera::Camera;
 use crate::mesh::Mesh;
 use crate::mesh::Vertex;
+use crate::preview_renderer::{IsometricView, LIGHT_DIRECTION};
+use crate::slice_job::PREVIEW_BACKGROUND;
 use crate::texture::Texture;
 use crate::ScopedVAOBinding;
 use crate::ScopedVBOBinding;
 use glow::Context as GlowContext;
 use glow::HasContext;
-use nalgebra::Vector3;
+use image::{Rgb, RgbImage};
+use nalgebra::{Matrix4, Vector3};
 pub struct MeshRenderer {
     gl: Rc<GlowContext>,
     program: glow::Program,
@@ -24,6 +27,7 @@ pub struct MeshRenderer {
     view_direction_location: glow::UniformLocation,
     light_direction_location: glow::UniformLocation,
     model_location: glow::UniformLocation,
+    highlight_location: glow::UniformLocation,
     displayed_texture: Texture,
     next_texture: Texture,
     bodies: Vec<Rc<RefCell<Body>>>,
@@ -100,6 +104,9 @@ impl MeshRenderer {
 
             // Get attribute and uniform locations
             let model_location = gl.get_uniform_location(shader_program, "model").unwrap();
+            let highlight_location = gl
+                .get_uniform_location(shader_program, "highlight")
+                .unwrap();
 
             // Set up VBO, EBO, VAO
             let vbo = gl.create_buffer().expect("Cannot create buffer");
@@ -170,6 +177,7 @@ impl MeshRenderer {
                 view_direction_location,
                 light_direction_location,
                 model_location,
+                highlight_location,
                 vao,
                 vbo,
                 ebo,
@@ -225,74 +233,8 @@ impl MeshRenderer {
                 let projection = self.camera.projection_matrix;
                 let view = self.camera.view_matrix();
                 let view_proj = projection * view;
-                // Assuming `view_proj_location`, `view_direction_location`, and `light_direction_location` are obtained using `gl.get_uniform_location` or equivalent
-
                 let view_dir = self.camera.get_view_direction_vector();
-                gl.uniform_3_f32(
-                    Some(&self.view_direction_location),
-                    view_dir.x,
-                    view_dir.y,
-                    view_dir.z,
-                );
-
-                // Set the light direction (e.g., a fixed directional light)
-                gl.uniform_3_f32(Some(&self.light_direction_location), 1.0, -1.0, 0.5);
-
-                // Convert to column-major array
-                let view_proj_matrix: [f32; 16] = view_proj
-                    .as_slice()
-                    .try_into()
-                    .expect("Slice with incorrect length");
-
-                // Set the view_proj uniform
-                gl.uniform_matrix_4_f32_slice(
-                    Some(&self.view_proj_location),
-                    false,
-                    &view_proj_matrix,
-                );
-                gl.bind_vertex_array(Some(self.vao));
-                gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.vbo));
-                gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(self.ebo));
-                for body in &self.bodies {
-                    let mesh = &body.borrow().mesh;
-                    // Set the model uniform
-                    gl.uniform_matrix_4_f32_slice(
-                        Some(&self.model_location),
-                        false,
-                        &body.borrow().get_model_matrix().as_slice(),
-                    );
-
-                    // Upload the vertex data to the GPU
-                    self.gl.buffer_data_u8_slice(
-                        glow::ARRAY_BUFFER,
-                        bytemuck::cast_slice(&mesh.vertices),
-                        glow::STATIC_DRAW,
-                    );
-
-                    // Upload the index data to the GPU
-                    self.gl.buffer_data_u8_slice(
-                        glow::ELEMENT_ARRAY_BUFFER,
-                        bytemuck::cast_slice(&mesh.indices),
-                        glow::STATIC_DRAW,
-                    );
-
-                    if gl.check_framebuffer_status(glow::FRAMEBUFFER) != glow::FRAMEBUFFER_COMPLETE
-                    {
-                        panic!("Framebuffer is not complete!");
-                    }
-
-                    gl.draw_elements(
-                        glow::TRIANGLES,
-                        mesh.indices.len() as i32,
-                        glow::UNSIGNED_INT,
-                        0,
-                    );
-                }
-
-                // Unbind the buffers
-                gl.bind_vertex_array(None);
-                self.gl.bind_buffer(glow::ARRAY_BUFFER, None);
-                self.gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, None);
+                self.draw_bodies(&self.bodies, &view_proj, &view_dir, true);
 
                 // Restore viewport
                 gl.viewport(
@@ -321,6 +263,153 @@ impl MeshRenderer {
         result_texture
     }
 
+    /// Draws bodies into the bound framebuffer. The program, buffers and viewport must
+    /// already be set up.
+    unsafe fn draw_bodies(
+        &self,
+        bodies: &[Rc<RefCell<Body>>],
+        view_proj: &Matrix4<f32>,
+        view_dir: &Vector3<f32>,
+        show_placement_issues: bool,
+    ) {
+        let gl = &self.gl;
+        gl.uniform_3_f32(
+            Some(&self.view_direction_location),
+            view_dir.x,
+            view_dir.y,
+            view_dir.z,
+        );
+        // Set the light direction (e.g., a fixed directional light)
+        let [light_x, light_y, light_z] = LIGHT_DIRECTION;
+        gl.uniform_3_f32(
+            Some(&self.light_direction_location),
+            light_x,
+            light_y,
+            light_z,
+        );
+
+        // Convert to column-major array
+        let view_proj_matrix: [f32; 16] = view_proj
+            .as_slice()
+            .try_into()
+            .expect("Slice with incorrect length");
+
+        // Set the view_proj uniform
+        gl.uniform_matrix_4_f32_slice(Some(&self.view_proj_location), false, &view_proj_matrix);
+        gl.bind_vertex_array(Some(self.vao));
+        gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.vbo));
+        gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(self.ebo));
+        for body in bodies {
+            let mesh = &body.borrow().mesh;
+            // Set the model uniform
+            gl.uniform_matrix_4_f32_slice(
+                Some(&self.model_location),
+                false,
+                &body.borrow().get_model_matrix().as_slice(),
+            );
+            gl.uniform_1_i32(
+                Some(&self.highlight_location),
+                (show_placement_issues && body.borrow().has_placement_issue) as i32,
+            );
+
+            // Upload the vertex data to the GPU
+            gl.buffer_data_u8_slice(
+                glow::ARRAY_BUFFER,
+                bytemuck::cast_slice(&mesh.vertices),
+                glow::STATIC_DRAW,
+            );
+
+            // Upload the index data to the GPU
+            gl.buffer_data_u8_slice(
+                glow::ELEMENT_ARRAY_BUFFER,
+                bytemuck::cast_slice(&mesh.indices),
+                glow::STATIC_DRAW,
+            );
+
+            if gl.check_framebuffer_status(glow::FRAMEBUFFER) != glow::FRAMEBUFFER_COMPLETE {
+                panic!("Framebuffer is not complete!");
+            }
+
+            gl.draw_elements(
+                glow::TRIANGLES,
+                mesh.indices.len() as i32,
+                glow::UNSIGNED_INT,
+                0,
+            );
+        }
+
+        // Unbind the buffers
+        gl.bind_vertex_array(None);
+        gl.bind_buffer(glow::ARRAY_BUFFER, None);
+        gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, None);
+    }
+
+    /// Renders the bodies from the isometric preview camera into an offscreen texture and
+    /// reads it back, for printer file thumbnails. The OpenGL context is only current in
+    /// the rendering notifier, so this must be called from there.
+    pub fn render_thumbnail(
+        &mut self,
+        bodies: &[Rc<RefCell<Body>>],
+        width: u32,
+        height: u32,
+    ) -> RgbImage {
+        if width == 0 || height == 0 {
+            return RgbImage::new(width, height);
+        }
+        let view = IsometricView::fit(bodies, width as f32 / height as f32);
+        let mut rgba = vec![0u8; (width * height * 4) as usize];
+        unsafe {
+            let gl = &self.gl;
+            let texture = Texture::new(gl, width, height);
+            gl.use_program(Some(self.program));
+            let _saved_vbo = ScopedVBOBinding::new(gl, Some(self.vbo));
+            let _saved_vao = ScopedVAOBinding::new(gl, Some(self.vao));
+            gl.enable(glow::CULL_FACE);
+            gl.cull_face(glow::BACK);
+
+            texture.with_texture_as_active_fbo(|| {
+                let mut saved_viewport: [i32; 4] = [0; 4];
+                gl.get_parameter_i32_slice(glow::VIEWPORT, &mut saved_viewport);
+                let mut saved_clear_color: [f32; 4] = [0.0; 4];
+                gl.get_parameter_f32_slice(glow::COLOR_CLEAR_VALUE, &mut saved_clear_color);
+                gl.viewport(0, 0, width as i32, height as i32);
+
+                let [red, green, blue] = PREVIEW_BACKGROUND.0.map(|c| c as f32 / 255.0);
+                gl.clear_color(red, green, blue, 1.0);
+                gl.enable(glow::DEPTH_TEST);
+                gl.depth_func(glow::LEQUAL);
+                gl.clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+                self.draw_bodies(bodies, &view.view_proj, &view.view_direction, false);
+                gl.read_pixels(
+                    0,
+                    0,
+                    width as i32,
+                    height as i32,
+                    glow::RGBA,
+                    glow::UNSIGNED_BYTE,
+                    glow::PixelPackData::Slice(&mut rgba),
+                );
+
+                let [red, green, blue, alpha] = saved_clear_color;
+                gl.clear_color(red, green, blue, alpha);
+                gl.viewport(
+                    saved_viewport[0],
+                    saved_viewport[1],
+                    saved_viewport[2],
+                    saved_viewport[3],
+                );
+            });
+
+            gl.use_program(None);
+        }
+
+        // OpenGL returns the bottom row first
+        RgbImage::from_fn(width, height, |x, y| {
+            let offset = (((height - 1 - y) * width + x) * 4) as usize;
+            Rgb([rgba[offset], rgba[offset + 1], rgba[offset + 2]])
+        })
+    }
+
     pub fn camera_pitch_yaw(&mut self, delta_x: f32, delta_y: f32) {
         self.camera.pitch_yaw(delta_x, -delta_y);
     }
@@ -0,0 +1,442 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Repairs meshes that would slice badly.
+//!
+//! [`repair`] welds equal corners and then, in this order:
+//! - removes faces stored twice, and pairs of coincident faces facing each other, which are
+//!   the internal walls left where two shells touch
+//! - splits vertices shared by surfaces that only touch there, so every edge joins at most
+//!   two faces
+//! - turns faces so neighbours agree on their orientation
+//! - fills the holes bounded by loops of open edges
+//! - turns every shell to face outwards, the shells inside another one inwards
+//!
+//! The returned [`RepairReport`] counts what was changed.
+
+use crate::mesh::{Mesh, Vertex};
+use crate::mesh_analysis::{edge_faces, edge_key, shells, weld, UnionFind};
+use crate::mesh_importer::triangulate;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    pub duplicate_faces_removed: usize,
+    pub internal_faces_removed: usize,
+    pub non_manifold_edges_split: usize,
+    pub faces_flipped: usize,
+    pub holes_filled: usize,
+    pub shells_reversed: usize,
+}
+
+impl RepairReport {
+    /// Whether the mesh was left as it was
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let changes: Vec<String> = [
+            (self.duplicate_faces_removed, "duplicate faces removed"),
+            (self.internal_faces_removed, "internal faces removed"),
+            (self.non_manifold_edges_split, "non-manifold edges split"),
+            (self.faces_flipped, "faces flipped"),
+            (self.holes_filled, "holes filled"),
+            (self.shells_reversed, "shells reversed"),
+        ]
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, change)| format!("{} {}", count, change))
+        .collect();
+        if changes.is_empty() {
+            write!(f, "Nothing to repair")
+        } else {
+            write!(f, "{}", changes.join(", "))
+        }
+    }
+}
+
+/// Repairs the mesh in place and recomputes its normals
+pub fn repair(mesh: &mut Mesh) -> RepairReport {
+    let mut report = RepairReport::default();
+    let (mut positions, faces) = weld(mesh);
+    let mut faces = remove_duplicate_faces(faces, &mut report);
+    report.non_manifold_edges_split = split_non_manifold(&mut positions, &mut faces);
+    report.faces_flipped = unify_orientation(&mut faces);
+    report.holes_filled = fill_holes(&positions, &mut faces);
+    report.shells_reversed = orient_shells_outward(&positions, &mut faces);
+
+    mesh.vertices = positions
+        .into_iter()
+        .map(|position| Vertex::new(position, [0.0, 0.0, 0.0]))
+        .collect();
+    mesh.indices = faces.into_iter().flatten().collect();
+    mesh.compute_vertex_normals();
+    mesh.remove_degenerate_triangles();
+    mesh.ready_for_slicing();
+    report
+}
+
+/// The face rotated to start at its smallest vertex, equal for faces of the same corners
+/// and orientation
+fn canonical(face: [u32; 3]) -> [u32; 3] {
+    let [a, b, c] = face;
+    if a < b && a < c {
+        [a, b, c]
+    } else if b < c {
+        [b, c, a]
+    } else {
+        [c, a, b]
+    }
+}
+
+fn remove_duplicate_faces(faces: Vec<[u32; 3]>, report: &mut RepairReport) -> Vec<[u32; 3]> {
+    // Faces of the same corners by orientation, as seen from the first of them
+    let mut groups: HashMap<[u32; 3], (usize, usize, usize)> = HashMap::new();
+    for (i, &face) in faces.iter().enumerate() {
+        let mut sorted = face;
+        sorted.sort_unstable();
+        let group = groups.entry(sorted).or_insert((i, 0, 0));
+        if canonical(face) == canonical(faces[group.0]) {
+            group.1 += 1;
+        } else {
+            group.2 += 1;
+        }
+    }
+
+    // Opposite faces cancel out, one face of the orientation left over is kept
+    let mut kept = HashSet::new();
+    for &(first, same, opposite) in groups.values() {
+        let internal = same.min(opposite);
+        report.internal_faces_removed += 2 * internal;
+        report.duplicate_faces_removed += (same.max(opposite) - internal).saturating_sub(1);
+        if same != opposite {
+            kept.insert((first, same > opposite));
+        }
+    }
+    let mut seen = HashSet::new();
+    faces
+        .iter()
+        .filter(|&&face| {
+            let mut sorted = face;
+            sorted.sort_unstable();
+            let (first, _, _) = groups[&sorted];
+            let same = canonical(face) == canonical(faces[first]);
+            kept.contains(&(first, same)) && seen.insert(first)
+        })
+        .copied()
+        .collect()
+}
+
+/// Gives every fan of faces around a vertex a vertex of its own. Fans are faces connected
+/// through edges of exactly two faces, so surfaces meeting at an edge of more than two
+/// faces, or only at the vertex, come apart. Returns the edges of more than two faces.
+fn split_non_manifold(positions: &mut Vec<[f32; 3]>, faces: &mut [[u32; 3]]) -> usize {
+    let edges = edge_faces(faces);
+    let non_manifold = edges.values().filter(|at_edge| at_edge.len() > 2).count();
+
+    let mut vertex_faces: Vec<Vec<usize>> = vec![Vec::new(); positions.len()];
+    for (i, face) in faces.iter().enumerate() {
+        for &vertex in face {
+            vertex_faces[vertex as usize].push(i);
+        }
+    }
+    let original = faces.to_vec();
+    for (vertex, around) in vertex_faces.iter().enumerate() {
+        if around.len() < 2 {
+            continue;
+        }
+        let vertex = vertex as u32;
+        let mut fans = UnionFind::new(around.len());
+        for (i, &face) in around.iter().enumerate() {
+            for &other in &original[face] {
+                if other == vertex {
+                    continue;
+                }
+                if let [first, second] = edges[&edge_key(vertex, other)][..] {
+                    let neighbour = if first == face { second } else { first };
+                    if let Some(j) = around.iter().position(|&f| f == neighbour) {
+                        fans.union(i, j);
+                    }
+                }
+            }
+        }
+        // The fan of the first face keeps the vertex
+        let mut copies: HashMap<usize, u32> = HashMap::new();
+        let first_fan = fans.find(0);
+        for (i, &face) in around.iter().enumerate() {
+            let fan = fans.find(i);
+            if fan == first_fan {
+                continue;
+            }
+            let copy = *copies.entry(fan).or_insert_with(|| {
+                positions.push(positions[vertex as usize]);
+                positions.len() as u32 - 1
+            });
+            for corner in faces[face].iter_mut() {
+                if *corner == vertex {
+                    *corner = copy;
+                }
+            }
+        }
+    }
+    non_manifold
+}
+
+fn flip(face: &mut [u32; 3]) {
+    face.swap(1, 2);
+}
+
+/// Whether the face runs along the edge from `a` to `b`
+fn has_directed_edge(face: &[u32; 3], a: u32, b: u32) -> bool {
+    (0..3).any(|i| face[i] == a && face[(i + 1) % 3] == b)
+}
+
+/// Flips faces until neighbours run along their shared edges in opposite directions.
+/// The first face of every shell decides its orientation. Returns the faces flipped.
+fn unify_orientation(faces: &mut [[u32; 3]]) -> usize {
+    let edges = edge_faces(faces);
+    let mut visited = vec![false; faces.len()];
+    let mut flipped = 0;
+    for start in 0..faces.len() {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        let mut stack = vec![start];
+        while let Some(face) = stack.pop() {
+            let corners = faces[face];
+            for j in 0..3 {
+                let (a, b) = (corners[j], corners[(j + 1) % 3]);
+                let [first, second] = edges[&edge_key(a, b)][..] else {
+                    continue;
+                };
+                let neighbour = if first == face { second } else { first };
+                if visited[neighbour] {
+                    continue;
+                }
+                visited[neighbour] = true;
+                if has_directed_edge(&faces[neighbour], a, b) {
+                    flip(&mut faces[neighbour]);
+                    flipped += 1;
+                }
+                stack.push(neighbour);
+            }
+        }
+    }
+    flipped
+}
+
+/// Closes every loop of open edges with new faces. Returns the holes filled.
+fn fill_holes(positions: &[[f32; 3]], faces: &mut Vec<[u32; 3]>) -> usize {
+    let directed: HashSet<(u32, u32)> = faces
+        .iter()
+        .flat_map(|face| (0..3).map(move |i| (face[i], face[(i + 1) % 3])))
+        .collect();
+    // A cap runs along the open edges in the opposite direction of the faces beside it
+    let mut open: HashMap<u32, Vec<u32>> = HashMap::new();
+    let mut open_edges = Vec::new();
+    for &(a, b) in &directed {
+        if !directed.contains(&(b, a)) {
+            open.entry(b).or_default().push(a);
+            open_edges.push((b, a));
+        }
+    }
+    // Walk the holes in the same order on every run
+    open_edges.sort_unstable();
+
+    let mut used = HashSet::new();
+    let mut filled = 0;
+    for &(start, second) in &open_edges {
+        if used.contains(&(start, second)) {
+            continue;
+        }
+        let mut hole = vec![start];
+        let mut walked = vec![(start, second)];
+        let mut current = second;
+        let closed = loop {
+            if current == start {
+                break true;
+            }
+            let next = open[&current].iter().copied().find(|&next| {
+                !used.contains(&(current, next)) && !walked.contains(&(current, next))
+            });
+            let Some(next) = next else {
+                break false;
+            };
+            hole.push(current);
+            walked.push((current, next));
+            current = next;
+        };
+        used.extend(walked);
+        if !closed || hole.len() < 3 {
+            continue;
+        }
+        let polygon: Vec<[f32; 3]> = hole
+            .iter()
+            .map(|&vertex| positions[vertex as usize])
+            .collect();
+        faces.extend(
+            triangulate(&polygon)
+                .into_iter()
+                .map(|triangle| triangle.map(|i| hole[i])),
+        );
+        filled += 1;
+    }
+    filled
+}
+
+/// Turns shells to enclose a positive volume, or a negative one when they lie inside an
+/// odd number of other shells like the inner wall of a hollowed part. Returns the shells
+/// turned.
+fn orient_shells_outward(positions: &[[f32; 3]], faces: &mut [[u32; 3]]) -> usize {
+    let shells = shells(positions, faces);
+    let mut turned = 0;
+    for shell in &shells {
+        if shell.is_inverted(&shells) {
+            for &face in &shell.faces {
+                flip(&mut faces[face]);
+            }
+            turned += 1;
+        }
+    }
+    turned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh_analysis::{CUBE_CORNERS, CUBE_FACES};
+    use nalgebra::Vector3;
+
+    fn mesh(corners: &[[f32; 3]], faces: &[[u32; 3]]) -> Mesh {
+        Mesh {
+            vertices: corners
+                .iter()
+                .map(|&position| Vertex::new(position, [0.0, 0.0, 0.0]))
+                .collect(),
+            indices: faces.iter().flatten().copied().collect(),
+            ..Mesh::default()
+        }
+    }
+
+    fn volume(mesh: &Mesh) -> f32 {
+        mesh.indices
+            .chunks_exact(3)
+            .map(|face| {
+                let [a, b, c] =
+                    [0, 1, 2].map(|i| Vector3::from(mesh.vertices[face[i] as usize].position));
+                a.dot(&b.cross(&c)) / 6.0
+            })
+            .sum()
+    }
+
+    /// Every edge is joined by two faces running along it in opposite directions
+    fn is_closed(mesh: &Mesh) -> bool {
+        let faces: Vec<[u32; 3]> = mesh
+            .indices
+            .chunks_exact(3)
+            .map(|face| [face[0], face[1], face[2]])
+            .collect();
+        let directed: Vec<(u32, u32)> = faces
+            .iter()
+            .flat_map(|face| (0..3).map(move |i| (face[i], face[(i + 1) % 3])))
+            .collect();
+        let unique: HashSet<_> = directed.iter().copied().collect();
+        unique.len() == directed.len() && directed.iter().all(|&(a, b)| unique.contains(&(b, a)))
+    }
+
+    #[test]
+    fn test_intact_cube_is_left_alone() {
+        let mut cube = mesh(&CUBE_CORNERS, &CUBE_FACES);
+        let report = repair(&mut cube);
+        assert!(report.is_empty(), "{}", report);
+        assert_eq!(report.to_string(), "Nothing to repair");
+        assert_eq!(cube.indices.len(), 36);
+        assert!((volume(&cube) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_fill_holes() {
+        // The top is missing
+        let faces: Vec<[u32; 3]> = CUBE_FACES
+            .iter()
+            .copied()
+            .filter(|face| !face.iter().all(|&corner| corner >= 4))
+            .collect();
+        let mut cube = mesh(&CUBE_CORNERS, &faces);
+        let report = repair(&mut cube);
+        assert_eq!(report.holes_filled, 1);
+        assert!(is_closed(&cube));
+        assert!((volume(&cube) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_unify_and_turn_outward() {
+        let mut faces = CUBE_FACES.to_vec();
+        faces[3].swap(1, 2);
+        faces[7].swap(1, 2);
+        let mut cube = mesh(&CUBE_CORNERS, &faces);
+        let report = repair(&mut cube);
+        assert_eq!(report.faces_flipped, 2);
+        assert!(is_closed(&cube));
+        assert!((volume(&cube) - 1.0).abs() < 1e-5);
+
+        // Inside out as a whole
+        let faces: Vec<[u32; 3]> = CUBE_FACES.iter().map(|&[a, b, c]| [a, c, b]).collect();
+        let mut cube = mesh(&CUBE_CORNERS, &faces);
+        let report = repair(&mut cube);
+        assert_eq!(report.shells_reversed, 1);
+        assert!((volume(&cube) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_hollow_part_keeps_inner_wall_inward() {
+        let mut corners = CUBE_CORNERS.to_vec();
+        corners.extend(CUBE_CORNERS.map(|corner| corner.map(|c| 0.25 + c * 0.5)));
+        let mut faces = CUBE_FACES.to_vec();
+        faces.extend(CUBE_FACES.map(|face| face.map(|i| i + 8)));
+        let mut part = mesh(&corners, &faces);
+        let report = repair(&mut part);
+        assert_eq!(report.shells_reversed, 1);
+        assert!((volume(&part) - (1.0 - 0.125)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_remove_duplicate_and_internal_faces() {
+        // Two cubes side by side, each with its own wall where they touch
+        let mut corners = CUBE_CORNERS.to_vec();
+        // Mirrored, so the walls are split into the same triangles
+        corners.extend(CUBE_CORNERS.map(|[x, y, z]| [2.0 - x, y, z]));
+        let mut faces = CUBE_FACES.to_vec();
+        faces.extend(CUBE_FACES.map(|[a, b, c]| [a + 8, c + 8, b + 8]));
+        faces.push(CUBE_FACES[0]);
+        let mut cubes = mesh(&corners, &faces);
+        let report = repair(&mut cubes);
+        assert_eq!(report.duplicate_faces_removed, 1);
+        assert_eq!(report.internal_faces_removed, 4);
+        assert_eq!(cubes.indices.len(), 3 * 20);
+        assert!(is_closed(&cubes));
+        assert!((volume(&cubes) - 2.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_split_non_manifold_edges() {
+        // Two cubes touching along an edge, which then joins four faces
+        let mut corners = CUBE_CORNERS.to_vec();
+        corners.extend(CUBE_CORNERS.map(|[x, y, z]| [x + 1.0, y + 1.0, z]));
+        let mut faces = CUBE_FACES.to_vec();
+        faces.extend(CUBE_FACES.map(|face| face.map(|i| i + 8)));
+        let mut cubes = mesh(&corners, &faces);
+        let report = repair(&mut cubes);
+        assert_eq!(report.non_manifold_edges_split, 1);
+        assert_eq!(report.faces_flipped, 0);
+        assert_eq!(report.holes_filled, 0);
+        assert!(is_closed(&cubes));
+        assert!((volume(&cubes) - 2.0).abs() < 1e-5);
+        assert_eq!(report.to_string(), "1 non-manifold edges split");
+    }
+}
@@ -0,0 +1,250 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Importer for Wavefront `.obj` files.
+//!
+//! Only the geometry is read: vertex positions and faces. Every object (`o`) and group
+//! (`g`) becomes a mesh of its own, faces with more than three corners are split into
+//! triangles by ear clipping. Texture coordinates, normals and materials are ignored.
+
+use crate::mesh_importer::{triangle, triangulate, ImportProgress, ImportedMesh, MeshImporter};
+use std::io::{self, BufRead, Cursor};
+use std::path::Path;
+use stl_io::Triangle;
+
+/// Statements that can start an OBJ file, used to recognise one without its extension
+const STATEMENTS: [&str; 11] = [
+    "v", "vt", "vn", "vp", "f", "o", "g", "s", "l", "mtllib", "usemtl",
+];
+
+pub struct ObjImporter;
+
+impl MeshImporter for ObjImporter {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["obj"]
+    }
+
+    fn sniff(&self, header: &[u8], _file_size: u64) -> bool {
+        let Ok(text) = std::str::from_utf8(header) else {
+            return false;
+        };
+        text.lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .and_then(|line| line.split_whitespace().next())
+            .is_some_and(|statement| STATEMENTS.contains(&statement))
+    }
+
+    fn read_bytes(&self, data: &[u8]) -> io::Result<Vec<ImportedMesh>> {
+        read(Cursor::new(data))
+    }
+
+    fn import(&self, path: &Path, progress: &ImportProgress) -> io::Result<Vec<ImportedMesh>> {
+        read(progress.open(path)?)
+    }
+}
+
+/// Reads the meshes of an OBJ file, objects and groups without faces are skipped
+pub fn read<R: BufRead>(input: R) -> io::Result<Vec<ImportedMesh>> {
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    // Name and triangles of every object or group
+    let mut parts: Vec<(Option<String>, Vec<Triangle>)> = vec![(None, Vec::new())];
+    let mut statement = String::new();
+    let mut first_line = 0;
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if statement.is_empty() {
+            first_line = i + 1;
+        }
+        // A trailing backslash continues the statement on the next line
+        if let Some(part) = line.strip_suffix('\\') {
+            statement.push_str(part);
+            statement.push(' ');
+            continue;
+        }
+        statement.push_str(&line);
+        let line_number = first_line;
+        let text = std::mem::take(&mut statement);
+        let text = text.split('#').next().unwrap_or_default();
+        let mut tokens = text.split_whitespace();
+
+        match tokens.next() {
+            Some("v") => {
+                let mut coordinates = [0.0; 3];
+                for coordinate in &mut coordinates {
+                    *coordinate = tokens
+                        .next()
+                        .and_then(|token| token.parse().ok())
+                        .ok_or_else(|| invalid(line_number, "Invalid vertex"))?;
+                }
+                positions.push(coordinates);
+            }
+            Some("f") => {
+                let corners = tokens
+                    .map(|token| vertex_index(token, positions.len(), line_number))
+                    .collect::<io::Result<Vec<_>>>()?;
+                if corners.len() < 3 {
+                    return Err(invalid(line_number, "Face needs at least 3 vertices"));
+                }
+                let polygon: Vec<[f32; 3]> = corners.iter().map(|&i| positions[i]).collect();
+                let (_, triangles) = parts.last_mut().expect("There is always a current part");
+                triangles.extend(
+                    triangulate(&polygon)
+                        .into_iter()
+                        .map(|[a, b, c]| triangle(polygon[a], polygon[b], polygon[c])),
+                );
+            }
+            Some("o") | Some("g") => {
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                parts.push((Some(name).filter(|name| !name.is_empty()), Vec::new()));
+            }
+            _ => {}
+        }
+    }
+
+    parts.retain(|(_, triangles)| !triangles.is_empty());
+    // Name unnamed parts after their position so the bodies can be told apart
+    let numbered = parts.len() > 1;
+    Ok(parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, (name, triangles))| {
+            let name = name.or_else(|| numbered.then(|| format!("Part {}", i + 1)));
+            ImportedMesh::from_triangles(name, triangles)
+        })
+        .collect())
+}
+
+/// Resolves a face corner such as `3`, `3/1`, `3//2` or `-1` to a position index
+fn vertex_index(token: &str, vertex_count: usize, line_number: usize) -> io::Result<usize> {
+    let position = token.split('/').next().unwrap_or_default();
+    let index: i64 = position
+        .parse()
+        .map_err(|_| invalid(line_number, &format!("Invalid face vertex {}", token)))?;
+    // Indices count from 1, negative ones count back from the last vertex
+    let resolved = match index {
+        1.. => index - 1,
+        ..=-1 => vertex_count as i64 + index,
+        0 => -1,
+    };
+    if resolved < 0 || resolved >= vertex_count as i64 {
+        return Err(invalid(
+            line_number,
+            &format!("Face vertex {} doesn't exist", token),
+        ));
+    }
+    Ok(resolved as usize)
+}
+
+fn invalid(line_number: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} on line {}", message, line_number),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    fn read_str(obj: &str) -> io::Result<Vec<ImportedMesh>> {
+        read(Cursor::new(obj.as_bytes()))
+    }
+
+    fn area(triangles: &[Triangle]) -> f32 {
+        triangles
+            .iter()
+            .map(|triangle| {
+                let [a, b, c] = triangle.vertices.map(Vector3::from);
+                (b - a).cross(&(c - a)).norm() / 2.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_single_mesh() {
+        let meshes = read_str(
+            "# A unit square\n\
+             v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+             vn 0 0 1\n\
+             f 1//1 2//1 3//1 4//1\n",
+        )
+        .unwrap();
+
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].name, None);
+        assert_eq!(meshes[0].triangles().len(), 2);
+        assert!((area(meshes[0].triangles()) - 1.0).abs() < 1e-6);
+        for triangle in meshes[0].triangles() {
+            assert_eq!(triangle.normal, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn test_objects_and_groups() {
+        let meshes = read_str(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\n\
+             f 1 2 3\n\
+             o Cube\nf 1 3 4\n\
+             o Empty\n\
+             g Lid Top\nf -4/1 -3/2 -1/3\n",
+        )
+        .unwrap();
+
+        let names: Vec<Option<&str>> = meshes.iter().map(|mesh| mesh.name.as_deref()).collect();
+        assert_eq!(names, [Some("Part 1"), Some("Cube"), Some("Lid Top")]);
+        assert_eq!(
+            meshes[2].triangles()[0].vertices,
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]]
+        );
+    }
+
+    #[test]
+    fn test_concave_polygon() {
+        // An L shape, a fan from the first corner would cover the notch
+        let meshes = read_str(
+            "v 0 0 0\nv 2 0 0\nv 2 1 0\nv 1 1 0\nv 1 2 0\nv 0 2 0\n\
+             f 1 2 3 4 5 6\n",
+        )
+        .unwrap();
+
+        let triangles = meshes[0].triangles();
+        assert_eq!(triangles.len(), 4);
+        assert!((area(triangles) - 3.0).abs() < 1e-6);
+        for triangle in triangles {
+            assert_eq!(triangle.normal, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn test_line_continuation() {
+        let meshes = read_str("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 \\\n 2 3\n").unwrap();
+        assert_eq!(meshes[0].triangles().len(), 1);
+    }
+
+    #[test]
+    fn test_errors() {
+        for (obj, message) in [
+            ("v 0 0\n", "Invalid vertex on line 1"),
+            (
+                "v 0 0 0\nv 1 0 0\n\nf 1 2\n",
+                "Face needs at least 3 vertices on line 4",
+            ),
+            (
+                "v 0 0 0\nf 1 2 3\n",
+                "Face vertex 2 doesn't exist on line 2",
+            ),
+            (
+                "v 0 0 0\nf 1 0 1\n",
+                "Face vertex 0 doesn't exist on line 2",
+            ),
+            ("f a b c\n", "Invalid face vertex a on line 1"),
+        ] {
+            let error = read_str(obj).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), message);
+        }
+    }
+}
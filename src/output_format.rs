@@ -0,0 +1,216 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Registry of the printer file formats a job can be written in.
+//!
+//! Every writer module implements [`OutputFormat`] for a unit struct next to its writer
+//! and lists it in [`FORMATS`]. The printer profile and the UI look formats up here, so
+//! adding a format doesn't need changes anywhere else. Formats with a reader return it
+//! from [`OutputFormat::reader`], the others can only be written.
+
+use crate::ctb_writer::CtbFormat;
+use crate::goo_writer::GooFormat;
+use crate::photon_workshop_writer::PhotonWorkshopFormat;
+use crate::sl1_writer::Sl1Format;
+use crate::slice_job::SliceJob;
+use crate::zip_gcode_writer::ZipGcodeFormat;
+use std::io;
+use std::path::Path;
+
+/// What a format can store besides the layer images
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Layers keep their grey levels, otherwise they are written black and white
+    pub grayscale: bool,
+    /// Exposure and motion can differ between layers, e.g. for bottom and transition layers
+    pub per_layer_settings: bool,
+    /// Sizes of the preview images embedded in the file
+    pub thumbnail_sizes: &'static [(u32, u32)],
+}
+
+pub trait OutputFormat: Sync {
+    /// Name shown to the user
+    fn name(&self) -> &'static str;
+
+    /// File extensions in lowercase, the first one is used for new files
+    fn extensions(&self) -> &'static [&'static str];
+
+    fn capabilities(&self) -> Capabilities;
+
+    /// Writes the job for the printer in its profile
+    fn write_to_file(&self, job: &SliceJob, path: &Path) -> io::Result<()>;
+
+    /// Reads a written file back into a job, `None` for formats that can only be written
+    fn reader(&self) -> Option<fn(&Path) -> io::Result<SliceJob>> {
+        None
+    }
+
+    /// Describes what of the job is lost when it is written in this format
+    fn limitations(&self, job: &SliceJob) -> Vec<String> {
+        let capabilities = self.capabilities();
+        let exposure = &job.resin.exposure;
+        let mut limitations = Vec::new();
+        if !capabilities.grayscale && job.printer.anti_alias_level > 1 {
+            limitations.push(format!(
+                "{} only stores black and white layers, anti-aliasing is lost",
+                self.name()
+            ));
+        }
+        if !capabilities.per_layer_settings
+            && exposure.bottom_layer_count > 0
+            && exposure.bottom_motion != exposure.normal_motion
+        {
+            limitations.push(format!(
+                "{} has no per layer settings, bottom layers lift like normal layers",
+                self.name()
+            ));
+        }
+        if !capabilities.thumbnail_sizes.is_empty() && job.thumbnail.is_none() {
+            limitations.push(format!(
+                "No thumbnail was rendered, the {} previews are blank",
+                self.name()
+            ));
+        }
+        limitations
+    }
+}
+
+/// Every format a job can be written in
+pub static FORMATS: [&dyn OutputFormat; 5] = [
+    &CtbFormat,
+    &GooFormat,
+    &PhotonWorkshopFormat,
+    &Sl1Format,
+    &ZipGcodeFormat,
+];
+
+/// Looks up the format writing files with the given extension, ignoring case
+pub fn find(extension: &str) -> Option<&'static dyn OutputFormat> {
+    let extension = extension.to_lowercase();
+    FORMATS
+        .iter()
+        .copied()
+        .find(|format| format.extensions().contains(&extension.as_str()))
+}
+
+/// Looks up the format for the extension of `path`
+pub fn for_path(path: &Path) -> io::Result<&'static dyn OutputFormat> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    find(extension).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unsupported output format: {}", path.display()),
+        )
+    })
+}
+
+/// Extensions of every format that can be opened again
+pub fn readable_extensions() -> Vec<&'static str> {
+    FORMATS
+        .iter()
+        .filter(|format| format.reader().is_some())
+        .flat_map(|format| format.extensions().iter().copied())
+        .collect()
+}
+
+/// Reads a printer file back into a job with the reader of its format
+pub fn read_from_file(path: &Path) -> io::Result<SliceJob> {
+    let format = for_path(path)?;
+    let reader = format.reader().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} files can only be written, not opened", format.name()),
+        )
+    })?;
+    reader(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printer_profile::PrinterProfile;
+    use crate::resin_profile::ResinProfile;
+    use image::{ImageBuffer, Luma};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_extensions_are_unique() {
+        let mut seen = HashSet::new();
+        for format in FORMATS {
+            assert!(!format.extensions().is_empty(), "{}", format.name());
+            for extension in format.extensions() {
+                assert_eq!(*extension, extension.to_lowercase());
+                assert!(seen.insert(*extension), "{} is listed twice", extension);
+            }
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        assert_eq!(find("CTB").map(|format| format.name()), Some("Chitubox"));
+        assert_eq!(
+            find("pwma").map(|format| format.name()),
+            Some("Photon Workshop")
+        );
+        assert!(find("stl").is_none());
+
+        let error = for_path(Path::new("model.stl")).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_every_format_but_zip_gcode_reads_back() {
+        let directory = tempfile::tempdir().unwrap();
+        let layers = vec![ImageBuffer::from_fn(16, 9, |x, _| Luma([x as u8 * 16]))];
+        let printer = PrinterProfile {
+            pixel_x: 16,
+            pixel_y: 9,
+            ..PrinterProfile::default()
+        };
+        let job = SliceJob::new(layers, 0.05, printer, ResinProfile::default());
+
+        for format in FORMATS {
+            let path = directory
+                .path()
+                .join(format!("job.{}", format.extensions()[0]));
+            format.write_to_file(&job, &path).unwrap();
+            match read_from_file(&path) {
+                Ok(read_job) => assert_eq!(read_job.layer_count(), 1, "{}", format.name()),
+                Err(error) => {
+                    assert_eq!(format.name(), ZipGcodeFormat.name());
+                    assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+                    assert!(error.to_string().contains("can only be written"));
+                }
+            }
+        }
+        assert!(readable_extensions().contains(&"pwma"));
+        assert!(!readable_extensions().contains(&"zip"));
+    }
+
+    #[test]
+    fn test_limitations() {
+        let layers = vec![ImageBuffer::from_pixel(4, 4, Luma([255]))];
+        let printer = PrinterProfile {
+            pixel_x: 4,
+            pixel_y: 4,
+            ..PrinterProfile::default()
+        };
+        let mut job = SliceJob::new(layers, 0.05, printer, ResinProfile::default());
+        job.thumbnail = Some(image::RgbImage::new(8, 6));
+
+        assert!(CtbFormat.limitations(&job).is_empty());
+        assert_eq!(
+            Sl1Format.limitations(&job),
+            ["Prusa SL1 has no per layer settings, bottom layers lift like normal layers"]
+        );
+
+        job.thumbnail = None;
+        assert_eq!(
+            GooFormat.limitations(&job),
+            ["No thumbnail was rendered, the Elegoo GOO previews are blank"]
+        );
+    }
+}
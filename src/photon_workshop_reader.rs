@@ -0,0 +1,252 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Reader for Anycubic Photon Workshop printer files (`.pwmx`, `.pwma`, `.pwms`).
+//!
+//! Reads the layout written by
+//! [`PhotonWorkshopWriter`](crate::photon_workshop_writer::PhotonWorkshopWriter) back into a
+//! [`SliceJob`]. Every section is found through the addresses in the file mark and its name
+//! is checked before its fields are read. The bottom layer motion comes from the first layer
+//! definition, the file doesn't store retract distances or the light PWM.
+
+use crate::byte_reader::{f32_at, read_at, to_f64, u32_at};
+use crate::layer_codec::decode_pw0;
+use crate::photon_workshop_writer::{
+    decode_preview, EXTENSIONS, FILE_MARK, HEADER_SIZE, IMAGE_FORMAT_SIZE, LAYER_DEF_SIZE,
+    LAYER_IMAGE_FORMAT, MACHINE_NAME_SIZE, MACHINE_SIZE, SECTION_MARK_SIZE,
+};
+use crate::printer_profile::PrinterProfile;
+use crate::resin_profile::{ExposureSettings, MotionSettings, ResinProfile};
+use crate::slice_job::SliceJob;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek};
+use std::path::Path;
+
+/// File mark name, version and section count before the section addresses
+const FILE_MARK_FIELDS_SIZE: usize = SECTION_MARK_SIZE + 4 + 4;
+/// Header, software, preview, preview end, layer definition, extra, machine and layer images
+const SECTION_ADDRESS_COUNT: usize = 8;
+
+pub fn read_from_file<P: AsRef<Path>>(path: P) -> io::Result<SliceJob> {
+    read(&mut BufReader::new(File::open(path)?))
+}
+
+pub fn read<R: Read + Seek>(input: &mut R) -> io::Result<SliceJob> {
+    let file_mark = read_at(input, 0, FILE_MARK_FIELDS_SIZE + 4 * SECTION_ADDRESS_COUNT)?;
+    if name_at(&file_mark, 0, SECTION_MARK_SIZE) != FILE_MARK {
+        return Err(invalid("Not a Photon Workshop file"));
+    }
+    let address = |index: usize| u32_at(&file_mark, FILE_MARK_FIELDS_SIZE + 4 * index) as u64;
+
+    let header = read_section(input, address(0), "HEADER", HEADER_SIZE as usize)?;
+    let anti_alias_level = u32_at(&header, 40);
+    let pixel_x = u32_at(&header, 44);
+    let pixel_y = u32_at(&header, 48);
+
+    let preview_header = read_section(input, address(2), "PREVIEW", 12)?;
+    let preview_width = u32_at(&preview_header, 0);
+    let preview_height = u32_at(&preview_header, 8);
+    let preview_size = preview_width as usize * preview_height as usize * 2;
+    let preview = read_at(input, address(2) + 16 + 12, preview_size)?;
+    let thumbnail = decode_preview(&preview, preview_width, preview_height);
+
+    let layer_count = u32_at(&read_section(input, address(4), "LAYERDEF", 4)?, 0) as usize;
+    let layer_definitions = read_at(
+        input,
+        address(4) + 16 + 4,
+        LAYER_DEF_SIZE as usize * layer_count,
+    )?;
+
+    let machine = read_section(input, address(6), "MACHINE", MACHINE_SIZE as usize)?;
+    let machine_name = name_at(&machine, 0, MACHINE_NAME_SIZE);
+    let image_format = name_at(&machine, MACHINE_NAME_SIZE, IMAGE_FORMAT_SIZE);
+    if image_format != LAYER_IMAGE_FORMAT {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unsupported layer image format: {}", image_format),
+        ));
+    }
+    let properties = MACHINE_NAME_SIZE + IMAGE_FORMAT_SIZE + 8;
+
+    // Photon Workshop speeds are in millimeters per second
+    let normal_motion = MotionSettings {
+        lift_distance: f32_at(&header, 24),
+        lift_speed: f32_at(&header, 28) * 60.0,
+        retract_distance: f32_at(&header, 24),
+        retract_speed: f32_at(&header, 32) * 60.0,
+    };
+    let bottom_layer_count = f32_at(&header, 20) as u32;
+    let bottom_motion = if bottom_layer_count > 0 && layer_count > 0 {
+        MotionSettings {
+            lift_distance: f32_at(&layer_definitions, 8),
+            lift_speed: f32_at(&layer_definitions, 12) * 60.0,
+            retract_distance: f32_at(&layer_definitions, 8),
+            retract_speed: normal_motion.retract_speed,
+        }
+    } else {
+        normal_motion
+    };
+    let resin = ResinProfile {
+        exposure: ExposureSettings {
+            normal_exposure_time: f32_at(&header, 8),
+            bottom_exposure_time: f32_at(&header, 16),
+            bottom_layer_count,
+            transition_layer_count: u32_at(&header, 72),
+            light_off_delay: f32_at(&header, 12),
+            bottom_motion,
+            normal_motion,
+            ..ExposureSettings::default()
+        },
+        ..ResinProfile::default()
+    };
+
+    let layer_data = layer_definitions
+        .chunks_exact(LAYER_DEF_SIZE as usize)
+        .map(|definition| {
+            read_at(
+                input,
+                u32_at(definition, 0) as u64,
+                u32_at(definition, 4) as usize,
+            )
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let layers = layer_data
+        .par_iter()
+        .map(|data| decode_pw0(data, pixel_x, pixel_y))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let printer = PrinterProfile {
+        name: machine_name,
+        pixel_x,
+        pixel_y,
+        physical_x: to_f64(f32_at(&machine, properties)),
+        physical_y: to_f64(f32_at(&machine, properties + 4)),
+        physical_z: to_f64(f32_at(&machine, properties + 8)),
+        output_format: EXTENSIONS[0].to_string(),
+        anti_alias_level: anti_alias_level.max(1),
+        ..PrinterProfile::default()
+    };
+    let mut job = SliceJob::new(layers, to_f64(f32_at(&header, 4)), printer, resin);
+    job.thumbnail = Some(thumbnail);
+    Ok(job)
+}
+
+/// Reads the first `size` bytes of a section after checking its name
+fn read_section<R: Read + Seek>(
+    input: &mut R,
+    address: u64,
+    name: &str,
+    size: usize,
+) -> io::Result<Vec<u8>> {
+    let section = read_at(input, address, SECTION_MARK_SIZE + 4 + size)?;
+    if name_at(&section, 0, SECTION_MARK_SIZE) != name {
+        return Err(invalid(&format!("Missing {} section", name)));
+    }
+    Ok(section[SECTION_MARK_SIZE + 4..].to_vec())
+}
+
+/// Reads a fixed size zero padded string field
+fn name_at(bytes: &[u8], offset: usize, size: usize) -> String {
+    let field = &bytes[offset..offset + size];
+    let end = field.iter().position(|&b| b == 0).unwrap_or(size);
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer_codec::quantize_grey;
+    use crate::photon_workshop_writer::{PhotonWorkshopWriter, PREVIEW_SIZE};
+    use image::{ImageBuffer, Luma};
+    use std::io::Cursor;
+
+    fn test_job() -> SliceJob {
+        let layers = (0..3)
+            .map(|i| ImageBuffer::from_fn(40, 10, |x, y| Luma([((x * 7 + y) * (i + 1)) as u8])))
+            .collect();
+        let printer = PrinterProfile {
+            name: "Photon Mono X".to_string(),
+            pixel_x: 40,
+            pixel_y: 10,
+            output_format: "pwmx".to_string(),
+            anti_alias_level: 4,
+            ..PrinterProfile::default()
+        };
+        let motion = |lift_distance, lift_speed| MotionSettings {
+            lift_distance,
+            lift_speed,
+            retract_distance: lift_distance,
+            retract_speed: 180.0,
+        };
+        let resin = ResinProfile {
+            exposure: ExposureSettings {
+                normal_exposure_time: 2.0,
+                bottom_exposure_time: 25.0,
+                bottom_layer_count: 1,
+                transition_layer_count: 2,
+                bottom_motion: motion(8.0, 60.0),
+                normal_motion: motion(5.0, 120.0),
+                ..ExposureSettings::default()
+            },
+            ..ResinProfile::default()
+        };
+        SliceJob::new(layers, 0.05, printer, resin)
+    }
+
+    fn write(job: &SliceJob) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        PhotonWorkshopWriter::for_printer(&job.printer)
+            .write(job, &mut bytes)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let job = test_job();
+
+        let read_job = read(&mut Cursor::new(write(&job))).expect("Written file should read back");
+
+        assert_eq!(read_job.printer.name, "Photon Mono X");
+        assert_eq!(read_job.printer.output_format, "pwmx");
+        assert_eq!(read_job.printer.anti_alias_level, 4);
+        assert_eq!(read_job.printer.physical_y, job.printer.physical_y);
+        assert_eq!(read_job.layer_height, 0.05);
+        assert_eq!(read_job.resin.exposure, job.resin.exposure);
+        assert_eq!(
+            read_job
+                .thumbnail
+                .as_ref()
+                .map(|thumbnail| thumbnail.dimensions()),
+            Some(PREVIEW_SIZE)
+        );
+        assert_eq!(read_job.layer_count(), job.layer_count());
+        for (read_layer, layer) in read_job.layers.iter().zip(&job.layers) {
+            for (read_value, value) in read_layer.as_raw().iter().zip(layer.as_raw()) {
+                assert_eq!(read_value >> 4, quantize_grey(*value, 4) >> 4);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        let bytes = write(&test_job());
+
+        let Err(error) = read(&mut Cursor::new(bytes[..bytes.len() / 2].to_vec())) else {
+            panic!("Reading should fail");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut renamed = bytes.clone();
+        renamed[..8].copy_from_slice(b"ANYCUBIX");
+        let Err(error) = read(&mut Cursor::new(renamed)) else {
+            panic!("Reading should fail");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
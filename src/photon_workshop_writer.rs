@@ -0,0 +1,443 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Writer for Anycubic Photon Workshop printer files (`.pwmx`, `.pwma`, `.pwms`).
+//!
+//! The file starts with a file mark holding the version and the address of every section.
+//! Each section begins with a 12 byte name and its length, followed by its fields.
+//! The sections are written in the order header, preview, layer definitions and machine,
+//! followed by the PW0 encoded layer images. All values are little endian.
+
+use crate::layer_codec::encode_pw0;
+use crate::output_format::{Capabilities, OutputFormat};
+use crate::photon_workshop_reader;
+use crate::printer_profile::PrinterProfile;
+use crate::slice_job::SliceJob;
+use image::RgbImage;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+pub const FILE_MARK: &str = "ANYCUBIC";
+const FILE_VERSION: u32 = 516;
+/// Number of named sections in the file
+const AREA_COUNT: u32 = 4;
+const FILE_MARK_SIZE: u32 = 52;
+pub const SECTION_MARK_SIZE: usize = 12;
+/// Section name plus its length field
+const SECTION_HEADER_SIZE: u32 = SECTION_MARK_SIZE as u32 + 4;
+pub const HEADER_SIZE: u32 = 80;
+pub const LAYER_DEF_SIZE: u32 = 32;
+pub const MACHINE_SIZE: u32 = 140;
+pub const MACHINE_NAME_SIZE: usize = 96;
+pub const IMAGE_FORMAT_SIZE: usize = 16;
+pub const LAYER_IMAGE_FORMAT: &str = "pw0Img";
+const MAX_ANTI_ALIAS_LEVEL: u32 = 16;
+/// Price currency written into the header, a dollar sign
+const PRICE_CURRENCY: u32 = 0x24;
+pub const PREVIEW_SIZE: (u32, u32) = (224, 168);
+const PREVIEW_DPI: u32 = 42;
+
+/// File extensions of the Photon Workshop family, they only differ in the target machine
+pub const EXTENSIONS: [&str; 3] = ["pwmx", "pwma", "pwms"];
+
+pub struct PhotonWorkshopFormat;
+
+impl OutputFormat for PhotonWorkshopFormat {
+    fn name(&self) -> &'static str {
+        "Photon Workshop"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &EXTENSIONS
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            grayscale: true,
+            per_layer_settings: true,
+            thumbnail_sizes: &[PREVIEW_SIZE],
+        }
+    }
+
+    fn write_to_file(&self, job: &SliceJob, path: &Path) -> io::Result<()> {
+        PhotonWorkshopWriter::for_printer(&job.printer).write_to_file(job, path)
+    }
+
+    fn reader(&self) -> Option<fn(&Path) -> io::Result<SliceJob>> {
+        Some(|path| photon_workshop_reader::read_from_file(path))
+    }
+}
+
+pub struct PhotonWorkshopWriter {
+    /// Number of grey levels kept in the layer images, 1 writes pure black and white
+    pub anti_alias_level: u32,
+}
+
+impl Default for PhotonWorkshopWriter {
+    fn default() -> Self {
+        Self::new(8)
+    }
+}
+
+impl PhotonWorkshopWriter {
+    pub fn new(anti_alias_level: u32) -> Self {
+        Self {
+            anti_alias_level: anti_alias_level.clamp(1, MAX_ANTI_ALIAS_LEVEL),
+        }
+    }
+
+    pub fn for_printer(printer: &PrinterProfile) -> Self {
+        Self::new(printer.anti_alias_level)
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, job: &SliceJob, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(job, &mut writer)?;
+        writer.flush()
+    }
+
+    pub fn write<W: Write>(&self, job: &SliceJob, out: &mut W) -> io::Result<()> {
+        let exposure = &job.resin.exposure;
+        let layer_count = job.layer_count() as u32;
+        let preview = encode_preview(&job.preview(PREVIEW_SIZE.0, PREVIEW_SIZE.1));
+        let layer_data: Vec<Vec<u8>> = job
+            .layers
+            .par_iter()
+            .map(|layer| encode_pw0(layer, self.anti_alias_level))
+            .collect();
+
+        let header_address = FILE_MARK_SIZE;
+        let preview_address = header_address + SECTION_HEADER_SIZE + HEADER_SIZE;
+        let preview_size = 12 + preview.len() as u32;
+        let layer_definition_address = preview_address + SECTION_HEADER_SIZE + preview_size;
+        let layer_definition_size = 4 + LAYER_DEF_SIZE * layer_count;
+        let machine_address =
+            layer_definition_address + SECTION_HEADER_SIZE + layer_definition_size;
+        let layer_image_address = machine_address + SECTION_HEADER_SIZE + MACHINE_SIZE;
+
+        // File mark
+        put_str(out, FILE_MARK, SECTION_MARK_SIZE)?;
+        put_u32(out, FILE_VERSION)?;
+        put_u32(out, AREA_COUNT)?;
+        put_u32(out, header_address)?;
+        put_u32(out, 0)?; // Software section, not written
+        put_u32(out, preview_address)?;
+        put_u32(out, layer_definition_address)?; // End of the preview
+        put_u32(out, layer_definition_address)?;
+        put_u32(out, 0)?; // Extra section, not written
+        put_u32(out, machine_address)?;
+        put_u32(out, layer_image_address)?;
+
+        // Header
+        put_str(out, "HEADER", SECTION_MARK_SIZE)?;
+        put_u32(out, HEADER_SIZE)?;
+        put_f32(out, (job.printer.pixel_size_x() * 1000.0) as f32)?;
+        put_f32(out, job.layer_height as f32)?;
+        put_f32(out, exposure.normal_exposure_time)?;
+        put_f32(out, exposure.light_off_delay)?;
+        put_f32(out, exposure.bottom_exposure_time)?;
+        put_f32(out, exposure.bottom_layer_count as f32)?;
+        put_f32(out, exposure.normal_motion.lift_distance)?;
+        // Photon Workshop speeds are in millimeters per second
+        put_f32(out, exposure.normal_motion.lift_speed / 60.0)?;
+        put_f32(out, exposure.normal_motion.retract_speed / 60.0)?;
+        put_f32(out, job.estimated_volume_ml() as f32)?;
+        put_u32(out, self.anti_alias_level)?;
+        put_u32(out, job.printer.pixel_x)?;
+        put_u32(out, job.printer.pixel_y)?;
+        put_f32(out, 0.0)?; // Weight in grams
+        put_f32(out, 0.0)?; // Price
+        put_u32(out, PRICE_CURRENCY)?;
+        put_u32(out, 1)?; // Use the per layer settings from the layer definitions
+        put_u32(out, job.estimated_print_time().as_secs() as u32)?;
+        put_u32(out, exposure.transition_layer_count)?;
+        put_u32(out, 0)?;
+
+        // Preview
+        put_str(out, "PREVIEW", SECTION_MARK_SIZE)?;
+        put_u32(out, preview_size)?;
+        put_u32(out, PREVIEW_SIZE.0)?;
+        put_u32(out, PREVIEW_DPI)?;
+        put_u32(out, PREVIEW_SIZE.1)?;
+        out.write_all(&preview)?;
+
+        // Layer definitions
+        put_str(out, "LAYERDEF", SECTION_MARK_SIZE)?;
+        put_u32(out, layer_definition_size)?;
+        put_u32(out, layer_count)?;
+        let mut data_address = layer_image_address;
+        for (i, data) in layer_data.iter().enumerate() {
+            let settings = job.layer_settings(i);
+            put_u32(out, data_address)?;
+            put_u32(out, data.len() as u32)?;
+            put_f32(out, settings.motion.lift_distance)?;
+            put_f32(out, settings.motion.lift_speed / 60.0)?;
+            put_f32(out, settings.exposure_time)?;
+            put_f32(out, job.layer_height as f32)?;
+            put_u32(out, 0)?;
+            put_u32(out, 0)?;
+            data_address += data.len() as u32;
+        }
+
+        // Machine
+        put_str(out, "MACHINE", SECTION_MARK_SIZE)?;
+        put_u32(out, MACHINE_SIZE)?;
+        put_str(out, &job.printer.name, MACHINE_NAME_SIZE)?;
+        put_str(out, LAYER_IMAGE_FORMAT, IMAGE_FORMAT_SIZE)?;
+        put_u32(out, MAX_ANTI_ALIAS_LEVEL)?;
+        put_u32(out, 7)?; // Property fields
+        put_f32(out, job.printer.physical_x as f32)?;
+        put_f32(out, job.printer.physical_y as f32)?;
+        put_f32(out, job.printer.physical_z as f32)?;
+        put_u32(out, FILE_VERSION)?;
+        put_u32(out, 0)?; // Machine background
+
+        for data in &layer_data {
+            out.write_all(data)?;
+        }
+        Ok(())
+    }
+}
+
+fn put_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn put_f32<W: Write>(out: &mut W, value: f32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+/// Writes a string into a fixed size zero padded field, cutting it if it is too long
+fn put_str<W: Write>(out: &mut W, value: &str, size: usize) -> io::Result<()> {
+    let mut field = vec![0u8; size];
+    let bytes = value.as_bytes();
+    let length = bytes.len().min(size);
+    field[..length].copy_from_slice(&bytes[..length]);
+    out.write_all(&field)
+}
+
+/// Encodes a preview as uncompressed little endian RGB565
+pub fn encode_preview(image: &RgbImage) -> Vec<u8> {
+    let mut data = Vec::with_capacity((image.width() * image.height() * 2) as usize);
+    for pixel in image.pixels() {
+        let color =
+            ((pixel[0] as u16 >> 3) << 11) | ((pixel[1] as u16 >> 2) << 5) | (pixel[2] as u16 >> 3);
+        data.extend_from_slice(&color.to_le_bytes());
+    }
+    data
+}
+
+/// Decodes an RGB565 preview back into an image
+pub fn decode_preview(data: &[u8], width: u32, height: u32) -> RgbImage {
+    let mut pixels: Vec<u8> = data
+        .chunks_exact(2)
+        .take(width as usize * height as usize)
+        .flat_map(|chunk| {
+            let color = u16::from_le_bytes([chunk[0], chunk[1]]);
+            [
+                ((color >> 11) as u8) << 3,
+                (((color >> 5) & 0x3F) as u8) << 2,
+                ((color & 0x1F) as u8) << 3,
+            ]
+        })
+        .collect();
+    pixels.resize(width as usize * height as usize * 3, 0);
+    RgbImage::from_raw(width, height, pixels).expect("Pixel count matches the size")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resin_profile::{ExposureSettings, ResinProfile};
+    use image::{ImageBuffer, Luma};
+
+    fn f32_bytes(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn u32_bytes(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn padded(value: &str, size: usize) -> Vec<u8> {
+        let mut field = value.as_bytes().to_vec();
+        field.resize(size, 0);
+        field
+    }
+
+    // A 4x2 layer with black, white and one mid grey pixel
+    fn small_layer() -> ImageBuffer<Luma<u8>, Vec<u8>> {
+        ImageBuffer::from_raw(4, 2, vec![0, 0, 255, 255, 255, 255, 255, 128]).unwrap()
+    }
+
+    fn small_job() -> SliceJob {
+        let printer = PrinterProfile {
+            name: "Photon Mono X".to_string(),
+            pixel_x: 4,
+            pixel_y: 2,
+            physical_x: 0.2,
+            physical_y: 0.1,
+            physical_z: 245.0,
+            output_format: "pwmx".to_string(),
+            ..PrinterProfile::default()
+        };
+        let resin = ResinProfile {
+            name: "Test".to_string(),
+            exposure: ExposureSettings {
+                normal_exposure_time: 2.0,
+                bottom_exposure_time: 20.0,
+                bottom_layer_count: 1,
+                transition_layer_count: 0,
+                light_off_delay: 1.0,
+                ..ExposureSettings::default()
+            },
+        };
+        SliceJob::new(vec![small_layer(), small_layer()], 0.05, printer, resin)
+    }
+
+    #[test]
+    fn test_small_file_bytes() {
+        // Arrange
+        let job = small_job();
+        let preview_size = (PREVIEW_SIZE.0 * PREVIEW_SIZE.1 * 2) as usize;
+        let layer_data = [0x00, 0x02, 0xF0, 0x05, 0x81];
+
+        // Act
+        let mut bytes = Vec::new();
+        PhotonWorkshopWriter::new(16)
+            .write(&job, &mut bytes)
+            .expect("Writing to memory should succeed");
+
+        // Assert
+        let preview_address = 52 + 16 + 80;
+        let layer_definition_address = preview_address + 16 + 12 + preview_size as u32;
+        let machine_address = layer_definition_address + 16 + 4 + 2 * 32;
+        let layer_image_address = machine_address + 16 + 140;
+        let file_mark = [
+            padded("ANYCUBIC", 12),
+            u32_bytes(&[
+                516,
+                4,
+                52,
+                0,
+                preview_address,
+                layer_definition_address,
+                layer_definition_address,
+                0,
+                machine_address,
+                layer_image_address,
+            ]),
+        ]
+        .concat();
+        assert_eq!(&bytes[..52], &file_mark[..], "File mark");
+
+        let volume = job.estimated_volume_ml() as f32;
+        let print_time = job.estimated_print_time().as_secs() as u32;
+        let header = [
+            padded("HEADER", 12),
+            u32_bytes(&[80]),
+            f32_bytes(&[
+                50.0,
+                0.05,
+                2.0,
+                1.0,
+                20.0,
+                1.0,
+                6.0,
+                65.0 / 60.0,
+                2.5,
+                volume,
+            ]),
+            u32_bytes(&[16, 4, 2]),
+            f32_bytes(&[0.0, 0.0]),
+            u32_bytes(&[0x24, 1, print_time, 0, 0]),
+        ]
+        .concat();
+        assert_eq!(&bytes[52..preview_address as usize], &header[..], "Header");
+
+        let preview_start = preview_address as usize;
+        let preview_header = [
+            padded("PREVIEW", 12),
+            u32_bytes(&[12 + preview_size as u32, 224, 42, 168]),
+        ]
+        .concat();
+        assert_eq!(
+            &bytes[preview_start..preview_start + 28],
+            &preview_header[..],
+            "Preview header"
+        );
+
+        let layer_definitions = [
+            padded("LAYERDEF", 12),
+            u32_bytes(&[68, 2, layer_image_address, 5]),
+            f32_bytes(&[6.0, 45.0 / 60.0, 20.0, 0.05]),
+            u32_bytes(&[0, 0, layer_image_address + 5, 5]),
+            f32_bytes(&[6.0, 65.0 / 60.0, 2.0, 0.05]),
+            u32_bytes(&[0, 0]),
+        ]
+        .concat();
+        assert_eq!(
+            &bytes[layer_definition_address as usize..machine_address as usize],
+            &layer_definitions[..],
+            "Layer definitions"
+        );
+
+        let machine = [
+            padded("MACHINE", 12),
+            u32_bytes(&[140]),
+            padded("Photon Mono X", 96),
+            padded("pw0Img", 16),
+            u32_bytes(&[16, 7]),
+            f32_bytes(&[0.2, 0.1, 245.0]),
+            u32_bytes(&[516, 0]),
+        ]
+        .concat();
+        assert_eq!(
+            &bytes[machine_address as usize..layer_image_address as usize],
+            &machine[..],
+            "Machine"
+        );
+
+        let layers = [layer_data, layer_data].concat();
+        assert_eq!(
+            &bytes[layer_image_address as usize..],
+            &layers[..],
+            "Layers"
+        );
+    }
+
+    #[test]
+    fn test_preview_is_rgb565() {
+        let image = RgbImage::from_pixel(2, 1, image::Rgb([255, 0, 255]));
+        assert_eq!(encode_preview(&image), vec![0x1F, 0xF8, 0x1F, 0xF8]);
+    }
+
+    #[test]
+    fn test_preview_round_trip() {
+        let image = RgbImage::from_fn(3, 2, |x, y| image::Rgb([x as u8 * 80, y as u8 * 60, 200]));
+        let decoded = decode_preview(&encode_preview(&image), 3, 2);
+        for (a, b) in image.pixels().zip(decoded.pixels()) {
+            assert_eq!([a[0] & 0xF8, a[1] & 0xFC, a[2] & 0xF8], b.0);
+        }
+    }
+
+    #[test]
+    fn test_long_machine_name_is_cut() {
+        let mut job = small_job();
+        job.printer.name = "X".repeat(200);
+
+        let mut bytes = Vec::new();
+        PhotonWorkshopWriter::default()
+            .write(&job, &mut bytes)
+            .unwrap();
+        let mut reference = Vec::new();
+        job.printer.name = "X".repeat(96);
+        PhotonWorkshopWriter::default()
+            .write(&job, &mut reference)
+            .unwrap();
+
+        assert_eq!(bytes, reference);
+    }
+}
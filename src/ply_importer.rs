@@ -0,0 +1,690 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Importer for Stanford `.ply` files, as written by 3D scanners.
+//!
+//! ASCII and binary files of either byte order are read. Of the vertices only the position
+//! and, when present, the normal are kept, other properties and elements are skipped.
+//! Faces keep their shared vertices, so large scans don't pass through a triangle soup.
+//! The element counts of the header are checked against the size of the file before
+//! anything is read for them, and nothing is reserved for them.
+
+use crate::mesh_importer::{
+    check_triangle_count, triangulate, Geometry, ImportProgress, ImportedMesh, MeshImporter,
+};
+use std::io::{self, BufRead, Cursor, Seek, SeekFrom};
+use std::path::Path;
+
+pub struct PlyImporter;
+
+impl MeshImporter for PlyImporter {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["ply"]
+    }
+
+    fn sniff(&self, header: &[u8], _file_size: u64) -> bool {
+        header.starts_with(b"ply\n") || header.starts_with(b"ply\r\n")
+    }
+
+    fn read_bytes(&self, data: &[u8]) -> io::Result<Vec<ImportedMesh>> {
+        read(Cursor::new(data))
+    }
+
+    fn import(&self, path: &Path, progress: &ImportProgress) -> io::Result<Vec<ImportedMesh>> {
+        read(progress.open(path)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+/// Type of a property value
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return None,
+        })
+    }
+
+    /// Bytes a binary value takes
+    fn size(self) -> u64 {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PropertyType {
+    Scalar(Scalar),
+    /// A count followed by that many items
+    List {
+        count: Scalar,
+        item: Scalar,
+    },
+}
+
+struct Property {
+    name: String,
+    kind: PropertyType,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Element {
+    fn scalar_index(&self, name: &str) -> Option<usize> {
+        self.properties.iter().position(|property| {
+            property.name == name && matches!(property.kind, PropertyType::Scalar(_))
+        })
+    }
+
+    /// Fewest bytes an instance of the element takes, lists counted as empty. An ASCII
+    /// value takes at least a character and a separator.
+    fn min_size(&self, encoding: Encoding) -> u64 {
+        self.properties
+            .iter()
+            .map(|property| match (encoding, property.kind) {
+                (Encoding::Ascii, _) => 2,
+                (_, PropertyType::Scalar(scalar)) => scalar.size(),
+                (_, PropertyType::List { count, .. }) => count.size(),
+            })
+            .sum()
+    }
+}
+
+/// Reads the mesh of a PLY file, faces with more than three corners are triangulated
+pub fn read<R: BufRead + Seek>(mut input: R) -> io::Result<Vec<ImportedMesh>> {
+    let start = input.stream_position()?;
+    let size = input.seek(SeekFrom::End(0))? - start;
+    input.seek(SeekFrom::Start(start))?;
+    let (encoding, elements, header_size) = read_header(&mut input)?;
+    check_counts(&elements, encoding, size.saturating_sub(header_size))?;
+    let mut values: Box<dyn Values> = match encoding {
+        Encoding::Ascii => Box::new(AsciiValues {
+            input,
+            line: String::new(),
+            offset: 0,
+        }),
+        Encoding::LittleEndian | Encoding::BigEndian => Box::new(BinaryValues {
+            input,
+            big_endian: encoding == Encoding::BigEndian,
+        }),
+    };
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    // Corners of every face, faces are split once all vertices are known
+    let mut corners = Vec::new();
+    let mut corner_counts = Vec::new();
+    let mut row = Vec::new();
+
+    for element in &elements {
+        match element.name.as_str() {
+            "vertex" => {
+                let [Some(x), Some(y), Some(z)] =
+                    ["x", "y", "z"].map(|name| element.scalar_index(name))
+                else {
+                    return Err(invalid("Vertices have no x, y and z".to_string()));
+                };
+                let normal = match ["nx", "ny", "nz"].map(|name| element.scalar_index(name)) {
+                    [Some(x), Some(y), Some(z)] => Some([x, y, z]),
+                    _ => None,
+                };
+                for _ in 0..element.count {
+                    row.clear();
+                    for property in &element.properties {
+                        match property.kind {
+                            PropertyType::Scalar(scalar) => row.push(values.read(scalar)?),
+                            PropertyType::List { count, item } => {
+                                row.push(0.0);
+                                skip_list(values.as_mut(), count, item)?;
+                            }
+                        }
+                    }
+                    positions.push([x, y, z].map(|i| row[i] as f32));
+                    if let Some(normal) = normal {
+                        normals.push(normal.map(|i| row[i] as f32));
+                    }
+                }
+            }
+            "face" => {
+                let index_property = element
+                    .properties
+                    .iter()
+                    .position(|property| {
+                        matches!(property.name.as_str(), "vertex_indices" | "vertex_index")
+                            && matches!(property.kind, PropertyType::List { .. })
+                    })
+                    .ok_or_else(|| invalid("Faces have no vertex_indices".to_string()))?;
+                // Faces with more corners split into more triangles
+                let mut triangles = 0usize;
+                for _ in 0..element.count {
+                    for (i, property) in element.properties.iter().enumerate() {
+                        match property.kind {
+                            PropertyType::List { count, item } if i == index_property => {
+                                let n = read_count(values.as_mut(), count)?;
+                                triangles = triangles.saturating_add(n.saturating_sub(2));
+                                check_triangle_count(triangles)?;
+                                for _ in 0..n {
+                                    corners.push(read_index(values.as_mut(), item)?);
+                                }
+                                corner_counts.push(n);
+                            }
+                            PropertyType::List { count, item } => {
+                                skip_list(values.as_mut(), count, item)?
+                            }
+                            PropertyType::Scalar(scalar) => {
+                                values.read(scalar)?;
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {
+                for _ in 0..element.count {
+                    for property in &element.properties {
+                        match property.kind {
+                            PropertyType::Scalar(scalar) => {
+                                values.read(scalar)?;
+                            }
+                            PropertyType::List { count, item } => {
+                                skip_list(values.as_mut(), count, item)?
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if corner_counts.is_empty() {
+        return Err(invalid(
+            "The file has no faces, point clouds can't be sliced".to_string(),
+        ));
+    }
+    let indices = triangulate_faces(&positions, &corners, &corner_counts)?;
+    let normals = Some(normals).filter(|normals| normals.len() == positions.len());
+    Ok(vec![ImportedMesh {
+        name: None,
+        geometry: Geometry::Indexed {
+            positions,
+            normals,
+            indices,
+        },
+    }])
+}
+
+/// Refuses a header declaring more faces than can be imported or more elements than the
+/// `data_size` bytes after it can hold
+fn check_counts(elements: &[Element], encoding: Encoding, data_size: u64) -> io::Result<()> {
+    for element in elements.iter().filter(|element| element.name == "face") {
+        check_triangle_count(element.count)?;
+    }
+    let mut needed = elements
+        .iter()
+        .map(|element| (element.count as u64).saturating_mul(element.min_size(encoding)))
+        .fold(0u64, u64::saturating_add);
+    if encoding == Encoding::Ascii {
+        // The last value needs no separator
+        needed = needed.saturating_sub(1);
+    }
+    if needed > data_size {
+        return Err(unexpected_end());
+    }
+    Ok(())
+}
+
+/// Splits the faces into triangles, checking that their corners exist
+fn triangulate_faces(
+    positions: &[[f32; 3]],
+    corners: &[u32],
+    corner_counts: &[usize],
+) -> io::Result<Vec<u32>> {
+    let mut indices = Vec::with_capacity(corners.len());
+    let mut polygon = Vec::new();
+    let mut start = 0;
+    for (face, &n) in corner_counts.iter().enumerate() {
+        let face_corners = &corners[start..start + n];
+        start += n;
+        if n < 3 {
+            return Err(invalid(format!("Face {} has fewer than 3 vertices", face)));
+        }
+        if let Some(&corner) = face_corners
+            .iter()
+            .find(|&&corner| corner as usize >= positions.len())
+        {
+            return Err(invalid(format!(
+                "Face {} uses vertex {} which doesn't exist",
+                face, corner
+            )));
+        }
+        if n == 3 {
+            indices.extend_from_slice(face_corners);
+            continue;
+        }
+        polygon.clear();
+        polygon.extend(
+            face_corners
+                .iter()
+                .map(|&corner| positions[corner as usize]),
+        );
+        for triangle in triangulate(&polygon) {
+            indices.extend(triangle.map(|i| face_corners[i]));
+        }
+    }
+    Ok(indices)
+}
+
+/// Reads the header up to `end_header`, leaving `input` at the first element. Returns
+/// the size of the header in bytes along with its contents.
+fn read_header<R: BufRead>(input: &mut R) -> io::Result<(Encoding, Vec<Element>, u64)> {
+    let mut line = String::new();
+    let mut line_number = 0;
+    let mut header_size = 0;
+    let mut next_line = |line: &mut String| -> io::Result<usize> {
+        line.clear();
+        let length = input.read_line(line)?;
+        header_size += length as u64;
+        if length == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The header has no end_header",
+            ));
+        }
+        line_number += 1;
+        Ok(line_number)
+    };
+
+    next_line(&mut line)?;
+    if line.trim_end() != "ply" {
+        return Err(invalid("Not a PLY file".to_string()));
+    }
+    let mut encoding = None;
+    let mut elements: Vec<Element> = Vec::new();
+    loop {
+        let line_number = next_line(&mut line)?;
+        let error = || {
+            invalid(format!(
+                "Invalid header line {}: {}",
+                line_number,
+                line.trim()
+            ))
+        };
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            ["end_header"] => break,
+            ["format", format, "1.0"] => {
+                encoding = Some(match *format {
+                    "ascii" => Encoding::Ascii,
+                    "binary_little_endian" => Encoding::LittleEndian,
+                    "binary_big_endian" => Encoding::BigEndian,
+                    _ => return Err(error()),
+                });
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().map_err(|_| error())?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => {
+                let kind = PropertyType::List {
+                    count: Scalar::parse(count).ok_or_else(error)?,
+                    item: Scalar::parse(item).ok_or_else(error)?,
+                };
+                let element = elements.last_mut().ok_or_else(error)?;
+                element.properties.push(Property {
+                    name: name.to_string(),
+                    kind,
+                });
+            }
+            ["property", scalar, name] => {
+                let kind = PropertyType::Scalar(Scalar::parse(scalar).ok_or_else(error)?);
+                let element = elements.last_mut().ok_or_else(error)?;
+                element.properties.push(Property {
+                    name: name.to_string(),
+                    kind,
+                });
+            }
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => return Err(error()),
+        }
+    }
+    let encoding = encoding.ok_or_else(|| invalid("The header has no format".to_string()))?;
+    Ok((encoding, elements, header_size))
+}
+
+/// Source of property values, ASCII tokens or binary numbers
+trait Values {
+    fn read(&mut self, scalar: Scalar) -> io::Result<f64>;
+}
+
+struct AsciiValues<R> {
+    input: R,
+    line: String,
+    /// Position of the next token in `line`
+    offset: usize,
+}
+
+impl<R: BufRead> Values for AsciiValues<R> {
+    fn read(&mut self, _scalar: Scalar) -> io::Result<f64> {
+        let (start, end) = loop {
+            let rest = &self.line[self.offset..];
+            let token = rest.trim_start();
+            if !token.is_empty() {
+                let start = self.line.len() - token.len();
+                let length = token.find(char::is_whitespace).unwrap_or(token.len());
+                break (start, start + length);
+            }
+            self.line.clear();
+            self.offset = 0;
+            if self.input.read_line(&mut self.line)? == 0 {
+                return Err(unexpected_end());
+            }
+        };
+        self.offset = end;
+        let token = &self.line[start..end];
+        token
+            .parse()
+            .map_err(|_| invalid(format!("Invalid number {}", token)))
+    }
+}
+
+struct BinaryValues<R> {
+    input: R,
+    big_endian: bool,
+}
+
+impl<R: BufRead> BinaryValues<R> {
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.input
+            .read_exact(&mut bytes)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => unexpected_end(),
+                _ => e,
+            })?;
+        if self.big_endian {
+            bytes.reverse();
+        }
+        Ok(bytes)
+    }
+}
+
+impl<R: BufRead> Values for BinaryValues<R> {
+    fn read(&mut self, scalar: Scalar) -> io::Result<f64> {
+        // Bytes are turned little endian first
+        Ok(match scalar {
+            Scalar::I8 => i8::from_le_bytes(self.bytes()?) as f64,
+            Scalar::U8 => u8::from_le_bytes(self.bytes()?) as f64,
+            Scalar::I16 => i16::from_le_bytes(self.bytes()?) as f64,
+            Scalar::U16 => u16::from_le_bytes(self.bytes()?) as f64,
+            Scalar::I32 => i32::from_le_bytes(self.bytes()?) as f64,
+            Scalar::U32 => u32::from_le_bytes(self.bytes()?) as f64,
+            Scalar::F32 => f32::from_le_bytes(self.bytes()?) as f64,
+            Scalar::F64 => f64::from_le_bytes(self.bytes()?),
+        })
+    }
+}
+
+fn read_count(values: &mut dyn Values, scalar: Scalar) -> io::Result<usize> {
+    let count = values.read(scalar)?;
+    if count < 0.0 || count.fract() != 0.0 {
+        return Err(invalid(format!("Invalid list length {}", count)));
+    }
+    Ok(count as usize)
+}
+
+fn read_index(values: &mut dyn Values, scalar: Scalar) -> io::Result<u32> {
+    let index = values.read(scalar)?;
+    if index < 0.0 || index.fract() != 0.0 || index > u32::MAX as f64 {
+        return Err(invalid(format!("Invalid vertex index {}", index)));
+    }
+    Ok(index as u32)
+}
+
+fn skip_list(values: &mut dyn Values, count: Scalar, item: Scalar) -> io::Result<()> {
+    for _ in 0..read_count(values, count)? {
+        values.read(item)?;
+    }
+    Ok(())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn unexpected_end() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "The file ends before all elements were read",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh_importer::{ImportError, MAX_TRIANGLES};
+
+    /// A unit square as one quad, with a color per vertex and an edge element
+    const SQUARE_HEADER: &str = "ply\n\
+        format {} 1.0\n\
+        comment made by a scanner\n\
+        element vertex 4\n\
+        property float x\nproperty float y\nproperty float z\n\
+        property uchar red\n\
+        property double nx\nproperty double ny\nproperty double nz\n\
+        element face 1\n\
+        property uchar flags\n\
+        property list uchar int vertex_indices\n\
+        element edge 1\n\
+        property int vertex1\nproperty int vertex2\n\
+        end_header\n";
+
+    const SQUARE: [[f32; 3]; 4] = [
+        [0.0, 0.0, 0.0],
+        [1.0, 0.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [0.0, 1.0, 0.0],
+    ];
+
+    fn header(format: &str) -> Vec<u8> {
+        SQUARE_HEADER.replace("{}", format).into_bytes()
+    }
+
+    /// The square in binary, with the bytes of every value reversed for big endian
+    fn binary_square(format: &str, big_endian: bool) -> Vec<u8> {
+        let order = |mut bytes: Vec<u8>| {
+            if big_endian {
+                bytes.reverse();
+            }
+            bytes
+        };
+        let mut data = header(format);
+        for position in SQUARE {
+            for coordinate in position {
+                data.extend(order(coordinate.to_le_bytes().to_vec()));
+            }
+            data.push(200);
+            for normal in [0.0f64, 0.0, 1.0] {
+                data.extend(order(normal.to_le_bytes().to_vec()));
+            }
+        }
+        data.extend([0, 4]);
+        for index in [0i32, 1, 2, 3, 0, 1] {
+            data.extend(order(index.to_le_bytes().to_vec()));
+        }
+        data
+    }
+
+    fn assert_square(meshes: &[ImportedMesh]) {
+        assert_eq!(meshes.len(), 1);
+        let Geometry::Indexed {
+            positions,
+            normals,
+            indices,
+        } = &meshes[0].geometry
+        else {
+            panic!("PLY meshes are indexed");
+        };
+        assert_eq!(positions, &SQUARE);
+        assert_eq!(normals.as_deref(), Some(&[[0.0, 0.0, 1.0]; 4][..]));
+        assert_eq!(indices.len(), 6);
+        let mut used = indices.clone();
+        used.sort();
+        used.dedup();
+        assert_eq!(used, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_ascii() {
+        let mut data = header("ascii");
+        data.extend_from_slice(
+            b"0 0 0 200 0 0 1\n1 0 0 200 0 0 1\n1 1 0 200 0 0 1\n0 1 0 200 0 0 1\n\
+              0 4 0 1 2 3\n\
+              0 1\n",
+        );
+        assert_square(&read(Cursor::new(data)).unwrap());
+    }
+
+    #[test]
+    fn test_binary() {
+        for (format, big_endian) in [("binary_little_endian", false), ("binary_big_endian", true)] {
+            let data = binary_square(format, big_endian);
+            assert_square(&read(Cursor::new(data)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_without_normals() {
+        let data = "ply\nformat ascii 1.0\n\
+            element vertex 3\nproperty float x\nproperty float y\nproperty float z\n\
+            element face 1\nproperty list uchar uint vertex_index\nend_header\n\
+            0 0 0\n1 0 0\n0 1 0\n3 0 1 2\n";
+        let meshes = read(Cursor::new(data)).unwrap();
+        let Geometry::Indexed {
+            normals, indices, ..
+        } = &meshes[0].geometry
+        else {
+            panic!("PLY meshes are indexed");
+        };
+        assert_eq!(normals, &None);
+        assert_eq!(indices, &[0, 1, 2]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let vertices = "ply\nformat ascii 1.0\n\
+            element vertex 3\nproperty float x\nproperty float y\nproperty float z\n";
+        for (data, kind, message) in [
+            (
+                "solid cube\n".to_string(),
+                io::ErrorKind::InvalidData,
+                "Not a PLY file",
+            ),
+            (
+                format!("{}end_header\n0 0 0\n1 0 0\n0 1 0\n", vertices),
+                io::ErrorKind::InvalidData,
+                "The file has no faces, point clouds can't be sliced",
+            ),
+            (
+                format!(
+                    "{}element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+                     0 0 0\n1 0 0\n0 1 0\n3 0 1 3\n",
+                    vertices
+                ),
+                io::ErrorKind::InvalidData,
+                "Face 0 uses vertex 3 which doesn't exist",
+            ),
+            (
+                format!("{}property float w x\nend_header\n", vertices),
+                io::ErrorKind::InvalidData,
+                "Invalid header line 7: property float w x",
+            ),
+            (
+                format!("{}end_header\n0 0 0\n1 0\n", vertices),
+                io::ErrorKind::UnexpectedEof,
+                "The file ends before all elements were read",
+            ),
+        ] {
+            let error = read(Cursor::new(data)).unwrap_err();
+            assert_eq!(error.kind(), kind);
+            assert_eq!(error.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn test_counts_beyond_the_file_size() {
+        // A million faces can't fit in the bytes after the header
+        let mut data = binary_square("binary_little_endian", false);
+        let header_end = data.len() - (4 * (12 + 1 + 24) + 2 + 6 * 4);
+        let header = String::from_utf8(data[..header_end].to_vec())
+            .unwrap()
+            .replace("element face 1", "element face 1000000");
+        data.splice(..header_end, header.into_bytes());
+        let error = read(Cursor::new(data)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_huge_counts_in_truncated_files() {
+        // Nothing is allocated for the claimed vertices, reading stops at the end
+        let data = "ply\nformat binary_little_endian 1.0\n\
+            element vertex 4000000000\nproperty float x\nproperty float y\nproperty float z\n\
+            end_header\n\0\0\0\0";
+        let error = read(Cursor::new(data)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let data = "ply\nformat ascii 1.0\n\
+            element vertex 3\nproperty float x\nproperty float y\nproperty float z\n\
+            element face 4000000000\nproperty list uchar int vertex_indices\nend_header\n\
+            0 0 0\n1 0 0\n0 1 0\n";
+        let error = ImportError::from(read(Cursor::new(data)).unwrap_err());
+        assert!(matches!(
+            error,
+            ImportError::TooLarge {
+                triangles: Some(4_000_000_000)
+            }
+        ));
+
+        // A single face with enough corners to split into too many triangles
+        let mut data = "ply\nformat ascii 1.0\n\
+            element vertex 3\nproperty float x\nproperty float y\nproperty float z\n\
+            element face 1\nproperty list uint int vertex_indices\nend_header\n\
+            0 0 0\n1 0 0\n0 1 0\n"
+            .to_string();
+        data.push_str(&format!("{} 0 1 2", MAX_TRIANGLES + 3));
+        let error = ImportError::from(read(Cursor::new(data)).unwrap_err());
+        assert!(matches!(error, ImportError::TooLarge { .. }));
+    }
+}
@@ -0,0 +1,326 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Preview images of the plate for printer file thumbnails.
+//!
+//! The bodies are seen through a fixed isometric camera with an orthographic projection
+//! fitted around them, so a thumbnail never depends on where the user left the viewport
+//! camera. The OpenGL context is only current inside slint's rendering notifier, so a
+//! thumbnail is queued as a [`PreviewRequest`] that the notifier draws with the viewport
+//! shaders through [`MeshRenderer::render_thumbnail`]. Without a context, e.g. in headless
+//! runs, [`render_preview`] applies the same shading in software.
+//!
+//! [`MeshRenderer::render_thumbnail`]: crate::mesh_renderer::MeshRenderer::render_thumbnail
+
+use crate::body::Body;
+use crate::slice_job::PREVIEW_BACKGROUND;
+use futures::channel::oneshot;
+use image::imageops::{self, FilterType};
+use image::{Rgb, RgbImage};
+use nalgebra::{Matrix4, Point3, Vector3};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Size of the thumbnail rendered for a slice job. Every writer scales it to its own
+/// preview sizes, the background fills any difference in aspect ratio.
+pub const THUMBNAIL_SIZE: (u32, u32) = (800, 600);
+/// Light direction used by the viewport shader
+pub const LIGHT_DIRECTION: [f32; 3] = [1.0, -1.0, 0.5];
+/// Share of the image left empty around the bodies
+const MARGIN: f32 = 0.05;
+/// The software rasterizer renders this many samples per pixel along each axis
+const SUPERSAMPLING: u32 = 2;
+
+/// Thumbnail waiting for the rendering notifier to draw it on the GPU
+pub struct PreviewRequest {
+    pub bodies: Vec<Rc<RefCell<Body>>>,
+    pub width: u32,
+    pub height: u32,
+    /// Dropped unanswered when the OpenGL context goes away
+    pub reply: oneshot::Sender<RgbImage>,
+}
+
+/// View and projection of the isometric preview camera
+pub struct IsometricView {
+    pub view_proj: Matrix4<f32>,
+    /// Direction the camera looks in
+    pub view_direction: Vector3<f32>,
+}
+
+impl IsometricView {
+    /// Looks at the bodies from the front left, down the diagonal of a cube, and fits
+    /// the projection around their bounds for an image of the given aspect ratio.
+    pub fn fit(bodies: &[Rc<RefCell<Body>>], aspect_ratio: f32) -> Self {
+        let (min, max) = bodies
+            .iter()
+            .filter_map(|body| body.borrow().world_aabb())
+            .map(|aabb| (aabb.min, aabb.max))
+            .reduce(|(min, max), (next_min, next_max)| (min.inf(&next_min), max.sup(&next_max)))
+            .unwrap_or((Vector3::repeat(-5.0), Vector3::repeat(5.0)));
+        let center = Point3::from((min + max) / 2.0);
+        let radius = ((max - min).norm() / 2.0).max(1.0);
+
+        let view_direction = Vector3::new(1.0, 1.0, -1.0).normalize();
+        let eye = center - view_direction * radius * 2.0;
+        let view = Matrix4::look_at_rh(&eye, &center, &Vector3::z());
+
+        // Bounds of the box corners as seen by the camera
+        let mut view_min = Vector3::repeat(f32::MAX);
+        let mut view_max = Vector3::repeat(f32::MIN);
+        for corner in 0..8 {
+            let point = Point3::new(
+                if corner & 1 == 0 { min.x } else { max.x },
+                if corner & 2 == 0 { min.y } else { max.y },
+                if corner & 4 == 0 { min.z } else { max.z },
+            );
+            let seen = view.transform_point(&point).coords;
+            view_min = view_min.inf(&seen);
+            view_max = view_max.sup(&seen);
+        }
+
+        // Grow the smaller side so the image aspect ratio is kept
+        let mut half_width = (view_max.x - view_min.x) / 2.0 * (1.0 + 2.0 * MARGIN);
+        let mut half_height = (view_max.y - view_min.y) / 2.0 * (1.0 + 2.0 * MARGIN);
+        half_width = half_width.max(half_height * aspect_ratio).max(f32::EPSILON);
+        half_height = half_height.max(half_width / aspect_ratio);
+        let center_x = (view_min.x + view_max.x) / 2.0;
+        let center_y = (view_min.y + view_max.y) / 2.0;
+        // The camera looks down negative z, near and far are distances in front of it
+        let projection = Matrix4::new_orthographic(
+            center_x - half_width,
+            center_x + half_width,
+            center_y - half_height,
+            center_y + half_height,
+            -view_max.z - 1.0,
+            -view_min.z + 1.0,
+        );
+
+        Self {
+            view_proj: projection * view,
+            view_direction,
+        }
+    }
+}
+
+/// Renders the bodies with a software rasterizer. Triangles are flat shaded, depth
+/// tested and supersampled to smooth their edges.
+pub fn render_preview(bodies: &[Rc<RefCell<Body>>], width: u32, height: u32) -> RgbImage {
+    if width == 0 || height == 0 {
+        return RgbImage::new(width, height);
+    }
+    let view = IsometricView::fit(bodies, width as f32 / height as f32);
+    let (sample_width, sample_height) = (width * SUPERSAMPLING, height * SUPERSAMPLING);
+    let mut samples = RgbImage::from_pixel(sample_width, sample_height, PREVIEW_BACKGROUND);
+    let mut depth = vec![f32::INFINITY; (sample_width * sample_height) as usize];
+
+    for body in bodies {
+        let body = body.borrow();
+        let model = body.get_model_matrix();
+        let model_view_proj = view.view_proj * model;
+        let world: Vec<Point3<f32>> = body
+            .mesh
+            .vertices
+            .iter()
+            .map(|vertex| model.transform_point(&Point3::from(vertex.position)))
+            .collect();
+        // Orthographic, so there is no perspective divide to worry about
+        let screen: Vec<Point3<f32>> = body
+            .mesh
+            .vertices
+            .iter()
+            .map(|vertex| {
+                let ndc = model_view_proj.transform_point(&Point3::from(vertex.position));
+                Point3::new(
+                    (ndc.x + 1.0) / 2.0 * sample_width as f32,
+                    (1.0 - ndc.y) / 2.0 * sample_height as f32,
+                    ndc.z,
+                )
+            })
+            .collect();
+
+        for triangle in body.mesh.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| triangle[i] as usize);
+            if [a, b, c].iter().any(|&i| i >= world.len()) {
+                continue;
+            }
+            let normal = (world[b] - world[a]).cross(&(world[c] - world[a]));
+            let Some(mut normal) = normal.try_normalize(f32::EPSILON) else {
+                continue;
+            };
+            // Meshes do not always wind their triangles consistently, light the side we see
+            if normal.dot(&view.view_direction) > 0.0 {
+                normal = -normal;
+            }
+            let color = shade(&normal, &view.view_direction);
+            rasterize(
+                [screen[a], screen[b], screen[c]],
+                color,
+                &mut samples,
+                &mut depth,
+            );
+        }
+    }
+
+    imageops::resize(&samples, width, height, FilterType::Triangle)
+}
+
+/// Fills a triangle given in sample coordinates, keeping the nearest depth per sample
+fn rasterize(vertices: [Point3<f32>; 3], color: Rgb<u8>, image: &mut RgbImage, depth: &mut [f32]) {
+    let [a, b, c] = vertices;
+    let edge = |p: &Point3<f32>, q: &Point3<f32>, x: f32, y: f32| {
+        (q.x - p.x) * (y - p.y) - (q.y - p.y) * (x - p.x)
+    };
+    let area = edge(&a, &b, c.x, c.y);
+    if area.abs() < f32::EPSILON {
+        return;
+    }
+    let (width, height) = image.dimensions();
+    let min_x = a.x.min(b.x).min(c.x).floor().max(0.0) as u32;
+    let min_y = a.y.min(b.y).min(c.y).floor().max(0.0) as u32;
+    let max_x = (a.x.max(b.x).max(c.x).ceil().max(0.0) as u32).min(width);
+    let max_y = (a.y.max(b.y).max(c.y).ceil().max(0.0) as u32).min(height);
+    for y in min_y..max_y {
+        for x in min_x..max_x {
+            let (sample_x, sample_y) = (x as f32 + 0.5, y as f32 + 0.5);
+            // Barycentric weights, all positive inside whichever way the triangle winds
+            let wa = edge(&b, &c, sample_x, sample_y) / area;
+            let wb = edge(&c, &a, sample_x, sample_y) / area;
+            let wc = edge(&a, &b, sample_x, sample_y) / area;
+            if wa < 0.0 || wb < 0.0 || wc < 0.0 {
+                continue;
+            }
+            let z = wa * a.z + wb * b.z + wc * c.z;
+            let index = (y * width + x) as usize;
+            if z <= depth[index] {
+                depth[index] = z;
+                image.put_pixel(x, y, color);
+            }
+        }
+    }
+}
+
+/// Phong shading tinted by the normal, as in the viewport fragment shader
+fn shade(normal: &Vector3<f32>, view_direction: &Vector3<f32>) -> Rgb<u8> {
+    let light = Vector3::from(LIGHT_DIRECTION).normalize();
+    let ambient = 0.2;
+    let diffuse = normal.dot(&light).max(0.0);
+    let reflected = -light - 2.0 * normal.dot(&-light) * normal;
+    let specular = 0.5 * view_direction.dot(&reflected).max(0.0).powi(32);
+    let intensity = ambient + diffuse + specular;
+    Rgb(std::array::from_fn(|i| {
+        let tint = normal[i] * 0.1 + 0.5;
+        ((intensity * tint).clamp(0.0, 1.0) * 255.0).round() as u8
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::{Mesh, Vertex};
+
+    // A 10mm cube sitting on the plate, centered on the origin
+    fn cube_body() -> Rc<RefCell<Body>> {
+        let mut vertices = Vec::new();
+        for z in [0.0, 10.0] {
+            for y in [-5.0, 5.0] {
+                for x in [-5.0, 5.0] {
+                    vertices.push(Vertex::new([x, y, z], [0.0, 0.0, 1.0]));
+                }
+            }
+        }
+        let indices = vec![
+            0, 2, 1, 1, 2, 3, // Bottom
+            4, 5, 6, 5, 7, 6, // Top
+            0, 1, 4, 1, 5, 4, // Front
+            2, 6, 3, 3, 6, 7, // Back
+            0, 4, 2, 2, 4, 6, // Left
+            1, 3, 5, 3, 7, 5, // Right
+        ];
+        let mesh = Mesh {
+            vertices,
+            indices,
+            triangles_for_slicing: Vec::new(),
+        };
+        Rc::new(RefCell::new(Body::new(mesh)))
+    }
+
+    fn is_background(pixel: &Rgb<u8>) -> bool {
+        *pixel == PREVIEW_BACKGROUND
+    }
+
+    #[test]
+    fn test_empty_plate_is_background() {
+        let image = render_preview(&[], 64, 48);
+
+        assert_eq!(image.dimensions(), (64, 48));
+        assert!(image.pixels().all(is_background));
+    }
+
+    #[test]
+    fn test_cube_fills_the_center() {
+        for (width, height) in [(120, 90), (290, 290), (200, 125), (31, 77)] {
+            let image = render_preview(&[cube_body()], width, height);
+
+            assert_eq!(image.dimensions(), (width, height));
+            assert!(!is_background(image.get_pixel(width / 2, height / 2)));
+            // The margin keeps the whole cube inside the image
+            for x in 0..width {
+                assert!(is_background(image.get_pixel(x, 0)));
+                assert!(is_background(image.get_pixel(x, height - 1)));
+            }
+            for y in 0..height {
+                assert!(is_background(image.get_pixel(0, y)));
+                assert!(is_background(image.get_pixel(width - 1, y)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_top_is_lit_differently_from_the_sides() {
+        let image = render_preview(&[cube_body()], 200, 200);
+
+        // The top face is at the top of the image, the side faces below it
+        let top = image.get_pixel(100, 60);
+        let left_side = image.get_pixel(70, 140);
+        let right_side = image.get_pixel(130, 140);
+        assert!(!is_background(top));
+        assert_ne!(top, left_side);
+        assert_ne!(left_side, right_side);
+    }
+
+    #[test]
+    fn test_view_follows_the_body_transform() {
+        let cube = cube_body();
+        let centered = render_preview(&[Rc::clone(&cube)], 100, 100);
+        cube.borrow_mut()
+            .set_position(Vector3::new(80.0, -40.0, 0.0));
+        let moved = render_preview(&[cube], 100, 100);
+
+        // The camera fits around the body wherever it is on the plate, only rounding
+        // may differ along the edges
+        let differing = centered
+            .pixels()
+            .zip(moved.pixels())
+            .filter(|(a, b)| a.0.iter().zip(b.0).any(|(&a, b)| a.abs_diff(b) > 2))
+            .count();
+        assert!(differing < 100, "{} pixels differ", differing);
+    }
+
+    #[test]
+    fn test_fit_keeps_the_aspect_ratio() {
+        let bodies = [cube_body()];
+        let wide = IsometricView::fit(&bodies, 2.0);
+        let square = IsometricView::fit(&bodies, 1.0);
+
+        // Same height on screen, twice the width of view
+        let scale_x =
+            |view: &IsometricView| view.view_proj[(0, 0)].abs() + view.view_proj[(0, 1)].abs();
+        let scale_y = |view: &IsometricView| {
+            view.view_proj[(1, 0)].abs()
+                + view.view_proj[(1, 1)].abs()
+                + view.view_proj[(1, 2)].abs()
+        };
+        approx::assert_relative_eq!(scale_x(&wide) * 2.0, scale_x(&square), epsilon = 1e-5);
+        approx::assert_relative_eq!(scale_y(&wide), scale_y(&square), epsilon = 1e-5);
+    }
+}
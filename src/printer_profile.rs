@@ -0,0 +1,146 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+use crate::build_volume::BuildVolume;
+use crate::output_format::{self, OutputFormat};
+use crate::zip_gcode_writer::ZipGcodeSettings;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Description of the printer a job is sliced for. Physical sizes are in millimeters.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrinterProfile {
+    /// Machine name written into printer files, some firmwares check it
+    pub name: String,
+    pub pixel_x: u32,
+    pub pixel_y: u32,
+    pub physical_x: f64,
+    pub physical_y: f64,
+    pub physical_z: f64,
+    /// File extension of the printer file to write after slicing
+    pub output_format: String,
+    /// Version of the printer file format the firmware understands
+    pub file_version: u32,
+    /// Number of grey levels kept in the layer images, 1 disables anti-aliasing
+    pub anti_alias_level: u32,
+    /// Writes v4 and v5 `.ctb` files in the encrypted container newer firmwares require
+    pub ctb_encrypted: bool,
+    /// Archive layout used when the output format is `zip`
+    pub zip_gcode: ZipGcodeSettings,
+}
+
+impl Default for PrinterProfile {
+    fn default() -> Self {
+        Self {
+            name: "Generic MSLA".to_string(),
+            pixel_x: 1920,
+            pixel_y: 1080,
+            physical_x: 218.880,
+            physical_y: 122.880,
+            physical_z: 220.000,
+            output_format: "ctb".to_string(),
+            file_version: 4,
+            anti_alias_level: 8,
+            ctb_encrypted: false,
+            zip_gcode: ZipGcodeSettings::default(),
+        }
+    }
+}
+
+impl PrinterProfile {
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let json = fs::read_to_string(path)?;
+        let profile = Self::from_json(&json)?;
+        profile.zip_gcode.gcode.validate()?;
+        Ok(profile)
+    }
+
+    /// Format of the printer file written after slicing, looked up from `output_format`
+    pub fn printer_file_format(&self) -> io::Result<&'static dyn OutputFormat> {
+        output_format::find(&self.output_format).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unsupported output format: {}", self.output_format),
+            )
+        })
+    }
+
+    pub fn build_volume(&self) -> BuildVolume {
+        BuildVolume::new(
+            self.physical_x as f32,
+            self.physical_y as f32,
+            self.physical_z as f32,
+        )
+    }
+
+    /// Width of a single LCD pixel in millimeters
+    pub fn pixel_size_x(&self) -> f64 {
+        self.physical_x / self.pixel_x as f64
+    }
+
+    /// Height of a single LCD pixel in millimeters
+    pub fn pixel_size_y(&self) -> f64 {
+        self.physical_y / self.pixel_y as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_json_partial_uses_defaults() {
+        let json = r#"{ "name": "Saturn 4 Ultra", "pixel_x": 11520, "pixel_y": 5120 }"#;
+
+        let profile = PrinterProfile::from_json(json).expect("Profile should parse");
+
+        assert_eq!(profile.name, "Saturn 4 Ultra");
+        assert_eq!(profile.pixel_x, 11520);
+        assert_eq!(profile.pixel_y, 5120);
+        assert_eq!(profile.physical_z, PrinterProfile::default().physical_z);
+        assert_eq!(profile.output_format, "ctb");
+    }
+
+    #[test]
+    fn test_pixel_size_and_build_volume() {
+        let profile = PrinterProfile::default();
+
+        assert!((profile.pixel_size_x() - 0.114).abs() < 1e-9);
+        assert!((profile.pixel_size_y() - 0.11377777).abs() < 1e-6);
+        assert_eq!(
+            profile.build_volume(),
+            BuildVolume::new(218.88, 122.88, 220.0)
+        );
+    }
+
+    #[test]
+    fn test_bundled_profile_matches_default() {
+        let json = include_str!("../profiles/printers/generic_msla.json");
+        let profile = PrinterProfile::from_json(json).expect("Bundled profile should parse");
+        assert_eq!(profile, PrinterProfile::default());
+    }
+
+    #[test]
+    fn test_load_rejects_unknown_placeholders() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(
+            &mut file,
+            br#"{ "zip_gcode": { "gcode": { "layer": "G4 P{exposure}" } } }"#,
+        )
+        .unwrap();
+
+        let error = PrinterProfile::load_from_file(file.path()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unknown placeholder {exposure} in the layer template"
+        );
+    }
+}
@@ -0,0 +1,294 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// Movement of the build plate between two layers.
+/// Distances are in millimeters and speeds in millimeters per minute.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MotionSettings {
+    pub lift_distance: f32,
+    pub lift_speed: f32,
+    pub retract_distance: f32,
+    pub retract_speed: f32,
+}
+
+impl Default for MotionSettings {
+    fn default() -> Self {
+        Self {
+            lift_distance: 6.0,
+            lift_speed: 65.0,
+            retract_distance: 6.0,
+            retract_speed: 150.0,
+        }
+    }
+}
+
+impl MotionSettings {
+    /// Time in seconds spent lifting the plate and bringing it back down
+    pub fn duration(&self) -> f32 {
+        let lift = if self.lift_speed > 0.0 {
+            self.lift_distance / self.lift_speed * 60.0
+        } else {
+            0.0
+        };
+        let retract = if self.retract_speed > 0.0 {
+            self.retract_distance / self.retract_speed * 60.0
+        } else {
+            0.0
+        };
+        lift + retract
+    }
+}
+
+/// Exposure and motion parameters for a print. Times are in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExposureSettings {
+    pub normal_exposure_time: f32,
+    pub bottom_exposure_time: f32,
+    pub bottom_layer_count: u32,
+    /// Layers after the bottom layers whose exposure steps down from the bottom to the normal time
+    pub transition_layer_count: u32,
+    pub light_off_delay: f32,
+    pub bottom_motion: MotionSettings,
+    pub normal_motion: MotionSettings,
+    pub light_pwm: u8,
+}
+
+impl Default for ExposureSettings {
+    fn default() -> Self {
+        Self {
+            normal_exposure_time: 2.5,
+            bottom_exposure_time: 30.0,
+            bottom_layer_count: 5,
+            transition_layer_count: 0,
+            light_off_delay: 1.0,
+            bottom_motion: MotionSettings {
+                lift_speed: 45.0,
+                ..MotionSettings::default()
+            },
+            normal_motion: MotionSettings::default(),
+            light_pwm: 255,
+        }
+    }
+}
+
+/// The resolved settings for a single layer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayerSettings {
+    pub exposure_time: f32,
+    pub light_off_delay: f32,
+    pub motion: MotionSettings,
+    pub light_pwm: u8,
+}
+
+impl ExposureSettings {
+    pub fn is_bottom_layer(&self, layer_index: usize) -> bool {
+        layer_index < self.bottom_layer_count as usize
+    }
+
+    pub fn is_transition_layer(&self, layer_index: usize) -> bool {
+        let bottom = self.bottom_layer_count as usize;
+        layer_index >= bottom && layer_index < bottom + self.transition_layer_count as usize
+    }
+
+    /// Exposure time for a layer, stepping linearly from the bottom time to the
+    /// normal time across the transition layers.
+    pub fn exposure_time_for_layer(&self, layer_index: usize) -> f32 {
+        if self.is_bottom_layer(layer_index) {
+            return self.bottom_exposure_time;
+        }
+        if !self.is_transition_layer(layer_index) {
+            return self.normal_exposure_time;
+        }
+        let step = (self.bottom_exposure_time - self.normal_exposure_time)
+            / (self.transition_layer_count + 1) as f32;
+        let transition_index = layer_index - self.bottom_layer_count as usize + 1;
+        self.bottom_exposure_time - step * transition_index as f32
+    }
+
+    pub fn motion_for_layer(&self, layer_index: usize) -> MotionSettings {
+        if self.is_bottom_layer(layer_index) {
+            self.bottom_motion
+        } else {
+            self.normal_motion
+        }
+    }
+
+    pub fn layer_settings(&self, layer_index: usize) -> LayerSettings {
+        LayerSettings {
+            exposure_time: self.exposure_time_for_layer(layer_index),
+            light_off_delay: self.light_off_delay,
+            motion: self.motion_for_layer(layer_index),
+            light_pwm: self.light_pwm,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResinProfile {
+    pub name: String,
+    pub exposure: ExposureSettings,
+}
+
+impl Default for ResinProfile {
+    fn default() -> Self {
+        Self {
+            name: "Default".to_string(),
+            exposure: ExposureSettings::default(),
+        }
+    }
+}
+
+impl ResinProfile {
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let json = fs::read_to_string(path)?;
+        Ok(Self::from_json(&json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const EPSILON: f32 = 1e-5;
+
+    fn settings_with_transition() -> ExposureSettings {
+        ExposureSettings {
+            normal_exposure_time: 2.0,
+            bottom_exposure_time: 30.0,
+            bottom_layer_count: 3,
+            transition_layer_count: 3,
+            ..ExposureSettings::default()
+        }
+    }
+
+    #[test]
+    fn test_bottom_layers_use_bottom_exposure() {
+        let settings = settings_with_transition();
+        for i in 0..3 {
+            assert_eq!(settings.exposure_time_for_layer(i), 30.0);
+            assert_eq!(settings.motion_for_layer(i), settings.bottom_motion);
+        }
+        assert_eq!(settings.motion_for_layer(3), settings.normal_motion);
+    }
+
+    #[test]
+    fn test_transition_layers_interpolate() {
+        let settings = settings_with_transition();
+
+        // Three transition layers split the 28 second difference into four steps
+        let expected = [23.0, 16.0, 9.0];
+        for (i, expected_time) in expected.iter().enumerate() {
+            let time = settings.exposure_time_for_layer(3 + i);
+            assert!(
+                (time - expected_time).abs() < EPSILON,
+                "Transition layer {} should expose for {}, got {}",
+                i,
+                expected_time,
+                time
+            );
+        }
+        assert_eq!(settings.exposure_time_for_layer(6), 2.0);
+        assert_eq!(settings.exposure_time_for_layer(500), 2.0);
+    }
+
+    #[test]
+    fn test_no_transition_layers() {
+        let settings = ExposureSettings {
+            transition_layer_count: 0,
+            ..settings_with_transition()
+        };
+        assert_eq!(settings.exposure_time_for_layer(2), 30.0);
+        assert_eq!(settings.exposure_time_for_layer(3), 2.0);
+        assert!(!settings.is_transition_layer(3));
+    }
+
+    #[test]
+    fn test_motion_duration() {
+        let motion = MotionSettings {
+            lift_distance: 6.0,
+            lift_speed: 60.0,
+            retract_distance: 6.0,
+            retract_speed: 120.0,
+        };
+        assert!((motion.duration() - 9.0).abs() < EPSILON);
+
+        let stationary = MotionSettings {
+            lift_speed: 0.0,
+            retract_speed: 0.0,
+            ..motion
+        };
+        assert_eq!(stationary.duration(), 0.0);
+    }
+
+    #[test]
+    fn test_from_json_partial_uses_defaults() {
+        let json = r#"{
+            "name": "Grey Standard",
+            "exposure": {
+                "normal_exposure_time": 1.8,
+                "bottom_layer_count": 6,
+                "normal_motion": { "lift_distance": 5.0 }
+            }
+        }"#;
+
+        let profile = ResinProfile::from_json(json).expect("Profile should parse");
+
+        assert_eq!(profile.name, "Grey Standard");
+        assert_eq!(profile.exposure.normal_exposure_time, 1.8);
+        assert_eq!(profile.exposure.bottom_layer_count, 6);
+        assert_eq!(profile.exposure.normal_motion.lift_distance, 5.0);
+        assert_eq!(
+            profile.exposure.normal_motion.lift_speed,
+            MotionSettings::default().lift_speed
+        );
+        assert_eq!(
+            profile.exposure.bottom_exposure_time,
+            ExposureSettings::default().bottom_exposure_time
+        );
+    }
+
+    #[test]
+    fn test_load_from_file_round_trip() {
+        let profile = ResinProfile {
+            name: "Tough".to_string(),
+            exposure: settings_with_transition(),
+        };
+        let mut temp_file = NamedTempFile::new().expect("Failed to create temp file");
+        write!(temp_file, "{}", serde_json::to_string(&profile).unwrap())
+            .expect("Failed to write to temp file");
+
+        let loaded = ResinProfile::load_from_file(temp_file.path()).expect("Profile should load");
+
+        assert_eq!(loaded, profile);
+    }
+
+    #[test]
+    fn test_bundled_profile_parses() {
+        let json = include_str!("../profiles/resins/standard_grey.json");
+        let profile = ResinProfile::from_json(json).expect("Bundled profile should parse");
+        assert_eq!(profile.name, "Standard Grey");
+        assert_eq!(profile.exposure.transition_layer_count, 3);
+    }
+
+    #[test]
+    fn test_load_from_file_invalid_json() {
+        let mut temp_file = NamedTempFile::new().expect("Failed to create temp file");
+        write!(temp_file, "{{ not json").expect("Failed to write to temp file");
+
+        assert!(ResinProfile::load_from_file(temp_file.path()).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Reader for Prusa SL1/SL1S `.sl1` archives.
+//!
+//! Reads the layout written by [`Sl1Writer`](crate::sl1_writer::Sl1Writer) back into a
+//! [`SliceJob`]. The layer count, layer height and image names come from `config.ini`,
+//! the display size from `prusaslicer.ini`. The format has no bottom layer count or motion
+//! settings, those keep their defaults.
+
+use crate::printer_profile::PrinterProfile;
+use crate::resin_profile::{ExposureSettings, ResinProfile};
+use crate::sl1_writer::THUMBNAIL_SIZES;
+use crate::slice_job::SliceJob;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek};
+use std::path::Path;
+use std::str::FromStr;
+use zip::ZipArchive;
+
+type Ini = HashMap<String, String>;
+
+pub fn read_from_file<P: AsRef<Path>>(path: P) -> io::Result<SliceJob> {
+    read(BufReader::new(File::open(path)?))
+}
+
+pub fn read<R: Read + Seek>(input: R) -> io::Result<SliceJob> {
+    let mut archive = ZipArchive::new(input).map_err(io::Error::from)?;
+    let config = parse_ini(&read_entry(&mut archive, "config.ini")?);
+    let prusaslicer = match read_entry(&mut archive, "prusaslicer.ini") {
+        Ok(bytes) => parse_ini(&bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ini::new(),
+        Err(e) => return Err(e),
+    };
+
+    let job_dir: String = field(&config, "jobDir", "config.ini")?;
+    let layer_count: usize = field(&config, "numFast", "config.ini")?;
+    let mut pngs = Vec::with_capacity(layer_count.min(archive.len()));
+    for i in 0..layer_count {
+        pngs.push(read_entry(
+            &mut archive,
+            &format!("{}{:05}.png", job_dir, i),
+        )?);
+    }
+    let layers = pngs
+        .par_iter()
+        .map(|png| {
+            image::load_from_memory(png)
+                .map(|image| image.into_luma8())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // Prefer the biggest thumbnail, it scales down best
+    let mut thumbnail = None;
+    for (width, height) in THUMBNAIL_SIZES.iter().rev() {
+        let name = format!("thumbnail/thumbnail{}x{}.png", width, height);
+        if let Ok(png) = read_entry(&mut archive, &name) {
+            thumbnail = image::load_from_memory(&png)
+                .ok()
+                .map(|image| image.into_rgb8());
+            break;
+        }
+    }
+
+    let defaults = PrinterProfile::default();
+    let (first_x, first_y) = layers
+        .first()
+        .map(|layer| layer.dimensions())
+        .unwrap_or((defaults.pixel_x, defaults.pixel_y));
+    let printer_model: String =
+        field(&config, "printerModel", "config.ini").unwrap_or_else(|_| "SL1".to_string());
+    let printer = PrinterProfile {
+        name: field(&config, "printerProfile", "config.ini").unwrap_or(defaults.name),
+        pixel_x: field(&prusaslicer, "display_pixels_x", "prusaslicer.ini").unwrap_or(first_x),
+        pixel_y: field(&prusaslicer, "display_pixels_y", "prusaslicer.ini").unwrap_or(first_y),
+        physical_x: field(&prusaslicer, "display_width", "prusaslicer.ini")
+            .unwrap_or(defaults.physical_x),
+        physical_y: field(&prusaslicer, "display_height", "prusaslicer.ini")
+            .unwrap_or(defaults.physical_y),
+        physical_z: field(&prusaslicer, "max_print_height", "prusaslicer.ini")
+            .unwrap_or(defaults.physical_z),
+        output_format: printer_model.to_lowercase(),
+        ..PrinterProfile::default()
+    };
+
+    let exposure_defaults = ExposureSettings::default();
+    let resin = ResinProfile {
+        name: field(&config, "materialName", "config.ini")
+            .unwrap_or_else(|_| ResinProfile::default().name),
+        exposure: ExposureSettings {
+            normal_exposure_time: field(&config, "expTime", "config.ini")?,
+            bottom_exposure_time: field(&config, "expTimeFirst", "config.ini")?,
+            transition_layer_count: field(&config, "numFade", "config.ini")
+                .unwrap_or(exposure_defaults.transition_layer_count),
+            ..exposure_defaults
+        },
+    };
+
+    let layer_height = field(&config, "layerHeight", "config.ini")?;
+    let mut job = SliceJob::new(layers, layer_height, printer, resin);
+    job.thumbnail = thumbnail;
+    Ok(job)
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> io::Result<Vec<u8>> {
+    let mut entry = archive.by_name(name).map_err(|e| match e {
+        zip::result::ZipError::FileNotFound => {
+            io::Error::new(io::ErrorKind::NotFound, format!("Missing {}", name))
+        }
+        e => io::Error::from(e),
+    })?;
+    let mut bytes = Vec::new();
+    entry.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn parse_ini(bytes: &[u8]) -> Ini {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// Parses a setting, naming the file and key when it is missing or malformed
+fn field<T: FromStr>(ini: &Ini, key: &str, file_name: &str) -> io::Result<T> {
+    let value = ini.get(key).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Missing {} in {}", key, file_name),
+        )
+    })?;
+    value.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid {} in {}: {}", key, file_name, value),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sl1_writer::Sl1Writer;
+    use image::{ImageBuffer, Luma};
+    use std::io::{Cursor, Write};
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    fn test_job() -> SliceJob {
+        let layers = (0..3)
+            .map(|i| ImageBuffer::from_fn(16, 9, |x, y| Luma([((x + y) * 8 + i) as u8])))
+            .collect();
+        let printer = PrinterProfile {
+            name: "Original Prusa SL1S SPEED".to_string(),
+            pixel_x: 16,
+            pixel_y: 9,
+            physical_z: 150.0,
+            output_format: "sl1s".to_string(),
+            ..PrinterProfile::default()
+        };
+        let resin = ResinProfile {
+            name: "Prusa Orange Tough".to_string(),
+            exposure: ExposureSettings {
+                normal_exposure_time: 2.5,
+                bottom_exposure_time: 35.0,
+                transition_layer_count: 10,
+                ..ExposureSettings::default()
+            },
+        };
+        SliceJob::new(layers, 0.05, printer, resin)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let job = test_job();
+        let mut cursor = Cursor::new(Vec::new());
+        Sl1Writer::new("job").write(&job, &mut cursor).unwrap();
+
+        let read_job = read(Cursor::new(cursor.into_inner())).expect("Archive should read back");
+
+        assert_eq!(read_job.layers, job.layers);
+        assert_eq!(read_job.layer_height, 0.05);
+        assert_eq!(read_job.printer, job.printer);
+        assert_eq!(read_job.resin, job.resin);
+        assert_eq!(
+            read_job
+                .thumbnail
+                .as_ref()
+                .map(|thumbnail| thumbnail.dimensions()),
+            Some((800, 480))
+        );
+    }
+
+    #[test]
+    fn test_missing_layer() {
+        let mut cursor = Cursor::new(Vec::new());
+        let mut zip = ZipWriter::new(&mut cursor);
+        zip.start_file("config.ini", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(b"jobDir = job\nnumFast = 1\nexpTime = 2\nexpTimeFirst = 20\n")
+            .unwrap();
+        zip.finish().unwrap();
+
+        let Err(error) = read(Cursor::new(cursor.into_inner())) else {
+            panic!("Reading should fail");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(error.to_string(), "Missing job00000.png");
+    }
+
+    #[test]
+    fn test_field_errors_name_the_key() {
+        let ini = parse_ini(b"layerHeight = thick\n");
+
+        let missing = field::<f64>(&ini, "expTime", "config.ini").unwrap_err();
+        assert_eq!(missing.to_string(), "Missing expTime in config.ini");
+        let invalid = field::<f64>(&ini, "layerHeight", "config.ini").unwrap_err();
+        assert_eq!(
+            invalid.to_string(),
+            "Invalid layerHeight in config.ini: thick"
+        );
+    }
+}
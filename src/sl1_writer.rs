@@ -0,0 +1,396 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Writer for Prusa SL1/SL1S `.sl1` archives.
+//!
+//! An archive is a zip holding `config.ini` with the settings the printer reads,
+//! `prusaslicer.ini` with the settings PrusaSlicer reads back, one 8 bit grayscale PNG per
+//! layer named after the job and the layer index, and PNG thumbnails.
+
+use crate::image_exporter::{png_entry_options, write_png_layers};
+use crate::output_format::{Capabilities, OutputFormat};
+use crate::sl1_reader;
+use crate::slice_job::SliceJob;
+use crate::time_util::{unix_now, utc_date_time};
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder, RgbImage};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// File extensions of Prusa SLA archives
+pub const EXTENSIONS: [&str; 2] = ["sl1", "sl1s"];
+pub const THUMBNAIL_SIZES: [(u32, u32); 2] = [(400, 400), (800, 480)];
+
+pub struct Sl1Format;
+
+impl OutputFormat for Sl1Format {
+    fn name(&self) -> &'static str {
+        "Prusa SL1"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &EXTENSIONS
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            grayscale: true,
+            per_layer_settings: false,
+            thumbnail_sizes: &THUMBNAIL_SIZES,
+        }
+    }
+
+    fn write_to_file(&self, job: &SliceJob, path: &Path) -> io::Result<()> {
+        // The job is named after the file, it prefixes the layer images
+        let job_name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("job");
+        Sl1Writer::new(job_name).write_to_file(job, path)
+    }
+
+    fn reader(&self) -> Option<fn(&Path) -> io::Result<SliceJob>> {
+        Some(|path| sl1_reader::read_from_file(path))
+    }
+}
+
+pub struct Sl1Writer {
+    /// Name of the job, prefixes every layer image
+    pub job_name: String,
+}
+
+impl Sl1Writer {
+    pub fn new(job_name: &str) -> Self {
+        Self {
+            job_name: job_name.to_string(),
+        }
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, job: &SliceJob, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(job, &mut writer)?;
+        writer.flush()
+    }
+
+    /// Writes the archive
+    pub fn write<W: Write + Seek>(&self, job: &SliceJob, out: W) -> io::Result<()> {
+        let mut zip = ZipWriter::new(out);
+        let text_options =
+            SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        let image_options = png_entry_options();
+
+        zip.start_file("config.ini", text_options)
+            .map_err(io::Error::from)?;
+        zip.write_all(self.config_ini(job).as_bytes())?;
+        zip.start_file("prusaslicer.ini", text_options)
+            .map_err(io::Error::from)?;
+        zip.write_all(prusaslicer_ini(job).as_bytes())?;
+
+        write_png_layers(
+            &mut zip,
+            &job.layers,
+            |layer| {
+                encode_png(
+                    layer.as_raw(),
+                    layer.width(),
+                    layer.height(),
+                    ExtendedColorType::L8,
+                )
+            },
+            |index| self.layer_file_name(index),
+        )?;
+
+        for (width, height) in THUMBNAIL_SIZES {
+            let thumbnail: RgbImage = job.preview(width, height);
+            let png = encode_png(thumbnail.as_raw(), width, height, ExtendedColorType::Rgb8)?;
+            zip.start_file(
+                format!("thumbnail/thumbnail{}x{}.png", width, height),
+                image_options,
+            )
+            .map_err(io::Error::from)?;
+            zip.write_all(&png)?;
+        }
+
+        zip.finish().map_err(io::Error::from)?;
+        Ok(())
+    }
+
+    pub fn layer_file_name(&self, layer_index: usize) -> String {
+        format!("{}{:05}.png", self.job_name, layer_index)
+    }
+
+    /// The settings the printer firmware reads
+    fn config_ini(&self, job: &SliceJob) -> String {
+        let exposure = &job.resin.exposure;
+        let entries = [
+            ("action", "print".to_string()),
+            ("jobDir", self.job_name.clone()),
+            ("expTime", exposure.normal_exposure_time.to_string()),
+            ("expTimeFirst", exposure.bottom_exposure_time.to_string()),
+            ("fileCreationTimestamp", creation_timestamp()),
+            ("hollow", "0".to_string()),
+            ("layerHeight", job.layer_height.to_string()),
+            ("materialName", job.resin.name.clone()),
+            ("numFade", exposure.transition_layer_count.to_string()),
+            ("numFast", job.layer_count().to_string()),
+            ("numSlow", "0".to_string()),
+            ("printProfile", format!("{}mm SealSlicer", job.layer_height)),
+            (
+                "printTime",
+                job.estimated_print_time().as_secs().to_string(),
+            ),
+            ("printerModel", printer_model(&job.printer.name).to_string()),
+            ("printerProfile", job.printer.name.clone()),
+            ("printerVariant", "default".to_string()),
+            ("prusaSlicerVersion", "SealSlicer".to_string()),
+            ("usedMaterial", format!("{:.3}", job.estimated_volume_ml())),
+        ];
+        to_ini(&entries)
+    }
+}
+
+/// The settings PrusaSlicer needs to open the archive again
+fn prusaslicer_ini(job: &SliceJob) -> String {
+    let exposure = &job.resin.exposure;
+    let printer = &job.printer;
+    let orientation = if printer.pixel_x >= printer.pixel_y {
+        "landscape"
+    } else {
+        "portrait"
+    };
+    let entries = [
+        ("display_height", printer.physical_y.to_string()),
+        ("display_mirror_x", "1".to_string()),
+        ("display_mirror_y", "0".to_string()),
+        ("display_orientation", orientation.to_string()),
+        ("display_pixels_x", printer.pixel_x.to_string()),
+        ("display_pixels_y", printer.pixel_y.to_string()),
+        ("display_width", printer.physical_x.to_string()),
+        ("exposure_time", exposure.normal_exposure_time.to_string()),
+        ("faded_layers", exposure.transition_layer_count.to_string()),
+        (
+            "initial_exposure_time",
+            exposure.bottom_exposure_time.to_string(),
+        ),
+        ("initial_layer_height", job.layer_height.to_string()),
+        ("layer_height", job.layer_height.to_string()),
+        ("material_print_speed", "fast".to_string()),
+        ("max_print_height", printer.physical_z.to_string()),
+        ("printer_model", printer_model(&printer.name).to_string()),
+        ("printer_settings_id", printer.name.clone()),
+        ("printer_technology", "SLA".to_string()),
+        ("sla_material_settings_id", job.resin.name.clone()),
+    ];
+    to_ini(&entries)
+}
+
+fn to_ini(entries: &[(&str, String)]) -> String {
+    entries
+        .iter()
+        .map(|(key, value)| format!("{} = {}\n", key, value))
+        .collect()
+}
+
+/// The firmware refuses archives sliced for a different model
+fn printer_model(printer_name: &str) -> &'static str {
+    if printer_name.to_uppercase().contains("SL1S") {
+        "SL1S"
+    } else {
+        "SL1"
+    }
+}
+
+fn encode_png(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    color_type: ExtendedColorType,
+) -> io::Result<Vec<u8>> {
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png)
+        .write_image(pixels, width, height, color_type)
+        .map_err(io::Error::other)?;
+    Ok(png)
+}
+
+/// Current time formatted like PrusaSlicer does, e.g. `2024-03-01 at 12:30:05 UTC`
+fn creation_timestamp() -> String {
+    format_utc(unix_now())
+}
+
+fn format_utc(unix_seconds: u64) -> String {
+    let (date, time) = utc_date_time(unix_seconds);
+    format!("{} at {} UTC", date, time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printer_profile::PrinterProfile;
+    use crate::resin_profile::{ExposureSettings, ResinProfile};
+    use image::{ImageBuffer, Luma};
+    use std::collections::HashMap;
+    use std::io::{Cursor, Read};
+    use zip::ZipArchive;
+
+    fn test_job(layer_count: usize) -> SliceJob {
+        let layers = (0..layer_count)
+            .map(|i| ImageBuffer::from_fn(16, 9, |x, y| Luma([((x + y) * 8 + i as u32) as u8])))
+            .collect();
+        let printer = PrinterProfile {
+            name: "Original Prusa SL1S SPEED".to_string(),
+            pixel_x: 16,
+            pixel_y: 9,
+            output_format: "sl1".to_string(),
+            ..PrinterProfile::default()
+        };
+        let resin = ResinProfile {
+            name: "Prusa Orange Tough".to_string(),
+            exposure: ExposureSettings {
+                normal_exposure_time: 2.5,
+                bottom_exposure_time: 35.0,
+                transition_layer_count: 10,
+                ..ExposureSettings::default()
+            },
+        };
+        SliceJob::new(layers, 0.05, printer, resin)
+    }
+
+    fn write_archive(job: &SliceJob) -> ZipArchive<Cursor<Vec<u8>>> {
+        let mut cursor = Cursor::new(Vec::new());
+        Sl1Writer::new("test")
+            .write(job, &mut cursor)
+            .expect("Writing to memory should succeed");
+        ZipArchive::new(Cursor::new(cursor.into_inner())).expect("Archive should open")
+    }
+
+    fn read_entry(archive: &mut ZipArchive<Cursor<Vec<u8>>>, name: &str) -> Vec<u8> {
+        let mut entry = archive.by_name(name).expect("Entry should exist");
+        let mut bytes = Vec::new();
+        entry.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn parse_ini(bytes: &[u8]) -> HashMap<String, String> {
+        String::from_utf8(bytes.to_vec())
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.split_once(" = "))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_every_entry_reads_cleanly() {
+        let mut archive = write_archive(&test_job(3));
+
+        let mut names = Vec::new();
+        for i in 0..archive.len() {
+            // Reading every entry to the end checks its CRC
+            let mut entry = archive.by_index(i).unwrap();
+            let mut bytes = Vec::new();
+            entry
+                .read_to_end(&mut bytes)
+                .unwrap_or_else(|e| panic!("Failed to read {}: {}", entry.name(), e));
+            names.push(entry.name().to_string());
+        }
+
+        assert_eq!(
+            names,
+            vec![
+                "config.ini",
+                "prusaslicer.ini",
+                "test00000.png",
+                "test00001.png",
+                "test00002.png",
+                "thumbnail/thumbnail400x400.png",
+                "thumbnail/thumbnail800x480.png",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_ini_fields() {
+        let job = test_job(3);
+        let mut archive = write_archive(&job);
+
+        let config = parse_ini(&read_entry(&mut archive, "config.ini"));
+
+        assert_eq!(config["jobDir"], "test");
+        assert_eq!(config["expTime"], "2.5");
+        assert_eq!(config["expTimeFirst"], "35");
+        assert_eq!(config["layerHeight"], "0.05");
+        assert_eq!(config["materialName"], "Prusa Orange Tough");
+        assert_eq!(config["numFade"], "10");
+        assert_eq!(config["numFast"], "3");
+        assert_eq!(config["printerModel"], "SL1S");
+        assert_eq!(
+            config["printTime"],
+            job.estimated_print_time().as_secs().to_string()
+        );
+        assert_eq!(
+            config["usedMaterial"],
+            format!("{:.3}", job.estimated_volume_ml())
+        );
+
+        let prusaslicer = parse_ini(&read_entry(&mut archive, "prusaslicer.ini"));
+        assert_eq!(prusaslicer["display_pixels_x"], "16");
+        assert_eq!(prusaslicer["display_pixels_y"], "9");
+        assert_eq!(prusaslicer["display_width"], "218.88");
+        assert_eq!(prusaslicer["initial_exposure_time"], "35");
+        assert_eq!(prusaslicer["printer_technology"], "SLA");
+    }
+
+    #[test]
+    fn test_layers_are_8_bit_grayscale_pngs() {
+        let job = test_job(2);
+        let mut archive = write_archive(&job);
+
+        for (i, layer) in job.layers.iter().enumerate() {
+            let png = read_entry(&mut archive, &format!("test{:05}.png", i));
+            let decoded = image::load_from_memory(&png).unwrap();
+            assert_eq!(decoded.color(), image::ColorType::L8);
+            assert_eq!(&decoded.into_luma8(), layer);
+        }
+    }
+
+    #[test]
+    fn test_thumbnails() {
+        let mut archive = write_archive(&test_job(1));
+
+        for (width, height) in THUMBNAIL_SIZES {
+            let name = format!("thumbnail/thumbnail{}x{}.png", width, height);
+            let decoded = image::load_from_memory(&read_entry(&mut archive, &name)).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (width, height));
+        }
+    }
+
+    #[test]
+    fn test_more_layers_than_a_batch() {
+        let layer_count = rayon::current_num_threads() * 2 + 1;
+        let job = test_job(layer_count);
+        let mut archive = write_archive(&job);
+
+        let last = read_entry(&mut archive, &format!("test{:05}.png", layer_count - 1));
+        assert_eq!(
+            &image::load_from_memory(&last).unwrap().into_luma8(),
+            job.layers.last().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_printer_model() {
+        assert_eq!(printer_model("Original Prusa SL1S SPEED"), "SL1S");
+        assert_eq!(printer_model("Original Prusa SL1"), "SL1");
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01 at 00:00:00 UTC");
+        assert_eq!(format_utc(1_709_296_205), "2024-03-01 at 12:30:05 UTC");
+        assert_eq!(format_utc(951_782_400), "2000-02-29 at 00:00:00 UTC");
+    }
+}
@@ -0,0 +1,301 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+use crate::printer_profile::PrinterProfile;
+use crate::resin_profile::{LayerSettings, ResinProfile};
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Luma, Rgb, RgbImage};
+use rayon::prelude::*;
+use std::time::Duration;
+
+/// Background of generated previews
+pub const PREVIEW_BACKGROUND: Rgb<u8> = Rgb([32, 32, 32]);
+/// Color of the model silhouette in generated previews
+const PREVIEW_FOREGROUND: Rgb<u8> = Rgb([90, 160, 220]);
+
+/// The output of a slicing run along with everything needed to print it
+#[derive(Clone)]
+pub struct SliceJob {
+    pub layers: Vec<ImageBuffer<Luma<u8>, Vec<u8>>>,
+    /// Layer height in millimeters
+    pub layer_height: f64,
+    pub printer: PrinterProfile,
+    pub resin: ResinProfile,
+    /// Rendered image of the plate used for printer file previews
+    pub thumbnail: Option<RgbImage>,
+}
+
+impl SliceJob {
+    pub fn new(
+        layers: Vec<ImageBuffer<Luma<u8>, Vec<u8>>>,
+        layer_height: f64,
+        printer: PrinterProfile,
+        resin: ResinProfile,
+    ) -> Self {
+        Self {
+            layers,
+            layer_height,
+            printer,
+            resin,
+            thumbnail: None,
+        }
+    }
+
+    /// Prepares a job read from a printer file for writing in another format. The output
+    /// settings and exposure come from the given profiles, while the resolution and plate
+    /// size stay those the layers were sliced for.
+    pub fn retarget(&mut self, printer: &PrinterProfile, resin: &ResinProfile) {
+        self.printer = PrinterProfile {
+            pixel_x: self.printer.pixel_x,
+            pixel_y: self.printer.pixel_y,
+            physical_x: self.printer.physical_x,
+            physical_y: self.printer.physical_y,
+            physical_z: self.printer.physical_z,
+            ..printer.clone()
+        };
+        self.resin = resin.clone();
+    }
+
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn layer_settings(&self, layer_index: usize) -> LayerSettings {
+        self.resin.exposure.layer_settings(layer_index)
+    }
+
+    /// Height of the top of a layer above the build plate, in millimeters
+    pub fn layer_z(&self, layer_index: usize) -> f64 {
+        (layer_index + 1) as f64 * self.layer_height
+    }
+
+    /// Estimate of the time the printer will take: exposure, light off delay and
+    /// plate movement for every layer.
+    pub fn estimated_print_time(&self) -> Duration {
+        let seconds: f32 = (0..self.layer_count())
+            .map(|i| {
+                let settings = self.layer_settings(i);
+                settings.exposure_time + settings.light_off_delay + settings.motion.duration()
+            })
+            .sum();
+        Duration::from_secs_f32(seconds)
+    }
+
+    /// Volume of cured resin in milliliters. Grey pixels count for their share of a full pixel.
+    pub fn estimated_volume_ml(&self) -> f64 {
+        let lit: u64 = self
+            .layers
+            .par_iter()
+            .map(|layer| layer.as_raw().iter().map(|&v| v as u64).sum::<u64>())
+            .sum();
+        let voxel_volume =
+            self.printer.pixel_size_x() * self.printer.pixel_size_y() * self.layer_height;
+        lit as f64 / 255.0 * voxel_volume / 1000.0
+    }
+
+    /// Preview image of the requested size. Uses the rendered thumbnail when there is one,
+    /// otherwise a top down silhouette of every layer.
+    pub fn preview(&self, width: u32, height: u32) -> RgbImage {
+        let source = match &self.thumbnail {
+            Some(thumbnail) => thumbnail.clone(),
+            None => self.silhouette(),
+        };
+        let mut preview = RgbImage::from_pixel(width, height, PREVIEW_BACKGROUND);
+        if source.width() == 0 || source.height() == 0 {
+            return preview;
+        }
+        // Fit inside the preview while keeping the aspect ratio
+        let scale = f64::min(
+            width as f64 / source.width() as f64,
+            height as f64 / source.height() as f64,
+        );
+        let fit_width = ((source.width() as f64 * scale).round() as u32).clamp(1, width);
+        let fit_height = ((source.height() as f64 * scale).round() as u32).clamp(1, height);
+        let resized = imageops::resize(&source, fit_width, fit_height, FilterType::Triangle);
+        imageops::overlay(
+            &mut preview,
+            &resized,
+            ((width - fit_width) / 2) as i64,
+            ((height - fit_height) / 2) as i64,
+        );
+        preview
+    }
+
+    fn silhouette(&self) -> RgbImage {
+        let (width, height) = match self.layers.first() {
+            Some(layer) => layer.dimensions(),
+            None => (self.printer.pixel_x, self.printer.pixel_y),
+        };
+        let coverage = self
+            .layers
+            .par_iter()
+            .fold(
+                || vec![0u8; (width * height) as usize],
+                |mut acc, layer| {
+                    for (a, &v) in acc.iter_mut().zip(layer.as_raw()) {
+                        *a = (*a).max(v);
+                    }
+                    acc
+                },
+            )
+            .reduce(
+                || vec![0u8; (width * height) as usize],
+                |mut a, b| {
+                    for (x, y) in a.iter_mut().zip(b) {
+                        *x = (*x).max(y);
+                    }
+                    a
+                },
+            );
+        RgbImage::from_fn(width, height, |x, y| {
+            let t = coverage[(y * width + x) as usize] as f32 / 255.0;
+            Rgb(std::array::from_fn(|c| {
+                let bg = PREVIEW_BACKGROUND[c] as f32;
+                let fg = PREVIEW_FOREGROUND[c] as f32;
+                (bg + (fg - bg) * t).round() as u8
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resin_profile::{ExposureSettings, MotionSettings};
+
+    fn blank_layers(count: usize) -> Vec<ImageBuffer<Luma<u8>, Vec<u8>>> {
+        (0..count)
+            .map(|_| ImageBuffer::from_pixel(4, 4, Luma([0u8])))
+            .collect()
+    }
+
+    #[test]
+    fn test_layer_z() {
+        let job = SliceJob::new(
+            blank_layers(3),
+            0.05,
+            PrinterProfile::default(),
+            ResinProfile::default(),
+        );
+        assert!((job.layer_z(0) - 0.05).abs() < 1e-9);
+        assert!((job.layer_z(2) - 0.15).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_retarget_keeps_the_geometry() {
+        let source = PrinterProfile {
+            name: "Original Prusa SL1".to_string(),
+            pixel_x: 4,
+            pixel_y: 4,
+            physical_x: 10.0,
+            output_format: "sl1".to_string(),
+            ..PrinterProfile::default()
+        };
+        let target = PrinterProfile {
+            name: "Saturn".to_string(),
+            output_format: "ctb".to_string(),
+            file_version: 5,
+            ..PrinterProfile::default()
+        };
+        let resin = ResinProfile {
+            name: "Faster".to_string(),
+            ..ResinProfile::default()
+        };
+        let mut job = SliceJob::new(blank_layers(2), 0.05, source, ResinProfile::default());
+
+        job.retarget(&target, &resin);
+
+        assert_eq!(job.printer.name, "Saturn");
+        assert_eq!(job.printer.output_format, "ctb");
+        assert_eq!(job.printer.file_version, 5);
+        assert_eq!((job.printer.pixel_x, job.printer.pixel_y), (4, 4));
+        assert_eq!(job.printer.physical_x, 10.0);
+        assert_eq!(job.resin, resin);
+        assert_eq!(job.layer_count(), 2);
+    }
+
+    #[test]
+    fn test_estimated_print_time() {
+        let motion = MotionSettings {
+            lift_distance: 5.0,
+            lift_speed: 60.0,
+            retract_distance: 5.0,
+            retract_speed: 60.0,
+        };
+        let resin = ResinProfile {
+            name: "Test".to_string(),
+            exposure: ExposureSettings {
+                normal_exposure_time: 2.0,
+                bottom_exposure_time: 20.0,
+                bottom_layer_count: 2,
+                transition_layer_count: 0,
+                light_off_delay: 1.0,
+                bottom_motion: motion,
+                normal_motion: motion,
+                light_pwm: 255,
+            },
+        };
+        let job = SliceJob::new(blank_layers(5), 0.05, PrinterProfile::default(), resin);
+
+        // 2 bottom layers at 20s, 3 normal layers at 2s, plus 1s delay and 10s of motion each
+        let expected = 2.0 * 20.0 + 3.0 * 2.0 + 5.0 * (1.0 + 10.0);
+        assert!((job.estimated_print_time().as_secs_f32() - expected).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_empty_job_takes_no_time() {
+        let job = SliceJob::new(
+            Vec::new(),
+            0.05,
+            PrinterProfile::default(),
+            ResinProfile::default(),
+        );
+        assert_eq!(job.estimated_print_time(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_estimated_volume() {
+        let printer = PrinterProfile {
+            pixel_x: 10,
+            pixel_y: 10,
+            physical_x: 10.0,
+            physical_y: 10.0,
+            ..PrinterProfile::default()
+        };
+        // One fully lit layer of 1x1x0.5mm voxels and one half grey layer
+        let layers = vec![
+            ImageBuffer::from_pixel(10, 10, Luma([255u8])),
+            ImageBuffer::from_pixel(10, 10, Luma([0u8])),
+        ];
+        let mut job = SliceJob::new(layers, 0.5, printer, ResinProfile::default());
+        assert!((job.estimated_volume_ml() - 0.05).abs() < 1e-9);
+
+        job.layers[1] = ImageBuffer::from_pixel(10, 10, Luma([51u8]));
+        assert!((job.estimated_volume_ml() - 0.06).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_preview_silhouette_keeps_aspect_ratio() {
+        // A 40x10 plate with the left half lit on the first layer
+        let mut layer = ImageBuffer::from_pixel(40, 10, Luma([0u8]));
+        for x in 0..20 {
+            for y in 0..10 {
+                layer.put_pixel(x, y, Luma([255]));
+            }
+        }
+        let job = SliceJob::new(
+            vec![layer, ImageBuffer::from_pixel(40, 10, Luma([0u8]))],
+            0.05,
+            PrinterProfile::default(),
+            ResinProfile::default(),
+        );
+
+        let preview = job.preview(20, 20);
+
+        assert_eq!(preview.dimensions(), (20, 20));
+        // The plate is scaled to 20x5 and centered vertically
+        assert_eq!(*preview.get_pixel(0, 0), PREVIEW_BACKGROUND);
+        assert_eq!(*preview.get_pixel(2, 10), PREVIEW_FOREGROUND);
+        assert_eq!(*preview.get_pixel(17, 10), PREVIEW_BACKGROUND);
+    }
+}
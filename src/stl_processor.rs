@@ -2,60 +2,120 @@
 // Distributed under the GNU Affero General Public License v3.0 or later.
 // See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
 
-use std::io::BufReader;
-use std::{ffi::OsStr, fs::File};
+use crate::mesh_importer::{check_triangle_count, ImportProgress, ImportedMesh, MeshImporter};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
 use stl_io::{self, Triangle};
+
+/// Size of the header of a binary STL, the triangle count follows it
+const BINARY_HEADER_SIZE: usize = 80;
+/// Size of a triangle in a binary STL: normal, three vertices and attributes
+const BINARY_TRIANGLE_SIZE: u64 = 50;
+
 pub struct StlProcessor;
 
-// Define a trait for processing STL files
-pub trait StlProcessorTrait {
-    fn read_stl(&self, filename: &OsStr) -> Result<Vec<Triangle>, std::io::Error>;
-}
+impl MeshImporter for StlProcessor {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["stl"]
+    }
+
+    fn sniff(&self, header: &[u8], file_size: u64) -> bool {
+        if header.starts_with(b"solid") {
+            return true;
+        }
+        // A binary STL is exactly as long as its triangle count says
+        let Some(count) = header.get(BINARY_HEADER_SIZE..BINARY_HEADER_SIZE + 4) else {
+            return false;
+        };
+        let count = u32::from_le_bytes(count.try_into().unwrap()) as u64;
+        file_size == BINARY_HEADER_SIZE as u64 + 4 + count * BINARY_TRIANGLE_SIZE
+    }
 
-// Implement the trait for the actual `StlProcessor`
-impl StlProcessorTrait for StlProcessor {
-    fn read_stl(&self, filename: &OsStr) -> Result<Vec<Triangle>, std::io::Error> {
-        StlProcessor::read_stl(filename)
+    fn read_bytes(&self, data: &[u8]) -> io::Result<Vec<ImportedMesh>> {
+        Ok(vec![ImportedMesh::from_triangles(
+            None,
+            read_triangles(&mut Cursor::new(data))?,
+        )])
+    }
+
+    fn import(
+        &self,
+        path: &Path,
+        progress: &ImportProgress,
+    ) -> Result<Vec<ImportedMesh>, std::io::Error> {
+        Ok(vec![ImportedMesh::from_triangles(
+            None,
+            read_triangles(&mut progress.open(path)?)?,
+        )])
     }
 }
-impl StlProcessor {
-    pub fn new() -> Self {
-        Self {}
+
+/// Reads an ASCII or binary STL
+fn read_triangles<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<Triangle>> {
+    check_binary_count(reader)?;
+    let indexed_mesh = stl_io::read_stl(reader)?;
+
+    // Convert IndexedMesh into Vec<Triangle>
+    let triangles = indexed_mesh
+        .faces
+        .iter()
+        .map(|face| {
+            let vertices = [
+                indexed_mesh.vertices[face.vertices[0] as usize],
+                indexed_mesh.vertices[face.vertices[1] as usize],
+                indexed_mesh.vertices[face.vertices[2] as usize],
+            ];
+            Triangle {
+                normal: face.normal,
+                vertices,
+            }
+        })
+        .collect();
+
+    Ok(triangles)
+}
+
+/// Refuses a binary STL whose triangle count is more than can be imported or than the
+/// file holds, before any triangle is read. ASCII files, which `stl_io` recognizes by
+/// their first line, are left alone.
+fn check_binary_count<R: Read + Seek>(reader: &mut R) -> io::Result<()> {
+    let mut header = Vec::new();
+    reader
+        .by_ref()
+        .take(BINARY_HEADER_SIZE as u64 + 4)
+        .read_to_end(&mut header)?;
+    let size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    if header.starts_with(b"solid ") {
+        return Ok(());
     }
-    // Read the STL file and return the list of triangles
-    pub fn read_stl(filename: &OsStr) -> Result<Vec<Triangle>, std::io::Error> {
-        let file = File::open(filename)?;
-        let mut reader = BufReader::new(file);
-        let indexed_mesh = stl_io::read_stl(&mut reader)?;
-
-        // Convert IndexedMesh into Vec<Triangle>
-        let triangles = indexed_mesh
-            .faces
-            .iter()
-            .map(|face| {
-                let vertices = [
-                    indexed_mesh.vertices[face.vertices[0] as usize],
-                    indexed_mesh.vertices[face.vertices[1] as usize],
-                    indexed_mesh.vertices[face.vertices[2] as usize],
-                ];
-                Triangle {
-                    normal: face.normal,
-                    vertices,
-                }
-            })
-            .collect();
-
-        Ok(triangles)
+    let Some(count) = header.get(BINARY_HEADER_SIZE..BINARY_HEADER_SIZE + 4) else {
+        return Ok(());
+    };
+    let count = u32::from_le_bytes(count.try_into().unwrap());
+    check_triangle_count(count as usize)?;
+    if size < BINARY_HEADER_SIZE as u64 + 4 + count as u64 * BINARY_TRIANGLE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("The file ends before its {} triangles", count),
+        ));
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Write;
+    use std::fs::File;
+    use std::io::{BufReader, Write};
     use stl_io::Triangle;
     use tempfile::NamedTempFile;
 
+    // Helper function to read the triangles of an STL file
+    fn read_stl(path: &Path) -> io::Result<Vec<Triangle>> {
+        read_triangles(&mut BufReader::new(File::open(path)?))
+    }
+
     // Helper function to create a Triangle
     fn create_triangle(v0: [f32; 3], v1: [f32; 3], v2: [f32; 3], normal: [f32; 3]) -> Triangle {
         Triangle {
@@ -157,8 +217,7 @@ mod tests {
         let temp_file = write_ascii_stl(&triangles);
 
         // Act: Read the STL file
-        let processor = StlProcessor::new();
-        let result = processor.read_stl(temp_file.path().as_os_str());
+        let result = read_stl(temp_file.path());
 
         // Assert: Ensure no errors and triangles match
         assert!(
@@ -206,8 +265,7 @@ mod tests {
         let temp_file = write_binary_stl(&triangles);
 
         // Act: Read the STL file
-        let processor = StlProcessor::new();
-        let result = processor.read_stl(temp_file.path().as_os_str());
+        let result = read_stl(temp_file.path());
 
         // Assert: Ensure no errors and triangles match
         assert!(
@@ -236,11 +294,10 @@ mod tests {
     #[test]
     fn test_read_stl_nonexistent_file() {
         // Arrange: Define a filename that doesn't exist
-        let filename = OsStr::new("/do/not/ever/make/a/file/here/or/you/will/be/cursed");
+        let filename = Path::new("/do/not/ever/make/a/file/here/or/you/will/be/cursed");
 
         // Act: Attempt to read the non-existent file
-        let processor = StlProcessor::new();
-        let result = processor.read_stl(filename);
+        let result = read_stl(filename);
 
         // Assert: Ensure an error is returned
         assert!(
@@ -267,8 +324,7 @@ mod tests {
         write!(temp_file, "{}", malformed_content).expect("Failed to write to temp file");
 
         // Act: Attempt to read the malformed STL file
-        let processor = StlProcessor::new();
-        let result = processor.read_stl(temp_file.path().as_os_str());
+        let result = read_stl(temp_file.path());
 
         // Assert: Ensure an error is returned
         assert!(
@@ -283,8 +339,7 @@ mod tests {
         let temp_file = NamedTempFile::new().expect("Failed to create temp file");
 
         // Act: Attempt to read the empty STL file
-        let processor = StlProcessor::new();
-        let result = processor.read_stl(temp_file.path().as_os_str());
+        let result = read_stl(temp_file.path());
 
         // Assert: Ensure an error is returned (since the STL content is invalid)
         assert!(
@@ -304,8 +359,7 @@ mod tests {
         write!(temp_file, "{}", stl_content).expect("Failed to write to temp file");
 
         // Act: Read the STL file
-        let processor = StlProcessor::new();
-        let result = processor.read_stl(temp_file.path().as_os_str());
+        let result = read_stl(temp_file.path());
 
         // Assert: Ensure no errors and no triangles are parsed
         assert!(
@@ -335,8 +389,7 @@ mod tests {
             .expect("Failed to write triangle count");
 
         // Act: Read the STL file
-        let processor = StlProcessor::new();
-        let result = processor.read_stl(temp_file.path().as_os_str());
+        let result = read_stl(temp_file.path());
 
         // Assert: Ensure no errors and no triangles are parsed
         assert!(
@@ -366,8 +419,7 @@ mod tests {
             .expect("Failed to write triangle count");
 
         // Act: Attempt to read the malformed binary STL file
-        let processor = StlProcessor::new();
-        let result = processor.read_stl(temp_file.path().as_os_str());
+        let result = read_stl(temp_file.path());
 
         // Assert: Ensure an error is returned due to insufficient data
         assert!(
@@ -375,4 +427,28 @@ mod tests {
             "read_stl should return Err for malformed binary STL files"
         );
     }
+
+    #[test]
+    fn test_read_stl_binary_huge_count() {
+        let binary = |count: u32| {
+            let mut data = vec![0u8; BINARY_HEADER_SIZE];
+            data.extend(count.to_le_bytes());
+            data.extend([0u8; BINARY_TRIANGLE_SIZE as usize]);
+            data
+        };
+
+        // More triangles than can be imported
+        let Err(error) = StlProcessor.read_bytes(&binary(u32::MAX)) else {
+            panic!("read_bytes should return Err for too many triangles");
+        };
+        assert!(error.to_string().contains(&u32::MAX.to_string()));
+
+        // More triangles than the file holds
+        let Err(error) = StlProcessor.read_bytes(&binary(1000)) else {
+            panic!("read_bytes should return Err for a truncated file");
+        };
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        assert!(StlProcessor.read_bytes(&binary(1)).is_ok());
+    }
 }
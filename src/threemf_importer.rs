@@ -0,0 +1,538 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Importer for 3D Manufacturing Format `.3mf` packages.
+//!
+//! A package is a zip archive with the model as XML, usually in `3D/3dmodel.model`.
+//! Objects are either meshes or assemblies of components placing other objects, which may
+//! live in further model files of the package (production extension). Every build item
+//! becomes a mesh of its own with the transforms of the item and its components baked in,
+//! scaled to millimeters from the unit of the model file each object comes from.
+
+use crate::archive_importer::{read_limited, MAX_ENTRY_SIZE};
+use crate::mesh_analysis::is_mirroring;
+use crate::mesh_importer::{
+    check_triangle_count, triangle, ImportProgress, ImportedMesh, MeshImporter,
+};
+use nalgebra::{Matrix4, Point3};
+use roxmltree::{Document, Node};
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Seek};
+use std::path::Path;
+use std::str::FromStr;
+use stl_io::Triangle;
+use zip::ZipArchive;
+
+const PRODUCTION_NAMESPACE: &str =
+    "http://schemas.microsoft.com/3dmanufacturing/production/2015/06";
+pub const MODEL_RELATIONSHIP: &str = "http://schemas.microsoft.com/3dmanufacturing/2013/01/3dmodel";
+pub const RELATIONSHIPS_PATH: &str = "_rels/.rels";
+pub const DEFAULT_MODEL_PATH: &str = "3D/3dmodel.model";
+/// Deepest nesting of components followed, guards against components including themselves
+const MAX_COMPONENT_DEPTH: usize = 32;
+
+pub struct ThreeMfImporter;
+
+impl MeshImporter for ThreeMfImporter {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["3mf"]
+    }
+
+    fn sniff(&self, header: &[u8], _file_size: u64) -> bool {
+        // A zip archive whose first entries are the package parts
+        header.starts_with(b"PK\x03\x04")
+            && [&b"[Content_Types].xml"[..], b"_rels/", b"3D/"]
+                .iter()
+                .any(|name| header.windows(name.len()).any(|window| window == *name))
+    }
+
+    fn read_bytes(&self, data: &[u8]) -> io::Result<Vec<ImportedMesh>> {
+        read(Cursor::new(data))
+    }
+
+    fn import(&self, path: &Path, progress: &ImportProgress) -> io::Result<Vec<ImportedMesh>> {
+        read(progress.open(path)?)
+    }
+}
+
+/// Reads every build item of the package as a mesh named after its object
+pub fn read<R: Read + Seek>(input: R) -> io::Result<Vec<ImportedMesh>> {
+    let mut archive = ZipArchive::new(input).map_err(io::Error::from)?;
+    let root_path = root_model_path(&mut archive)?;
+
+    // Load the root model and every model its components point to
+    let mut models = HashMap::new();
+    let mut pending = vec![root_path.clone()];
+    while let Some(path) = pending.pop() {
+        if models.contains_key(&path) {
+            continue;
+        }
+        let model = parse_model(&read_entry(&mut archive, &path)?, &path)?;
+        pending.extend(model.referenced_paths());
+        models.insert(path, model);
+    }
+
+    let root = &models[&root_path];
+    let root_scale = Matrix4::new_scaling(root.unit_scale);
+    let mut meshes = Vec::with_capacity(root.build_items.len());
+    for item in &root.build_items {
+        let path = item.path.as_deref().unwrap_or(&root_path);
+        let object = find_object(&models, path, &item.object_id)?;
+        let mut triangles = Vec::new();
+        collect_triangles(
+            &models,
+            path,
+            &item.object_id,
+            root_scale * item.transform * unit_change(&models, path, &root_path),
+            0,
+            &mut triangles,
+        )?;
+        let name = object
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("Object {}", item.object_id));
+        meshes.push(ImportedMesh::from_triangles(Some(name), triangles));
+    }
+    Ok(meshes)
+}
+
+struct Model {
+    /// Millimeters per unit of the model
+    unit_scale: f32,
+    objects: HashMap<String, Object>,
+    build_items: Vec<Component>,
+}
+
+impl Model {
+    fn referenced_paths(&self) -> impl Iterator<Item = String> + '_ {
+        self.objects
+            .values()
+            .flat_map(|object| object.components.iter())
+            .chain(self.build_items.iter())
+            .filter_map(|component| component.path.clone())
+    }
+}
+
+struct Object {
+    name: Option<String>,
+    vertices: Vec<Point3<f32>>,
+    triangles: Vec<[usize; 3]>,
+    components: Vec<Component>,
+}
+
+/// A component of an object or a build item, placing an object with a transform
+struct Component {
+    /// Model file holding the object, None for the file of the component itself
+    path: Option<String>,
+    object_id: String,
+    transform: Matrix4<f32>,
+}
+
+/// Finds the model file the package's relationships point to
+fn root_model_path<R: Read + Seek>(archive: &mut ZipArchive<R>) -> io::Result<String> {
+    let relationships = match read_entry(archive, RELATIONSHIPS_PATH) {
+        Ok(relationships) => relationships,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(DEFAULT_MODEL_PATH.to_string()),
+        Err(e) => return Err(e),
+    };
+    let document = Document::parse(&relationships).map_err(|e| xml_error(RELATIONSHIPS_PATH, e))?;
+    let target = document
+        .descendants()
+        .filter(|node| node.has_tag_name("Relationship"))
+        .find(|node| node.attribute("Type") == Some(MODEL_RELATIONSHIP))
+        .and_then(|node| node.attribute("Target"));
+    Ok(target.map_or_else(|| DEFAULT_MODEL_PATH.to_string(), part_name))
+}
+
+/// Turns a part reference such as `/3D/3dmodel.model` into the name of its zip entry
+fn part_name(reference: &str) -> String {
+    reference.trim_start_matches('/').to_string()
+}
+
+fn parse_model(xml: &str, path: &str) -> io::Result<Model> {
+    let document = Document::parse(xml).map_err(|e| xml_error(path, e))?;
+    let root = document.root_element();
+    if !root.has_tag_name("model") {
+        return Err(invalid(format!("{} is not a 3MF model", path)));
+    }
+    let unit = root.attribute("unit").unwrap_or("millimeter");
+    let unit_scale = match unit {
+        "micron" => 0.001,
+        "millimeter" => 1.0,
+        "centimeter" => 10.0,
+        "inch" => 25.4,
+        "foot" => 304.8,
+        "meter" => 1000.0,
+        _ => return Err(invalid(format!("Unknown unit {} in {}", unit, path))),
+    };
+
+    let mut objects = HashMap::new();
+    for node in children(root, "resources").flat_map(|resources| children(resources, "object")) {
+        let id: String = attribute(node, "id", path)?;
+        let mut object = Object {
+            name: node.attribute("name").map(str::to_string),
+            vertices: Vec::new(),
+            triangles: Vec::new(),
+            components: Vec::new(),
+        };
+        for mesh in children(node, "mesh") {
+            for vertex in children(mesh, "vertices").flat_map(|v| children(v, "vertex")) {
+                object.vertices.push(Point3::new(
+                    attribute(vertex, "x", path)?,
+                    attribute(vertex, "y", path)?,
+                    attribute(vertex, "z", path)?,
+                ));
+            }
+            for triangle in children(mesh, "triangles").flat_map(|t| children(t, "triangle")) {
+                let corners = [
+                    attribute(triangle, "v1", path)?,
+                    attribute(triangle, "v2", path)?,
+                    attribute(triangle, "v3", path)?,
+                ];
+                if corners
+                    .iter()
+                    .any(|&corner| corner >= object.vertices.len())
+                {
+                    return Err(invalid(format!(
+                        "Triangle of object {} uses a missing vertex in {}",
+                        id, path
+                    )));
+                }
+                object.triangles.push(corners);
+            }
+        }
+        for component in children(node, "components").flat_map(|c| children(c, "component")) {
+            object.components.push(parse_component(component, path)?);
+        }
+        objects.insert(id, object);
+    }
+
+    let build_items = children(root, "build")
+        .flat_map(|build| children(build, "item"))
+        .map(|item| parse_component(item, path))
+        .collect::<io::Result<_>>()?;
+
+    Ok(Model {
+        unit_scale,
+        objects,
+        build_items,
+    })
+}
+
+fn parse_component(node: Node, path: &str) -> io::Result<Component> {
+    let transform = match node.attribute("transform") {
+        Some(transform) => parse_transform(transform)
+            .ok_or_else(|| invalid(format!("Invalid transform {} in {}", transform, path)))?,
+        None => Matrix4::identity(),
+    };
+    Ok(Component {
+        path: node
+            .attribute((PRODUCTION_NAMESPACE, "path"))
+            .map(part_name),
+        object_id: attribute(node, "objectid", path)?,
+        transform,
+    })
+}
+
+/// Parses the 12 values of a 3MF transform. They are the columns of a 4x3 matrix
+/// applied to row vectors, so they fill the rows of the usual 4x4 matrix.
+fn parse_transform(text: &str) -> Option<Matrix4<f32>> {
+    let values = text
+        .split_whitespace()
+        .map(|value| value.parse().ok())
+        .collect::<Option<Vec<f32>>>()?;
+    let m: [f32; 12] = values.try_into().ok()?;
+    #[rustfmt::skip]
+    let matrix = Matrix4::new(
+        m[0], m[3], m[6], m[9],
+        m[1], m[4], m[7], m[10],
+        m[2], m[5], m[8], m[11],
+        0.0, 0.0, 0.0, 1.0,
+    );
+    Some(matrix)
+}
+
+fn find_object<'a>(
+    models: &'a HashMap<String, Model>,
+    path: &str,
+    object_id: &str,
+) -> io::Result<&'a Object> {
+    models
+        .get(path)
+        .and_then(|model| model.objects.get(object_id))
+        .ok_or_else(|| invalid(format!("Object {} doesn't exist in {}", object_id, path)))
+}
+
+/// Scales coordinates in the unit of the model at `inner` to the unit of the one at `outer`
+fn unit_change(models: &HashMap<String, Model>, inner: &str, outer: &str) -> Matrix4<f32> {
+    let unit_scale = |path| {
+        models
+            .get(path)
+            .map_or(1.0, |model: &Model| model.unit_scale)
+    };
+    Matrix4::new_scaling(unit_scale(inner) / unit_scale(outer))
+}
+
+/// Adds the triangles of an object and its components. `transform` takes coordinates in
+/// the unit of the model at `path` to millimeters on the plate.
+fn collect_triangles(
+    models: &HashMap<String, Model>,
+    path: &str,
+    object_id: &str,
+    transform: Matrix4<f32>,
+    depth: usize,
+    out: &mut Vec<Triangle>,
+) -> io::Result<()> {
+    if depth > MAX_COMPONENT_DEPTH {
+        return Err(invalid(format!(
+            "Components of object {} nest too deep in {}",
+            object_id, path
+        )));
+    }
+    let object = find_object(models, path, object_id)?;
+
+    let mirrored = is_mirroring(&transform);
+    let vertices: Vec<[f32; 3]> = object
+        .vertices
+        .iter()
+        .map(|vertex| transform.transform_point(vertex).coords.into())
+        .collect();
+    check_triangle_count(out.len() + object.triangles.len())?;
+    out.extend(object.triangles.iter().map(|&[a, b, c]| {
+        if mirrored {
+            triangle(vertices[a], vertices[c], vertices[b])
+        } else {
+            triangle(vertices[a], vertices[b], vertices[c])
+        }
+    }));
+
+    for component in &object.components {
+        let component_path = component.path.as_deref().unwrap_or(path);
+        collect_triangles(
+            models,
+            component_path,
+            &component.object_id,
+            transform * component.transform * unit_change(models, component_path, path),
+            depth + 1,
+            out,
+        )?;
+    }
+    Ok(())
+}
+
+fn children<'a, 'input>(
+    node: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children()
+        .filter(move |child| child.has_tag_name(name))
+}
+
+/// Parses an attribute, naming the element and file when it is missing or malformed
+fn attribute<T: FromStr>(node: Node, name: &str, path: &str) -> io::Result<T> {
+    let element = node.tag_name().name();
+    let value = node
+        .attribute(name)
+        .ok_or_else(|| invalid(format!("Missing {} on {} in {}", name, element, path)))?;
+    value.parse().map_err(|_| {
+        invalid(format!(
+            "Invalid {} on {} in {}: {}",
+            name, element, path, value
+        ))
+    })
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> io::Result<String> {
+    let entry = archive.by_name(name).map_err(|e| match e {
+        zip::result::ZipError::FileNotFound => {
+            io::Error::new(io::ErrorKind::NotFound, format!("Missing {}", name))
+        }
+        e => io::Error::from(e),
+    })?;
+    String::from_utf8(read_limited(entry, MAX_ENTRY_SIZE)?)
+        .map_err(|_| invalid(format!("{} is not UTF-8", name)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn xml_error(path: &str, error: roxmltree::Error) -> io::Error {
+    invalid(format!("Invalid XML in {}: {}", path, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    const RELATIONSHIPS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+  <Relationship Target="/3D/model.model" Id="rel0"
+    Type="http://schemas.microsoft.com/3dmanufacturing/2013/01/3dmodel"/>
+</Relationships>"#;
+
+    /// A triangle in the XY plane facing up, as the mesh of object `id`
+    fn triangle_object(id: u32, name: &str) -> String {
+        format!(
+            r#"<object id="{}" name="{}" type="model"><mesh>
+              <vertices>
+                <vertex x="0" y="0" z="0"/><vertex x="1" y="0" z="0"/><vertex x="0" y="1" z="0"/>
+              </vertices>
+              <triangles><triangle v1="0" v2="1" v3="2"/></triangles>
+            </mesh></object>"#,
+            id, name
+        )
+    }
+
+    fn package(entries: &[(&str, String)]) -> Cursor<Vec<u8>> {
+        let mut cursor = Cursor::new(Vec::new());
+        let mut zip = ZipWriter::new(&mut cursor);
+        for (name, content) in entries {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        Cursor::new(cursor.into_inner())
+    }
+
+    fn model(unit: &str, resources: &str, build: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<model unit="{}" xmlns="http://schemas.microsoft.com/3dmanufacturing/core/2015/02"
+  xmlns:p="http://schemas.microsoft.com/3dmanufacturing/production/2015/06">
+  <resources>{}</resources>
+  <build>{}</build>
+</model>"#,
+            unit, resources, build
+        )
+    }
+
+    #[test]
+    fn test_build_items_with_transforms_and_units() {
+        let resources = format!(
+            r#"{}
+            <object id="2" name="Assembly" type="model"><components>
+              <component objectid="1" transform="1 0 0 0 1 0 0 0 1 0 0 1"/>
+              <component objectid="7" p:path="/3D/Objects/part.model"/>
+            </components></object>"#,
+            triangle_object(1, "Base")
+        );
+        let build = r#"<item objectid="1"/>
+            <item objectid="2" transform="0 1 0 -1 0 0 0 0 1 5 0 0"/>"#;
+        let part = model("centimeter", &triangle_object(7, "Part"), "");
+        let input = package(&[
+            ("_rels/.rels", RELATIONSHIPS.to_string()),
+            ("3D/model.model", model("centimeter", &resources, build)),
+            ("3D/Objects/part.model", part),
+        ]);
+
+        let meshes = read(input).unwrap();
+
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[0].name.as_deref(), Some("Base"));
+        assert_eq!(
+            meshes[0].triangles()[0].vertices,
+            [[0.0, 0.0, 0.0], [10.0, 0.0, 0.0], [0.0, 10.0, 0.0]]
+        );
+
+        // A quarter turn about Z and a move along X, in centimeters
+        assert_eq!(meshes[1].name.as_deref(), Some("Assembly"));
+        let triangles = meshes[1].triangles();
+        assert_eq!(triangles.len(), 2);
+        assert_eq!(
+            triangles[0].vertices,
+            [[50.0, 0.0, 10.0], [50.0, 10.0, 10.0], [40.0, 0.0, 10.0]]
+        );
+        assert_eq!(
+            triangles[1].vertices,
+            [[50.0, 0.0, 0.0], [50.0, 10.0, 0.0], [40.0, 0.0, 0.0]]
+        );
+        for triangle in triangles {
+            assert_eq!(triangle.normal, [0.0, 0.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn test_components_keep_the_unit_of_their_model() {
+        let resources = r#"<object id="2" name="Assembly" type="model"><components>
+              <component objectid="7" p:path="/3D/Objects/part.model"
+                transform="1 0 0 0 1 0 0 0 1 1 0 0"/>
+            </components></object>"#;
+        let part = model("inch", &triangle_object(7, "Part"), "");
+        let input = package(&[
+            ("_rels/.rels", RELATIONSHIPS.to_string()),
+            (
+                "3D/model.model",
+                model("millimeter", resources, r#"<item objectid="2"/>"#),
+            ),
+            ("3D/Objects/part.model", part),
+        ]);
+
+        let meshes = read(input).unwrap();
+
+        // The part is an inch across, moved by a millimeter of the assembly
+        assert_eq!(
+            meshes[0].triangles()[0].vertices,
+            [[1.0, 0.0, 0.0], [26.4, 0.0, 0.0], [1.0, 25.4, 0.0]]
+        );
+    }
+
+    #[test]
+    fn test_mirrored_item_keeps_facing_out() {
+        let input = package(&[(
+            "3D/3dmodel.model",
+            model(
+                "millimeter",
+                &triangle_object(1, "Base"),
+                r#"<item objectid="1" transform="-1 0 0 0 1 0 0 0 1 0 0 0"/>"#,
+            ),
+        )]);
+
+        let meshes = read(input).unwrap();
+
+        let triangle = &meshes[0].triangles()[0];
+        assert_eq!(
+            triangle.vertices,
+            [[0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [-1.0, 0.0, 0.0]]
+        );
+        assert_eq!(triangle.normal, [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let cycle = r#"<object id="1"><components><component objectid="1"/></components></object>"#;
+        for (resources, build, message) in [
+            (
+                "",
+                r#"<item objectid="3"/>"#,
+                "Object 3 doesn't exist in 3D/3dmodel.model",
+            ),
+            (
+                cycle,
+                r#"<item objectid="1"/>"#,
+                "Components of object 1 nest too deep in 3D/3dmodel.model",
+            ),
+            (
+                &triangle_object(1, "Base"),
+                r#"<item objectid="1" transform="1 0 0"/>"#,
+                "Invalid transform 1 0 0 in 3D/3dmodel.model",
+            ),
+            (
+                r#"<object id="1"><mesh><vertices><vertex x="0" y="a" z="0"/></vertices></mesh></object>"#,
+                "",
+                "Invalid y on vertex in 3D/3dmodel.model: a",
+            ),
+        ] {
+            let input = package(&[("3D/3dmodel.model", model("millimeter", resources, build))]);
+            let error = read(input).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), message);
+        }
+
+        let input = package(&[("3D/other.model", String::new())]);
+        assert_eq!(read(input).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
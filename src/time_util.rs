@@ -0,0 +1,61 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Timestamps written into printer files.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds since the unix epoch, 0 if the clock is set before it
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Splits a unix time into a `YYYY-MM-DD` date and a `HH:MM:SS` time in UTC
+pub fn utc_date_time(unix_seconds: u64) -> (String, String) {
+    let days = (unix_seconds / 86_400) as i64;
+    let time_of_day = unix_seconds % 86_400;
+    // Civil date from days since the epoch, see Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        format!("{:04}-{:02}-{:02}", year, month, day),
+        format!(
+            "{:02}:{:02}:{:02}",
+            time_of_day / 3600,
+            time_of_day / 60 % 60,
+            time_of_day % 60
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utc_date_time() {
+        assert_eq!(
+            utc_date_time(0),
+            ("1970-01-01".to_string(), "00:00:00".to_string())
+        );
+        assert_eq!(
+            utc_date_time(1_709_296_205),
+            ("2024-03-01".to_string(), "12:30:05".to_string())
+        );
+        assert_eq!(
+            utc_date_time(4_107_542_399),
+            ("2100-02-28".to_string(), "23:59:59".to_string())
+        );
+    }
+}
@@ -0,0 +1,344 @@
+// Distributed under the GNU Affero General Public License v3.0 or later.
+// See accompanying file LICENSE or https://www.gnu.org/licenses/agpl-3.0.html for details.
+
+//! Writer for the generic zip layout read by open firmwares such as NanoDLP.
+//!
+//! The archive holds a `run.gcode` that drives the printer, a `preview.png` and one
+//! grayscale PNG per layer. The G-code comes from the printer's templates, by default it
+//! shows each layer image with `M6054`, moves the plate to the layer, waits for the resin
+//! to settle, switches the light on with `M106` for the exposure time and lifts the plate.
+
+use crate::gcode::GcodeTemplates;
+use crate::image_exporter::{encode_layer_png, png_entry_options, write_png_layers};
+use crate::output_format::{Capabilities, OutputFormat};
+use crate::printer_profile::PrinterProfile;
+use crate::slice_job::SliceJob;
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// File extensions of generic zip archives
+pub const EXTENSIONS: [&str; 1] = ["zip"];
+pub const PREVIEW_SIZE: (u32, u32) = (400, 300);
+
+/// How hard the layer PNGs are compressed. Better compression is slower to write
+/// but some printers only have a small amount of storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageCompression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+impl From<ImageCompression> for png::Compression {
+    fn from(compression: ImageCompression) -> Self {
+        match compression {
+            ImageCompression::Fast => png::Compression::Fast,
+            ImageCompression::Default => png::Compression::Default,
+            ImageCompression::Best => png::Compression::Best,
+        }
+    }
+}
+
+/// Layout of the archive, set per printer in the printer profile
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ZipGcodeSettings {
+    /// Name of the layer images, `{n}` is replaced by the layer number
+    pub image_name: String,
+    /// Number of the first layer, most firmwares count from 1
+    pub first_layer_number: usize,
+    /// Layer numbers are padded with zeros to at least this many digits
+    pub layer_number_digits: usize,
+    /// Bits per pixel of the layer images, 1, 4 or 8
+    pub bit_depth: u8,
+    pub compression: ImageCompression,
+    /// Templates of `run.gcode`
+    pub gcode: GcodeTemplates,
+}
+
+impl Default for ZipGcodeSettings {
+    fn default() -> Self {
+        Self {
+            image_name: "{n}.png".to_string(),
+            first_layer_number: 1,
+            layer_number_digits: 0,
+            bit_depth: 8,
+            compression: ImageCompression::Default,
+            gcode: GcodeTemplates::default(),
+        }
+    }
+}
+
+impl ZipGcodeSettings {
+    pub fn layer_file_name(&self, layer_index: usize) -> String {
+        let number = format!(
+            "{:0width$}",
+            layer_index + self.first_layer_number,
+            width = self.layer_number_digits
+        );
+        self.image_name.replace("{n}", &number)
+    }
+}
+
+pub struct ZipGcodeFormat;
+
+impl OutputFormat for ZipGcodeFormat {
+    fn name(&self) -> &'static str {
+        "Zip + G-code"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &EXTENSIONS
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            grayscale: true,
+            per_layer_settings: true,
+            thumbnail_sizes: &[PREVIEW_SIZE],
+        }
+    }
+
+    fn write_to_file(&self, job: &SliceJob, path: &Path) -> io::Result<()> {
+        ZipGcodeWriter::for_printer(&job.printer).write_to_file(job, path)
+    }
+}
+
+pub struct ZipGcodeWriter {
+    pub settings: ZipGcodeSettings,
+}
+
+impl ZipGcodeWriter {
+    pub fn for_printer(printer: &PrinterProfile) -> Self {
+        Self {
+            settings: printer.zip_gcode.clone(),
+        }
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, job: &SliceJob, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(job, &mut writer)?;
+        writer.flush()
+    }
+
+    /// Writes the archive
+    pub fn write<W: Write + Seek>(&self, job: &SliceJob, out: W) -> io::Result<()> {
+        let bit_depth = png_bit_depth(self.settings.bit_depth)?;
+        let gcode = generate_gcode(job, &self.settings)?;
+        let compression = png::Compression::from(self.settings.compression);
+        let mut zip = ZipWriter::new(out);
+        let text_options =
+            SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        zip.start_file("run.gcode", text_options)
+            .map_err(io::Error::from)?;
+        zip.write_all(gcode.as_bytes())?;
+
+        let (width, height) = PREVIEW_SIZE;
+        let preview = job.preview(width, height);
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png)
+            .write_image(preview.as_raw(), width, height, ExtendedColorType::Rgb8)
+            .map_err(io::Error::other)?;
+        zip.start_file("preview.png", png_entry_options())
+            .map_err(io::Error::from)?;
+        zip.write_all(&png)?;
+
+        write_png_layers(
+            &mut zip,
+            &job.layers,
+            |layer| encode_layer_png(layer, bit_depth, compression),
+            |index| self.settings.layer_file_name(index),
+        )?;
+
+        zip.finish().map_err(io::Error::from)?;
+        Ok(())
+    }
+}
+
+/// Builds `run.gcode` from the templates and the exposure settings of the job's resin
+pub fn generate_gcode(job: &SliceJob, settings: &ZipGcodeSettings) -> io::Result<String> {
+    settings
+        .gcode
+        .generate(job, |layer_index| settings.layer_file_name(layer_index))
+}
+
+fn png_bit_depth(bits: u8) -> io::Result<png::BitDepth> {
+    match bits {
+        1 => Ok(png::BitDepth::One),
+        4 => Ok(png::BitDepth::Four),
+        8 => Ok(png::BitDepth::Eight),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported layer bit depth: {}", bits),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resin_profile::{ExposureSettings, ResinProfile};
+    use image::{ImageBuffer, Luma};
+    use std::io::{Cursor, Read};
+    use zip::ZipArchive;
+
+    fn test_job(layer_count: usize) -> SliceJob {
+        let layers = (0..layer_count)
+            .map(|i| {
+                ImageBuffer::from_fn(13, 5, |x, y| Luma([((x * 20 + y * 7) as usize + i) as u8]))
+            })
+            .collect();
+        let printer = PrinterProfile {
+            name: "Open MSLA".to_string(),
+            pixel_x: 13,
+            pixel_y: 5,
+            output_format: "zip".to_string(),
+            ..PrinterProfile::default()
+        };
+        let resin = ResinProfile {
+            name: "Test Resin".to_string(),
+            exposure: ExposureSettings {
+                normal_exposure_time: 2.5,
+                bottom_exposure_time: 30.0,
+                bottom_layer_count: 1,
+                light_off_delay: 0.5,
+                ..ExposureSettings::default()
+            },
+        };
+        SliceJob::new(layers, 0.05, printer, resin)
+    }
+
+    fn write_archive(job: &SliceJob, settings: ZipGcodeSettings) -> ZipArchive<Cursor<Vec<u8>>> {
+        let mut cursor = Cursor::new(Vec::new());
+        ZipGcodeWriter { settings }
+            .write(job, &mut cursor)
+            .expect("Writing to memory should succeed");
+        ZipArchive::new(Cursor::new(cursor.into_inner())).expect("Archive should open")
+    }
+
+    fn read_entry(archive: &mut ZipArchive<Cursor<Vec<u8>>>, name: &str) -> Vec<u8> {
+        let mut entry = archive.by_name(name).expect("Entry should exist");
+        let mut bytes = Vec::new();
+        entry.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_entry_names() {
+        let settings = ZipGcodeSettings {
+            image_name: "layer_{n}.png".to_string(),
+            first_layer_number: 0,
+            layer_number_digits: 3,
+            ..ZipGcodeSettings::default()
+        };
+        let archive = write_archive(&test_job(2), settings);
+
+        let names: Vec<&str> = archive.file_names().collect();
+        assert_eq!(names.len(), 4);
+        for name in ["run.gcode", "preview.png", "layer_000.png", "layer_001.png"] {
+            assert!(names.contains(&name), "Missing {}", name);
+        }
+    }
+
+    #[test]
+    fn test_layer_bit_depths() {
+        let job = test_job(1);
+        let layer = &job.layers[0];
+
+        for (bits, expected) in [
+            (8, layer.clone()),
+            (
+                4,
+                ImageBuffer::from_fn(13, 5, |x, y| Luma([(layer.get_pixel(x, y)[0] >> 4) * 17])),
+            ),
+            (
+                1,
+                ImageBuffer::from_fn(13, 5, |x, y| {
+                    Luma([if layer.get_pixel(x, y)[0] >= 128 {
+                        255
+                    } else {
+                        0
+                    }])
+                }),
+            ),
+        ] {
+            let settings = ZipGcodeSettings {
+                bit_depth: bits,
+                compression: ImageCompression::Best,
+                ..ZipGcodeSettings::default()
+            };
+            let mut archive = write_archive(&job, settings);
+
+            let png = read_entry(&mut archive, "1.png");
+            let decoded = image::load_from_memory(&png).unwrap().into_luma8();
+            assert_eq!(decoded, expected, "{} bit layer differs", bits);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_bit_depth() {
+        let writer = ZipGcodeWriter {
+            settings: ZipGcodeSettings {
+                bit_depth: 3,
+                ..ZipGcodeSettings::default()
+            },
+        };
+        let result = writer.write(&test_job(1), Cursor::new(Vec::new()));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_gcode_follows_exposure_settings() {
+        let job = test_job(2);
+        let settings = ZipGcodeSettings::default();
+        let mut archive = write_archive(&job, settings.clone());
+
+        let gcode = String::from_utf8(read_entry(&mut archive, "run.gcode")).unwrap();
+        assert_eq!(gcode, generate_gcode(&job, &settings).unwrap());
+
+        let layers: Vec<&str> = gcode
+            .split(";LAYER_START:")
+            .skip(1)
+            .map(|layer| layer.split(";LAYER_END").next().unwrap())
+            .collect();
+        assert_eq!(layers.len(), 2);
+        assert_eq!(
+            layers[0],
+            "0\n\
+             ;currPos:0.050\n\
+             M6054 \"1.png\" ;Show layer image\n\
+             G0 Z0.050 F150 ;Move to layer\n\
+             G4 P500 ;Wait for the resin to settle\n\
+             M106 S255 ;Light on\n\
+             G4 P30000 ;Expose\n\
+             M106 S0 ;Light off\n\
+             G0 Z6.050 F45 ;Lift\n"
+        );
+        assert!(layers[1].contains("M6054 \"2.png\""));
+        assert!(layers[1].contains("G0 Z0.100 F150 ;Move to layer"));
+        assert!(layers[1].contains("G4 P2500 ;Expose"));
+        assert!(layers[1].contains("G0 Z6.100 F65 ;Lift"));
+        assert!(gcode.ends_with("G0 Z220.000 F65 ;Raise the build plate\nM18 ;Disable motors\n"));
+    }
+
+    #[test]
+    fn test_settings_from_json() {
+        let json = r#"{ "zip_gcode": { "image_name": "{n}.png", "bit_depth": 1, "compression": "best" } }"#;
+
+        let printer = PrinterProfile::from_json(json).expect("Profile should parse");
+
+        assert_eq!(printer.zip_gcode.bit_depth, 1);
+        assert_eq!(printer.zip_gcode.compression, ImageCompression::Best);
+        assert_eq!(printer.zip_gcode.first_layer_number, 1);
+        assert_eq!(printer.zip_gcode.layer_file_name(41), "42.png");
+    }
+}
@@ -0,0 +1,53 @@
+;Header generated by SealSlicer
+;fileName:ChiTu Zip MSLA
+;machineType:ChiTu Zip MSLA
+;resolutionX:16
+;resolutionY:9
+;layerHeight:0.05
+;totalLayer:3
+;bottomLayCount:1
+;bottomLayExposureTime:30
+;normalExposureTime:2.5
+;estimatedPrintTime:62
+;volume:1.914
+;START_GCODE_BEGIN
+G21;
+G90;
+M106 S0;
+G28 Z0;
+;START_GCODE_END
+;LAYER_START:0
+;currPos:0.050
+M6054 "1.png";show Image
+G0 Z6.050 F45;
+G0 Z0.050 F150;
+G4 P500;
+M106 S255;light on
+G4 P30000;
+M106 S0;light off
+;LAYER_END
+;LAYER_START:1
+;currPos:0.100
+M6054 "2.png";show Image
+G0 Z6.100 F65;
+G0 Z0.100 F150;
+G4 P500;
+M106 S255;light on
+G4 P2500;
+M106 S0;light off
+;LAYER_END
+;LAYER_START:2
+;currPos:0.150
+M6054 "3.png";show Image
+G0 Z6.150 F65;
+G0 Z0.150 F150;
+G4 P500;
+M106 S255;light on
+G4 P2500;
+M106 S0;light off
+;LAYER_END
+;END_GCODE_BEGIN
+M106 S0;
+G1 Z220.000 F65;
+M18;
+;END_GCODE_END
@@ -0,0 +1,40 @@
+;(****Build and Slicing Parameters****)
+;(Printer                 = Creation Workshop MSLA )
+;(X Resolution            = 16 )
+;(Y Resolution            = 9 )
+;(Layer Thickness         = 0.05 mm )
+;(Number of Slices        = 3 )
+;(Bottom Layers Count     = 1 )
+;(Layer Time              = 2.5 s )
+;(Bottom Layers Time      = 30 s )
+G21 ;Set units to be mm
+G91 ;Relative Positioning
+M17 ;Enable motors
+M106 S0
+;<Slice> 0
+M106 S255
+;<Delay> 30000
+M106 S0
+;<Slice> Blank
+G1 Z6 F45
+G1 Z-5.950 F150
+;<Delay> 500
+;<Slice> 1
+M106 S255
+;<Delay> 2500
+M106 S0
+;<Slice> Blank
+G1 Z6 F65
+G1 Z-5.950 F150
+;<Delay> 500
+;<Slice> 2
+M106 S255
+;<Delay> 2500
+M106 S0
+;<Slice> Blank
+G1 Z6 F65
+G1 Z-5.950 F150
+;<Delay> 500
+M106 S0
+M18 ;Disable motors
+;<Completed>
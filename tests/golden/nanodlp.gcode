@@ -0,0 +1,48 @@
+;printer:Generic MSLA
+;resolutionX:16
+;resolutionY:9
+;layerHeight:0.05
+;totalLayer:3
+;bottomLayerCount:1
+;bottomExposureTime:30
+;normalExposureTime:2.5
+;estimatedPrintTime:62
+;volume:1.914
+G21 ;Set units to millimeters
+G90 ;Use absolute positioning
+M17 ;Enable motors
+M106 S0 ;Light off
+G28 Z0 ;Home the build plate
+;LAYER_START:0
+;currPos:0.050
+M6054 "1.png" ;Show layer image
+G0 Z0.050 F150 ;Move to layer
+G4 P500 ;Wait for the resin to settle
+M106 S255 ;Light on
+G4 P30000 ;Expose
+M106 S0 ;Light off
+G0 Z6.050 F45 ;Lift
+;LAYER_END
+;LAYER_START:1
+;currPos:0.100
+M6054 "2.png" ;Show layer image
+G0 Z0.100 F150 ;Move to layer
+G4 P500 ;Wait for the resin to settle
+M106 S255 ;Light on
+G4 P2500 ;Expose
+M106 S0 ;Light off
+G0 Z6.100 F65 ;Lift
+;LAYER_END
+;LAYER_START:2
+;currPos:0.150
+M6054 "3.png" ;Show layer image
+G0 Z0.150 F150 ;Move to layer
+G4 P500 ;Wait for the resin to settle
+M106 S255 ;Light on
+G4 P2500 ;Expose
+M106 S0 ;Light off
+G0 Z6.150 F65 ;Lift
+;LAYER_END
+M106 S0 ;Light off
+G0 Z220.000 F65 ;Raise the build plate
+M18 ;Disable motors